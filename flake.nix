@@ -66,6 +66,7 @@
           rustToolchain
           pkgs.qemu_kvm
           pkgs.tmux # needed for integration test
+          pkgs.zstd # decompresses coredumps in tests
           (pkgs.python3.withPackages (ps: [
             ps.pytest
             ps.pytest-xdist
@@ -14,7 +14,8 @@ buildDiskImage {
   extraCommands = ''
     pushd root
     ln -s ${busybox}/bin bin
-    mkdir -p proc dev tmp sys
+    # mountpoint of the old root, needed if the image is attached with --read-only
+    mkdir -p proc dev tmp sys var/lib/vmsh
     popd
   '';
 }
@@ -1,76 +1,333 @@
+use ioutils::block::mount_env;
+use ioutils::exec::EXEC_ENV;
+use ioutils::share::{SHARE_ENV, SHARE_TAG};
 use log::{error, info};
-use nix::unistd::Pid;
-use simple_error::{require_with, try_with};
-use std::fs::read_to_string;
-use std::path::PathBuf;
-use std::sync::mpsc::sync_channel;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{getpid, Pid};
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, require_with, try_with};
+use std::path::{Component, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::devices::use_ioregionfd;
-use crate::devices::DeviceSet;
+use crate::control::{Control, Status};
+use crate::devices::virtio::console::{ConsoleBackend, ExecStatus, RawTerminal};
+use crate::devices::virtio::vsock::VsockOptions;
+use crate::devices::{DeviceOptions, DeviceSet, DriverNotifier, MmioBackend, Threads};
+use crate::interrutable_thread::InterrutableThread;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::kvm::{irq, AllocatorState};
 use crate::result::Result;
-use crate::stage1::Stage1;
+use crate::session::{self, DetachedState, Session, SessionDevices};
+use crate::stage1::{DeviceStatus, DriverStatus, Stage1, Stage1State};
 use crate::{kvm, signal_handler};
 
+/// Where stage1 writes stage2 to in the VM unless told otherwise.
+pub const DEFAULT_STAGE2_PATH: &str = "/dev/.vmsh";
+
+/// How long `detach` waits for the vmsh process to save its session.
+const DETACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Splits the `:<option>` suffixes accepted by `is_option` off `s`. Everything before the
+/// first unknown suffix belongs to the path, so paths may contain ':'.
+fn split_options(s: &str, is_option: impl Fn(&str) -> bool) -> (&str, Vec<&str>) {
+    let mut path = s;
+    let mut options = vec![];
+    while let Some((rest, option)) = path.rsplit_once(':') {
+        if !is_option(option) {
+            break;
+        }
+        options.push(option);
+        path = rest;
+    }
+    options.reverse();
+    (path, options)
+}
+
+/// Suffix of backing files and shared directories that are served read-only.
+const READ_ONLY_OPTION: &str = "ro";
+/// Suffix prefix of backing files that sets their mountpoint.
+const MOUNT_OPTION: &str = "mount=";
+
+/// A file served as block device and where stage2 mounts it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackingFile {
+    pub path: PathBuf,
+    /// relative to /var/lib/vmsh, must not be set for the first backing file,
+    /// which is the root of the attached environment unless a directory is shared.
+    pub mountpoint: Option<PathBuf>,
+    pub read_only: bool,
+}
+
+impl BackingFile {
+    pub fn new(path: impl Into<PathBuf>) -> BackingFile {
+        BackingFile {
+            path: path.into(),
+            mountpoint: None,
+            read_only: false,
+        }
+    }
+}
+
+impl FromStr for BackingFile {
+    type Err = String;
+
+    /// Parses `<path>[:mount=<mountpoint>][:ro]`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (path, options) =
+            split_options(s, |o| o == READ_ONLY_OPTION || o.starts_with(MOUNT_OPTION));
+        let mut backing = BackingFile::new(path);
+        for option in options {
+            if option == READ_ONLY_OPTION {
+                backing.read_only = true;
+                continue;
+            }
+            let mountpoint = PathBuf::from(&option[MOUNT_OPTION.len()..]);
+            if mountpoint.as_os_str().is_empty()
+                || !mountpoint
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(format!(
+                    "mountpoint '{}' must be a relative path without '..'",
+                    mountpoint.display()
+                ));
+            }
+            backing.mountpoint = Some(mountpoint);
+        }
+        Ok(backing)
+    }
+}
+
+/// A host directory exported over 9p as root of the attached environment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedDir {
+    pub path: PathBuf,
+    pub read_only: bool,
+}
+
+impl FromStr for SharedDir {
+    type Err = String;
+
+    /// Parses `<path>[:ro]`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (path, options) = split_options(s, |o| o == READ_ONLY_OPTION);
+        Ok(SharedDir {
+            path: PathBuf::from(path),
+            read_only: !options.is_empty(),
+        })
+    }
+}
+
 pub struct AttachOptions {
     pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
     pub command: Vec<String>,
-    pub backing: PathBuf,
-    pub pts: Option<PathBuf>,
-}
-
-pub fn get_irq_num(pid: Pid) -> Result<usize> {
-    let mut comm_path = PathBuf::from("/proc");
-    comm_path.push(pid.as_raw().to_string());
-    comm_path.push("comm");
-    let comm = try_with!(
-        read_to_string(&comm_path),
-        "failed to read {}",
-        comm_path.display()
-    );
-    // dirty hack until we have a better way to find out what IRQs we can use
-    if comm.contains("crosvm") {
-        Ok(4)
-    } else {
-        Ok(6)
-    }
+    /// block devices, the first one is the root of the attached environment unless `share` is set
+    pub backing: Vec<BackingFile>,
+    /// host directory used as root of the attached environment instead of a block device
+    pub share: Option<SharedDir>,
+    /// ignored by `exec`, which forwards the output of the command to our stdout and stderr
+    pub console: ConsoleBackend,
+    /// interrupt line used by our devices, detected automatically if not set
+    pub irq: Option<usize>,
+    /// expose a vsock device to the guest, connected to a unix socket on the host
+    pub vsock: Option<VsockOptions>,
+    pub mmio: MmioBackend,
+}
+
+/// Options of `vmsh reattach`. The devices are created with the options of the detached session.
+pub struct ReattachOptions {
+    pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub console: ConsoleBackend,
+    pub mmio: MmioBackend,
 }
 
 pub fn attach(opts: &AttachOptions) -> Result<()> {
-    info!("attaching");
+    let (control, receiver) = Control::new();
+    signal_handler::setup(&control)?;
+    signal_handler::setup_detach()?;
+    run(opts, None, &control, receiver, true)
+}
 
-    let (sender, receiver) = sync_channel(1);
+/// Like `attach` but stopped through `control` instead of signals, i.e. by `vmsh daemon` or a
+/// `SessionHandle`.
+/// Such attachments cannot be detached.
+pub fn attach_controlled(
+    opts: &AttachOptions,
+    control: &Arc<Control>,
+    receiver: Receiver<()>,
+) -> Result<()> {
+    let res = run(opts, None, control, receiver, false);
+    control.set_status(match &res {
+        Ok(()) => Status::Stopped,
+        Err(e) => Status::Failed(e.to_string()),
+    });
+    res
+}
 
-    signal_handler::setup(&sender)?;
+/// Runs the command non-interactively and returns its exit code.
+/// Output of the command is written to our stdout and stderr.
+pub fn exec(opts: &AttachOptions) -> Result<i32> {
+    let status = Arc::new(ExecStatus::default());
+    let (control, receiver) = Control::new();
+    signal_handler::setup(&control)?;
+    // the output of `vmsh exec` cannot be reattached
+    run(opts, Some(Arc::clone(&status)), &control, receiver, false)?;
+    match (status.exit_code(), status.error()) {
+        (Some(code), _) => Ok(code),
+        (None, Some(e)) => bail!("{}", e),
+        (None, None) => bail!("vmsh stopped before the command finished"),
+    }
+}
 
+/// Stops the vmsh process attached to the VM but leaves its devices and the command in the VM
+/// running, so that `reattach` can take over.
+pub fn detach(pid: Pid, vm: Option<VmSelector>) -> Result<()> {
+    let path = session::find_session(pid, vm)?;
+    let session = require_with!(
+        Session::load(&path)?,
+        "no vmsh session found for process {}",
+        pid
+    );
+    let vmsh_pid = require_with!(
+        session.vmsh_pid,
+        "vmsh session of process {} is already detached",
+        pid
+    );
+    try_with!(
+        kill(Pid::from_raw(vmsh_pid), Signal::SIGUSR1),
+        "cannot signal vmsh process {}",
+        vmsh_pid
+    );
+
+    let start = Instant::now();
+    while start.elapsed() < DETACH_TIMEOUT {
+        match Session::load(&path)? {
+            Some(session) if session.detached.is_some() => {
+                info!("detached vmsh process {}", vmsh_pid);
+                return Ok(());
+            }
+            Some(_) => {}
+            None => bail!("vmsh process {} stopped without detaching", vmsh_pid),
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    bail!("vmsh process {} did not detach in time", vmsh_pid)
+}
+
+/// Stops and ptraces the hypervisor. Returns the mmio backend to use, which KVM supports.
+fn stop_vm(
+    pid: Pid,
+    vm: Option<VmSelector>,
+    mmio: MmioBackend,
+) -> Result<(Arc<Hypervisor>, MmioBackend)> {
     let mut vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
+        kvm::hypervisor::select_hypervisor(pid, vm),
         "cannot get vms for process {}",
-        opts.pid
+        pid
     );
     vm.stop()?;
+    let mmio = mmio.resolve(&vm)?;
+    if mmio == MmioBackend::WrapSyscall && vm.vcpus.iter().any(|vcpu| vcpu.vcpu_map.is_none()) {
+        bail!("--mmio wrap_syscall needs the mapped memory of all vcpus, try --mmio ioregionfd");
+    }
     try_with!(
         vm.setup_transfer_sockets(),
         "failed to setup unix sockets for fd transfer"
     );
-    let vm = Arc::new(vm);
+    Ok((Arc::new(vm), mmio))
+}
+
+fn run(
+    opts: &AttachOptions,
+    exec_status: Option<Arc<ExecStatus>>,
+    control: &Arc<Control>,
+    receiver: Receiver<()>,
+    detachable: bool,
+) -> Result<()> {
+    info!("attaching");
+
+    let (vm, mmio) = stop_vm(opts.pid, opts.vm, opts.mmio)?;
+
+    let session_path = session::session_path(vm.pid, vm.vm_fd);
+    if let Some(session) = Session::load(&session_path)? {
+        match session.vmsh_pid {
+            Some(pid) => bail!("vmsh process {} is already attached to the vm", pid),
+            None => bail!("the vm has a detached vmsh session, use `vmsh reattach`"),
+        }
+    }
 
     let mut allocator = try_with!(
         kvm::PhysMemAllocator::new(Arc::clone(&vm)),
         "cannot create allocator"
     );
 
-    let irq_num = try_with!(get_irq_num(opts.pid), "failed to get irq num");
+    let irq_num = match opts.irq {
+        Some(irq) => {
+            irq::check_irq(&vm, irq)?;
+            irq
+        }
+        None => try_with!(irq::find_free_irq(&vm), "failed to find free irq"),
+    };
+    info!("use irq {} for devices", irq_num);
+
+    if opts.share.is_none() {
+        if opts.backing.is_empty() {
+            bail!("no backing file given");
+        }
+        if let Some(mountpoint) = &opts.backing[0].mountpoint {
+            bail!(
+                "the first backing file is mounted as root, it cannot be mounted at {}",
+                mountpoint.display()
+            );
+        }
+    }
+
+    let mut environment = vec![];
+    if exec_status.is_some() {
+        environment.push(format!("{}=1", EXEC_ENV));
+    }
+    if opts.share.is_some() {
+        environment.push(format!("{}={}", SHARE_ENV, SHARE_TAG));
+    }
+    // the shared directory takes the place of the first block device
+    let first_idx = usize::from(opts.share.is_some());
+    for (idx, backing) in opts.backing.iter().enumerate() {
+        let idx = idx + first_idx;
+        if idx == 0 {
+            continue;
+        }
+        let mountpoint = backing
+            .mountpoint
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(format!("disk{}", idx)));
+        environment.push(format!("{}={}", mount_env(idx), mountpoint.display()));
+    }
+    let stdio_console = exec_status.is_none() && opts.console == ConsoleBackend::Stdio;
+    let session_devices = SessionDevices {
+        backing: opts.backing.clone(),
+        share: opts.share.clone(),
+        vsock: opts.vsock.clone(),
+        irq_num,
+    };
+    let device_opts = DeviceOptions {
+        backing: session_devices.backing.clone(),
+        share: session_devices.share.clone(),
+        console: opts.console.clone(),
+        exec_status,
+        vsock: session_devices.vsock.clone(),
+        mmio,
+        control: Arc::clone(control),
+    };
 
     let devices = try_with!(
-        DeviceSet::new(
-            &vm,
-            &mut allocator,
-            irq_num,
-            &opts.backing,
-            opts.pts.clone()
-        ),
+        DeviceSet::new(&vm, &mut allocator, irq_num, device_opts),
         "cannot create devices"
     );
 
@@ -80,31 +337,214 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
 
     let addrs = devices.mmio_addrs()?;
     let mut stage1 = try_with!(
-        Stage1::new(allocator, &opts.command, irq_num, addrs),
+        Stage1::new(&mut allocator, &opts.command, &environment, irq_num, addrs),
         "failed to initialize stage1"
     );
     let driver_status = require_with!(stage1.driver_status.take(), "no driver status set");
     let stage1_thread = try_with!(
-        stage1.spawn(Arc::clone(&vm), driver_status.clone(), &sender),
+        stage1.spawn(Arc::clone(&vm), driver_status.clone(), control.sender()),
         "failed to spawn stage1"
     );
     let device_status = require_with!(stage1.device_status.take(), "device status is not set");
+    let context = devices.context();
     let (threads, driver_notifier) = try_with!(
-        devices.start(&vm, device_status, driver_status, &sender),
+        devices.start(
+            &vm,
+            device_status.clone(),
+            driver_status.clone(),
+            None,
+            control.sender()
+        ),
         "failed to start devices"
     );
 
     info!("blkdev queue ready.");
-    drop(sender);
+    control.set_devices(Some(context));
+    control.set_status(Status::Running);
+
+    let session = if detachable {
+        let session = Session {
+            vmsh_pid: Some(getpid().as_raw()),
+            devices: session_devices,
+            detached: None,
+        };
+        session.save(&session_path)?;
+        Some((session_path, session))
+    } else {
+        None
+    };
+
+    serve(
+        Running {
+            vm,
+            stage1: LoadedStage1::Loaded(stage1),
+            stage1_thread: Some(stage1_thread),
+            threads,
+            driver_notifier,
+            device_status,
+            driver_status,
+            allocator: allocator.state(),
+            mmio,
+            session,
+        },
+        control,
+        receiver,
+        stdio_console,
+    )
+}
+
+/// Takes over the devices and the command left behind by `detach`.
+pub fn reattach(opts: &ReattachOptions) -> Result<()> {
+    info!("reattaching");
+
+    let (control, receiver) = Control::new();
+    signal_handler::setup(&control)?;
+    signal_handler::setup_detach()?;
+
+    let (vm, mmio) = stop_vm(opts.pid, opts.vm, opts.mmio)?;
+
+    let session_path = session::session_path(vm.pid, vm.vm_fd);
+    let mut session = require_with!(
+        Session::load(&session_path)?,
+        "no vmsh session found for the vm"
+    );
+    if let Some(pid) = session.vmsh_pid {
+        bail!("vmsh process {} is still attached to the vm", pid);
+    }
+    let detached = require_with!(session.detached.take(), "vmsh session is not detached");
+    detached.check(vm.pid)?;
+
+    let mut allocator = try_with!(
+        kvm::PhysMemAllocator::resume(Arc::clone(&vm), &detached.allocator),
+        "cannot resume allocator"
+    );
+    let device_opts = DeviceOptions {
+        backing: session.devices.backing.clone(),
+        share: session.devices.share.clone(),
+        console: opts.console.clone(),
+        exec_status: None,
+        vsock: session.devices.vsock.clone(),
+        mmio,
+        control: Arc::clone(&control),
+    };
+    let devices = try_with!(
+        DeviceSet::new(&vm, &mut allocator, session.devices.irq_num, device_opts),
+        "cannot create devices"
+    );
+
+    if receiver.recv_timeout(Duration::from_millis(0)).is_ok() {
+        return Ok(());
+    }
+
+    // stage1 is still running in the VM and waits for our devices
+    let device_status = detached.stage1.device_status();
+    let driver_status = detached.stage1.driver_status();
+    let context = devices.context();
+    let (threads, driver_notifier) = try_with!(
+        devices.start(
+            &vm,
+            device_status.clone(),
+            driver_status.clone(),
+            Some(&detached.virtio),
+            control.sender()
+        ),
+        "failed to restore devices"
+    );
+
+    info!("devices restored.");
+    control.set_devices(Some(context));
+    control.set_status(Status::Running);
+
+    session.vmsh_pid = Some(getpid().as_raw());
+    session.save(&session_path)?;
+
+    serve(
+        Running {
+            vm,
+            stage1: LoadedStage1::Detached(detached.stage1),
+            stage1_thread: None,
+            threads,
+            driver_notifier,
+            device_status,
+            driver_status,
+            allocator: allocator.state(),
+            mmio,
+            session: Some((session_path, session)),
+        },
+        &control,
+        receiver,
+        opts.console == ConsoleBackend::Stdio,
+    )
+}
+
+/// Stage1 in the VM, either loaded by us or left behind by a detached vmsh process.
+enum LoadedStage1 {
+    Loaded(Stage1),
+    Detached(Stage1State),
+}
+
+/// Everything needed to stop or detach once the devices are running.
+struct Running {
+    vm: Arc<Hypervisor>,
+    stage1: LoadedStage1,
+    stage1_thread: Option<InterrutableThread<(), ()>>,
+    threads: Threads,
+    driver_notifier: Arc<DriverNotifier>,
+    device_status: DeviceStatus,
+    driver_status: DriverStatus,
+    allocator: AllocatorState,
+    mmio: MmioBackend,
+    /// None if the session cannot be detached
+    session: Option<(PathBuf, Session)>,
+}
+
+fn serve(
+    running: Running,
+    control: &Control,
+    receiver: Receiver<()>,
+    stdio_console: bool,
+) -> Result<()> {
+    let Running {
+        vm,
+        stage1,
+        stage1_thread,
+        threads,
+        driver_notifier,
+        device_status,
+        driver_status,
+        allocator,
+        mmio,
+        session,
+    } = running;
+
+    let raw_terminal = if stdio_console {
+        info!("connected to the console, press Ctrl-] to stop vmsh");
+        Some(try_with!(
+            RawTerminal::new(libc::STDIN_FILENO),
+            "cannot use stdin as console"
+        ))
+    } else {
+        None
+    };
 
     // termination wait or vmsh_stop()
     let _ = receiver.recv();
-    stage1_thread.shutdown();
-    if let Err(e) = stage1_thread.join() {
-        error!("{}", e);
-    };
-    if let Err(e) = driver_notifier.terminate() {
-        error!("failed to stop device: {}", e);
+    control.set_status(Status::Stopping);
+    drop(raw_terminal);
+    // from here on only we may hold the devices, they need the tracer to clean up
+    control.set_devices(None);
+    let detach = session.is_some() && control.detach_requested();
+    if let Some(stage1_thread) = stage1_thread {
+        stage1_thread.shutdown();
+        if let Err(e) = stage1_thread.join() {
+            error!("{}", e);
+        };
+    }
+    // the devices in the VM stay in place when we detach
+    if !detach {
+        if let Err(e) = driver_notifier.terminate() {
+            error!("failed to stop device: {}", e);
+        }
     }
     threads.iter().for_each(|t| t.shutdown());
     let contexts = threads
@@ -121,17 +561,130 @@ pub fn attach(opts: &AttachOptions) -> Result<()> {
         })
         .collect::<Vec<_>>();
 
+    let virtio = if detach {
+        match contexts.iter().flatten().next() {
+            Some(ctx) => match ctx.save_virtio() {
+                Ok(virtio) => Some(virtio),
+                Err(e) => {
+                    error!("cannot detach, stopping devices instead: {}", e);
+                    None
+                }
+            },
+            None => {
+                error!("cannot detach, devices are gone");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if detach && virtio.is_none() {
+        if let Err(e) = driver_notifier.terminate() {
+            error!("failed to stop device: {}", e);
+        }
+    }
+
     // MMIO exit handler thread took over pthread control
     // We need ptrace the process again before we can finish.
     vm.stop()?;
-    if !use_ioregionfd() {
+    if mmio == MmioBackend::WrapSyscall {
         vm.finish_thread_transfer()?;
     }
     // now that we got the tracer back, we can cleanup physical memory and file descriptors
-    drop(stage1);
+    match (virtio, session) {
+        (Some(virtio), Some((path, mut session))) => {
+            let stage1 = match stage1 {
+                LoadedStage1::Loaded(stage1) => stage1.detach(&device_status, &driver_status),
+                LoadedStage1::Detached(stage1) => stage1,
+            };
+            session.vmsh_pid = None;
+            session.detached = Some(DetachedState {
+                allocator,
+                stage1,
+                virtio,
+            });
+            session.save(&path)?;
+            info!("detached from vm, continue with `vmsh reattach {}`", vm.pid);
+        }
+        (_, session) => {
+            match stage1 {
+                LoadedStage1::Loaded(stage1) => drop(stage1),
+                LoadedStage1::Detached(stage1) => {
+                    drop(try_with!(
+                        stage1.reclaim(Arc::clone(&vm)),
+                        "cannot free memory of stage1"
+                    ));
+                }
+            }
+            if let Some((path, _)) = session {
+                Session::remove(&path)?;
+            }
+        }
+    }
     drop(contexts);
     try_with!(vm.close_transfer_sockets(), "cannot close transfer sockets");
     vm.resume()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BackingFile, SharedDir};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_backing_file() {
+        assert_eq!(
+            "/tmp/disk.img".parse::<BackingFile>(),
+            Ok(BackingFile::new("/tmp/disk.img"))
+        );
+        assert_eq!(
+            "/tmp/disk.img:mount=data/disk".parse::<BackingFile>(),
+            Ok(BackingFile {
+                path: PathBuf::from("/tmp/disk.img"),
+                mountpoint: Some(PathBuf::from("data/disk")),
+                read_only: false,
+            })
+        );
+        assert_eq!(
+            "/tmp/disk.img:mount=data:ro".parse::<BackingFile>(),
+            Ok(BackingFile {
+                path: PathBuf::from("/tmp/disk.img"),
+                mountpoint: Some(PathBuf::from("data")),
+                read_only: true,
+            })
+        );
+        assert_eq!(
+            "/tmp/a:b.img:ro".parse::<BackingFile>(),
+            Ok(BackingFile {
+                path: PathBuf::from("/tmp/a:b.img"),
+                mountpoint: None,
+                read_only: true,
+            })
+        );
+        assert!("/tmp/disk.img:mount=/data".parse::<BackingFile>().is_err());
+        assert!("/tmp/disk.img:mount=../data"
+            .parse::<BackingFile>()
+            .is_err());
+        assert!("/tmp/disk.img:mount=".parse::<BackingFile>().is_err());
+    }
+
+    #[test]
+    fn test_parse_shared_dir() {
+        assert_eq!(
+            "/srv/a:b".parse::<SharedDir>(),
+            Ok(SharedDir {
+                path: PathBuf::from("/srv/a:b"),
+                read_only: false,
+            })
+        );
+        assert_eq!(
+            "/srv:ro".parse::<SharedDir>(),
+            Ok(SharedDir {
+                path: PathBuf::from("/srv"),
+                read_only: true,
+            })
+        );
+    }
+}
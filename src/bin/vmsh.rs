@@ -1,15 +1,25 @@
 use log::*;
 use std::path::PathBuf;
-use std::sync::atomic::Ordering;
 
-use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgMatches};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, ArgMatches};
 use nix::unistd::Pid;
 
-use vmsh::attach::{self, AttachOptions};
-use vmsh::coredump::CoredumpOptions;
-use vmsh::devices::USE_IOREGIONFD;
+use vmsh::attach::{
+    self, AttachOptions, BackingFile, ReattachOptions, SharedDir, DEFAULT_STAGE2_PATH,
+};
+use vmsh::coredump::{CoredumpOptions, CoredumpOutput};
+use vmsh::daemon::DaemonOptions;
+use vmsh::devices::virtio::console::{stdin_is_terminal, ConsoleBackend};
+use vmsh::devices::virtio::vsock::VsockOptions;
+use vmsh::devices::MmioBackend;
+use vmsh::gdbserver::{GdbListen, GdbServerOptions};
 use vmsh::inspect::InspectOptions;
-use vmsh::{coredump, inspect};
+use vmsh::kvm::hypervisor::VmSelector;
+use vmsh::list::ListOptions;
+use vmsh::mem::{GuestAddress, MemReadOptions, MemWriteOptions};
+use vmsh::snapshot::SnapshotOptions;
+use vmsh::translate::TranslateOptions;
+use vmsh::{coredump, daemon, gdbserver, inspect, list, mem, snapshot, translate};
 
 const VM_TYPES: &[&str] = &["process_id", "kubernetes", "vhive", "vhive_fc_vmid"];
 
@@ -44,6 +54,22 @@ fn vmid_type_arg() -> Arg<'static> {
         .possible_values(VM_TYPES)
 }
 
+fn vm_selector_arg() -> Arg<'static> {
+    Arg::new("vm")
+        .long("vm")
+        .takes_value(true)
+        .value_name("SELECTOR")
+        .help("Index or fd:<num> of the VM if the hypervisor runs more than one VM")
+}
+
+fn parse_vm_selector_arg(args: &ArgMatches) -> Option<VmSelector> {
+    if args.is_present("vm") {
+        Some(args.value_of_t_or_exit("vm"))
+    } else {
+        None
+    }
+}
+
 fn parse_vmid_arg(args: &ArgMatches) -> Pid {
     let mut container_types = vec![];
     if args.is_present("type") {
@@ -74,9 +100,137 @@ fn command_args(index: usize) -> Arg<'static> {
         .index(index)
 }
 
+fn mmio_arg() -> Arg<'static> {
+    Arg::new("mmio")
+        .long("mmio")
+        .takes_value(true)
+        .possible_values(&["wrap_syscall", "ioregionfd", "auto"])
+        .default_value("wrap_syscall")
+        .long_help(
+            "Backend used to serve Virtio MMIO memory of devices. \
+            auto uses ioregionfd if KVM supports it and wrap_syscall otherwise.",
+        )
+}
+
+fn mmio_backend(args: &ArgMatches) -> MmioBackend {
+    args.value_of_t_or_exit("mmio")
+}
+
+fn pts_arg() -> Arg<'static> {
+    Arg::new("pts")
+        .long("pts")
+        .takes_value(true)
+        .help("Pseudoterminal seat to use for the command run in the VM. Use this when interactivity is required. ")
+}
+
+fn console_socket_arg() -> Arg<'static> {
+    Arg::new("console-socket")
+        .long("console-socket")
+        .takes_value(true)
+        .value_name("PATH")
+        .conflicts_with("pts")
+        .help("Expose the console of the command as unix socket at PATH")
+        .long_help(
+            "Expose the console of the command as unix socket at PATH. \
+            One client at a time is connected to the console, a new client replaces the previous one. \
+            Without this option or --pts, vmsh uses its own stdin and stdout as console \
+            if stdin is a terminal. Press Ctrl-] to stop vmsh in this case.",
+        )
+}
+
+fn console_backend(args: &ArgMatches) -> ConsoleBackend {
+    if let Some(pts) = args.value_of("pts") {
+        ConsoleBackend::Pts(PathBuf::from(pts))
+    } else if let Some(path) = args.value_of("console-socket") {
+        ConsoleBackend::Socket(PathBuf::from(path))
+    } else if stdin_is_terminal() {
+        ConsoleBackend::Stdio
+    } else {
+        ConsoleBackend::Log
+    }
+}
+
+/// Arguments shared by all subcommands that attach devices to the VM
+fn attach_app(name: &'static str) -> App<'static> {
+    App::new(name)
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(vmid_arg(1))
+        .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
+        .arg(
+            Arg::new("stage2-path")
+                .long("stage2-path")
+                .takes_value(true)
+                .default_value(DEFAULT_STAGE2_PATH)
+                .help("Path where Stage2 is written to in the VM"),
+        )
+        .arg(command_args(2))
+        .arg(
+            Arg::new("backing-file")
+                .short('f')
+                .long("backing-file")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("FILE[:mount=MOUNTPOINT][:ro]")
+                .default_value("/dev/null")
+                .help("File which shall be served as a block device. Can be repeated.")
+                .long_help(
+                    "File which shall be served as a block device. Can be repeated. \
+                    The first file is the root of the attached environment, \
+                    further files are mounted at MOUNTPOINT below /var/lib/vmsh (default: disk<n>). \
+                    If --share is given, all files are mounted below /var/lib/vmsh. \
+                    Files with the suffix :ro are served read-only.",
+                ),
+        )
+        .arg(
+            Arg::new("share")
+                .long("share")
+                .takes_value(true)
+                .value_name("DIR[:ro]")
+                .help("Share a host directory over virtio-9p as root of the attached environment")
+                .long_help(
+                    "Share a host directory over virtio-9p as root of the attached environment \
+                    instead of the first backing file. This avoids building a filesystem image. \
+                    The root of the VM is still found at /var/lib/vmsh. \
+                    With the suffix :ro the directory is served read-only.",
+                ),
+        )
+        .arg(mmio_arg())
+        .arg(
+            Arg::new("irq")
+                .long("irq")
+                .takes_value(true)
+                .help("ISA interrupt line (0-15) for vmsh devices, also if the guest uses it [default: detect unused line]"),
+        )
+        .arg(
+            Arg::new("vsock")
+                .long("vsock")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Add a vsock device, reachable through the unix socket PATH")
+                .long_help(
+                    "Add a vsock device, reachable through the unix socket PATH. \
+                    Host processes connect to PATH and send 'CONNECT <port>\\n' \
+                    to reach a listener on <port> in the VM. \
+                    Connections from the VM to port <port> of the host are forwarded \
+                    to the unix socket PATH_<port>.",
+                ),
+        )
+        .arg(
+            Arg::new("vsock-cid")
+                .long("vsock-cid")
+                .takes_value(true)
+                .default_value("3")
+                .help("Context identifier of the VM on the vsock device"),
+        )
+}
+
 fn inspect(args: &ArgMatches) {
     let opts = InspectOptions {
         pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
+        json: args.is_present("json"),
     };
 
     if let Err(err) = inspect::inspect(&opts) {
@@ -85,22 +239,57 @@ fn inspect(args: &ArgMatches) {
     };
 }
 
-fn attach(args: &ArgMatches) {
+fn list(args: &ArgMatches) {
+    let opts = ListOptions {
+        json: args.is_present("json"),
+    };
+
+    if let Err(err) = list::list(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn attach_options(args: &ArgMatches, console: ConsoleBackend) -> AttachOptions {
     let mut command = args.values_of_t("command").unwrap_or_else(|_| vec![]);
     let stage2_path = args.value_of_t_or_exit::<String>("stage2-path");
     command.insert(0, stage2_path);
 
-    let opts = AttachOptions {
+    let share = if args.is_present("share") {
+        Some(args.value_of_t_or_exit::<SharedDir>("share"))
+    } else {
+        None
+    };
+    // the default backing file is only needed as root of the attached environment
+    let backing = if share.is_some() && args.occurrences_of("backing-file") == 0 {
+        vec![]
+    } else {
+        args.values_of_t::<BackingFile>("backing-file")
+            .unwrap_or_else(|e| e.exit())
+    };
+
+    AttachOptions {
         pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
         command,
-        backing: PathBuf::from(args.value_of_t_or_exit::<String>("backing-file")),
-        pts: args.value_of_t::<String>("pts").ok().map(PathBuf::from),
-    };
+        backing,
+        share,
+        console,
+        irq: if args.is_present("irq") {
+            Some(args.value_of_t_or_exit("irq"))
+        } else {
+            None
+        },
+        vsock: args.value_of("vsock").map(|path| VsockOptions {
+            uds_path: PathBuf::from(path),
+            guest_cid: args.value_of_t_or_exit("vsock-cid"),
+        }),
+        mmio: mmio_backend(args),
+    }
+}
 
-    USE_IOREGIONFD.store(
-        args.value_of_t_or_exit::<String>("mmio") == "ioregionfd",
-        Ordering::Release,
-    );
+fn attach(args: &ArgMatches) {
+    let opts = attach_options(args, console_backend(args));
 
     if let Err(err) = attach::attach(&opts) {
         error!("{}", err);
@@ -108,13 +297,67 @@ fn attach(args: &ArgMatches) {
     };
 }
 
+fn detach(args: &ArgMatches) {
+    if let Err(err) = attach::detach(parse_vmid_arg(args), parse_vm_selector_arg(args)) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn reattach(args: &ArgMatches) {
+    let opts = ReattachOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
+        console: console_backend(args),
+        mmio: mmio_backend(args),
+    };
+
+    if let Err(err) = attach::reattach(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn daemon(args: &ArgMatches) {
+    let opts = DaemonOptions {
+        socket: args.value_of_t_or_exit("socket"),
+        mmio: mmio_backend(args),
+    };
+
+    if let Err(err) = daemon::daemon(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn exec(args: &ArgMatches) {
+    let opts = attach_options(args, ConsoleBackend::Log);
+
+    match attach::exec(&opts) {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(255);
+        }
+    };
+}
+
 fn coredump(args: &ArgMatches) {
     let pid = parse_vmid_arg(args);
-    let path = args
-        .value_of_t("PATH")
-        .unwrap_or_else(|_| PathBuf::from(format!("core.{}", pid)));
+    let output = match args.value_of("PATH") {
+        Some("-") => CoredumpOutput::Stdout,
+        Some(path) => CoredumpOutput::File(PathBuf::from(path)),
+        None => CoredumpOutput::File(PathBuf::from(format!("core.{}", pid))),
+    };
 
-    let opts = CoredumpOptions { pid, path };
+    let opts = CoredumpOptions {
+        pid,
+        vm: parse_vm_selector_arg(args),
+        output,
+        format: args.value_of_t_or_exit("format"),
+        compression: args.value_of_t_or_exit("compress"),
+        kernel_image_mappings: args.is_present("kernel-image-mappings"),
+    };
 
     if let Err(err) = coredump::generate_coredump(&opts) {
         error!("{}", err);
@@ -122,6 +365,137 @@ fn coredump(args: &ArgMatches) {
     };
 }
 
+fn snapshot(args: &ArgMatches) {
+    let pid = parse_vmid_arg(args);
+    let path = match args.value_of("PATH") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(format!("snapshot.{}", pid)),
+    };
+
+    let opts = SnapshotOptions {
+        pid,
+        vm: parse_vm_selector_arg(args),
+        path,
+        max_rounds: args.value_of_t_or_exit("max-rounds"),
+        soft_dirty: args.is_present("soft-dirty"),
+    };
+
+    if let Err(err) = snapshot::snapshot(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn parse_address_arg(args: &ArgMatches, name: &str) -> Option<usize> {
+    let value = args.value_of(name)?;
+    match translate::parse_address(value) {
+        Ok(addr) => Some(addr),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn translate(args: &ArgMatches) {
+    let opts = TranslateOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
+        virt_addr: parse_address_arg(args, "ADDRESS").unwrap(), // safe, because address is .required
+        cr3: parse_address_arg(args, "cr3"),
+        vcpu: args.value_of_t_or_exit("vcpu"),
+    };
+
+    if let Err(err) = translate::translate(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn guest_address_arg(args: &ArgMatches) -> GuestAddress {
+    let addr = parse_address_arg(args, "ADDRESS").unwrap(); // safe, because address is .required
+    if args.is_present("phys") {
+        GuestAddress::Physical(addr)
+    } else {
+        GuestAddress::Virtual(addr)
+    }
+}
+
+fn mem_read(args: &ArgMatches) {
+    let opts = MemReadOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
+        addr: guest_address_arg(args),
+        len: parse_address_arg(args, "LENGTH").unwrap(), // safe, because length is .required
+        vcpu: args.value_of_t_or_exit("vcpu"),
+        cr3: parse_address_arg(args, "cr3"),
+        format: args.value_of_t_or_exit("format"),
+        output: args.value_of("output").map(PathBuf::from),
+    };
+
+    if let Err(err) = mem::read(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn mem_write(args: &ArgMatches) {
+    let data = match args.value_of("hex") {
+        Some(hex) => mem::parse_hex(hex).unwrap_or_else(|err| {
+            error!("{}", err);
+            std::process::exit(1);
+        }),
+        None => {
+            let path = args.value_of("input").unwrap(); // safe, because the data group is .required
+            std::fs::read(path).unwrap_or_else(|err| {
+                error!("cannot read {}: {}", path, err);
+                std::process::exit(1);
+            })
+        }
+    };
+    let opts = MemWriteOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
+        addr: guest_address_arg(args),
+        vcpu: args.value_of_t_or_exit("vcpu"),
+        cr3: parse_address_arg(args, "cr3"),
+        data,
+        allow_write: args.is_present("allow-write"),
+    };
+
+    if let Err(err) = mem::write_memory(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
+fn mem(args: &ArgMatches) {
+    match args.subcommand() {
+        Some(("read", sub_matches)) => mem_read(sub_matches),
+        Some(("write", sub_matches)) => mem_write(sub_matches),
+        Some((_, _)) => unreachable!(),
+        None => unreachable!(),
+    }
+}
+
+fn gdbserver(args: &ArgMatches) {
+    let listen = match args.value_of("socket") {
+        Some(path) => GdbListen::Unix(PathBuf::from(path)),
+        None => GdbListen::Tcp(args.value_of_t_or_exit("listen")),
+    };
+    let opts = GdbServerOptions {
+        pid: parse_vmid_arg(args),
+        vm: parse_vm_selector_arg(args),
+        listen,
+        allow_write: args.is_present("allow-write"),
+    };
+
+    if let Err(err) = gdbserver::gdbserver(&opts) {
+        error!("{}", err);
+        std::process::exit(1);
+    };
+}
+
 fn setup_logging(matches: &clap::ArgMatches) {
     if matches.is_present("verbose") {
         env_logger::Builder::new().parse_filters("debug").init();
@@ -144,55 +518,299 @@ fn main() {
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .arg(vmid_arg(1))
-        .arg(vmid_type_arg());
+        .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print a JSON document to stdout instead of log messages"),
+        );
 
-    let attach_command = App::new("attach")
+    let list_command = App::new("list")
+        .about("List all virtual machines on this host.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print the list as JSON instead of a table"),
+        );
+
+    let attach_command = attach_app("attach")
         .about("Attach (a block device) to a virtual machine.")
+        .arg(pts_arg())
+        .arg(console_socket_arg());
+
+    let detach_command = App::new("detach")
+        .about("Stop vmsh but keep the attached command running in the virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(vmid_arg(1))
+        .arg(vmid_type_arg())
+        .arg(vm_selector_arg());
+
+    let reattach_command = App::new("reattach")
+        .about("Serve the devices of a detached vmsh again and reconnect to its command.")
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .arg(vmid_arg(1))
         .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
+        .arg(mmio_arg())
+        .arg(pts_arg())
+        .arg(console_socket_arg());
+
+    let exec_command = attach_app("exec")
+        .about("Run a command non-interactively in a virtual machine.")
+        .after_help(
+            "stdout and stderr of the command are forwarded to vmsh's stdout and stderr. \
+            vmsh exits with the exit code of the command or 255 if vmsh itself failed.",
+        );
+
+    let daemon_command = App::new("daemon")
+        .about("Manage attachments through a control socket.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
         .arg(
-            Arg::new("stage2-path")
-                .long("stage2-path")
+            Arg::new("socket")
+                .long("socket")
                 .takes_value(true)
-                .default_value("/dev/.vmsh")
-                .help("Path where Stage2 is written to in the VM"),
+                .required(true)
+                .value_name("PATH")
+                .help("Unix socket to accept JSON requests on"),
         )
-        .arg(command_args(2))
+        .arg(mmio_arg())
+        .after_help(
+            "Each line sent to the socket is a JSON request and answered by a JSON line. Requests: \
+            {\"command\": \"attach\", \"pid\": PID, ...} with the options of `vmsh attach`, \
+            {\"command\": \"list\"}, {\"command\": \"stats\", \"id\": ID}, \
+            {\"command\": \"stop\", \"id\": ID} and {\"command\": \"shutdown\"}.",
+        );
+
+    let coredump_command = App::new("coredump")
+        .about("Get a coredump of a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(vmid_arg(1))
+        .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
         .arg(
-            Arg::new("backing-file")
-                .short('f')
-                .long("backing-file")
-                .takes_value(true)
-                .default_value("/dev/null")
-                .help("File which shall be served as a block device."),
+            Arg::new("PATH")
+                .help("path to coredump, - streams it to stdout. Defaults to core.${pid}")
+                .index(2),
         )
         .arg(
-            Arg::new("mmio")
-                .long("mmio")
+            Arg::new("format")
+                .long("format")
                 .takes_value(true)
-                .possible_values(&["wrap_syscall", "ioregionfd"])
-                .default_value("wrap_syscall")
-                .long_help("Backend used to serve Virtio MMIO memory of devices."),
+                .possible_values(&["elf", "kdump"])
+                .default_value("elf")
+                .help("elf core file or kdump-compressed format of makedumpfile for crash"),
         )
         .arg(
-            Arg::new("pts")
-                .long("pts")
+            Arg::new("compress")
+                .long("compress")
                 .takes_value(true)
-                .help("Pseudoterminal seat to use for the command run in the VM. Use this when interactivity is required. "),
+                .possible_values(&["none", "zstd"])
+                .default_value("none")
+                .help("compress the whole elf file or each page of the kdump file"),
+        )
+        .arg(
+            Arg::new("kernel-image-mappings")
+                .long("kernel-image-mappings")
+                .help(
+                    "Also add PT_LOAD headers with the virtual addresses of the kernel image, \
+                    not of the direct mapping, modules or vmalloc",
+                ),
         );
 
-    let coredump_command = App::new("coredump")
-        .about("Get a coredump of a virtual machine.")
+    let snapshot_command = App::new("snapshot")
+        .about("Get a coredump of a virtual machine while it keeps running.")
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .arg(vmid_arg(1))
         .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
         .arg(
             Arg::new("PATH")
-                .help("path to coredump. Defaults to core.${pid}")
+                .help("path to the ELF core file. Defaults to snapshot.${pid}")
                 .index(2),
+        )
+        .arg(
+            Arg::new("max-rounds")
+                .long("max-rounds")
+                .takes_value(true)
+                .default_value("5")
+                .help("How often pages written during the copy are copied again before the final pause"),
+        )
+        .arg(
+            Arg::new("soft-dirty")
+                .long("soft-dirty")
+                .help("Also copy memory the hypervisor writes again, e.g. DMA of emulated devices"),
+        )
+        .after_help(
+            "The VM is only paused to start dirty page logging and to copy the last dirty pages \
+            together with the vcpu state. Needs bpf to read the memslots. \
+            --soft-dirty finds the writes of the hypervisor with the soft-dirty bits of its page tables. \
+            Those are reset for the whole hypervisor process in every round, \
+            which breaks other users of them such as CRIU.",
+        );
+
+    let translate_command = App::new("translate")
+        .about("Translate a guest virtual address to its physical and hypervisor address.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(vmid_arg(1))
+        .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
+        .arg(
+            Arg::new("ADDRESS")
+                .help("Virtual address in the guest, in hex with 0x prefix or decimal")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::new("cr3")
+                .long("cr3")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .help("Physical address of the page tables to use instead of the CR3 of the vcpu"),
+        )
+        .arg(
+            Arg::new("vcpu")
+                .long("vcpu")
+                .takes_value(true)
+                .default_value("0")
+                .help("Vcpu whose page tables and paging mode are used"),
+        );
+
+    let mem_address_args = |app: App<'static>| {
+        app.arg(vmid_arg(1))
+            .arg(vmid_type_arg())
+            .arg(vm_selector_arg())
+            .arg(
+                Arg::new("ADDRESS")
+                    .help("Guest address, in hex with 0x prefix or decimal")
+                    .required(true)
+                    .index(2),
+            )
+            .arg(
+                Arg::new("phys")
+                    .long("phys")
+                    .help("ADDRESS is a physical address instead of a virtual one"),
+            )
+            .arg(
+                Arg::new("cr3")
+                    .long("cr3")
+                    .takes_value(true)
+                    .value_name("ADDRESS")
+                    .help(
+                        "Physical address of the page tables to use instead of the CR3 of the vcpu",
+                    ),
+            )
+            .arg(
+                Arg::new("vcpu")
+                    .long("vcpu")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Vcpu whose page tables translate virtual addresses"),
+            )
+    };
+
+    let mem_command = App::new("mem")
+        .about("Read or write the memory of a virtual machine.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands([
+            mem_address_args(App::new("read"))
+                .about("Read guest memory.")
+                .arg(
+                    Arg::new("LENGTH")
+                        .help("Number of bytes to read, in hex with 0x prefix or decimal")
+                        .required(true)
+                        .index(3),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["hexdump", "raw"])
+                        .default_value("hexdump")
+                        .help("Output format on stdout"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Write the raw memory to a file instead of stdout"),
+                ),
+            mem_address_args(App::new("write"))
+                .about("Write guest memory.")
+                .arg(
+                    Arg::new("hex")
+                        .long("hex")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .help("Bytes to write as hex string, e.g. deadbeef"),
+                )
+                .arg(
+                    Arg::new("input")
+                        .long("input")
+                        .short('i')
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Write the content of a file"),
+                )
+                .group(ArgGroup::new("data").args(&["hex", "input"]).required(true))
+                .arg(
+                    Arg::new("allow-write")
+                        .long("allow-write")
+                        .help("Confirm that the guest memory should be modified"),
+                )
+                .after_help(
+                    "Writes can crash the guest or corrupt its data. \
+                    They are refused without --allow-write and logged.",
+                ),
+        ]);
+
+    let gdbserver_command = App::new("gdbserver")
+        .about("Debug the kernel of a running virtual machine with gdb.")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .arg(vmid_arg(1))
+        .arg(vmid_type_arg())
+        .arg(vm_selector_arg())
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .default_value("127.0.0.1:1234")
+                .help("TCP address to wait for gdb on, any local user can connect to it"),
+        )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Wait for gdb on a unix socket only accessible by us instead of TCP"),
+        )
+        .arg(
+            Arg::new("allow-write")
+                .long("allow-write")
+                .help("Let gdb modify registers and guest memory"),
+        )
+        .after_help(
+            "The VM is stopped while gdb is connected. gdb can read registers and memory, \
+            with virtual addresses translated by the page tables of the selected vcpu, \
+            continue the VM and stop it again with ctrl-c. Breakpoints and single stepping are not supported. \
+            Vcpus are shown as threads. \
+            Writes are refused without --allow-write and logged. \
+            The first client connecting to the TCP address, which can be any user of the host, \
+            controls the VM, use --socket to restrict access to our user.",
         );
 
     let main_app = App::new("vmsh")
@@ -211,16 +829,34 @@ fn main() {
              .help("Finegrained verbosity control. See docs.rs/env_logger. Examples: [error, warn, info, debug, trace]"))
         .subcommands([
             inspect_command,
+            list_command,
             attach_command,
-            coredump_command
+            detach_command,
+            reattach_command,
+            exec_command,
+            daemon_command,
+            coredump_command,
+            snapshot_command,
+            translate_command,
+            mem_command,
+            gdbserver_command
         ]);
 
     let matches = main_app.get_matches();
     setup_logging(&matches);
     match matches.subcommand() {
         Some(("inspect", sub_matches)) => inspect(sub_matches),
+        Some(("list", sub_matches)) => list(sub_matches),
         Some(("attach", sub_matches)) => attach(sub_matches),
+        Some(("detach", sub_matches)) => detach(sub_matches),
+        Some(("reattach", sub_matches)) => reattach(sub_matches),
+        Some(("exec", sub_matches)) => exec(sub_matches),
+        Some(("daemon", sub_matches)) => daemon(sub_matches),
         Some(("coredump", sub_matches)) => coredump(sub_matches),
+        Some(("snapshot", sub_matches)) => snapshot(sub_matches),
+        Some(("translate", sub_matches)) => translate(sub_matches),
+        Some(("mem", sub_matches)) => mem(sub_matches),
+        Some(("gdbserver", sub_matches)) => gdbserver(sub_matches),
         Some((_, _)) => unreachable!(),
         None => unreachable!(),
     }
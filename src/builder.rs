@@ -0,0 +1,252 @@
+/// Embeddable API of vmsh. Unlike the functions behind the command line, it installs no signal
+/// handlers and keeps no process-wide state, so that several sessions can be driven from one
+/// process.
+use log::error;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::attach::{self, AttachOptions, BackingFile, SharedDir, DEFAULT_STAGE2_PATH};
+use crate::control::{Control, Status};
+use crate::coredump::{self, Compression, CoredumpFormat, CoredumpOptions, CoredumpOutput};
+use crate::devices::virtio::console::ConsoleBackend;
+use crate::devices::virtio::vsock::VsockOptions;
+use crate::devices::{DeviceStats, MmioBackend};
+use crate::guest_mem::Translation;
+use crate::inspect::{self, VmReport};
+use crate::kvm::hypervisor::VmSelector;
+use crate::result::Result;
+use crate::translate::{self, TranslateOptions};
+
+type StatusCallback = Box<dyn Fn(Status) + Send + Sync>;
+
+/// Options of a session with a VM of the hypervisor `pid`, see `vmsh attach --help` for their
+/// meaning. Defaults match the command line.
+pub struct SessionBuilder {
+    pid: Pid,
+    vm: Option<VmSelector>,
+    command: Vec<String>,
+    stage2_path: String,
+    backing: Vec<BackingFile>,
+    share: Option<SharedDir>,
+    console: ConsoleBackend,
+    irq: Option<usize>,
+    vsock: Option<VsockOptions>,
+    mmio: MmioBackend,
+    on_status: Option<StatusCallback>,
+}
+
+impl SessionBuilder {
+    pub fn new(pid: Pid) -> SessionBuilder {
+        SessionBuilder {
+            pid,
+            vm: None,
+            command: vec![],
+            stage2_path: DEFAULT_STAGE2_PATH.to_string(),
+            backing: vec![],
+            share: None,
+            console: ConsoleBackend::Log,
+            irq: None,
+            vsock: None,
+            mmio: MmioBackend::default(),
+            on_status: None,
+        }
+    }
+
+    /// Only needed if the hypervisor runs multiple VMs.
+    pub fn vm(mut self, vm: VmSelector) -> Self {
+        self.vm = Some(vm);
+        self
+    }
+
+    /// Command run in the VM, a shell by default.
+    pub fn command(mut self, command: Vec<String>) -> Self {
+        self.command = command;
+        self
+    }
+
+    /// Where stage1 writes stage2 to in the VM.
+    pub fn stage2_path(mut self, path: impl Into<String>) -> Self {
+        self.stage2_path = path.into();
+        self
+    }
+
+    /// Serves `file` as block device. The first one is the root of the attached environment
+    /// unless a directory is shared, /dev/null is used if neither is given.
+    pub fn backing_file(mut self, file: BackingFile) -> Self {
+        self.backing.push(file);
+        self
+    }
+
+    /// Uses the host directory `dir` as root of the attached environment.
+    pub fn share(mut self, dir: SharedDir) -> Self {
+        self.share = Some(dir);
+        self
+    }
+
+    /// `ConsoleBackend::Stdio` takes over the terminal of the process and is not supported.
+    pub fn console(mut self, console: ConsoleBackend) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Interrupt line used by the devices, detected automatically if not set.
+    pub fn irq(mut self, irq: usize) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    pub fn vsock(mut self, vsock: VsockOptions) -> Self {
+        self.vsock = Some(vsock);
+        self
+    }
+
+    pub fn mmio(mut self, mmio: MmioBackend) -> Self {
+        self.mmio = mmio;
+        self
+    }
+
+    /// Calls `f` from the thread of the attachment whenever its status changes.
+    pub fn on_status(mut self, f: impl Fn(Status) + Send + Sync + 'static) -> Self {
+        self.on_status = Some(Box::new(f));
+        self
+    }
+
+    /// Attaches to the VM in a background thread, which stays the tracer of the hypervisor until
+    /// the session stops.
+    pub fn attach(self) -> Result<SessionHandle> {
+        if self.console == ConsoleBackend::Stdio {
+            bail!("the stdio console is not supported by embedded sessions");
+        }
+        let mut command = self.command;
+        command.insert(0, self.stage2_path);
+        let mut backing = self.backing;
+        if backing.is_empty() && self.share.is_none() {
+            backing.push(BackingFile::new("/dev/null"));
+        }
+        let opts = AttachOptions {
+            pid: self.pid,
+            vm: self.vm,
+            command,
+            backing,
+            share: self.share,
+            console: self.console,
+            irq: self.irq,
+            vsock: self.vsock,
+            mmio: self.mmio,
+        };
+
+        let (control, receiver) = match self.on_status {
+            Some(f) => Control::with_status_callback(f),
+            None => Control::new(),
+        };
+        let thread_control = Arc::clone(&control);
+        let thread = try_with!(
+            thread::Builder::new()
+                .name(format!("vmsh-{}", self.pid))
+                .spawn(move || attach::attach_controlled(&opts, &thread_control, receiver)),
+            "cannot spawn attachment thread"
+        );
+        Ok(SessionHandle {
+            control,
+            thread: Some(thread),
+        })
+    }
+
+    /// Collects what `vmsh inspect` shows about the VM.
+    pub fn inspect(&self) -> Result<VmReport> {
+        inspect::report(self.pid, self.vm)
+    }
+
+    /// Writes a coredump of the VM to `path`.
+    pub fn coredump(&self, path: impl Into<PathBuf>) -> Result<()> {
+        coredump::generate_coredump(&CoredumpOptions {
+            pid: self.pid,
+            vm: self.vm,
+            output: CoredumpOutput::File(path.into()),
+            format: CoredumpFormat::Elf,
+            compression: Compression::None,
+            kernel_image_mappings: false,
+        })
+    }
+
+    /// Translates the guest virtual address `virt_addr` with the page tables of `vcpu` or the
+    /// ones at `cr3`.
+    pub fn translate(
+        &self,
+        virt_addr: usize,
+        cr3: Option<usize>,
+        vcpu: usize,
+    ) -> Result<Translation> {
+        translate::lookup(&TranslateOptions {
+            pid: self.pid,
+            vm: self.vm,
+            virt_addr,
+            cr3,
+            vcpu,
+        })
+    }
+}
+
+/// A running session returned by `SessionBuilder::attach`. Dropping it stops the session.
+pub struct SessionHandle {
+    control: Arc<Control>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl SessionHandle {
+    /// Asks the session to stop, returns false if it is stopping already. The devices are gone
+    /// once `wait` returns.
+    pub fn stop(&self) -> bool {
+        self.control.stop()
+    }
+
+    /// Waits until the session stops, i.e. after `stop` or when it fails.
+    pub fn wait(mut self) -> Result<()> {
+        self.join()
+    }
+
+    /// Fails if the devices are not running (yet).
+    pub fn stats(&self) -> Result<Vec<DeviceStats>> {
+        require_with!(
+            self.control.with_devices(|devices| devices.stats()),
+            "devices are not running"
+        )
+    }
+
+    fn join(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(e) => bail!("attachment thread panicked: {:?}", e),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.control.stop();
+            if let Err(e) = self.join() {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_stdio_console() {
+        let res = SessionBuilder::new(Pid::from_raw(1))
+            .console(ConsoleBackend::Stdio)
+            .attach();
+        assert!(res.is_err());
+    }
+}
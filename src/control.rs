@@ -0,0 +1,151 @@
+/// Stops or detaches a running attachment. Owned by whoever controls the attachment: the signal
+/// handler of `vmsh attach` or the `vmsh daemon`, and shared with devices that stop on their own,
+/// i.e. when the command of `vmsh exec` exits.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+use log::error;
+
+use crate::devices::DeviceContext;
+
+/// Progress of an attachment, see `Control::with_status_callback`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// the devices are running and the command was started in the VM
+    Running,
+    /// asked to stop or detach, the devices are being torn down
+    Stopping,
+    /// the attachment is gone
+    Stopped,
+    /// the attachment is gone because of this error
+    Failed(String),
+}
+
+type StatusCallback = Box<dyn Fn(Status) + Send + Sync>;
+
+pub struct Control {
+    /// also handed to the threads of the attachment, which stop it when they fail
+    sender: SyncSender<()>,
+    stopping: AtomicBool,
+    detach: AtomicBool,
+    /// set once the devices are running
+    devices: Mutex<Option<Arc<DeviceContext>>>,
+    on_status: Option<StatusCallback>,
+}
+
+impl Control {
+    /// The receiver returns once the attachment should stop.
+    pub fn new() -> (Arc<Control>, Receiver<()>) {
+        Self::build(None)
+    }
+
+    /// Like `new` but calls `f` from the thread of the attachment whenever its status changes.
+    pub fn with_status_callback(
+        f: impl Fn(Status) + Send + Sync + 'static,
+    ) -> (Arc<Control>, Receiver<()>) {
+        Self::build(Some(Box::new(f)))
+    }
+
+    fn build(on_status: Option<StatusCallback>) -> (Arc<Control>, Receiver<()>) {
+        let (sender, receiver) = sync_channel(1);
+        let control = Control {
+            sender,
+            stopping: AtomicBool::new(false),
+            detach: AtomicBool::new(false),
+            devices: Mutex::new(None),
+            on_status,
+        };
+        (Arc::new(control), receiver)
+    }
+
+    pub fn sender(&self) -> &SyncSender<()> {
+        &self.sender
+    }
+
+    /// Returns false if the attachment is stopping already.
+    pub fn stop(&self) -> bool {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        // fails if a failed thread asked to stop before us or the attachment is gone already
+        let _ = self.sender.try_send(());
+        true
+    }
+
+    /// Stops without tearing down the devices in the VM, see `attach::reattach`.
+    pub fn detach(&self) -> bool {
+        self.detach.store(true, Ordering::SeqCst);
+        self.stop()
+    }
+
+    pub fn detach_requested(&self) -> bool {
+        self.detach.load(Ordering::SeqCst)
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_status(&self, status: Status) {
+        if let Some(f) = &self.on_status {
+            f(status);
+        }
+    }
+
+    pub(crate) fn set_devices(&self, devices: Option<Arc<DeviceContext>>) {
+        match self.devices.lock() {
+            Ok(mut guard) => *guard = devices,
+            Err(e) => error!("cannot lock devices: {}", e),
+        }
+    }
+
+    /// Runs `f` on the devices of the attachment, returns None if they are not running.
+    /// The devices must be dropped by the attachment itself, so they are only lent out.
+    pub fn with_devices<R>(&self, f: impl FnOnce(&DeviceContext) -> R) -> Option<R> {
+        match self.devices.lock() {
+            Ok(guard) => guard.as_deref().map(f),
+            Err(e) => {
+                error!("cannot lock devices: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Control, Status};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_stop_once() {
+        let (control, receiver) = Control::new();
+        assert!(!control.is_stopping());
+        assert!(control.detach());
+        assert!(!control.stop());
+        assert!(control.detach_requested());
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_status_callback() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_by_callback = Arc::clone(&seen);
+        let (control, _receiver) = Control::with_status_callback(move |status| {
+            seen_by_callback.lock().expect("cannot lock").push(status)
+        });
+        control.set_status(Status::Running);
+        control.set_status(Status::Stopping);
+        control.set_status(Status::Failed("error".to_string()));
+        assert_eq!(
+            *seen.lock().expect("cannot lock"),
+            vec![
+                Status::Running,
+                Status::Stopping,
+                Status::Failed("error".to_string())
+            ]
+        );
+    }
+}
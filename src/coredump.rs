@@ -1,31 +1,189 @@
 use crate::cpu::{FpuRegs, Regs};
-use crate::kvm::hypervisor::VCPU;
+use crate::kvm::hypervisor::{VmSelector, VCPU};
 use kvm_bindings as kvmb;
-use libc::{c_void, off_t, timeval, PT_LOAD, PT_NOTE};
+use libc::{timeval, PT_LOAD, PT_NOTE};
 use nix::sys::{
-    mman::{mmap, MapFlags, ProtFlags},
+    mman::ProtFlags,
     uio::{process_vm_readv, IoVec, RemoteIoVec},
 };
 use nix::unistd::Pid;
-use simple_error::try_with;
+use simple_error::{bail, try_with};
+use std::cmp::{max, min};
+use std::fmt;
 use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom};
+use std::mem::size_of;
 use std::path::PathBuf;
-use std::{fs::File, io::Write, ptr, slice::from_raw_parts_mut};
-use std::{mem::size_of, os::unix::prelude::AsRawFd};
+use std::str::FromStr;
+use std::{fs::File, io::Write, ptr};
 
 use crate::elf::{
     elf_prpsinfo, elf_prstatus, elf_siginfo, Ehdr, Elf_Addr, Elf_Half, Elf_Off, Elf_Word, Nhdr,
     Phdr, Shdr, ELFARCH, ELFCLASS, ELFDATA2, ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELF_NGREG,
-    ET_CORE, EV_CURRENT, NT_PRPSINFO, NT_PRSTATUS, NT_PRXREG, PF_W, PF_X, SHN_UNDEF,
+    ET_CORE, EV_CURRENT, NT_PRPSINFO, NT_PRSTATUS, NT_PRXREG, NT_X86_XSTATE, PF_W, PF_X, SHN_UNDEF,
 };
+use crate::guest_mem::{GuestMem, MappedMemory};
+use crate::kdump;
+use crate::kernel::LINUX_KERNEL_KASLR_RANGE;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::page_math::{page_align, page_size};
 use crate::result::Result;
+use crate::vmcoreinfo::{self, VmcoreInfo, VMCOREINFO_NOTE_NAME};
 use crate::{kvm, tracer::proc::Mapping};
+use log::{info, warn};
+
+/// Guest memory is copied in chunks of this size.
+pub(crate) const DUMP_CHUNK_SIZE: usize = 2 * 1024 * 1024;
+pub(crate) const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoredumpFormat {
+    /// ELF core file with one PT_LOAD segment per memslot
+    Elf,
+    /// kdump-compressed format of makedumpfile
+    Kdump,
+}
+
+impl FromStr for CoredumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "elf" => Ok(CoredumpFormat::Elf),
+            "kdump" => Ok(CoredumpFormat::Kdump),
+            _ => Err(format!("unknown coredump format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// The whole ELF file or, for kdump, each page
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoredumpOutput {
+    File(PathBuf),
+    /// Streamed, zero pages are written out instead of becoming holes
+    Stdout,
+}
+
+impl fmt::Display for CoredumpOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoredumpOutput::File(path) => write!(f, "{}", path.display()),
+            CoredumpOutput::Stdout => write!(f, "stdout"),
+        }
+    }
+}
 
 pub struct CoredumpOptions {
     pub pid: Pid,
-    pub path: PathBuf,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub output: CoredumpOutput,
+    pub format: CoredumpFormat,
+    pub compression: Compression,
+    /// also add PT_LOAD headers with the virtual addresses of the kernel image (ELF only), not
+    /// the ones of the direct mapping, modules or vmalloc
+    pub kernel_image_mappings: bool,
+}
+
+/// Destination of an ELF coredump.
+enum CoreSink {
+    /// Zero pages become holes
+    File(File),
+    Stream(Box<dyn Write>),
+    Zstd(zstd::stream::write::Encoder<'static, Box<dyn Write>>),
+}
+
+impl CoreSink {
+    fn new(file: Option<File>, compression: Compression) -> Result<CoreSink> {
+        let stream: Box<dyn Write> = match (file, compression) {
+            (Some(file), Compression::None) => return Ok(CoreSink::File(file)),
+            (Some(file), _) => Box::new(file),
+            (None, _) => Box::new(io::stdout()),
+        };
+        Ok(match compression {
+            Compression::None => CoreSink::Stream(stream),
+            Compression::Zstd => CoreSink::Zstd(try_with!(
+                zstd::stream::write::Encoder::new(stream, ZSTD_LEVEL),
+                "cannot create zstd encoder"
+            )),
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            CoreSink::File(f) => f,
+            CoreSink::Stream(s) => s,
+            CoreSink::Zstd(e) => e,
+        }
+    }
+
+    /// Skips `len` zero bytes.
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        if let CoreSink::File(f) = self {
+            f.seek(SeekFrom::Current(len as i64))?;
+            return Ok(());
+        }
+        let zeros = [0; 4096];
+        let mut left = len;
+        while left > 0 {
+            let n = min(left, zeros.len());
+            self.writer().write_all(&zeros[..n])?;
+            left -= n;
+        }
+        Ok(())
+    }
+
+    /// Writes `data`, runs of zero pages are skipped.
+    fn write_sparse(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut start = 0;
+        while start < data.len() {
+            let zero = is_zero(&data[start..min(start + page_size(), data.len())]);
+            let mut end = start;
+            while end < data.len()
+                && is_zero(&data[end..min(end + page_size(), data.len())]) == zero
+            {
+                end = min(end + page_size(), data.len());
+            }
+            if zero {
+                self.skip(end - start)?;
+            } else {
+                self.writer().write_all(&data[start..end])?;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            CoreSink::File(mut f) => {
+                // skipped zero pages at the end of the file are not allocated yet
+                let len = f.stream_position()?;
+                f.set_len(len)?;
+                f.flush()
+            }
+            CoreSink::Stream(mut s) => s.flush(),
+            CoreSink::Zstd(e) => e.finish()?.flush(),
+        }
+    }
 }
 
 #[repr(C)]
@@ -54,48 +212,44 @@ fn protection_flags(f: &ProtFlags) -> Elf_Word {
     })
 }
 
-unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
+pub(crate) unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
     std::slice::from_raw_parts((p as *const T) as *const u8, size_of::<T>())
 }
 
-fn dump_mappings(
-    pid: Pid,
-    core_file: &mut File,
-    core_size: off_t,
-    file_offset: off_t,
-    maps: &[Mapping],
-) -> Result<()> {
-    let buf_size = core_size - file_offset;
-    let res = unsafe {
-        mmap(
-            ptr::null_mut::<c_void>(),
-            buf_size as usize,
-            ProtFlags::PROT_WRITE,
-            MapFlags::MAP_SHARED,
-            core_file.as_raw_fd(),
-            file_offset,
-        )
-    };
-    let raw_buf = try_with!(res, "cannot mmap core file");
-    let buf = unsafe { from_raw_parts_mut(raw_buf as *mut u8, buf_size as usize) };
-
-    let dst_iovs = vec![IoVec::from_mut_slice(buf)];
-    let src_iovs = maps
-        .iter()
-        .map(|m| RemoteIoVec {
-            base: m.start,
-            len: m.size(),
-        })
-        .collect::<Vec<_>>();
+pub(crate) fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
 
-    try_with!(
-        process_vm_readv(pid, dst_iovs.as_slice(), src_iovs.as_slice()),
+/// Reads hypervisor memory at `addr` into `buf`.
+pub(crate) fn read_hypervisor(pid: Pid, addr: usize, buf: &mut [u8]) -> Result<()> {
+    let len = buf.len();
+    let dst_iovs = [IoVec::from_mut_slice(buf)];
+    let src_iovs = [RemoteIoVec { base: addr, len }];
+    let read = try_with!(
+        process_vm_readv(pid, &dst_iovs, &src_iovs),
         "cannot read hypervisor memory"
     );
+    if read != len {
+        bail!("short read, expected {}, read: {}", len, read);
+    }
+    Ok(())
+}
+
+fn dump_mappings(pid: Pid, sink: &mut CoreSink, maps: &[Mapping]) -> Result<()> {
+    let mut buf = vec![0; DUMP_CHUNK_SIZE];
+    for m in maps {
+        let mut offset = 0;
+        while offset < m.size() {
+            let len = min(buf.len(), m.size() - offset);
+            read_hypervisor(pid, m.start + offset, &mut buf[..len])?;
+            try_with!(sink.write_sparse(&buf[..len]), "cannot write core file");
+            offset += len;
+        }
+    }
     Ok(())
 }
 
-fn elf_header(phnum: Elf_Half) -> Ehdr {
+pub(crate) fn elf_header(phnum: Elf_Half) -> Ehdr {
     Ehdr {
         e_ident: [
             ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3, ELFCLASS, ELFDATA2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -116,7 +270,7 @@ fn elf_header(phnum: Elf_Half) -> Ehdr {
     }
 }
 
-fn pt_note_header(core_size: Elf_Off, file_size: Elf_Off) -> Phdr {
+pub(crate) fn pt_note_header(core_size: Elf_Off, file_size: Elf_Off) -> Phdr {
     Phdr {
         p_type: PT_NOTE,
         p_flags: 0,
@@ -129,7 +283,7 @@ fn pt_note_header(core_size: Elf_Off, file_size: Elf_Off) -> Phdr {
     }
 }
 
-fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
+pub(crate) fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
     Phdr {
         p_type: PT_LOAD,
         p_flags: protection_flags(&m.prot_flags),
@@ -142,7 +296,44 @@ fn pt_load_header(m: &Mapping, offset: Elf_Off) -> Phdr {
     }
 }
 
-fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload: &T) -> Result<()> {
+/// Headers for the virtual `mappings` of the guest kernel. Their data is not copied again,
+/// instead they point to the physical memory in the PT_LOAD segments of `maps`, which start at
+/// `offsets`.
+fn virtual_load_headers(
+    maps: &[Mapping],
+    offsets: &[Elf_Off],
+    mappings: &[MappedMemory],
+) -> Vec<Phdr> {
+    let mut headers = vec![];
+    for v in mappings {
+        let phys = v.phys_start.value..v.phys_start.value + v.len;
+        for (m, offset) in maps.iter().zip(offsets) {
+            let start = max(phys.start, m.phys_addr);
+            let end = min(phys.end, m.phys_addr + m.size());
+            if start >= end {
+                continue;
+            }
+            headers.push(Phdr {
+                p_type: PT_LOAD,
+                p_flags: protection_flags(&v.prot),
+                p_offset: offset + (start - m.phys_addr) as Elf_Off,
+                p_vaddr: (v.virt_start + (start - phys.start)) as Elf_Addr,
+                p_paddr: start as Elf_Addr,
+                p_filesz: (end - start) as Elf_Addr,
+                p_memsz: (end - start) as Elf_Addr,
+                p_align: page_size() as Elf_Addr,
+            });
+        }
+    }
+    headers.sort_by_key(|h| h.p_vaddr);
+    headers
+}
+
+fn write_note_section<T: Sized>(
+    core_file: &mut dyn Write,
+    ntype: Elf_Word,
+    payload: &T,
+) -> Result<()> {
     let hdr = &Nhdr {
         n_namesz: 5,
         n_descsz: size_of::<T>() as Elf_Word,
@@ -164,7 +355,7 @@ fn write_note_section<T: Sized>(core_file: &mut File, ntype: Elf_Word, payload:
 }
 
 #[cfg(target_arch = "x86_64")]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers(core_file: &mut dyn Write, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRXFPREG;
     let hdr = &Nhdr {
         n_namesz: 5,
@@ -187,7 +378,7 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
 }
 
 #[cfg(not(target_arch = "x86_64"))]
-fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
+fn write_fpu_registers(core_file: &mut dyn Write, regs: &FpuRegs) -> Result<()> {
     use crate::elf::NT_PRFPREG;
     try_with!(
         write_note_section(
@@ -200,7 +391,102 @@ fn write_fpu_registers(core_file: &mut File, regs: &FpuRegs) -> Result<()> {
     Ok(())
 }
 
-fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()> {
+fn note_padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Writes a note with an arbitrary `name` (including the NUL byte) and payload.
+fn write_note(core_file: &mut dyn Write, name: &[u8], ntype: Elf_Word, desc: &[u8]) -> Result<()> {
+    let hdr = &Nhdr {
+        n_namesz: name.len() as Elf_Word,
+        n_descsz: desc.len() as Elf_Word,
+        n_type: ntype,
+    };
+    try_with!(
+        core_file.write_all(unsafe { any_as_bytes(hdr) }),
+        "cannot write elf note header"
+    );
+    let padding = [0; 4];
+    try_with!(core_file.write_all(name), "cannot write note name");
+    try_with!(
+        core_file.write_all(&padding[..note_padding(name.len())]),
+        "cannot write note name"
+    );
+    try_with!(core_file.write_all(desc), "cannot write note");
+    try_with!(
+        core_file.write_all(&padding[..note_padding(desc.len())]),
+        "cannot write note"
+    );
+    Ok(())
+}
+
+/// Writes the notes with the state of a vcpu that does not fit into NT_PRSTATUS and
+/// NT_PRXFPREG. Parts that could not be read from KVM are left out.
+fn write_vcpu_state_notes(core_file: &mut dyn Write, vcpu: &VcpuState) -> Result<()> {
+    if let Some(xsave) = &vcpu.xsave {
+        let mut xsave = *xsave;
+        // Linux stores XCR0 in the software reserved bytes of the fxsave area, gdb reads it
+        // from there to know which state components are valid.
+        if let Some(xcr0) = vcpu.xcrs.as_ref().and_then(xcr0) {
+            xsave.region[XSAVE_XCR0_OFFSET / 4] = xcr0 as u32;
+            xsave.region[XSAVE_XCR0_OFFSET / 4 + 1] = (xcr0 >> 32) as u32;
+        }
+        try_with!(
+            write_note(core_file, b"LINUX\0", NT_X86_XSTATE, unsafe {
+                any_as_bytes(&xsave)
+            }),
+            "failed to write NT_X86_XSTATE"
+        );
+    }
+    if let Some(lapic) = &vcpu.lapic {
+        try_with!(
+            write_note(core_file, VMSH_NOTE_NAME, NT_VMSH_LAPIC, unsafe {
+                any_as_bytes(lapic)
+            }),
+            "failed to write NT_VMSH_LAPIC"
+        );
+    }
+    if let Some(events) = &vcpu.events {
+        try_with!(
+            write_note(core_file, VMSH_NOTE_NAME, NT_VMSH_VCPU_EVENTS, unsafe {
+                any_as_bytes(events)
+            }),
+            "failed to write NT_VMSH_VCPU_EVENTS"
+        );
+    }
+    if let Some(debugregs) = &vcpu.debugregs {
+        try_with!(
+            write_note(core_file, VMSH_NOTE_NAME, NT_VMSH_DEBUGREGS, unsafe {
+                any_as_bytes(debugregs)
+            }),
+            "failed to write NT_VMSH_DEBUGREGS"
+        );
+    }
+    if let Some(xcrs) = &vcpu.xcrs {
+        try_with!(
+            write_note(core_file, VMSH_NOTE_NAME, NT_VMSH_XCRS, unsafe {
+                any_as_bytes(xcrs)
+            }),
+            "failed to write NT_VMSH_XCRS"
+        );
+    }
+    let msrs = vcpu
+        .common_msrs
+        .iter()
+        .flat_map(|m| unsafe { any_as_bytes(m) }.to_vec())
+        .collect::<Vec<_>>();
+    try_with!(
+        write_note(core_file, VMSH_NOTE_NAME, NT_VMSH_MSRS, &msrs),
+        "failed to write NT_VMSH_MSRS"
+    );
+    Ok(())
+}
+
+pub(crate) fn write_note_sections(
+    core_file: &mut dyn Write,
+    vcpus: &[VcpuState],
+    vmcoreinfo: Option<&str>,
+) -> Result<()> {
     try_with!(
         write_note_section(
             core_file,
@@ -272,45 +558,61 @@ fn write_note_sections(core_file: &mut File, vcpus: &[VcpuState]) -> Result<()>
         );
 
         write_fpu_registers(core_file, &vcpu.fpu_regs)?;
+        write_vcpu_state_notes(core_file, vcpu)?;
     }
-    Ok(())
-}
 
-pub fn note_size<T>() -> usize {
-    // name is 4 bit aligned, we write CORE\0 to it
-    let name_size = 8;
-    size_of::<Nhdr>() + name_size + size_of::<T>()
+    if let Some(info) = vmcoreinfo {
+        try_with!(
+            write_note(core_file, VMCOREINFO_NOTE_NAME, 0, info.as_bytes()),
+            "failed to write VMCOREINFO"
+        );
+    }
+    Ok(())
 }
 
 fn write_corefile(
     pid: Pid,
-    core_file: &mut File,
+    sink: &mut CoreSink,
     maps: &[Mapping],
     vcpus: &[VcpuState],
+    vmcoreinfo: Option<&str>,
+    kernel_mappings: &[MappedMemory],
 ) -> Result<()> {
+    // offsets relative to the start of the PT_LOAD data
+    let mut offsets = vec![];
+    let mut data_size = 0;
+    for m in maps {
+        offsets.push(data_size as Elf_Off);
+        data_size += m.size();
+    }
+    let mut virtual_headers = virtual_load_headers(maps, &offsets, kernel_mappings);
+
     // +1 == PT_NOTE section
-    let ehdr = elf_header((maps.len() + 1) as Elf_Half);
+    let phnum = maps.len() + virtual_headers.len() + 1;
+    if phnum > Elf_Half::MAX as usize {
+        bail!("too many program headers: {}", phnum);
+    }
+    let ehdr = elf_header(phnum as Elf_Half);
 
     let metadata_size = size_of::<Ehdr>() + (size_of::<Phdr>() * ehdr.e_phnum as usize);
     let mut core_size = metadata_size;
 
-    let pt_note_size = note_size::<elf_prpsinfo>()
-        + vcpus.len()
-            * (note_size::<core_user>() + note_size::<elf_prstatus>() + note_size::<FpuRegs>());
+    let mut notes = vec![];
+    write_note_sections(&mut notes, vcpus, vmcoreinfo)?;
+    let pt_note_size = notes.len();
     let mut section_headers = vec![pt_note_header(core_size as Elf_Off, pt_note_size as u64)];
     core_size += pt_note_size;
     core_size = page_align(core_size);
 
-    for m in maps {
-        let phdr = pt_load_header(m, core_size as Elf_Off);
-        core_size += m.size();
-        section_headers.push(phdr);
+    for (m, offset) in maps.iter().zip(&offsets) {
+        section_headers.push(pt_load_header(m, core_size as Elf_Off + offset));
     }
+    for h in &mut virtual_headers {
+        h.p_offset += core_size as Elf_Off;
+    }
+    section_headers.extend(virtual_headers);
 
-    try_with!(
-        core_file.set_len(core_size as u64),
-        "cannot truncate core file"
-    );
+    let core_file = sink.writer();
     try_with!(
         core_file.write_all(unsafe { any_as_bytes(&ehdr) }),
         "cannot write elf header"
@@ -321,33 +623,98 @@ fn write_corefile(
             "cannot write elf header"
         );
     }
-    write_note_sections(core_file, vcpus)?;
-
-    try_with!(core_file.flush(), "cannot flush core file");
+    try_with!(core_file.write_all(&notes), "cannot write elf notes");
+    try_with!(
+        sink.skip(page_align(metadata_size + pt_note_size) - (metadata_size + pt_note_size)),
+        "cannot write core file"
+    );
 
-    dump_mappings(
-        pid,
-        core_file,
-        core_size as off_t,
-        page_align(metadata_size + pt_note_size) as off_t,
-        maps,
-    )
+    dump_mappings(pid, sink, maps)
 }
 
 const MSR_EFER: u32 = 0xc0000080;
-struct VcpuState {
+
+/// MSRs in the NT_VMSH_MSRS note. KVM stops reading at the first unsupported msr, so the ones
+/// that not every cpu has come last.
+const COMMON_MSRS: &[u32] = &[
+    MSR_EFER,
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SYSCALL_MASK
+    0xc000_0100, // FS_BASE
+    0xc000_0101, // GS_BASE
+    0xc000_0102, // KERNEL_GS_BASE
+    0x10,        // TSC
+    0x1b,        // APIC_BASE
+    0x174,       // SYSENTER_CS
+    0x175,       // SYSENTER_ESP
+    0x176,       // SYSENTER_EIP
+    0x277,       // PAT
+    0x1a0,       // MISC_ENABLE
+    0xc000_0103, // TSC_AUX
+];
+
+/// Owner of the notes with vcpu state that has no equivalent in Linux coredumps
+const VMSH_NOTE_NAME: &[u8] = b"VMSH\0";
+/// `struct kvm_lapic_state`, the registers of the local APIC
+pub const NT_VMSH_LAPIC: Elf_Word = 0x564d_0001;
+/// `struct kvm_vcpu_events`, pending exceptions, interrupts, NMIs and SMIs
+pub const NT_VMSH_VCPU_EVENTS: Elf_Word = 0x564d_0002;
+/// `struct kvm_debugregs`, DR0-3, DR6 and DR7
+pub const NT_VMSH_DEBUGREGS: Elf_Word = 0x564d_0003;
+/// `struct kvm_xcrs`, the extended control registers
+pub const NT_VMSH_XCRS: Elf_Word = 0x564d_0004;
+/// Array of `struct kvm_msr_entry` for `COMMON_MSRS`
+pub const NT_VMSH_MSRS: Elf_Word = 0x564d_0005;
+/// Offset of `sw_reserved` in the fxsave area, where Linux puts XCR0
+const XSAVE_XCR0_OFFSET: usize = 464;
+
+pub(crate) struct VcpuState {
     regs: Regs,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     sregs: kvmb::kvm_sregs,
     fpu_regs: FpuRegs,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     msrs: [kvmb::kvm_msr_entry; 1],
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    common_msrs: Vec<kvmb::kvm_msr_entry>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    lapic: Option<kvmb::kvm_lapic_state>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    events: Option<kvmb::kvm_vcpu_events>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    debugregs: Option<kvmb::kvm_debugregs>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    xsave: Option<kvmb::kvm_xsave>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    xcrs: Option<kvmb::kvm_xcrs>,
+}
+
+/// XCR0 from the extended control registers
+fn xcr0(xcrs: &kvmb::kvm_xcrs) -> Option<u64> {
+    xcrs.xcrs
+        .iter()
+        .take(xcrs.nr_xcrs as usize)
+        .find(|x| x.xcr == 0)
+        .map(|x| x.value)
+}
+
+/// State that not every vcpu has, e.g. the local APIC without in-kernel irqchip, is left out.
+fn optional<T>(vcpu: &VCPU, what: &str, res: Result<T>) -> Option<T> {
+    match res {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!("cannot get {} of vcpu {}: {}", what, vcpu.idx, e);
+            None
+        }
+    }
 }
 
 impl VcpuState {
     /// Requires the hypervisor to be stopped.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn new(vcpu: &VCPU, hv: &Hypervisor) -> Result<VcpuState> {
+    pub(crate) fn new(vcpu: &VCPU, hv: &Hypervisor) -> Result<VcpuState> {
         let regs = hv.get_regs(vcpu)?;
         let sregs = hv.get_sregs(vcpu)?;
         let fpu_regs = hv.get_fpu_regs(vcpu)?;
@@ -356,28 +723,45 @@ impl VcpuState {
             ..Default::default()
         };
         let msr = hv.get_msr(vcpu, &entry)?;
+        let common_msrs = hv.get_msrs(vcpu, COMMON_MSRS)?;
         Ok(VcpuState {
             regs,
             sregs,
             fpu_regs,
             msrs: [msr],
+            common_msrs,
+            lapic: optional(vcpu, "local APIC", hv.get_lapic(vcpu)),
+            events: optional(vcpu, "events", hv.get_vcpu_events(vcpu)),
+            debugregs: optional(vcpu, "debug registers", hv.get_debugregs(vcpu)),
+            xsave: optional(vcpu, "xsave area", hv.get_xsave(vcpu)),
+            xcrs: optional(vcpu, "extended control registers", hv.get_xcrs(vcpu)),
         })
     }
 }
 
 pub fn generate_coredump(opts: &CoredumpOptions) -> Result<()> {
-    println!("Write {}", opts.path.display());
-    let mut core_file = try_with!(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&opts.path),
-        "cannot open core_file: {}",
-        opts.path.display()
-    );
+    if opts.format == CoredumpFormat::Kdump && opts.output == CoredumpOutput::Stdout {
+        bail!("the kdump format cannot be streamed, write it to a file or use the elf format");
+    }
+    if opts.format == CoredumpFormat::Kdump && opts.kernel_image_mappings {
+        bail!("kernel image mappings are only supported in the elf format");
+    }
+    info!("write {}", opts.output);
+    let core_file = match &opts.output {
+        CoredumpOutput::File(path) => Some(try_with!(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path),
+            "cannot open core_file: {}",
+            path.display()
+        )),
+        CoredumpOutput::Stdout => None,
+    };
     let vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
+        kvm::hypervisor::select_hypervisor(opts.pid, opts.vm),
         "cannot get vms for process {}",
         opts.pid
     );
@@ -389,8 +773,68 @@ pub fn generate_coredump(opts: &CoredumpOptions) -> Result<()> {
         .map(|vcpu| VcpuState::new(vcpu, &vm))
         .collect::<Result<Vec<VcpuState>>>();
     let vcpu_states = try_with!(res, "fail to dump vcpu registers");
+    // crash and drgn need the note to find the kernel, the memory is still useful without it
+    let vmcoreinfo = match vmcoreinfo::collect(&vm) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            warn!("cannot collect VMCOREINFO, omit it from core file: {}", e);
+            None
+        }
+    };
+    match (opts.format, core_file) {
+        (CoredumpFormat::Kdump, Some(core_file)) => {
+            write_kdump(opts, &core_file, &maps, &vcpu_states, vmcoreinfo.as_ref())
+        }
+        (_, core_file) => {
+            let note = vmcoreinfo.map(|info| info.to_note());
+            let kernel_mappings = if opts.kernel_image_mappings {
+                let mem = GuestMem::new(&vm)?;
+                // KASLR places the kernel image somewhere in this range
+                try_with!(
+                    mem.virtual_mappings(&vm, LINUX_KERNEL_KASLR_RANGE),
+                    "cannot read mappings of the kernel image"
+                )
+            } else {
+                vec![]
+            };
+            let mut sink = CoreSink::new(core_file, opts.compression)?;
+            try_with!(
+                write_corefile(
+                    opts.pid,
+                    &mut sink,
+                    &maps,
+                    vcpu_states.as_slice(),
+                    note.as_deref(),
+                    &kernel_mappings
+                ),
+                "cannot write core file"
+            );
+            try_with!(sink.finish(), "cannot write core file");
+            Ok(())
+        }
+    }
+}
+
+fn write_kdump(
+    opts: &CoredumpOptions,
+    core_file: &File,
+    maps: &[Mapping],
+    vcpus: &[VcpuState],
+    vmcoreinfo: Option<&VmcoreInfo>,
+) -> Result<()> {
+    // the VMCOREINFO has its own place in the kdump sub header
+    let mut notes = vec![];
+    write_note_sections(&mut notes, vcpus, None)?;
     try_with!(
-        write_corefile(opts.pid, &mut core_file, &maps, vcpu_states.as_slice()),
+        kdump::write_kdump(
+            opts.pid,
+            core_file,
+            maps,
+            &notes,
+            vcpus.len(),
+            vmcoreinfo,
+            opts.compression
+        ),
         "cannot write core file"
     );
     Ok(())
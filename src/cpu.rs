@@ -1,7 +1,9 @@
 #[cfg(target_arch = "aarch64")]
 mod arch {
+    use serde::Serialize;
+
     #[repr(C)]
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Serialize)]
     pub struct Regs {
         pub regs: [u64; 31],
         pub sp: u64,
@@ -50,8 +52,10 @@ mod arch {
 
 #[cfg(target_arch = "x86_64")]
 mod arch {
+    use serde::Serialize;
+
     #[repr(C)]
-    #[derive(Clone, Copy, Debug, Default)]
+    #[derive(Clone, Copy, Debug, Default, Serialize)]
     pub struct Regs {
         pub r15: u64,
         pub r14: u64,
@@ -0,0 +1,457 @@
+/// `vmsh daemon` manages attachments on behalf of clients of its control socket.
+///
+/// Clients send one JSON request per line and receive one JSON response per line, e.g.
+/// `{"command": "attach", "pid": 1234, "backing": ["disk.img"], "command_line": ["/bin/sh"]}`.
+/// The socket is only accessible by its owner, clients of other users than root or the owner are
+/// rejected.
+use log::{error, info, warn};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{geteuid, Pid};
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, try_with};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::attach::{self, AttachOptions, BackingFile, SharedDir, DEFAULT_STAGE2_PATH};
+use crate::control::Control;
+use crate::devices::virtio::console::ConsoleBackend;
+use crate::devices::virtio::vsock::VsockOptions;
+use crate::devices::{DeviceStats, MmioBackend};
+use crate::kvm::hypervisor::VmSelector;
+use crate::result::Result;
+use crate::signal_handler;
+
+/// How often the daemon checks whether it should stop while waiting for clients.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct DaemonOptions {
+    /// path of the control socket
+    pub socket: PathBuf,
+    /// used by attachments that do not choose a backend
+    pub mmio: MmioBackend,
+}
+
+/// Options of an attachment, see `vmsh attach --help` for their meaning.
+#[derive(Debug, Deserialize)]
+pub struct AttachRequest {
+    pub pid: i32,
+    /// `<index>` or `fd:<num>`
+    pub vm: Option<String>,
+    #[serde(default)]
+    pub command_line: Vec<String>,
+    pub stage2_path: Option<String>,
+    /// `<path>[:mount=<mountpoint>][:ro]`
+    #[serde(default)]
+    pub backing: Vec<String>,
+    /// `<path>[:ro]`
+    pub share: Option<String>,
+    pub pts: Option<PathBuf>,
+    pub console_socket: Option<PathBuf>,
+    pub irq: Option<usize>,
+    pub vsock: Option<VsockOptions>,
+    pub mmio: Option<MmioBackend>,
+}
+
+impl AttachRequest {
+    fn options(self, default_mmio: MmioBackend) -> std::result::Result<AttachOptions, String> {
+        let vm = match &self.vm {
+            Some(vm) => Some(vm.parse::<VmSelector>()?),
+            None => None,
+        };
+        let mut command = self.command_line;
+        command.insert(
+            0,
+            self.stage2_path
+                .unwrap_or_else(|| DEFAULT_STAGE2_PATH.to_string()),
+        );
+        let share = match &self.share {
+            Some(share) => Some(share.parse::<SharedDir>()?),
+            None => None,
+        };
+        let mut backing = self
+            .backing
+            .iter()
+            .map(|b| b.parse::<BackingFile>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        // same default as the command line
+        if backing.is_empty() && share.is_none() {
+            backing.push(BackingFile::new("/dev/null"));
+        }
+        // we have no terminal to offer
+        let console = match (self.pts, self.console_socket) {
+            (Some(_), Some(_)) => return Err("pts and console_socket conflict".to_string()),
+            (Some(pts), None) => ConsoleBackend::Pts(pts),
+            (None, Some(path)) => ConsoleBackend::Socket(path),
+            (None, None) => ConsoleBackend::Log,
+        };
+        Ok(AttachOptions {
+            pid: Pid::from_raw(self.pid),
+            vm,
+            command,
+            backing,
+            share,
+            console,
+            irq: self.irq,
+            vsock: self.vsock,
+            mmio: self.mmio.unwrap_or(default_mmio),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// lists all attachments
+    List,
+    /// starts an attachment in the background and returns its id
+    Attach(AttachRequest),
+    /// stops an attachment and waits until it is gone
+    Stop { id: usize },
+    /// reports the devices of a running attachment
+    Stats { id: usize },
+    /// stops all attachments and the daemon
+    Shutdown,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachmentState {
+    Starting,
+    Running,
+    Stopping,
+    Stopped,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttachmentInfo {
+    pub id: usize,
+    pub pid: i32,
+    pub state: AttachmentState,
+    /// why the attachment failed
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Attached { id: usize },
+    Attachments(Vec<AttachmentInfo>),
+    Stats(Vec<DeviceStats>),
+    Error(String),
+}
+
+struct Attachment {
+    pid: Pid,
+    control: Arc<Control>,
+    /// set once the attachment stopped, possibly on its own
+    outcome: Arc<Mutex<Option<std::result::Result<(), String>>>>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Attachment {
+    fn info(&self, id: usize) -> AttachmentInfo {
+        let outcome = match self.outcome.lock() {
+            Ok(outcome) => outcome.clone(),
+            Err(e) => Some(Err(format!("cannot lock outcome: {}", e))),
+        };
+        let (state, error) = match outcome {
+            Some(Ok(())) => (AttachmentState::Stopped, None),
+            Some(Err(e)) => (AttachmentState::Failed, Some(e)),
+            None => (self.running_state(), None),
+        };
+        AttachmentInfo {
+            id,
+            pid: self.pid.as_raw(),
+            state,
+            error,
+        }
+    }
+
+    fn running_state(&self) -> AttachmentState {
+        if self.control.is_stopping() {
+            AttachmentState::Stopping
+        } else if self.control.with_devices(|_| ()).is_some() {
+            AttachmentState::Running
+        } else {
+            AttachmentState::Starting
+        }
+    }
+
+    fn stop(self) -> Result<()> {
+        self.control.stop();
+        match self.thread.join() {
+            Ok(res) => res,
+            Err(e) => bail!("attachment thread panicked: {:?}", e),
+        }
+    }
+}
+
+struct Daemon {
+    control: Arc<Control>,
+    mmio: MmioBackend,
+    attachments: Mutex<BTreeMap<usize, Attachment>>,
+    next_id: AtomicUsize,
+}
+
+impl Daemon {
+    fn attach(&self, request: AttachRequest) -> Result<usize> {
+        let opts = match request.options(self.mmio) {
+            Ok(opts) => opts,
+            Err(e) => bail!("invalid attach request: {}", e),
+        };
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let pid = opts.pid;
+        let (control, receiver) = Control::new();
+        let outcome = Arc::new(Mutex::new(None));
+
+        let thread_control = Arc::clone(&control);
+        let thread_outcome = Arc::clone(&outcome);
+        let thread = try_with!(
+            thread::Builder::new()
+                .name(format!("attach-{}", id))
+                .spawn(move || {
+                    // this thread stays the tracer of the hypervisor until the attachment stops
+                    let res = attach::attach_controlled(&opts, &thread_control, receiver);
+                    if let Err(e) = &res {
+                        error!("attachment {} failed: {}", id, e);
+                    }
+                    let result = match &res {
+                        Ok(()) => Ok(()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    match thread_outcome.lock() {
+                        Ok(mut outcome) => *outcome = Some(result),
+                        Err(e) => error!("cannot lock outcome: {}", e),
+                    }
+                    res
+                }),
+            "cannot spawn attachment thread"
+        );
+
+        let attachment = Attachment {
+            pid,
+            control,
+            outcome,
+            thread,
+        };
+        try_with!(self.attachments.lock(), "cannot lock attachments").insert(id, attachment);
+        Ok(id)
+    }
+
+    fn take(&self, id: usize) -> Result<Attachment> {
+        let mut attachments = try_with!(self.attachments.lock(), "cannot lock attachments");
+        match attachments.remove(&id) {
+            Some(attachment) => Ok(attachment),
+            None => bail!("no attachment with id {}", id),
+        }
+    }
+
+    fn handle(&self, request: Request) -> Result<Response> {
+        Ok(match request {
+            Request::List => {
+                let attachments = try_with!(self.attachments.lock(), "cannot lock attachments");
+                Response::Attachments(
+                    attachments
+                        .iter()
+                        .map(|(id, attachment)| attachment.info(*id))
+                        .collect(),
+                )
+            }
+            Request::Attach(request) => Response::Attached {
+                id: self.attach(request)?,
+            },
+            Request::Stop { id } => {
+                self.take(id)?.stop()?;
+                Response::Ok
+            }
+            Request::Stats { id } => {
+                let attachments = try_with!(self.attachments.lock(), "cannot lock attachments");
+                let attachment = match attachments.get(&id) {
+                    Some(attachment) => attachment,
+                    None => bail!("no attachment with id {}", id),
+                };
+                match attachment.control.with_devices(|devices| devices.stats()) {
+                    Some(stats) => Response::Stats(stats?),
+                    None => bail!("attachment {} is not running", id),
+                }
+            }
+            Request::Shutdown => {
+                self.control.stop();
+                Response::Ok
+            }
+        })
+    }
+
+    fn serve_client(&self, stream: UnixStream) -> Result<()> {
+        let reader = BufReader::new(try_with!(stream.try_clone(), "cannot clone stream"));
+        let mut writer = stream;
+        for line in reader.lines() {
+            let line = try_with!(line, "cannot read request");
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => match self.handle(request) {
+                    Ok(response) => response,
+                    Err(e) => Response::Error(e.to_string()),
+                },
+                Err(e) => Response::Error(format!("invalid request: {}", e)),
+            };
+            try_with!(
+                serde_json::to_writer(&mut writer, &response),
+                "cannot write response"
+            );
+            try_with!(writer.write_all(b"\n"), "cannot write response");
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let attachments = match self.attachments.lock() {
+            Ok(mut attachments) => std::mem::take(&mut *attachments),
+            Err(e) => {
+                error!("cannot lock attachments: {}", e);
+                return;
+            }
+        };
+        // stop all at once, each one needs some time to clean up
+        for attachment in attachments.values() {
+            attachment.control.stop();
+        }
+        for (id, attachment) in attachments {
+            if let Err(e) = attachment.stop() {
+                warn!("attachment {} stopped with error: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Binds `path` with permissions 0600 so that only its owner can connect.
+pub(crate) fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let old = umask(Mode::from_bits_truncate(0o077));
+    let res = UnixListener::bind(path);
+    umask(old);
+    res
+}
+
+/// Only root and the user running the daemon may control it.
+fn peer_allowed(stream: &UnixStream) -> Result<bool> {
+    let creds = try_with!(
+        getsockopt(stream.as_raw_fd(), PeerCredentials),
+        "cannot get peer credentials"
+    );
+    Ok(creds.uid() == 0 || creds.uid() == geteuid().as_raw())
+}
+
+/// Serves the control socket until a client requests shutdown or we receive SIGTERM.
+pub fn daemon(opts: &DaemonOptions) -> Result<()> {
+    let (control, receiver) = Control::new();
+    signal_handler::setup(&control)?;
+
+    let listener = try_with!(
+        bind_private(&opts.socket),
+        "cannot bind control socket {}",
+        opts.socket.display()
+    );
+    try_with!(
+        listener.set_nonblocking(true),
+        "cannot set control socket non-blocking"
+    );
+    info!("listening on {}", opts.socket.display());
+
+    let daemon = Arc::new(Daemon {
+        control,
+        mmio: opts.mmio,
+        attachments: Mutex::new(BTreeMap::new()),
+        next_id: AtomicUsize::new(1),
+    });
+
+    let res = loop {
+        match receiver.try_recv() {
+            Err(TryRecvError::Empty) => {}
+            _ => break Ok(()),
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                match peer_allowed(&stream) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("rejecting client of another user");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+                        continue;
+                    }
+                }
+                if let Err(e) = stream.set_nonblocking(false) {
+                    warn!("cannot set client socket blocking: {}", e);
+                    continue;
+                }
+                let daemon = Arc::clone(&daemon);
+                let res = thread::Builder::new()
+                    .name("daemon-client".to_string())
+                    .spawn(move || {
+                        if let Err(e) = daemon.serve_client(stream) {
+                            warn!("{}", e);
+                        }
+                    });
+                if let Err(e) = res {
+                    warn!("cannot spawn client thread: {}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+            Err(e) => break Err(e),
+        }
+    };
+
+    info!("shutdown daemon");
+    daemon.shutdown();
+    if let Err(e) = std::fs::remove_file(&opts.socket) {
+        warn!("cannot remove {}: {}", opts.socket.display(), e);
+    }
+    try_with!(res, "cannot accept clients");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request: Request = serde_json::from_str(
+            r#"{"command": "attach", "pid": 42, "vm": "fd:11", "backing": ["disk.img:mount=data:ro"], "mmio": "ioregionfd"}"#,
+        )
+        .expect("cannot parse attach request");
+        let opts = match request {
+            Request::Attach(request) => request
+                .options(MmioBackend::WrapSyscall)
+                .expect("invalid options"),
+            r => panic!("unexpected request {:?}", r),
+        };
+        assert_eq!(opts.pid, Pid::from_raw(42));
+        assert_eq!(opts.vm, Some(VmSelector::Fd(11)));
+        assert_eq!(opts.command, vec![DEFAULT_STAGE2_PATH.to_string()]);
+        assert_eq!(opts.backing[0].mountpoint, Some(PathBuf::from("data")));
+        assert!(opts.backing[0].read_only);
+        assert_eq!(opts.console, ConsoleBackend::Log);
+        assert_eq!(opts.mmio, MmioBackend::IoRegionFd);
+
+        let request: Request =
+            serde_json::from_str(r#"{"command": "stop", "id": 3}"#).expect("cannot parse stop");
+        assert!(matches!(request, Request::Stop { id: 3 }));
+    }
+}
@@ -2,39 +2,119 @@ pub mod mmio;
 mod threads;
 pub mod virtio;
 
+use crate::attach::{BackingFile, SharedDir};
+use crate::control::Control;
 use crate::devices::mmio::IoPirate;
 use crate::devices::threads::SubscriberEventManager;
 use crate::devices::virtio::block::{self, BlockArgs};
-use crate::devices::virtio::console::{self, ConsoleArgs};
-use crate::devices::virtio::{CommonArgs, MmioConfig};
+use crate::devices::virtio::console::{self, ConsoleArgs, ConsoleBackend, ExecStatus};
+use crate::devices::virtio::p9::{self, P9Args};
+use crate::devices::virtio::state::{self, Restore, VirtioState};
+use crate::devices::virtio::vsock::{self, VsockArgs, VsockOptions};
+use crate::devices::virtio::{CommonArgs, IoStats, MmioConfig};
 use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
 use crate::result::Result;
 use crate::tracer::proc::Mapping;
+use ioutils::block::serial;
+use ioutils::share::SHARE_TAG;
 use libc::pid_t;
-use simple_error::{bail, try_with};
-use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
+use log::info;
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, require_with, try_with};
+use std::borrow::{Borrow, BorrowMut};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use virtio_device::{VirtioConfig, VirtioMmioDevice};
 use vm_device::device_manager::MmioManager;
 use vm_memory::guest_memory::GuestAddress;
 use vm_memory::mmap::MmapRegion;
 use vm_memory::GuestMemoryRegion;
 use vm_memory::{GuestMemoryMmap, GuestRegionMmap};
 
-pub use self::threads::DeviceSet;
+pub use self::threads::{DeviceSet, DriverNotifier, Threads};
 
-/// Should be initialized by the argument parser.
-pub static USE_IOREGIONFD: AtomicBool = AtomicBool::new(false);
+/// How the devices of an attachment receive the MMIO accesses of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MmioBackend {
+    /// traps the KVM_RUN ioctl of the hypervisor and handles MMIO exits in the vcpu threads
+    #[serde(rename = "wrap_syscall")]
+    WrapSyscall,
+    /// receives accesses on an ioregionfd, requires a kernel with ioregionfd support
+    #[serde(rename = "ioregionfd")]
+    IoRegionFd,
+    /// ioregionfd if KVM supports it, wrap_syscall otherwise. Replaced by `resolve` before the
+    /// devices are created.
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl Default for MmioBackend {
+    fn default() -> Self {
+        MmioBackend::WrapSyscall
+    }
+}
+
+impl FromStr for MmioBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "wrap_syscall" => Ok(MmioBackend::WrapSyscall),
+            "ioregionfd" => Ok(MmioBackend::IoRegionFd),
+            "auto" => Ok(MmioBackend::Auto),
+            _ => Err(format!("unknown mmio backend: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for MmioBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MmioBackend::WrapSyscall => "wrap_syscall",
+            MmioBackend::IoRegionFd => "ioregionfd",
+            MmioBackend::Auto => "auto",
+        };
+        write!(f, "{}", name)
+    }
+}
 
-pub fn use_ioregionfd() -> bool {
-    USE_IOREGIONFD.load(Ordering::Relaxed)
+impl MmioBackend {
+    /// Picks the backend for `Auto` and checks that KVM supports the chosen one.
+    /// Needs the hypervisor to be stopped.
+    pub fn resolve(self, vm: &Hypervisor) -> Result<MmioBackend> {
+        let ioregionfd = IoRegionFd::capability_present(vm)?;
+        let backend = match self {
+            MmioBackend::IoRegionFd if !ioregionfd => {
+                bail!("--mmio ioregionfd needs KVM_CAP_IOREGIONFD which your KVM does not have, try --mmio wrap_syscall")
+            }
+            MmioBackend::Auto if ioregionfd => MmioBackend::IoRegionFd,
+            MmioBackend::Auto => MmioBackend::WrapSyscall,
+            backend => backend,
+        };
+        if self == MmioBackend::Auto {
+            let support = if ioregionfd {
+                "supports"
+            } else {
+                "does not support"
+            };
+            info!(
+                "use mmio backend {} picked by --mmio auto, kvm {} ioregionfd",
+                backend, support
+            );
+        } else {
+            info!("use mmio backend {}", backend);
+        }
+        Ok(backend)
+    }
 }
 
 pub type Block = block::Block<Arc<GuestMemoryMmap>>;
 pub type Console = console::Console<Arc<GuestMemoryMmap>>;
+pub type Vsock = vsock::Vsock<Arc<GuestMemoryMmap>>;
+pub type P9 = p9::P9<Arc<GuestMemoryMmap>>;
 
 fn convert(pid: pid_t, mappings: &[Mapping]) -> Result<GuestMemoryMmap> {
     let mut regions: Vec<Arc<GuestRegionMmap>> = vec![];
@@ -71,42 +151,258 @@ fn convert(pid: pid_t, mappings: &[Mapping]) -> Result<GuestMemoryMmap> {
     ))
 }
 
+/// Configuration of the devices we attach to the VM
+pub struct DeviceOptions {
+    /// files served as block devices, the first one is the root of the attached environment
+    /// unless `share` is set
+    pub backing: Vec<BackingFile>,
+    /// host directory exported over 9p as root of the attached environment
+    pub share: Option<SharedDir>,
+    pub console: ConsoleBackend,
+    /// set by `vmsh exec` to receive the exit status of the command
+    pub exec_status: Option<Arc<ExecStatus>>,
+    /// adds a vsock device if set
+    pub vsock: Option<VsockOptions>,
+    pub mmio: MmioBackend,
+    pub control: Arc<Control>,
+}
+
 trait MaybeIoRegionFd {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd>;
 }
 
 pub struct DeviceContext {
-    pub blkdev: Arc<Mutex<Block>>,
+    /// the first block device is the root of the attached environment unless `share` is set
+    pub blkdevs: Vec<Arc<Mutex<Block>>>,
     pub console: Arc<Mutex<Console>>,
+    pub vsock: Option<Arc<Mutex<Vsock>>>,
+    pub share: Option<Arc<Mutex<P9>>>,
     pub mmio_mgr: Arc<Mutex<IoPirate>>,
     /// start address of mmio space
     pub first_mmio_addr: u64,
     /// start address of mmio space
     pub last_mmio_addr: u64,
+    pub mmio_backend: MmioBackend,
+    mem: Arc<GuestMemoryMmap>,
+}
+
+/// Negotiated state and progress of a device, reported by `vmsh daemon`.
+#[derive(Debug, Serialize)]
+pub struct DeviceStats {
+    pub kind: &'static str,
+    pub mmio_base: u64,
+    pub activated: bool,
+    pub device_status: u8,
+    pub driver_features: u64,
+    pub queues: Vec<QueueStats>,
+    pub io: IoStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    pub size: u16,
+    pub ready: bool,
+    /// index of the next request we take from the queue, wraps around
+    pub next_avail: u16,
+}
+
+fn device_stats<D>(kind: &'static str, dev: &D, mmio_base: u64, io: IoStats) -> DeviceStats
+where
+    D: Borrow<VirtioConfig<Arc<GuestMemoryMmap>>>,
+{
+    let cfg: &VirtioConfig<Arc<GuestMemoryMmap>> = dev.borrow();
+    DeviceStats {
+        kind,
+        mmio_base,
+        activated: cfg.device_activated,
+        device_status: cfg.device_status,
+        driver_features: cfg.driver_features,
+        queues: cfg
+            .queues
+            .iter()
+            .map(|q| QueueStats {
+                size: q.size,
+                ready: q.ready,
+                next_avail: q.next_avail(),
+            })
+            .collect(),
+        io,
+    }
+}
+
+fn restore_device<D>(
+    dev: &Mutex<D>,
+    mmio_base: u64,
+    states: &[VirtioState],
+    mem: &GuestMemoryMmap,
+) -> Result<()>
+where
+    D: VirtioMmioDevice<Arc<GuestMemoryMmap>>
+        + BorrowMut<VirtioConfig<Arc<GuestMemoryMmap>>>
+        + Restore,
+{
+    let state = match states.iter().find(|s| s.mmio_base == mmio_base) {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+    let mut dev = try_with!(dev.lock(), "cannot lock device");
+    state::restore(&mut *dev, state, mem)
 }
 
 impl DeviceContext {
     pub fn mmio_addrs(&self) -> Result<Vec<u64>> {
-        Ok(vec![
-            try_with!(self.blkdev.lock(), "cannot lock block device")
+        let mut addrs = vec![];
+        for blkdev in &self.blkdevs {
+            addrs.push(
+                try_with!(blkdev.lock(), "cannot lock block device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        addrs.push(
+            try_with!(self.console.lock(), "cannot lock console device")
                 .mmio_cfg
                 .range
                 .base()
                 .0,
-            try_with!(self.console.lock(), "cannot lock console device")
+        );
+        if let Some(vsock) = &self.vsock {
+            addrs.push(
+                try_with!(vsock.lock(), "cannot lock vsock device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        if let Some(share) = &self.share {
+            addrs.push(
+                try_with!(share.lock(), "cannot lock 9p device")
+                    .mmio_cfg
+                    .range
+                    .base()
+                    .0,
+            );
+        }
+        Ok(addrs)
+    }
+
+    pub fn stats(&self) -> Result<Vec<DeviceStats>> {
+        let mut stats = vec![];
+        for blkdev in &self.blkdevs {
+            let dev = try_with!(blkdev.lock(), "cannot lock block device");
+            stats.push(device_stats(
+                "block",
+                &*dev,
+                dev.mmio_cfg.range.base().0,
+                dev.io.stats(),
+            ));
+        }
+        let console = try_with!(self.console.lock(), "cannot lock console device");
+        stats.push(device_stats(
+            "console",
+            &*console,
+            console.mmio_cfg.range.base().0,
+            console.io.stats(),
+        ));
+        if let Some(vsock) = &self.vsock {
+            let dev = try_with!(vsock.lock(), "cannot lock vsock device");
+            stats.push(device_stats(
+                "vsock",
+                &*dev,
+                dev.mmio_cfg.range.base().0,
+                dev.io.stats(),
+            ));
+        }
+        if let Some(share) = &self.share {
+            let dev = try_with!(share.lock(), "cannot lock 9p device");
+            stats.push(device_stats(
+                "9p",
+                &*dev,
+                dev.mmio_cfg.range.base().0,
+                dev.io.stats(),
+            ));
+        }
+        Ok(stats)
+    }
+
+    /// Returns what the driver negotiated with our devices, so that another vmsh process can
+    /// take them over with `restore_virtio`.
+    pub fn save_virtio(&self) -> Result<Vec<VirtioState>> {
+        let mut states = vec![];
+        for blkdev in &self.blkdevs {
+            let dev = try_with!(blkdev.lock(), "cannot lock block device");
+            states.push(state::save::<Arc<GuestMemoryMmap>, _>(
+                &*dev,
+                dev.mmio_cfg.range.base().0,
+            ));
+        }
+        let console = try_with!(self.console.lock(), "cannot lock console device");
+        states.push(state::save::<Arc<GuestMemoryMmap>, _>(
+            &*console,
+            console.mmio_cfg.range.base().0,
+        ));
+        if let Some(vsock) = &self.vsock {
+            let dev = try_with!(vsock.lock(), "cannot lock vsock device");
+            states.push(state::save::<Arc<GuestMemoryMmap>, _>(
+                &*dev,
+                dev.mmio_cfg.range.base().0,
+            ));
+        }
+        if let Some(share) = &self.share {
+            let dev = try_with!(share.lock(), "cannot lock 9p device");
+            states.push(state::save::<Arc<GuestMemoryMmap>, _>(
+                &*dev,
+                dev.mmio_cfg.range.base().0,
+            ));
+        }
+        Ok(states)
+    }
+
+    /// Puts our devices into the state saved by `save_virtio`. Devices are matched by their mmio
+    /// address, so they have to be allocated in the same order as before.
+    pub fn restore_virtio(&self, states: &[VirtioState]) -> Result<()> {
+        for blkdev in &self.blkdevs {
+            let base = try_with!(blkdev.lock(), "cannot lock block device")
                 .mmio_cfg
                 .range
                 .base()
-                .0,
-        ])
+                .0;
+            restore_device(blkdev, base, states, &self.mem)?;
+        }
+        let base = try_with!(self.console.lock(), "cannot lock console device")
+            .mmio_cfg
+            .range
+            .base()
+            .0;
+        restore_device(&self.console, base, states, &self.mem)?;
+        if let Some(vsock) = &self.vsock {
+            let base = try_with!(vsock.lock(), "cannot lock vsock device")
+                .mmio_cfg
+                .range
+                .base()
+                .0;
+            restore_device(vsock, base, states, &self.mem)?;
+        }
+        if let Some(share) = &self.share {
+            let base = try_with!(share.lock(), "cannot lock 9p device")
+                .mmio_cfg
+                .range
+                .base()
+                .0;
+            restore_device(share, base, states, &self.mem)?;
+        }
+        Ok(())
     }
+
     pub fn new(
         vmm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
         event_mgr: &mut SubscriberEventManager,
         irq_num: usize,
-        backing: &Path,
-        pts: Option<PathBuf>,
+        opts: DeviceOptions,
     ) -> Result<DeviceContext> {
         let guest_memory = try_with!(vmm.get_maps(), "cannot get guests memory");
         let mem = Arc::new(try_with!(
@@ -114,22 +410,62 @@ impl DeviceContext {
             "cannot convert Mapping to GuestMemoryMmap"
         ));
 
-        let block_mmio_cfg = MmioConfig {
-            range: allocator.alloc_mmio_range(0x1000)?,
-            gsi: irq_num as u32,
-        };
+        let mut block_mmio_cfgs = vec![];
+        for _ in &opts.backing {
+            block_mmio_cfgs.push(MmioConfig {
+                range: allocator.alloc_mmio_range(0x1000)?,
+                gsi: irq_num as u32,
+                backend: opts.mmio,
+            });
+        }
 
         let console_mmio_cfg = MmioConfig {
             range: allocator.alloc_mmio_range(0x1000)?,
             gsi: irq_num as u32,
+            backend: opts.mmio,
         };
 
-        let first_mmio_addr = console_mmio_cfg.range.base().0;
-        let last_mmio_addr = block_mmio_cfg.range.last().0;
+        let vsock_mmio_cfg = match opts.vsock {
+            Some(_) => Some(MmioConfig {
+                range: allocator.alloc_mmio_range(0x1000)?,
+                gsi: irq_num as u32,
+                backend: opts.mmio,
+            }),
+            None => None,
+        };
+
+        let share_mmio_cfg = match opts.share {
+            Some(_) => Some(MmioConfig {
+                range: allocator.alloc_mmio_range(0x1000)?,
+                gsi: irq_num as u32,
+                backend: opts.mmio,
+            }),
+            None => None,
+        };
+
+        let mmio_ranges = block_mmio_cfgs
+            .iter()
+            .chain(std::iter::once(&console_mmio_cfg))
+            .chain(vsock_mmio_cfg.iter())
+            .chain(share_mmio_cfg.iter())
+            .map(|cfg| cfg.range)
+            .collect::<Vec<_>>();
+        let first_mmio_addr = require_with!(
+            mmio_ranges.iter().map(|r| r.base().0).min(),
+            "no mmio ranges allocated"
+        );
+        let last_mmio_addr = require_with!(
+            mmio_ranges.iter().map(|r| r.last().0).max(),
+            "no mmio ranges allocated"
+        );
 
         // IoManager replacement:
         let device_manager = Arc::new(Mutex::new(IoPirate::default()));
-        let blkdev = {
+        let mut blkdevs = vec![];
+        // a shared directory takes the place of the root block device
+        let first_serial = usize::from(opts.share.is_some());
+        for (idx, (backing, block_mmio_cfg)) in opts.backing.iter().zip(block_mmio_cfgs).enumerate()
+        {
             let guard = try_with!(device_manager.lock(), "cannot lock device manager");
             guard.mmio_device(block_mmio_cfg.range.base());
 
@@ -142,28 +478,38 @@ impl DeviceContext {
             };
             let args = BlockArgs {
                 common,
-                file_path: backing.to_path_buf(),
-                read_only: false,
-                root_device: true,
+                file_path: backing.path.clone(),
+                read_only: backing.read_only,
+                root_device: idx + first_serial == 0,
                 advertise_flush: true,
+                serial: serial(idx + first_serial),
             };
             match Block::new(args) {
-                Ok(v) => v,
-                Err(e) => bail!("cannot create block device: {:?}", e),
+                Ok(v) => blkdevs.push(v),
+                Err(e) => bail!(
+                    "cannot create block device for {}: {:?}",
+                    backing.path.display(),
+                    e
+                ),
             }
-        };
+        }
         let console = {
             let guard = try_with!(device_manager.lock(), "cannot lock device manager");
             guard.mmio_device(console_mmio_cfg.range.base());
 
             let common = CommonArgs {
-                mem,
+                mem: Arc::clone(&mem),
                 vmm: vmm.clone(),
                 event_mgr,
                 mmio_mgr: guard,
                 mmio_cfg: console_mmio_cfg,
             };
-            let args = ConsoleArgs { common, pts };
+            let args = ConsoleArgs {
+                common,
+                backend: opts.console,
+                exec_status: opts.exec_status,
+                control: opts.control,
+            };
 
             match Console::new(args) {
                 Ok(v) => v,
@@ -171,12 +517,73 @@ impl DeviceContext {
             }
         };
 
+        let vsock = match (opts.vsock, vsock_mmio_cfg) {
+            (Some(vsock_opts), Some(vsock_mmio_cfg)) => {
+                let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+                guard.mmio_device(vsock_mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg: vsock_mmio_cfg,
+                };
+                let path = vsock_opts.uds_path.clone();
+                let args = VsockArgs {
+                    common,
+                    opts: vsock_opts,
+                };
+
+                match Vsock::new(args) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!("cannot create vsock device on {}: {:?}", path.display(), e),
+                }
+            }
+            _ => None,
+        };
+
+        let share = match (opts.share, share_mmio_cfg) {
+            (Some(share), Some(share_mmio_cfg)) => {
+                let guard = try_with!(device_manager.lock(), "cannot lock device manager");
+                guard.mmio_device(share_mmio_cfg.range.base());
+
+                let common = CommonArgs {
+                    mem: Arc::clone(&mem),
+                    vmm: vmm.clone(),
+                    event_mgr,
+                    mmio_mgr: guard,
+                    mmio_cfg: share_mmio_cfg,
+                };
+                let args = P9Args {
+                    common,
+                    root: share.path.clone(),
+                    tag: SHARE_TAG.to_string(),
+                    read_only: share.read_only,
+                };
+
+                match P9::new(args) {
+                    Ok(v) => Some(v),
+                    Err(e) => bail!(
+                        "cannot create 9p device for {}: {:?}",
+                        share.path.display(),
+                        e
+                    ),
+                }
+            }
+            _ => None,
+        };
+
         let device = DeviceContext {
-            blkdev,
+            blkdevs,
             console,
+            vsock,
+            share,
             mmio_mgr: device_manager,
             first_mmio_addr,
             last_mmio_addr,
+            mmio_backend: opts.mmio,
+            mem,
         };
 
         Ok(device)
@@ -8,16 +8,16 @@ use log::error;
 use log::{info, log_enabled, trace, Level};
 use simple_error::{bail, require_with, simple_error, try_with};
 use stage1_interface::DeviceState;
-use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::SyncSender;
 use std::sync::Arc;
 use std::sync::{Condvar, Mutex};
 use virtio_device::{VirtioDevice, WithDriverSelect};
 
-use crate::devices;
-use crate::devices::DeviceContext;
+use crate::devices::virtio::state::VirtioState;
 use crate::devices::MaybeIoRegionFd;
+use crate::devices::MmioBackend;
+use crate::devices::{DeviceContext, DeviceOptions};
 use crate::interrutable_thread::InterrutableThread;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::kvm::PhysMemAllocator;
@@ -129,11 +129,19 @@ fn event_thread(
     device_space: &DeviceContext,
     err_sender: &SyncSender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
-    let blkdev = device_space.blkdev.clone();
-    let ack_handler = {
+    let mut ack_handlers = vec![];
+    for blkdev in &device_space.blkdevs {
         let blkdev = try_with!(blkdev.lock(), "cannot unlock thread");
-        blkdev.irq_ack_handler.clone()
-    };
+        ack_handlers.push(blkdev.irq_ack_handler.clone());
+    }
+    if let Some(vsock) = &device_space.vsock {
+        let vsock = try_with!(vsock.lock(), "cannot lock vsock device");
+        ack_handlers.push(vsock.irq_ack_handler.clone());
+    }
+    if let Some(share) = &device_space.share {
+        let share = try_with!(share.lock(), "cannot lock 9p device");
+        ack_handlers.push(share.irq_ack_handler.clone());
+    }
     log::debug!("event thread started");
 
     let res = InterrutableThread::spawn(
@@ -149,7 +157,7 @@ fn event_thread(
                     }
                     Err(e) => log::warn!("Failed to handle events: {:?}", e),
                 }
-                {
+                for ack_handler in &ack_handlers {
                     let mut ack_handler = try_with!(ack_handler.lock(), "failed to lock");
                     ack_handler.handle_timeouts();
                 }
@@ -169,14 +177,14 @@ fn blkdev_monitor_thread(
     device: &DeviceContext,
     err_sender: &SyncSender<()>,
 ) -> Result<InterrutableThread<(), Option<Arc<DeviceContext>>>> {
-    let blkdev = device.blkdev.clone();
+    let blkdevs = device.blkdevs.clone();
     let res = InterrutableThread::spawn(
         "blkdev-monitor",
         err_sender,
         move |_ctx: &Option<Arc<DeviceContext>>, should_stop: Arc<AtomicBool>| {
             //std::thread::sleep(std::time::Duration::from_millis(10000));
             loop {
-                {
+                for blkdev in &blkdevs {
                     let blkdev = try_with!(blkdev.lock(), "cannot unlock thread");
                     // debug!("");
                     // debug!("dev type {}", blkdev.device_type());
@@ -380,25 +388,21 @@ impl DeviceSet {
         self.context.mmio_addrs()
     }
 
+    pub fn context(&self) -> Arc<DeviceContext> {
+        Arc::clone(&self.context)
+    }
+
     pub fn new(
         vm: &Arc<Hypervisor>,
         allocator: &mut PhysMemAllocator,
         irq_num: usize,
-        backing_file: &Path,
-        pts: Option<PathBuf>,
+        opts: DeviceOptions,
     ) -> Result<DeviceSet> {
         let mut event_manager =
             try_with!(SubscriberEventManager::new(), "cannot create event manager");
         // instantiate blkdev
         let context = Arc::new(try_with!(
-            DeviceContext::new(
-                vm,
-                allocator,
-                &mut event_manager,
-                irq_num,
-                backing_file,
-                pts
-            ),
+            DeviceContext::new(vm, allocator, &mut event_manager, irq_num, opts),
             "cannot create device context"
         ));
         Ok(DeviceSet {
@@ -407,11 +411,14 @@ impl DeviceSet {
         })
     }
 
+    /// `restore` takes over the devices of a detached vmsh process instead of waiting for the
+    /// driver to set them up.
     pub fn start(
         self,
         vm: &Arc<Hypervisor>,
         device_status: DeviceStatus,
         driver_status: DriverStatus,
+        restore: Option<&[VirtioState]>,
         err_sender: &SyncSender<()>,
     ) -> Result<(Threads, Arc<DriverNotifier>)> {
         let driver_notifier = Arc::new(DriverNotifier::new(
@@ -421,11 +428,19 @@ impl DeviceSet {
         ));
         let mut threads = vec![event_thread(self.event_manager, &self.context, err_sender)?];
 
+        // needs the event thread to register the queue handlers on activation
+        if let Some(states) = restore {
+            try_with!(
+                self.context.restore_virtio(states),
+                "cannot restore device state"
+            );
+        }
+
         if log_enabled!(Level::Debug) {
             threads.push(blkdev_monitor_thread(&self.context, err_sender)?);
         }
 
-        if devices::use_ioregionfd() {
+        if self.context.mmio_backend == MmioBackend::IoRegionFd {
             vm.resume()?;
             // Device was ready already before that but this way,
             // we only only indicate readiness just before we create our io threads.
@@ -433,15 +448,17 @@ impl DeviceSet {
                 driver_notifier.notify(DeviceState::Ready),
                 "cannot update device status"
             );
-            threads.push(try_with!(
-                ioregion_handler_thread(
-                    self.context.clone(),
-                    self.context.blkdev.clone(),
-                    self.context.mmio_mgr.clone(),
-                    err_sender,
-                ),
-                "cannot spawn block ioregion handler"
-            ));
+            for blkdev in &self.context.blkdevs {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        blkdev.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender,
+                    ),
+                    "cannot spawn block ioregion handler"
+                ));
+            }
             threads.push(try_with!(
                 ioregion_handler_thread(
                     self.context.clone(),
@@ -451,6 +468,28 @@ impl DeviceSet {
                 ),
                 "cannot spawn console ioregion handler"
             ));
+            if let Some(vsock) = &self.context.vsock {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        vsock.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender,
+                    ),
+                    "cannot spawn vsock ioregion handler"
+                ));
+            }
+            if let Some(share) = &self.context.share {
+                threads.push(try_with!(
+                    ioregion_handler_thread(
+                        self.context.clone(),
+                        share.clone(),
+                        self.context.mmio_mgr.clone(),
+                        err_sender,
+                    ),
+                    "cannot spawn 9p ioregion handler"
+                ));
+            }
         } else {
             threads.push(mmio_exit_handler_thread(
                 vm,
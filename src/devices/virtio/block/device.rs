@@ -22,7 +22,6 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestAddressSpace;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::devices::use_ioregionfd;
 use crate::devices::virtio::block::inorder_handler::Mmap;
 use crate::devices::virtio::block::{
     BLOCK_DEVICE_ID, SECTOR_SHIFT, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO,
@@ -30,8 +29,12 @@ use crate::devices::virtio::block::{
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::state::{notify_ioevents, Restore};
+use crate::devices::virtio::{
+    IoCounters, IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+};
 use crate::devices::MaybeIoRegionFd;
+use crate::devices::MmioBackend;
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
 };
@@ -55,9 +58,12 @@ pub struct Block<M: GuestAddressSpace> {
     /// only used when ioregionfd != None
     file_path: PathBuf,
     read_only: bool,
+    device_id: [u8; 20],
     sub_id: Option<SubscriberId>,
     guest_memory: Arc<Mutex<Option<M>>>,
     pid: Pid,
+    /// shared with the queue handler
+    pub io: Arc<IoCounters>,
 
     // Before resetting we return the handler to the mmio thread for cleanup
     #[allow(dead_code)]
@@ -95,6 +101,16 @@ where
         let mem = args.common.mem.clone();
         let queues = vec![Queue::new(args.common.mem, QUEUE_MAX_SIZE)];
         let config_space = build_config_space(&args.file_path)?;
+
+        let mut device_id = [0u8; 20];
+        let serial = args.serial.as_bytes();
+        if serial.len() > device_id.len() {
+            return Err(Error::Simple(SimpleError::new(format!(
+                "block device serial is too long: {}",
+                args.serial
+            ))));
+        }
+        device_id[..serial.len()].copy_from_slice(serial);
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Used to send notifications to the driver.
@@ -115,7 +131,7 @@ where
         )));
 
         let mut ioregionfd = None;
-        if use_ioregionfd() {
+        if mmio_cfg.backend == MmioBackend::IoRegionFd {
             ioregionfd = Some(
                 args.common
                     .vmm
@@ -138,11 +154,13 @@ where
             uioefd,
             file_path: args.file_path,
             read_only: args.read_only,
+            device_id,
             pid: args.common.vmm.pid,
             sub_id: None,
             handler: None,
             _root_device: args.root_device,
             guest_memory: Arc::new(Mutex::new(Some(mem))),
+            io: Arc::new(IoCounters::default()),
         }));
 
         // Register the device on the MMIO bus.
@@ -185,7 +203,7 @@ where
 
         let disk_size = file.seek(SeekFrom::End(0)).map_err(Error::Seek)?;
 
-        let mmap = match Mmap::new(&file, disk_size as usize) {
+        let mmap = match Mmap::new(&file, disk_size as usize, self.read_only) {
             Ok(m) => m,
             Err(e) => {
                 return Err(Error::Simple(SimpleError::new(format!(
@@ -205,7 +223,7 @@ where
         // TODO: Create the backend earlier (as part of `Block::new`)?
         let disk = StdIoBackend::new(file, features)
             .map_err(Error::Backend)?
-            .with_device_id(*b"vmsh0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+            .with_device_id(self.device_id);
 
         let driver_notify = SingleFdSignalQueue {
             irqfd: self.irqfd.clone(),
@@ -220,8 +238,10 @@ where
             disk,
             sectors: disk_size >> SECTOR_SHIFT,
             mmap,
+            read_only: self.read_only,
             guest_addresspace: guest_mem.memory(),
             remote_iovs: vec![],
+            io: Arc::clone(&self.io),
         };
         let handler = Arc::new(Mutex::new(QueueHandler {
             inner,
@@ -272,6 +292,12 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for Block<M>
     }
 }
 
+impl<M: GuestAddressSpace + Clone + Send + 'static> Restore for Block<M> {
+    fn notify_queues(&self) {
+        notify_ioevents(self.ioeventfd.iter());
+    }
+}
+
 // We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
 // for `VirtioDevice`.
 impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Block<M> {
@@ -314,7 +340,7 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Bloc
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for Block<M> {
     fn queue_notify(&mut self, val: u32) {
-        if use_ioregionfd() {
+        if self.mmio_cfg.backend == MmioBackend::IoRegionFd {
             self.uioefd.queue_notify(val);
             log::trace!("queue_notify {}", val);
         }
@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::fs::File;
+use std::sync::Arc;
 use std::{io, ptr, result, slice};
 
 use libc::c_void;
@@ -17,7 +18,7 @@ use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue};
 use vm_memory::{self, Bytes, GuestAddressSpace, GuestMemory, GuestMemoryError};
 
-use crate::devices::virtio::SignalUsedQueue;
+use crate::devices::virtio::{IoCounters, SignalUsedQueue};
 
 #[derive(Debug)]
 pub enum Error {
@@ -45,12 +46,17 @@ pub struct Mmap {
 unsafe impl Send for Mmap {}
 
 impl Mmap {
-    pub fn new(file: &File, len: usize) -> nix::Result<Mmap> {
+    pub fn new(file: &File, len: usize, read_only: bool) -> nix::Result<Mmap> {
+        let prot = if read_only {
+            ProtFlags::PROT_READ
+        } else {
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
+        };
         let ptr = unsafe {
             mmap(
                 ptr::null_mut(),
                 len,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                prot,
                 MapFlags::MAP_SHARED,
                 file.as_raw_fd(),
                 0,
@@ -79,12 +85,15 @@ pub struct InOrderQueueHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub disk: StdIoBackend<File>,
     pub sectors: u64,
     pub mmap: Mmap,
+    /// reject write requests of the driver
+    pub read_only: bool,
     //pub guest_memory: Arc<Mutex<Option<M>>>,
     pub guest_addresspace: M::T,
     pub pid: Pid,
 
     // we have those here to safe reallocations across requests
     pub remote_iovs: Vec<RemoteIoVec>,
+    pub io: Arc<IoCounters>,
 }
 
 unsafe impl<M: GuestAddressSpace, S: SignalUsedQueue> Send for InOrderQueueHandler<M, S> {}
@@ -172,6 +181,11 @@ where
                         })? as u32;
             }
             RequestType::Out => {
+                if self.read_only {
+                    return Err(stdio_executor::Error::Write(GuestMemoryError::IOError(
+                        io::Error::from_raw_os_error(libc::EROFS),
+                    )));
+                }
                 self.check_access(total_len / SECTOR_SIZE, request.sector())?;
                 self.prepare_iovs(request)?;
                 let local_iovs = vec![IoVec::from_mut_slice(unsafe {
@@ -214,6 +228,7 @@ where
                 log::trace!("request: {:?}", request);
                 let status = match self.execute(chain.memory(), &request) {
                     Ok(l) => {
+                        self.io.request(l as usize);
                         // TODO: Using `saturating_add` until we consume the recent changes
                         // proposed for the executor upstream.
                         len = l.saturating_add(1);
@@ -222,6 +237,7 @@ where
                     }
                     Err(e) => {
                         warn!("failed to execute block request: {:?}", e);
+                        self.io.error();
                         len = 1;
                         // TODO: add `status` or similar method to executor error.
                         if let stdio_executor::Error::Unsupported(_) = e {
@@ -241,6 +257,7 @@ where
             Err(e) => {
                 len = 0;
                 warn!("block request parse error: {:?}", e);
+                self.io.error();
             }
         }
 
@@ -78,6 +78,8 @@ pub struct BlockArgs<'a, M, B> {
     pub read_only: bool,
     pub root_device: bool,
     pub advertise_flush: bool,
+    /// reported as device id to the guest, at most 20 bytes
+    pub serial: String,
 }
 
 #[cfg(test)]
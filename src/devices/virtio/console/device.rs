@@ -3,11 +3,13 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
-use std::fs::OpenOptions;
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::io::Write;
 use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixListener;
 use std::sync::{Arc, Mutex};
 use virtio_device::{VirtioDevice, VirtioDeviceType};
 
@@ -20,20 +22,29 @@ use vm_device::{DeviceMmio, MutDeviceMmio};
 use vm_memory::GuestAddressSpace;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::devices::use_ioregionfd;
+use crate::control::Control;
+use crate::devices::virtio::console::exec::{ExecOutput, ExecStatus};
 use crate::devices::virtio::console::log_handler::LogQueueHandler;
+use crate::devices::virtio::console::resize::{
+    window_size, PendingResize, ResizeHandler, WindowSize, DEFAULT_WINDOW_SIZE,
+};
+use crate::devices::virtio::console::socket::ConsoleSocket;
 use crate::devices::virtio::console::VIRTIO_CONSOLE_F_SIZE;
 use crate::devices::virtio::features::{
     VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
 };
-use crate::devices::virtio::{IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE};
+use crate::devices::virtio::state::{notify_ioevents, Restore};
+use crate::devices::virtio::{
+    IoCounters, IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+};
 use crate::devices::MaybeIoRegionFd;
+use crate::devices::MmioBackend;
 use crate::kvm::hypervisor::{
     ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
 };
 
 //use super::queue_handler::QueueHandler;
-use super::{build_config_space, ConsoleArgs, Error, Result, CONSOLE_DEVICE_ID};
+use super::{build_config_space, ConsoleArgs, ConsoleBackend, Error, Result, CONSOLE_DEVICE_ID};
 use simple_error::{map_err_with, SimpleError};
 
 pub(super) const RX_QUEUE_IDX: u16 = 0;
@@ -50,7 +61,20 @@ pub struct Console<M: GuestAddressSpace> {
     tx_fd: Option<IoEvent>,
     /// only used when ioregionfd != None
     sub_id: Option<SubscriberId>,
-    pts: Option<PathBuf>,
+    backend: ConsoleBackend,
+    /// only set for `ConsoleBackend::Socket`, shared with the queue handler so it survives a
+    /// reset of the device
+    listener: Option<Arc<UnixListener>>,
+    exec_status: Option<Arc<ExecStatus>>,
+    control: Arc<Control>,
+    /// size of the host terminal, reported to the driver in the config space
+    size: WindowSize,
+    /// size changes noticed by the resize handler that are not in the config space yet
+    pending_resize: Arc<PendingResize>,
+    /// only set for `ConsoleBackend::Stdio`
+    resize_sub_id: Option<SubscriberId>,
+    /// shared with the queue handler
+    pub io: Arc<IoCounters>,
 
     // Before resetting we return the handler to the mmio thread for cleanup
     #[allow(dead_code)]
@@ -78,7 +102,19 @@ where
         // A console device has two queue.
         let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE); 2];
 
-        let config_space = build_config_space();
+        let size = match (&args.exec_status, &args.backend) {
+            (None, ConsoleBackend::Stdio) => window_size(libc::STDIN_FILENO),
+            (None, ConsoleBackend::Pts(pts)) => OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(pts)
+                .ok()
+                .and_then(|f| window_size(f.as_raw_fd())),
+            _ => None,
+        }
+        .unwrap_or(DEFAULT_WINDOW_SIZE);
+
+        let config_space = build_config_space(size);
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Used to send notifications to the driver.
@@ -98,7 +134,7 @@ where
         )));
 
         let mut ioregionfd = None;
-        if use_ioregionfd() {
+        if mmio_cfg.backend == MmioBackend::IoRegionFd {
             ioregionfd = Some(
                 args.common
                     .vmm
@@ -107,8 +143,13 @@ where
             );
         }
 
-        let pts = args.pts;
-        log::info!("pts is {:?}", pts);
+        let backend = args.backend;
+        log::info!(
+            "console backend is {:?} ({}x{})",
+            backend,
+            size.cols,
+            size.rows
+        );
 
         //let rx_fd = IoEvent::register(&self.vmm, &mut self.uioefd, &self.mmio_cfg, RX_QUEUE_IDX as u64)
         //.map_err(Error::Simple)?;
@@ -121,6 +162,13 @@ where
         )
         .map_err(Error::Simple)?;
 
+        let listener = match &backend {
+            ConsoleBackend::Socket(path) if args.exec_status.is_none() => Some(Arc::new(
+                UnixListener::bind(path).map_err(Error::UnixSocket)?,
+            )),
+            _ => None,
+        };
+
         let console = Arc::new(Mutex::new(Console {
             virtio_cfg,
             mmio_cfg,
@@ -132,7 +180,14 @@ where
             uioefd,
             sub_id: None,
             handler: None,
-            pts,
+            backend,
+            listener,
+            exec_status: args.exec_status,
+            control: args.control,
+            size,
+            pending_resize: Arc::new(PendingResize::default()),
+            resize_sub_id: None,
+            io: Arc::new(IoCounters::default()),
         }));
 
         // Register the device on the MMIO bus.
@@ -160,10 +215,18 @@ where
             ack_handler: self.irq_ack_handler.clone(),
         };
 
-        let console_in;
+        let mut console_in = None;
+        let mut socket = None;
         let console_out: Box<dyn Write + Send>;
-        match &self.pts {
-            Some(pts) => {
+        match (&self.exec_status, &self.backend) {
+            (Some(status), _) => {
+                // exec is non-interactive
+                console_out = Box::new(ExecOutput::new(
+                    Arc::clone(status),
+                    Arc::clone(&self.control),
+                ));
+            }
+            (None, ConsoleBackend::Pts(pts)) => {
                 console_in = Some(
                     map_err_with!(
                         OpenOptions::new().read(true).open(pts),
@@ -179,8 +242,24 @@ where
                     .map_err(Error::Simple)?,
                 );
             }
-            None => {
-                console_in = None;
+            (None, ConsoleBackend::Stdio) => {
+                // we must not close our stdin when the handler is dropped
+                let stdin = map_err_with!(
+                    nix::unistd::dup(libc::STDIN_FILENO),
+                    "could not duplicate stdin"
+                )
+                .map_err(Error::Simple)?;
+                console_in = Some(unsafe { File::from_raw_fd(stdin) });
+                console_out = Box::new(io::stdout());
+            }
+            (None, ConsoleBackend::Socket(_)) => {
+                let listener = self.listener.as_ref().ok_or_else(|| {
+                    Error::Simple(SimpleError::new("no listener for the console socket"))
+                })?;
+                socket = Some(ConsoleSocket::new(Arc::clone(listener)));
+                console_out = Box::new(io::sink());
+            }
+            (None, ConsoleBackend::Log) => {
                 console_out = Box::new(io::stdout());
             }
         };
@@ -195,6 +274,14 @@ where
             txq: self.virtio_cfg.queues[TX_QUEUE_IDX as usize].clone(),
             console_out,
             console_in,
+            socket,
+            escape: if self.exec_status.is_none() && self.backend == ConsoleBackend::Stdio {
+                Some(Arc::clone(&self.control))
+            } else {
+                None
+            },
+            client_eof: false,
+            io: Arc::clone(&self.io),
         }));
 
         // Register the queue handler with the `EventManager`. We record the `sub_id`
@@ -210,11 +297,49 @@ where
             })?;
         self.sub_id = Some(sub_id);
 
+        // Only our own terminal tells us about size changes. For pts we would need to be the
+        // controlling process of its session.
+        if self.exec_status.is_none() && self.backend == ConsoleBackend::Stdio {
+            let resize = ResizeHandler::new(
+                libc::STDIN_FILENO,
+                self.size,
+                Arc::clone(&self.pending_resize),
+                SingleFdSignalQueue {
+                    irqfd: self.irqfd.clone(),
+                    interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+                    ack_handler: self.irq_ack_handler.clone(),
+                },
+            )
+            .map_err(Error::Simple)?;
+            let resize = Arc::new(Mutex::new(resize));
+            let sub_id = self
+                .endpoint
+                .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                    Ok(mgr.add_subscriber(resize))
+                })
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.resize_sub_id = Some(sub_id);
+        }
+
         log::debug!("activating device: ok");
         self.virtio_cfg.device_activated = true;
 
         Ok(())
     }
+
+    /// The driver re-reads the config space after a config change interrupt. We apply the new
+    /// size here rather than in the event thread, which must not lock the device.
+    fn apply_pending_resize(&mut self) {
+        if let Some(size) = self.pending_resize.take() {
+            self.size = size;
+            self.virtio_cfg.config_space = build_config_space(size);
+            self.virtio_cfg.config_generation = self.virtio_cfg.config_generation.wrapping_add(1);
+        }
+    }
+
     fn _reset(&mut self) -> Result<()> {
         // we remove the handler here, since we need to free up the ioeventfd resources
         // in the mmio thread rather the eventmanager thread.
@@ -228,16 +353,40 @@ where
                 })?;
             self.handler = Some(handler);
         }
+        if let Some(sub_id) = self.resize_sub_id.take() {
+            self.endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+        }
         Ok(())
     }
 }
 
+impl<M: GuestAddressSpace> Drop for Console<M> {
+    fn drop(&mut self) {
+        if let (ConsoleBackend::Socket(path), None) = (&self.backend, &self.exec_status) {
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("cannot remove console socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
 impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for Console<M> {
     fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
         &mut self.ioregionfd
     }
 }
 
+impl<M: GuestAddressSpace + Clone + Send + 'static> Restore for Console<M> {
+    fn notify_queues(&self) {
+        notify_ioevents(self.tx_fd.iter());
+    }
+}
+
 // We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
 // for `VirtioDevice`.
 impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Console<M> {
@@ -280,7 +429,7 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Cons
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for Console<M> {
     fn queue_notify(&mut self, val: u32) {
-        if use_ioregionfd() {
+        if self.mmio_cfg.backend == MmioBackend::IoRegionFd {
             self.uioefd.queue_notify(val);
             log::trace!("queue_notify {}", val);
         }
@@ -291,6 +440,7 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for Cons
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for Console<M> {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.apply_pending_resize();
         self.read(offset, data);
     }
 
@@ -0,0 +1,110 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use ioutils::exec::{Frame, FrameDecoder};
+use log::error;
+
+use crate::control::Control;
+
+/// Exit status of the command started by `vmsh exec`
+#[derive(Default)]
+pub struct ExecStatus {
+    exit_code: Mutex<Option<i32>>,
+    /// set if the output of stage2 could not be decoded
+    error: Mutex<Option<String>>,
+}
+
+impl ExecStatus {
+    /// Returns None if the command has not finished (yet).
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.exit_code.lock() {
+            Ok(code) => *code,
+            Err(e) => {
+                error!("cannot lock exit code: {}", e);
+                None
+            }
+        }
+    }
+
+    fn set_exit_code(&self, code: i32) {
+        match self.exit_code.lock() {
+            Ok(mut c) => *c = Some(code),
+            Err(e) => error!("cannot lock exit code: {}", e),
+        }
+    }
+
+    /// Returns why the output of the command could not be received, if it failed.
+    pub fn error(&self) -> Option<String> {
+        match self.error.lock() {
+            Ok(error) => error.clone(),
+            Err(e) => Some(format!("cannot lock exec error: {}", e)),
+        }
+    }
+
+    fn set_error(&self, error: String) {
+        match self.error.lock() {
+            Ok(mut e) => *e = Some(error),
+            Err(e) => error!("cannot lock exec error: {}", e),
+        }
+    }
+}
+
+/// Demultiplexes the framed console output of stage2 into our stdout and stderr.
+/// Stops vmsh once the exit status of the command was received or the output cannot be decoded.
+pub(crate) struct ExecOutput {
+    decoder: FrameDecoder,
+    status: Arc<ExecStatus>,
+    control: Arc<Control>,
+    failed: bool,
+}
+
+impl ExecOutput {
+    pub fn new(status: Arc<ExecStatus>, control: Arc<Control>) -> Self {
+        ExecOutput {
+            decoder: FrameDecoder::default(),
+            status,
+            control,
+            failed: false,
+        }
+    }
+}
+
+impl Write for ExecOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // the stream cannot be resynchronized, drop what comes after an error
+        if self.failed {
+            return Ok(buf.len());
+        }
+        self.decoder.push(buf);
+        loop {
+            let frame = match self.decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    self.failed = true;
+                    self.status
+                        .set_error(format!("cannot decode output of command: {}", e));
+                    self.control.stop();
+                    return Err(e);
+                }
+            };
+            match frame {
+                Frame::Stdout(data) => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&data)?;
+                    stdout.flush()?;
+                }
+                Frame::Stderr(data) => io::stderr().write_all(&data)?,
+                Frame::Exit(code) => {
+                    self.status.set_exit_code(code);
+                    self.control.stop();
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
@@ -3,8 +3,10 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
 use std::result;
+use std::sync::Arc;
 
 use event_manager::EventOps;
 use event_manager::EventSet;
@@ -16,9 +18,15 @@ use vm_memory::Bytes;
 use vm_memory::{self, GuestAddressSpace};
 
 use super::device::{RX_QUEUE_IDX, TX_QUEUE_IDX};
-use crate::devices::virtio::SignalUsedQueue;
+use super::socket::ConsoleSocket;
+use super::stdio::ESCAPE_CHAR;
+use crate::control::Control;
+use crate::devices::virtio::{IoCounters, SignalUsedQueue};
 use crate::kvm::hypervisor::ioevent::IoEvent;
 
+/// Event data of the listening console socket. The input of the console uses `RX_QUEUE_IDX`.
+const LISTENER_TOKEN: u32 = 2;
+
 #[derive(Debug)]
 pub enum Error {
     GuestMemory(vm_memory::GuestMemoryError),
@@ -45,6 +53,13 @@ pub(crate) struct LogQueueHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub txq: Queue<M>,
     pub console_out: Box<dyn Write + Send>,
     pub console_in: Option<File>,
+    /// replaces `console_in` and `console_out` if set
+    pub socket: Option<ConsoleSocket>,
+    /// stopped when `ESCAPE_CHAR` is read from the input
+    pub escape: Option<Arc<Control>>,
+    /// the socket client closed its end
+    pub client_eof: bool,
+    pub io: Arc<IoCounters>,
 }
 
 impl<M, S> LogQueueHandler<M, S>
@@ -58,19 +73,50 @@ where
             .expect("Failed to remove tx ioevent");
     }
 
+    fn output(&mut self) -> &mut dyn Write {
+        match &mut self.socket {
+            Some(socket) => socket,
+            None => &mut self.console_out,
+        }
+    }
+
+    fn input(&mut self) -> Option<&mut dyn Read> {
+        match &mut self.socket {
+            Some(socket) => socket.client.as_mut().map(|c| c as &mut dyn Read),
+            None => self.console_in.as_mut().map(|c| c as &mut dyn Read),
+        }
+    }
+
     /// Guest console sends (tx), we write to self.console_out fd
     fn process_tx_chain(&mut self, mut chain: DescriptorChain<M>) -> result::Result<(), Error> {
         log::debug!("process_chain");
 
         let mut i = 0;
+        let mut bytes = 0;
+        let mut failed = false;
         while let Some(desc) = chain.next() {
             log::debug!("chain.next()");
             let mem = chain.memory();
-            if let Err(e) = mem.write_to(desc.addr(), &mut self.console_out, desc.len() as usize) {
-                error!("error logging console tx (stdout/err): {}", e)
+            let mut out = self.output();
+            match mem.write_to(desc.addr(), &mut out, desc.len() as usize) {
+                Ok(n) => bytes += n,
+                Err(e) => {
+                    error!("error logging console tx (stdout/err): {}", e);
+                    failed = true;
+                }
             }
             i += 1;
         }
+        // stdout is line buffered, but prompts do not end with a newline
+        if let Err(e) = self.output().flush() {
+            error!("error flushing console tx (stdout/err): {}", e);
+            failed = true;
+        }
+        if failed {
+            self.io.error();
+        } else {
+            self.io.request(bytes);
+        }
         self.txq.add_used(chain.head_index(), i as u32)?;
 
         if self.txq.needs_notification()? {
@@ -110,24 +156,42 @@ where
             log::debug!("reading bytes");
             let mem = chain.memory();
             let mut buf = [0u8; LEN];
-            let pts = &mut self
-                .console_in
-                .as_mut()
-                .expect("programming error: rx chain cannot be processed if no pts is connected");
-            count = match pts.read(&mut buf) {
+            let input = self
+                .input()
+                .expect("programming error: rx chain cannot be processed if no input is connected");
+            let mut eof = true;
+            count = match input.read(&mut buf) {
                 Ok(count) => {
                     log::debug!("read {}", count);
                     count
                 }
+                // socket clients are non-blocking
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    eof = false;
+                    0
+                }
                 Err(e) => {
                     log::error!("error reading from console: {}", e);
+                    self.io.error();
                     0
                 }
             };
+            if count == 0 && eof && self.socket.is_some() {
+                self.client_eof = true;
+            }
+            if let Some(control) = &self.escape {
+                if let Some(pos) = buf[..count].iter().position(|&b| b == ESCAPE_CHAR) {
+                    count = pos;
+                    control.stop();
+                }
+            }
             let buf = &mut buf[..count];
             log::debug!("buf {:?} count {}", buf, count);
             if let Err(e) = mem.write_slice(buf, desc.addr()) {
-                error!("error logging console rx (stdin): {}", e)
+                error!("error logging console rx (stdin): {}", e);
+                self.io.error();
+            } else if count > 0 {
+                self.io.request(count);
             }
         }
         self.rxq.add_used(chain.head_index(), count as u32)?;
@@ -160,10 +224,61 @@ where
         //}
         Ok(())
     }
+
+    fn add_client(client: &UnixStream, ops: &mut EventOps) {
+        if let Err(e) = ops.add(Events::with_data(client, RX_QUEUE_IDX as u32, EventSet::IN)) {
+            error!("cannot register console client: {:?}", e);
+        }
+    }
+
+    fn remove_client(client: UnixStream, ops: &mut EventOps) {
+        if let Err(e) = ops.remove(Events::empty(&client)) {
+            error!("cannot unregister console client: {:?}", e);
+        }
+    }
+
+    fn accept_client(&mut self, ops: &mut EventOps) {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        match socket.accept() {
+            Ok(old) => {
+                if let Some(old) = old {
+                    Self::remove_client(old, ops);
+                }
+                if let Some(client) = &socket.client {
+                    Self::add_client(client, ops);
+                }
+            }
+            Err(e) => error!("cannot accept console client: {}", e),
+        }
+    }
+
+    fn disconnect_client(&mut self, ops: &mut EventOps) {
+        self.client_eof = false;
+        if let Some(client) = self.socket.as_mut().and_then(|s| s.disconnect()) {
+            Self::remove_client(client, ops);
+        }
+    }
 }
 
 impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for LogQueueHandler<M, S> {
     fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.data() == LISTENER_TOKEN {
+            self.accept_client(ops);
+            return;
+        }
+        if self.socket.is_some()
+            && events.data() == RX_QUEUE_IDX as u32
+            && events
+                .event_set()
+                .intersects(EventSet::HANG_UP | EventSet::ERROR)
+        {
+            self.disconnect_client(ops);
+            return;
+        }
+
         if events.event_set() != EventSet::IN {
             self.handle_error("Unexpected event_set", ops);
             return;
@@ -174,6 +289,9 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for LogQueueHa
                 if let Err(e) = self.process_rxq() {
                     self.handle_error(format!("Process rx error {:?}", e), ops);
                 }
+                if self.client_eof {
+                    self.disconnect_client(ops);
+                }
             }
             TX_QUEUE_IDX => {
                 if self.tx_fd.read().is_err() {
@@ -196,6 +314,14 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for LogQueueHa
             ))
             .expect("Failed to register rx ioeventfd for console queue handler");
         }
+        if let Some(socket) = &self.socket {
+            ops.add(Events::with_data(
+                socket.listener.as_ref(),
+                LISTENER_TOKEN,
+                EventSet::IN,
+            ))
+            .expect("Failed to register console socket for console queue handler");
+        }
 
         ops.add(Events::with_data(
             &self.tx_fd,
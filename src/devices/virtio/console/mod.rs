@@ -1,17 +1,26 @@
 mod device;
+mod exec;
 mod log_handler;
+mod resize;
+mod socket;
+mod stdio;
 
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use event_manager::Error as EvmgrError;
 use vm_device::bus;
 use vmm_sys_util::errno;
 
+use crate::control::Control;
 use crate::devices::virtio::CommonArgs;
+use resize::WindowSize;
 use simple_error::SimpleError;
 
 pub use device::Console;
+pub use exec::ExecStatus;
+pub use stdio::{stdin_is_terminal, RawTerminal};
 
 /// Console device ID as defined by the standard.
 pub const CONSOLE_DEVICE_ID: u32 = 3;
@@ -38,6 +47,7 @@ pub enum Error {
     RegisterIoevent(errno::Error),
     #[allow(dead_code)] // FIXME
     RegisterIrqfd(errno::Error),
+    UnixSocket(io::Error),
     Simple(SimpleError),
 }
 
@@ -59,46 +69,50 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
 }
 
-//fn get_winsize(term_fd: RawFd) -> winsize {
-//    use std::mem::zeroed;
-//    unsafe {
-//        let mut ws: winsize = zeroed();
-//        match libc::ioctl(term_fd, libc::TIOCGWINSZ, &mut ws) {
-//            0 => ws,
-//            _ => winsize {
-//                ws_row: 80,
-//                ws_col: 25,
-//                ws_xpixel: 0,
-//                ws_ypixel: 0,
-//            },
-//        }
-//    }
-//}
-//
-//fn resize_pty(pty_master: RawFd) {
-//    unsafe {
-//        libc::ioctl(
-//            pty_master,
-//            libc::TIOCSWINSZ,
-//            &mut get_winsize(libc::STDOUT_FILENO),
-//        );
-//    }
-//}
-
-fn build_config_space() -> Vec<u8> {
-    // FIXME think about terminal size
+fn build_config_space(size: WindowSize) -> Vec<u8> {
     let config = virtio_console_config {
-        cols: 80,
-        rows: 24,
+        cols: size.cols,
+        rows: size.rows,
         max_nr_ports: 2,
         emerg_wr: 0,
     };
     unsafe { any_as_u8_slice(&config) }.to_vec()
 }
 
+/// Where the console of the attached environment is connected to on the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleBackend {
+    /// Print the output of the guest to our stdout, no input.
+    Log,
+    /// Our own stdin and stdout. The terminal is expected to be in raw mode (see `RawTerminal`).
+    Stdio,
+    /// An existing pseudoterminal.
+    Pts(PathBuf),
+    /// A unix socket created at this path. One client at a time is connected to the console.
+    Socket(PathBuf),
+}
+
 // Arguments required when building a console device.
 pub struct ConsoleArgs<'a, M, B> {
     pub common: CommonArgs<'a, M, B>,
-    /// None shall be interpreted as "sane default".
-    pub pts: Option<PathBuf>,
+    pub backend: ConsoleBackend,
+    /// If set, the console carries the framed output of `vmsh exec` instead of a terminal.
+    pub exec_status: Option<Arc<ExecStatus>>,
+    /// stopped by the console, i.e. when the command of `vmsh exec` exits
+    pub control: Arc<Control>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_space_size() {
+        let config = build_config_space(WindowSize {
+            cols: 132,
+            rows: 43,
+        });
+        assert_eq!(config.len(), 12);
+        assert_eq!(&config[0..4], &[132, 0, 43, 0]);
+    }
 }
@@ -0,0 +1,160 @@
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{io, mem};
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use simple_error::try_with;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::devices::virtio::SingleFdSignalQueue;
+use crate::result::Result;
+
+/// Console size used if the size of the host terminal is unknown.
+pub(crate) const DEFAULT_WINDOW_SIZE: WindowSize = WindowSize { cols: 80, rows: 24 };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Returns the window size of the terminal `fd` or None if it is not a terminal.
+pub(crate) fn window_size(fd: RawFd) -> Option<WindowSize> {
+    let mut ws: libc::winsize = unsafe { mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) } != 0 {
+        return None;
+    }
+    // some terminals, i.e. serial lines, do not know their size
+    if ws.ws_col == 0 || ws.ws_row == 0 {
+        return None;
+    }
+    Some(WindowSize {
+        cols: ws.ws_col,
+        rows: ws.ws_row,
+    })
+}
+
+/// A window size change that has not been applied to the config space of the console yet.
+/// Set by the `ResizeHandler` in the event thread and consumed by the device in the mmio thread,
+/// so that the event thread never has to lock the device.
+#[derive(Default)]
+pub(crate) struct PendingResize(Mutex<Option<WindowSize>>);
+
+impl PendingResize {
+    fn set(&self, size: WindowSize) {
+        match self.0.lock() {
+            Ok(mut pending) => *pending = Some(size),
+            Err(e) => error!("cannot lock pending console resize: {}", e),
+        }
+    }
+
+    pub fn take(&self) -> Option<WindowSize> {
+        match self.0.lock() {
+            Ok(mut pending) => pending.take(),
+            Err(e) => {
+                error!("cannot lock pending console resize: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Eventfd of the active `ResizeHandler`, written by the signal handler.
+static SIGWINCH_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    let fd = SIGWINCH_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let val: u64 = 1;
+        // only async-signal-safe calls are allowed here
+        unsafe { libc::write(fd, &val as *const u64 as *const libc::c_void, 8) };
+    }
+}
+
+fn set_sigwinch_handler(handler: SigHandler) -> Result<()> {
+    // SA_RESTART: do not interrupt the syscalls of the other threads
+    let action = SigAction::new(handler, SaFlags::SA_RESTART, SigSet::empty());
+    try_with!(
+        unsafe { signal::sigaction(Signal::SIGWINCH, &action) },
+        "cannot set SIGWINCH handler"
+    );
+    Ok(())
+}
+
+/// Watches the host terminal for size changes (SIGWINCH) and tells the driver about them with a
+/// config change interrupt.
+pub(crate) struct ResizeHandler {
+    tty: RawFd,
+    size: WindowSize,
+    event: EventFd,
+    pending: Arc<PendingResize>,
+    driver_notify: SingleFdSignalQueue,
+}
+
+impl ResizeHandler {
+    /// Only one handler can exist at a time since the signal handler is process-wide.
+    pub fn new(
+        tty: RawFd,
+        size: WindowSize,
+        pending: Arc<PendingResize>,
+        driver_notify: SingleFdSignalQueue,
+    ) -> Result<ResizeHandler> {
+        let event = try_with!(EventFd::new(EFD_NONBLOCK), "cannot create eventfd");
+        SIGWINCH_FD.store(event.as_raw_fd(), Ordering::SeqCst);
+        set_sigwinch_handler(SigHandler::Handler(handle_sigwinch))?;
+        Ok(ResizeHandler {
+            tty,
+            size,
+            event,
+            pending,
+            driver_notify,
+        })
+    }
+
+    fn resize(&mut self) -> io::Result<()> {
+        self.event.read()?;
+        let size = match window_size(self.tty) {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        if size != self.size {
+            debug!("console resized to {}x{}", size.cols, size.rows);
+            self.size = size;
+            self.pending.set(size);
+            self.driver_notify.signal_config_change();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ResizeHandler {
+    fn drop(&mut self) {
+        SIGWINCH_FD.store(-1, Ordering::SeqCst);
+        if let Err(e) = set_sigwinch_handler(SigHandler::SigDfl) {
+            warn!("{}", e);
+        }
+    }
+}
+
+impl MutEventSubscriber for ResizeHandler {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set() != EventSet::IN {
+            error!("unexpected event_set");
+        } else if let Err(e) = self.resize() {
+            error!("cannot resize console: {}", e);
+        } else {
+            return;
+        }
+        ops.remove(events)
+            .expect("Failed to remove fd from event handling loop");
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::new(&self.event, EventSet::IN))
+            .expect("Failed to init console resize handler");
+    }
+}
@@ -0,0 +1,58 @@
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+
+use log::{debug, info};
+
+/// Console exposed as unix socket. Output of the guest is discarded while no client is
+/// connected or while the client does not read it. A new client replaces the previous one.
+pub(crate) struct ConsoleSocket {
+    pub listener: Arc<UnixListener>,
+    pub client: Option<UnixStream>,
+}
+
+impl ConsoleSocket {
+    pub fn new(listener: Arc<UnixListener>) -> Self {
+        ConsoleSocket {
+            listener,
+            client: None,
+        }
+    }
+
+    /// Accepts a new client and returns the one it replaced.
+    pub fn accept(&mut self) -> io::Result<Option<UnixStream>> {
+        let (stream, _) = self.listener.accept()?;
+        // writes happen in the event manager thread, which a stalled client must not block
+        stream.set_nonblocking(true)?;
+        info!("console client connected");
+        Ok(self.client.replace(stream))
+    }
+
+    pub fn disconnect(&mut self) -> Option<UnixStream> {
+        let client = self.client.take();
+        if client.is_some() {
+            info!("console client disconnected");
+        }
+        client
+    }
+}
+
+impl Write for ConsoleSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(client) = &mut self.client {
+            match client.write_all(buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("console client does not keep up, drop output");
+                }
+                // a vanished client is cleaned up once epoll reports the hang up
+                Err(e) => debug!("cannot write to console client: {}", e),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use log::warn;
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd;
+use simple_error::try_with;
+use std::os::unix::io::RawFd;
+
+use crate::result::Result;
+
+/// Typing this character (Ctrl-]) on our stdin stops vmsh instead of passing it to the guest.
+pub(crate) const ESCAPE_CHAR: u8 = 0x1d;
+
+/// Returns true if stdin is an interactive terminal that we can use as console.
+pub fn stdin_is_terminal() -> bool {
+    unistd::isatty(libc::STDIN_FILENO).unwrap_or(false)
+}
+
+/// Puts a terminal into raw mode, so that all input including control characters is passed to the
+/// guest, and restores the previous mode when dropped.
+pub struct RawTerminal {
+    fd: RawFd,
+    orig: Termios,
+}
+
+impl RawTerminal {
+    pub fn new(fd: RawFd) -> Result<RawTerminal> {
+        let orig = try_with!(termios::tcgetattr(fd), "cannot get terminal attributes");
+        let mut attrs = orig.clone();
+        termios::cfmakeraw(&mut attrs);
+        try_with!(
+            termios::tcsetattr(fd, SetArg::TCSANOW, &attrs),
+            "cannot put terminal into raw mode"
+        );
+        Ok(RawTerminal { fd, orig })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Err(e) = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.orig) {
+            warn!("cannot restore terminal attributes: {}", e);
+        }
+    }
+}
@@ -6,15 +6,20 @@
 
 pub mod block;
 pub mod console;
+pub mod p9;
+pub mod state;
+pub mod vsock;
 
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use crate::devices::MmioBackend;
 use crate::kvm::hypervisor::{ioeventfd::IoEventFd, Hypervisor};
 use crate::result::Result;
 use event_manager::{EventManager, MutEventSubscriber};
 use log::error;
+use serde::Serialize;
 
 use vm_device::bus::MmioRange;
 use vmm_sys_util::eventfd::EventFd;
@@ -35,6 +40,8 @@ mod features {
 // TODO: There seem to be similar semantics when the PCI transport is used with MSI-X cap
 // disabled. Let's figure out at some point if having MMIO as part of the name is necessary.
 const VIRTIO_MMIO_INT_VRING: u8 = 0x01;
+// This bit is set when the device configuration space changed.
+const VIRTIO_MMIO_INT_CONFIG: u8 = 0x02;
 
 // The driver will write to the register at this offset in the MMIO region to notify the device
 // about available queue events.
@@ -48,6 +55,8 @@ pub struct MmioConfig {
     pub range: MmioRange,
     // The interrupt assigned to the device.
     pub gsi: u32,
+    // How accesses to `range` reach the device.
+    pub backend: MmioBackend,
 }
 
 // These arguments are common for all virtio devices. We're always passing a mmio_cfg object
@@ -88,11 +97,9 @@ pub struct SingleFdSignalQueue {
     pub ack_handler: Arc<Mutex<IrqAckHandler>>,
 }
 
-impl SignalUsedQueue for SingleFdSignalQueue {
-    fn signal_used_queue(&self, _index: u16) {
-        log::trace!("irqfd << {}", _index);
-        self.interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+impl SingleFdSignalQueue {
+    fn signal(&self, status: u8) {
+        self.interrupt_status.fetch_or(status, Ordering::SeqCst);
         if let Err(e) = self.irqfd.write(1) {
             error!("Failed write to eventfd when signalling queue: {}", e);
         } else {
@@ -102,6 +109,56 @@ impl SignalUsedQueue for SingleFdSignalQueue {
             }
         }
     }
+
+    /// Notifies the driver that the device configuration space has changed.
+    pub fn signal_config_change(&self) {
+        log::trace!("irqfd << config change");
+        self.signal(VIRTIO_MMIO_INT_CONFIG);
+    }
+}
+
+impl SignalUsedQueue for SingleFdSignalQueue {
+    fn signal_used_queue(&self, _index: u16) {
+        log::trace!("irqfd << {}", _index);
+        self.signal(VIRTIO_MMIO_INT_VRING);
+    }
+}
+
+/// Requests served by a device, updated by its queue handler and read by `vmsh daemon` stats.
+#[derive(Debug, Default)]
+pub struct IoCounters {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct IoStats {
+    pub requests: u64,
+    /// payload moved between the driver and the host in both directions
+    pub bytes: u64,
+    /// requests that failed or were dropped, also counted in `requests`
+    pub errors: u64,
+}
+
+impl IoCounters {
+    pub fn request(&self, bytes: usize) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> IoStats {
+        IoStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
 }
 
 /// Note: `device::threads::EVENT_LOOP_TIMEOUT_MS` typically determines how often the irq ack
@@ -0,0 +1,268 @@
+use std::borrow::{Borrow, BorrowMut};
+use std::ops::DerefMut;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use virtio_device::{VirtioDevice, VirtioDeviceType};
+
+use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
+use virtio_queue::Queue;
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::MmioManager;
+use vm_device::{DeviceMmio, MutDeviceMmio};
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::features::{VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1};
+use crate::devices::virtio::p9::queue_handler::QueueHandler;
+use crate::devices::virtio::p9::server::Server;
+use crate::devices::virtio::state::{notify_ioevents, Restore};
+use crate::devices::virtio::{
+    IoCounters, IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+};
+use crate::devices::MaybeIoRegionFd;
+use crate::devices::MmioBackend;
+use crate::kvm::hypervisor::{
+    ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
+};
+
+use super::{build_config_space, Error, P9Args, Result, P9_DEVICE_ID, VIRTIO_9P_MOUNT_TAG};
+use simple_error::{map_err_with, SimpleError};
+
+pub struct P9<M: GuestAddressSpace> {
+    virtio_cfg: VirtioConfig<M>,
+    pub mmio_cfg: MmioConfig,
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    irqfd: Arc<EventFd>,
+    pub ioregionfd: Option<IoRegionFd>,
+    pub uioefd: UserspaceIoEventFd,
+    ioeventfd: Option<IoEvent>,
+    /// only used when ioregionfd != None
+    sub_id: Option<SubscriberId>,
+    root: PathBuf,
+    read_only: bool,
+    /// shared with the queue handler
+    pub io: Arc<IoCounters>,
+
+    // Before resetting we return the handler to the mmio thread for cleanup
+    #[allow(dead_code)]
+    handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+}
+
+impl<M> P9<M>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+{
+    pub fn new<B>(mut args: P9Args<M, B>) -> Result<Arc<Mutex<Self>>>
+    where
+        // We're using this (more convoluted) bound so we can pass both references and smart
+        // pointers such as mutex guards here.
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        let device_features =
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_RING_EVENT_IDX | 1 << VIRTIO_9P_MOUNT_TAG;
+
+        // The 9p transport has a single request queue.
+        let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE)];
+
+        let config_space = build_config_space(&args.tag);
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        // Used to send notifications to the driver.
+        log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
+        let irqfd = Arc::new(
+            args.common
+                .vmm
+                .irqfd(args.common.mmio_cfg.gsi)
+                .map_err(Error::Simple)?,
+        );
+
+        let mmio_cfg = args.common.mmio_cfg;
+
+        let irq_ack_handler = Arc::new(Mutex::new(IrqAckHandler::new(
+            virtio_cfg.interrupt_status.clone(),
+            Arc::clone(&irqfd),
+        )));
+
+        let mut ioregionfd = None;
+        if mmio_cfg.backend == MmioBackend::IoRegionFd {
+            ioregionfd = Some(
+                args.common
+                    .vmm
+                    .ioregionfd(mmio_cfg.range.base().0, mmio_cfg.range.size() as usize)
+                    .map_err(Error::Simple)?,
+            );
+        }
+
+        let mut uioefd = UserspaceIoEventFd::default();
+        let ioeventfd = IoEvent::register(&args.common.vmm, &mut uioefd, &mmio_cfg, 0)
+            .map_err(Error::Simple)?;
+
+        let p9 = Arc::new(Mutex::new(P9 {
+            virtio_cfg,
+            mmio_cfg,
+            endpoint: args.common.event_mgr.remote_endpoint(),
+            irq_ack_handler,
+            irqfd,
+            ioregionfd,
+            uioefd,
+            ioeventfd: Some(ioeventfd),
+            sub_id: None,
+            handler: None,
+            root: args.root,
+            read_only: args.read_only,
+            io: Arc::new(IoCounters::default()),
+        }));
+
+        // Register the device on the MMIO bus.
+        args.common
+            .mmio_mgr
+            .register_mmio(mmio_cfg.range, p9.clone())
+            .map_err(Error::Bus)?;
+
+        Ok(p9)
+    }
+
+    fn _activate(&mut self) -> Result<()> {
+        if self.virtio_cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        // We do not support legacy drivers.
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
+        }
+
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+            ack_handler: self.irq_ack_handler.clone(),
+        };
+
+        let ioeventfd = self.ioeventfd.take().ok_or_else(|| {
+            Error::Simple(SimpleError::new("9p device cannot be activated twice"))
+        })?;
+
+        let server = map_err_with!(
+            Server::new(&self.root, self.read_only),
+            "cannot open shared directory {}",
+            self.root.display()
+        )
+        .map_err(Error::Simple)?;
+
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            driver_notify,
+            queue: self.virtio_cfg.queues[0].clone(),
+            ioeventfd,
+            server,
+            io: Arc::clone(&self.io),
+        }));
+
+        // Register the queue handler with the `EventManager`. We record the `sub_id`
+        // (and/or keep a handler clone) to remove the subscriber when resetting the device
+        let sub_id = self
+            .endpoint
+            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                Ok(mgr.add_subscriber(handler))
+            })
+            .map_err(|e| {
+                log::warn!("{}", e);
+                Error::Endpoint(e)
+            })?;
+        self.sub_id = Some(sub_id);
+
+        log::debug!("activating device: ok");
+        self.virtio_cfg.device_activated = true;
+
+        Ok(())
+    }
+
+    fn _reset(&mut self) -> Result<()> {
+        // we remove the handler here, since we need to free up the ioeventfd resources
+        // in the mmio thread rather the eventmanager thread.
+        if let Some(sub_id) = self.sub_id.take() {
+            let handler = self
+                .endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.handler = Some(handler);
+        }
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for P9<M> {
+    fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
+        &mut self.ioregionfd
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Restore for P9<M> {
+    fn notify_queues(&self) {
+        notify_ioevents(self.ioeventfd.iter());
+    }
+}
+
+// We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
+// for `VirtioDevice`.
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for P9<M> {
+    fn device_type(&self) -> u32 {
+        P9_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for P9<M> {
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.virtio_cfg
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for P9<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.virtio_cfg
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for P9<M> {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let ret = self._activate();
+        if let Err(ref e) = ret {
+            log::warn!("failed to activate 9p device: {:?}", e);
+        }
+        ret
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.set_device_status(0);
+        self._reset()?;
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for P9<M> {
+    fn queue_notify(&mut self, val: u32) {
+        if self.mmio_cfg.backend == MmioBackend::IoRegionFd {
+            self.uioefd.queue_notify(val);
+            log::trace!("queue_notify {}", val);
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for P9<M> {}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for P9<M> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
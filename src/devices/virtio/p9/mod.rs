@@ -0,0 +1,49 @@
+mod device;
+mod protocol;
+mod queue_handler;
+mod server;
+
+use std::path::PathBuf;
+
+use event_manager::Error as EvmgrError;
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use simple_error::SimpleError;
+
+pub use device::P9;
+
+/// 9p transport device ID as defined by the standard.
+pub const P9_DEVICE_ID: u32 = 9;
+
+/// The config space contains the mount tag.
+pub const VIRTIO_9P_MOUNT_TAG: u32 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    Simple(SimpleError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn build_config_space(tag: &str) -> Vec<u8> {
+    // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+    let mut config = (tag.len() as u16).to_le_bytes().to_vec();
+    config.extend_from_slice(tag.as_bytes());
+    config
+}
+
+// Arguments required when building a 9p device.
+pub struct P9Args<'a, M, B> {
+    pub common: CommonArgs<'a, M, B>,
+    /// host directory exported to the guest
+    pub root: PathBuf,
+    /// name the guest uses to mount the share
+    pub tag: String,
+    /// reject all modifications of the guest
+    pub read_only: bool,
+}
@@ -0,0 +1,202 @@
+//! Wire format of 9P2000.L messages. All integers are little endian, strings are prefixed with
+//! their length as u16.
+
+use std::io;
+
+/// size[4] type[1] tag[2]
+pub const HEADER_SIZE: usize = 7;
+
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TMKNOD: u8 = 18;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TXATTRWALK: u8 = 30;
+pub const TXATTRCREATE: u8 = 32;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLOCK: u8 = 52;
+pub const TGETLOCK: u8 = 54;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TAUTH: u8 = 102;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+/// Identifies a file on the server, similar to an inode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+fn truncated() -> io::Error {
+    io::Error::from_raw_os_error(libc::EPROTO)
+}
+
+/// Decodes the fields of a message one after another.
+pub struct WireReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        WireReader { buf }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(truncated());
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        match String::from_utf8(self.bytes(len)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(io::Error::from_raw_os_error(libc::EILSEQ)),
+        }
+    }
+}
+
+/// Encodes a message. The size field is filled in by `finish`.
+pub struct WireWriter {
+    buf: Vec<u8>,
+}
+
+impl WireWriter {
+    pub fn new(type_: u8, tag: u16) -> Self {
+        let mut w = WireWriter { buf: vec![] };
+        w.u32(0);
+        w.u8(type_);
+        w.u16(tag);
+        w
+    }
+
+    /// Encodes data without a message header, e.g. to prefix it with its size later on.
+    pub fn headless() -> Self {
+        WireWriter { buf: vec![] }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.buf.extend_from_slice(b);
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn string(&mut self, s: &str) {
+        // names on Linux are much shorter than 64k
+        let len = std::cmp::min(s.len(), u16::MAX as usize);
+        self.u16(len as u16);
+        self.bytes(&s.as_bytes()[..len]);
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.type_);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Size of an encoded qid.
+pub const QID_SIZE: usize = 13;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_roundtrip() {
+        let qid = Qid {
+            type_: QTDIR,
+            version: 1,
+            path: 42,
+        };
+        let mut w = WireWriter::new(TVERSION + 1, 0xffff);
+        w.u32(8192);
+        w.string("9P2000.L");
+        w.qid(&qid);
+        let msg = w.finish();
+        assert_eq!(msg.len(), HEADER_SIZE + 4 + 2 + 8 + QID_SIZE);
+
+        let mut r = WireReader::new(&msg);
+        assert_eq!(r.u32().expect("size"), msg.len() as u32);
+        assert_eq!(r.u8().expect("type"), TVERSION + 1);
+        assert_eq!(r.u16().expect("tag"), 0xffff);
+        assert_eq!(r.u32().expect("msize"), 8192);
+        assert_eq!(r.string().expect("version"), "9P2000.L");
+        assert_eq!(r.u8().expect("qid type"), QTDIR);
+        assert_eq!(r.u32().expect("qid version"), 1);
+        assert_eq!(r.u64().expect("qid path"), 42);
+        assert!(r.u8().is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::{cmp, result};
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::{self, Bytes, GuestAddressSpace};
+use vmm_sys_util::epoll::EventSet;
+
+use super::server::{error_response, request_tag, Server, MAX_MSIZE};
+use crate::devices::virtio::{IoCounters, SignalUsedQueue};
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+const IOEVENT_DATA: u32 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Passes 9p requests of the driver to the file server. Each descriptor chain carries a request
+/// in its readable part and has room for the response in its writable part.
+pub(crate) struct QueueHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub queue: Queue<M>,
+    pub ioeventfd: IoEvent,
+    pub server: Server,
+    pub io: Arc<IoCounters>,
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> QueueHandler<M, S> {
+    fn process_chain(&mut self, mut chain: DescriptorChain<M>) -> result::Result<(), Error> {
+        let mut request = vec![];
+        let mut bufs = vec![];
+        while let Some(desc) = chain.next() {
+            if desc.is_write_only() {
+                bufs.push((desc.addr(), desc.len() as usize));
+                continue;
+            }
+            let start = request.len();
+            if start + desc.len() as usize > MAX_MSIZE as usize {
+                warn!("dropping oversized 9p request");
+                self.io.error();
+                self.queue.add_used(chain.head_index(), 0)?;
+                return Ok(());
+            }
+            request.resize(start + desc.len() as usize, 0);
+            chain
+                .memory()
+                .read_slice(&mut request[start..], desc.addr())?;
+        }
+
+        let capacity = bufs.iter().map(|(_, len)| len).sum::<usize>();
+        let mut response = self.server.handle(&request);
+        let oversized = response.len() > capacity;
+        if oversized {
+            warn!(
+                "9p response of {} bytes does not fit into {} bytes",
+                response.len(),
+                capacity
+            );
+            self.io.error();
+            response = error_response(request_tag(&request), libc::EMSGSIZE);
+            if response.len() > capacity {
+                self.queue.add_used(chain.head_index(), 0)?;
+                return Ok(());
+            }
+        }
+        let mut written = 0;
+        for (addr, len) in bufs {
+            if written == response.len() {
+                break;
+            }
+            let n = cmp::min(len, response.len() - written);
+            chain
+                .memory()
+                .write_slice(&response[written..written + n], addr)?;
+            written += n;
+        }
+        if !oversized {
+            self.io.request(request.len() + written);
+        }
+        self.queue.add_used(chain.head_index(), written as u32)?;
+        Ok(())
+    }
+
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        let mut used = false;
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.queue.disable_notification()?;
+
+            while let Some(chain) = self.queue.iter()?.next() {
+                self.process_chain(chain)?;
+                used = true;
+            }
+
+            if !self.queue.enable_notification()? {
+                break;
+            }
+        }
+        if used && self.queue.needs_notification()? {
+            self.driver_notify.signal_used_queue(0);
+        }
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for QueueHandler<M, S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let mut error = true;
+
+        if events.event_set() != EventSet::IN {
+            error!("unexpected event_set");
+        } else if events.data() != IOEVENT_DATA {
+            error!("unexpected events data {}", events.data());
+        } else if self.ioeventfd.read().is_err() {
+            error!("ioeventfd read error")
+        } else if let Err(e) = self.process_queue() {
+            error!("error processing 9p queue {:?}", e);
+        } else {
+            error = false;
+        }
+
+        if error {
+            ops.remove(events)
+                .expect("Failed to remove fd from event handling loop");
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            &self.ioeventfd,
+            IOEVENT_DATA,
+            EventSet::IN,
+        ))
+        .expect("Failed to init 9p queue handler");
+    }
+}
@@ -0,0 +1,1116 @@
+//! A 9P2000.L file server exporting a host directory.
+//!
+//! Fids refer to paths relative to the shared directory. Every access opens the path one
+//! component at a time from a descriptor of the shared directory with O_NOFOLLOW, so neither
+//! symlinks nor directories renamed on the host lead outside of it; the client resolves
+//! symlinks itself.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use nix::dir::Dir;
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat::{self, FchmodatFlags, Mode, SFlag, UtimensatFlags};
+use nix::sys::statvfs::fstatvfs;
+use nix::sys::time::TimeSpec;
+use nix::unistd::{self, FchownatFlags, Gid, LinkatFlags, Uid, UnlinkatFlags};
+
+use super::protocol::*;
+
+/// Largest message size we agree on. Each message has to fit into a single descriptor chain.
+pub const MAX_MSIZE: u32 = 128 * 1024;
+/// Smallest message size we agree on, like Linux. Rread and Rreaddir need room for their header.
+const MIN_MSIZE: u32 = 4096;
+
+const VERSION_9P2000_L: &str = "9P2000.L";
+const NO_UID: u32 = !0;
+/// size[4] type[1] tag[2] count[4] of Rread and Rreaddir
+const IO_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+/// reported as filesystem type, like QEMU does
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+// Flags of Tlopen and Tlcreate, these are the generic Linux values on all architectures.
+const DOTL_ACCMODE: u32 = 0o3;
+const DOTL_WRONLY: u32 = 0o1;
+const DOTL_RDWR: u32 = 0o2;
+const DOTL_EXCL: u32 = 0o200;
+const DOTL_TRUNC: u32 = 0o1000;
+const DOTL_APPEND: u32 = 0o2000;
+
+/// All fields up to ctime are valid in our Rgetattr.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// Valid fields of Tsetattr
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// Permission bits the client may set. Setuid and setgid files of the guest would also be
+/// setuid and setgid on the host.
+const MODE_MASK: u32 = 0o1777;
+
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+fn errno(e: i32) -> io::Error {
+    io::Error::from_raw_os_error(e)
+}
+
+fn qid(md: &Metadata) -> Qid {
+    let ft = md.file_type();
+    let type_ = if ft.is_dir() {
+        QTDIR
+    } else if ft.is_symlink() {
+        QTSYMLINK
+    } else {
+        QTFILE
+    };
+    Qid {
+        type_,
+        version: 0,
+        path: md.ino(),
+    }
+}
+
+/// `d_type` of a directory entry
+fn dirent_type(md: &Metadata) -> u8 {
+    let ft = md.file_type();
+    if ft.is_dir() {
+        libc::DT_DIR
+    } else if ft.is_symlink() {
+        libc::DT_LNK
+    } else if ft.is_file() {
+        libc::DT_REG
+    } else if ft.is_fifo() {
+        libc::DT_FIFO
+    } else if ft.is_socket() {
+        libc::DT_SOCK
+    } else if ft.is_char_device() {
+        libc::DT_CHR
+    } else if ft.is_block_device() {
+        libc::DT_BLK
+    } else {
+        libc::DT_UNKNOWN
+    }
+}
+
+/// Appends a single file name to a fid path.
+fn child(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok(dir.join(name))
+}
+
+/// Opens `name` in `dir` without following a symlink in its place.
+fn open_at(dir: RawFd, name: &Path, flags: OFlag, mode: Mode) -> io::Result<File> {
+    let fd = fcntl::openat(
+        dir,
+        name,
+        flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        mode,
+    )?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Rlerror with `errno` for the request with `tag`
+pub fn error_response(tag: u16, errno: i32) -> Vec<u8> {
+    let mut w = WireWriter::new(RLERROR, tag);
+    w.u32(errno as u32);
+    w.finish()
+}
+
+/// Tag of `request`, NOTAG if it is too short to have one.
+pub fn request_tag(request: &[u8]) -> u16 {
+    let mut r = WireReader::new(request);
+    match (r.u32(), r.u8(), r.u16()) {
+        (Ok(_), Ok(_), Ok(tag)) => tag,
+        _ => !0,
+    }
+}
+
+/// Refers to the file of `file` even if it is an O_PATH descriptor.
+fn proc_fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+fn open_flags(flags: u32) -> OFlag {
+    let mut oflags = match flags & DOTL_ACCMODE {
+        DOTL_WRONLY => OFlag::O_WRONLY,
+        DOTL_RDWR => OFlag::O_RDWR,
+        _ => OFlag::O_RDONLY,
+    };
+    if flags & DOTL_TRUNC != 0 {
+        oflags |= OFlag::O_TRUNC;
+    }
+    if flags & DOTL_APPEND != 0 {
+        oflags |= OFlag::O_APPEND;
+    }
+    oflags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC
+}
+
+fn writes(flags: u32) -> bool {
+    flags & DOTL_ACCMODE != 0 || flags & (DOTL_TRUNC | DOTL_APPEND) != 0
+}
+
+struct Dirent {
+    qid: Qid,
+    type_: u8,
+    name: String,
+}
+
+struct Fid {
+    /// relative to the shared directory
+    path: PathBuf,
+    /// owner of files created through this fid
+    uid: u32,
+    file: Option<File>,
+    /// directory listing, taken when the client starts reading the directory
+    dirents: Option<Vec<Dirent>>,
+}
+
+impl Fid {
+    fn file(&self) -> io::Result<&File> {
+        self.file.as_ref().ok_or_else(|| errno(libc::EBADF))
+    }
+}
+
+pub struct Server {
+    /// the shared directory, all paths are opened relative to it
+    root: File,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: &Path, read_only: bool) -> io::Result<Server> {
+        let fd = fcntl::open(
+            root,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        Ok(Server {
+            root: unsafe { File::from_raw_fd(fd) },
+            read_only,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Handles a single request and returns the response.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut r = WireReader::new(request);
+        let (type_, tag) = match (r.u32(), r.u8(), r.u16()) {
+            (Ok(_), Ok(type_), Ok(tag)) => (type_, tag),
+            _ => (0, !0),
+        };
+        let mut w = WireWriter::new(type_.wrapping_add(1), tag);
+        match self.dispatch(type_, &mut r, &mut w) {
+            Ok(()) => w.finish(),
+            Err(e) => {
+                debug!("9p request {} failed: {}", type_, e);
+                error_response(tag, e.raw_os_error().unwrap_or(libc::EIO))
+            }
+        }
+    }
+
+    fn dispatch(&mut self, type_: u8, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        match type_ {
+            TVERSION => self.version(r, w),
+            TATTACH => self.attach(r, w),
+            TFLUSH => Ok(()),
+            TWALK => self.walk(r, w),
+            TLOPEN => self.lopen(r, w),
+            TLCREATE => self.lcreate(r, w),
+            TSYMLINK => self.symlink(r, w),
+            TMKNOD => self.mknod(r, w),
+            TRENAME => self.rename(r),
+            TREADLINK => self.readlink(r, w),
+            TGETATTR => self.getattr(r, w),
+            TSETATTR => self.setattr(r),
+            TREADDIR => self.readdir(r, w),
+            TFSYNC => self.fsync(r),
+            TLOCK => self.lock(r, w),
+            TGETLOCK => self.getlock(r, w),
+            TLINK => self.link(r),
+            TMKDIR => self.mkdir(r, w),
+            TRENAMEAT => self.renameat(r),
+            TUNLINKAT => self.unlinkat(r),
+            TSTATFS => self.statfs(r, w),
+            TREAD => self.read(r, w),
+            TWRITE => self.write(r, w),
+            TCLUNK => self.clunk(r),
+            TREMOVE => self.remove(r),
+            // no extended attributes or authentication
+            TXATTRWALK | TXATTRCREATE | TAUTH => Err(errno(libc::EOPNOTSUPP)),
+            _ => Err(errno(libc::ENOSYS)),
+        }
+    }
+
+    /// Opens the directory that contains `path` and returns it with the last component of
+    /// `path`, which is "." for the shared directory itself.
+    fn parent(&self, path: &Path) -> io::Result<(File, PathBuf)> {
+        let mut dir = self.root.try_clone()?;
+        let name = match path.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => return Ok((dir, PathBuf::from("."))),
+        };
+        for component in path.parent().into_iter().flat_map(Path::iter) {
+            dir = open_at(
+                dir.as_raw_fd(),
+                Path::new(component),
+                OFlag::O_PATH | OFlag::O_DIRECTORY,
+                Mode::empty(),
+            )?;
+        }
+        Ok((dir, name))
+    }
+
+    fn open(&self, path: &Path, flags: OFlag) -> io::Result<File> {
+        let (dir, name) = self.parent(path)?;
+        open_at(dir.as_raw_fd(), &name, flags, Mode::empty())
+    }
+
+    fn lstat(&self, path: &Path) -> io::Result<Metadata> {
+        self.open(path, OFlag::O_PATH)?.metadata()
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(errno(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    /// Hands files created by the client to the user that created them.
+    fn chown(&self, path: &Path, uid: u32, gid: u32) {
+        let uid = if uid == NO_UID {
+            None
+        } else {
+            Some(Uid::from_raw(uid))
+        };
+        let res = self.parent(path).and_then(|(dir, name)| {
+            unistd::fchownat(
+                Some(dir.as_raw_fd()),
+                &name,
+                uid,
+                Some(Gid::from_raw(gid)),
+                FchownatFlags::NoFollowSymlink,
+            )?;
+            Ok(())
+        });
+        if let Err(e) = res {
+            debug!("cannot change owner of {}: {}", path.display(), e);
+        }
+    }
+
+    fn rename_path(&self, old_path: &Path, new_path: &Path) -> io::Result<()> {
+        let (old_dir, old_name) = self.parent(old_path)?;
+        let (new_dir, new_name) = self.parent(new_path)?;
+        fcntl::renameat(
+            Some(old_dir.as_raw_fd()),
+            &old_name,
+            Some(new_dir.as_raw_fd()),
+            &new_name,
+        )?;
+        Ok(())
+    }
+
+    fn unlink_path(&self, path: &Path, dir: bool) -> io::Result<()> {
+        let (parent, name) = self.parent(path)?;
+        let flags = if dir {
+            UnlinkatFlags::RemoveDir
+        } else {
+            UnlinkatFlags::NoRemoveDir
+        };
+        unistd::unlinkat(Some(parent.as_raw_fd()), &name, flags)?;
+        Ok(())
+    }
+
+    fn new_child(&self, dfid: u32, name: &str) -> io::Result<(PathBuf, u32)> {
+        self.check_writable()?;
+        let dir = self.fid(dfid)?;
+        Ok((child(&dir.path, name)?, dir.uid))
+    }
+
+    fn version(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+        if msize < MIN_MSIZE {
+            return Err(errno(libc::EINVAL));
+        }
+        // a new session starts
+        self.fids.clear();
+        self.msize = cmp::min(msize, MAX_MSIZE);
+        w.u32(self.msize);
+        if version.starts_with(VERSION_9P2000_L) {
+            w.string(VERSION_9P2000_L);
+        } else {
+            w.string("unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _afid = r.u32()?;
+        let _uname = r.string()?;
+        let _aname = r.string()?;
+        let uid = r.u32()?;
+        let md = self.lstat(Path::new(""))?;
+        self.fids.insert(
+            fid,
+            Fid {
+                path: PathBuf::new(),
+                uid,
+                file: None,
+                dirents: None,
+            },
+        );
+        w.qid(&qid(&md));
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let nwname = r.u16()?;
+        let names = (0..nwname)
+            .map(|_| r.string())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let (mut path, uid) = {
+            let fid = self.fid(fid)?;
+            (fid.path.clone(), fid.uid)
+        };
+        let mut qids = vec![];
+        for name in &names {
+            let res = if name == ".." {
+                // we cannot leave the shared directory
+                let mut parent = path.clone();
+                parent.pop();
+                Ok(parent)
+            } else if !self.lstat(&path)?.is_dir() {
+                Err(errno(libc::ENOTDIR))
+            } else {
+                child(&path, name)
+            };
+            match res.and_then(|next| Ok((self.lstat(&next)?, next))) {
+                Ok((md, next)) => {
+                    qids.push(qid(&md));
+                    path = next;
+                }
+                // only report errors for the first element, otherwise the client sees how far we got
+                Err(e) if qids.is_empty() && !names.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        if qids.len() == names.len() {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    path,
+                    uid,
+                    file: None,
+                    dirents: None,
+                },
+            );
+        }
+        w.u16(qids.len() as u16);
+        for qid in &qids {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let flags = r.u32()?;
+        if writes(flags) {
+            self.check_writable()?;
+        }
+        let path = self.fid(fid)?.path.clone();
+        let md = self.lstat(&path)?;
+        let file = if md.is_dir() {
+            // directories are read by path
+            None
+        } else {
+            Some(self.open(&path, open_flags(flags))?)
+        };
+        let fid = self.fid_mut(fid)?;
+        fid.file = file;
+        fid.dirents = None;
+        w.qid(&qid(&md));
+        // let the client pick the largest size that fits into msize
+        w.u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()?;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+        let (path, uid) = self.new_child(fid, &name)?;
+
+        let mut oflags = if flags & DOTL_ACCMODE == DOTL_WRONLY {
+            OFlag::O_WRONLY | OFlag::O_CREAT
+        } else {
+            OFlag::O_RDWR | OFlag::O_CREAT
+        };
+        if flags & DOTL_APPEND != 0 {
+            oflags |= OFlag::O_APPEND;
+        }
+        if flags & DOTL_EXCL != 0 {
+            oflags |= OFlag::O_EXCL;
+        }
+        let (dir, name) = self.parent(&path)?;
+        let file = open_at(
+            dir.as_raw_fd(),
+            &name,
+            oflags,
+            Mode::from_bits_truncate(mode & MODE_MASK),
+        )?;
+        self.chown(&path, uid, gid);
+        let md = file.metadata()?;
+
+        let fid = self.fid_mut(fid)?;
+        fid.path = path;
+        fid.file = Some(file);
+        w.qid(&qid(&md));
+        w.u32(0);
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let target = r.string()?;
+        let gid = r.u32()?;
+        let (path, uid) = self.new_child(fid, &name)?;
+        let (dir, name) = self.parent(&path)?;
+        unistd::symlinkat(target.as_str(), Some(dir.as_raw_fd()), &name)?;
+        self.chown(&path, uid, gid);
+        w.qid(&qid(&self.lstat(&path)?));
+        Ok(())
+    }
+
+    fn mknod(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let major = r.u32()?;
+        let minor = r.u32()?;
+        let gid = r.u32()?;
+        let (path, uid) = self.new_child(dfid, &name)?;
+
+        let kind = SFlag::from_bits_truncate(mode & libc::S_IFMT);
+        // device nodes on the host would give the guest access to host devices
+        if kind != SFlag::S_IFIFO && kind != SFlag::S_IFSOCK && kind != SFlag::S_IFREG {
+            return Err(errno(libc::EPERM));
+        }
+        let (dir, name) = self.parent(&path)?;
+        stat::mknodat(
+            dir.as_raw_fd(),
+            &name,
+            kind,
+            Mode::from_bits_truncate(mode & MODE_MASK),
+            stat::makedev(major as u64, minor as u64),
+        )?;
+        self.chown(&path, uid, gid);
+        w.qid(&qid(&self.lstat(&path)?));
+        Ok(())
+    }
+
+    fn rename(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let (new_path, _) = self.new_child(dfid, &name)?;
+        let old_path = self.fid(fid)?.path.clone();
+        self.rename_path(&old_path, &new_path)?;
+        self.fid_mut(fid)?.path = new_path;
+        Ok(())
+    }
+
+    fn readlink(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let (dir, name) = self.parent(&self.fid(fid)?.path)?;
+        let target = fcntl::readlinkat(dir.as_raw_fd(), &name)?;
+        w.string(&target.to_string_lossy());
+        Ok(())
+    }
+
+    fn getattr(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _request_mask = r.u64()?;
+        let md = self.lstat(&self.fid(fid)?.path)?;
+        w.u64(GETATTR_BASIC);
+        w.qid(&qid(&md));
+        w.u32(md.mode());
+        w.u32(md.uid());
+        w.u32(md.gid());
+        w.u64(md.nlink());
+        w.u64(md.rdev());
+        w.u64(md.size());
+        w.u64(md.blksize());
+        w.u64(md.blocks());
+        w.u64(md.atime() as u64);
+        w.u64(md.atime_nsec() as u64);
+        w.u64(md.mtime() as u64);
+        w.u64(md.mtime_nsec() as u64);
+        w.u64(md.ctime() as u64);
+        w.u64(md.ctime_nsec() as u64);
+        // btime, gen and data_version are not supported
+        w.u64(0);
+        w.u64(0);
+        w.u64(0);
+        w.u64(0);
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime_sec = r.u64()?;
+        let atime_nsec = r.u64()?;
+        let mtime_sec = r.u64()?;
+        let mtime_nsec = r.u64()?;
+        if valid == 0 {
+            return Ok(());
+        }
+        self.check_writable()?;
+
+        let path = self.fid(fid)?.path.clone();
+        let (dir, name) = self.parent(&path)?;
+        let dir = Some(dir.as_raw_fd());
+
+        if valid & SETATTR_MODE != 0 {
+            // Linux has no fchmodat without following symlinks and fchmod fails on O_PATH
+            // descriptors, but chmod through /proc/self/fd changes exactly the inode we opened
+            let file = self.open(&path, OFlag::O_PATH)?;
+            if !file.metadata()?.file_type().is_symlink() {
+                stat::fchmodat(
+                    None,
+                    &proc_fd_path(&file),
+                    Mode::from_bits_truncate(mode & MODE_MASK),
+                    FchmodatFlags::FollowSymlink,
+                )?;
+            }
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            unistd::fchownat(
+                dir,
+                &name,
+                Some(Uid::from_raw(uid)).filter(|_| valid & SETATTR_UID != 0),
+                Some(Gid::from_raw(gid)).filter(|_| valid & SETATTR_GID != 0),
+                FchownatFlags::NoFollowSymlink,
+            )?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            // non-blocking, so we do not wait for readers of fifos
+            let file = self.open(&path, OFlag::O_WRONLY | OFlag::O_NONBLOCK)?;
+            file.set_len(size)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let time = |set: u32, explicit: u32, sec: u64, nsec: u64| {
+                let ts = if valid & explicit != 0 {
+                    libc::timespec {
+                        tv_sec: sec as libc::time_t,
+                        tv_nsec: nsec as libc::c_long,
+                    }
+                } else {
+                    libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: if valid & set != 0 {
+                            libc::UTIME_NOW
+                        } else {
+                            libc::UTIME_OMIT
+                        },
+                    }
+                };
+                TimeSpec::from(ts)
+            };
+            stat::utimensat(
+                dir,
+                &name,
+                &time(SETATTR_ATIME, SETATTR_ATIME_SET, atime_sec, atime_nsec),
+                &time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime_sec, mtime_nsec),
+                UtimensatFlags::NoFollowSymlink,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<Dirent>> {
+        let mut parent = path.to_path_buf();
+        parent.pop();
+        let mut dirents = vec![];
+        for (name, path) in &[(".", path), ("..", parent.as_path())] {
+            let md = self.lstat(path)?;
+            dirents.push(Dirent {
+                qid: qid(&md),
+                type_: dirent_type(&md),
+                name: name.to_string(),
+            });
+        }
+        let file = self.open(path, OFlag::O_RDONLY | OFlag::O_DIRECTORY)?;
+        let mut dir = Dir::from_fd(file.into_raw_fd())?;
+        let fd = dir.as_raw_fd();
+        for entry in dir.iter() {
+            let entry = entry?;
+            let name = match entry.file_name().to_str() {
+                Ok(name) => name,
+                Err(_) => {
+                    debug!("skip non-utf8 file name {:?}", entry.file_name());
+                    continue;
+                }
+            };
+            if name == "." || name == ".." {
+                continue;
+            }
+            let md = open_at(fd, Path::new(name), OFlag::O_PATH, Mode::empty())?.metadata()?;
+            dirents.push(Dirent {
+                qid: qid(&md),
+                type_: dirent_type(&md),
+                name: name.to_string(),
+            });
+        }
+        Ok(dirents)
+    }
+
+    fn readdir(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = cmp::min(r.u32()?, self.msize - IO_HEADER_SIZE) as usize;
+
+        let path = self.fid(fid)?.path.clone();
+        if offset == 0 || self.fid(fid)?.dirents.is_none() {
+            let dirents = self.list_dir(&path)?;
+            self.fid_mut(fid)?.dirents = Some(dirents);
+        }
+        let dirents = self.fid(fid)?.dirents.as_deref().unwrap_or_default();
+
+        let mut data = WireWriter::headless();
+        for (idx, dirent) in dirents.iter().enumerate().skip(offset as usize) {
+            if data.size() + QID_SIZE + 8 + 1 + 2 + dirent.name.len() > count {
+                break;
+            }
+            data.qid(&dirent.qid);
+            // offset of the next entry
+            data.u64(idx as u64 + 1);
+            data.u8(dirent.type_);
+            data.string(&dirent.name);
+        }
+        let data = data.into_bytes();
+        w.u32(data.len() as u32);
+        w.bytes(&data);
+        Ok(())
+    }
+
+    fn fsync(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let datasync = r.u32()?;
+        let fid = self.fid(fid)?;
+        if let Some(file) = &fid.file {
+            if datasync != 0 {
+                file.sync_data()?;
+            } else {
+                file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// We are the only client of our files, so we grant every lock.
+    fn lock(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        self.fid(fid)?;
+        w.u8(LOCK_SUCCESS);
+        Ok(())
+    }
+
+    fn getlock(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _type = r.u8()?;
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let proc_id = r.u32()?;
+        let client_id = r.string()?;
+        self.fid(fid)?;
+        w.u8(LOCK_TYPE_UNLCK);
+        w.u64(start);
+        w.u64(length);
+        w.u32(proc_id);
+        w.string(&client_id);
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let (path, _) = self.new_child(dfid, &name)?;
+        let (old_dir, old_name) = self.parent(&self.fid(fid)?.path)?;
+        let (new_dir, new_name) = self.parent(&path)?;
+        unistd::linkat(
+            Some(old_dir.as_raw_fd()),
+            &old_name,
+            Some(new_dir.as_raw_fd()),
+            &new_name,
+            LinkatFlags::NoSymlinkFollow,
+        )?;
+        Ok(())
+    }
+
+    fn mkdir(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let dfid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+        let (path, uid) = self.new_child(dfid, &name)?;
+        let (dir, name) = self.parent(&path)?;
+        stat::mkdirat(
+            dir.as_raw_fd(),
+            &name,
+            Mode::from_bits_truncate(mode & MODE_MASK),
+        )?;
+        self.chown(&path, uid, gid);
+        w.qid(&qid(&self.lstat(&path)?));
+        Ok(())
+    }
+
+    fn renameat(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let olddirfid = r.u32()?;
+        let oldname = r.string()?;
+        let newdirfid = r.u32()?;
+        let newname = r.string()?;
+        let (old_path, _) = self.new_child(olddirfid, &oldname)?;
+        let (new_path, _) = self.new_child(newdirfid, &newname)?;
+        self.rename_path(&old_path, &new_path)
+    }
+
+    fn unlinkat(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let dirfid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()?;
+        let (path, _) = self.new_child(dirfid, &name)?;
+        self.unlink_path(&path, flags & AT_REMOVEDIR != 0)
+    }
+
+    fn statfs(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let st = fstatvfs(&self.open(&self.fid(fid)?.path, OFlag::O_PATH)?)?;
+        w.u32(V9FS_MAGIC);
+        w.u32(st.block_size() as u32);
+        w.u64(st.blocks() as u64);
+        w.u64(st.blocks_free() as u64);
+        w.u64(st.blocks_available() as u64);
+        w.u64(st.files() as u64);
+        w.u64(st.files_free() as u64);
+        w.u64(st.filesystem_id() as u64);
+        w.u32(st.name_max() as u32);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = cmp::min(r.u32()?, self.msize - IO_HEADER_SIZE);
+        let mut buf = vec![0u8; count as usize];
+        let len = self.fid(fid)?.file()?.read_at(&mut buf, offset)?;
+        w.u32(len as u32);
+        w.bytes(&buf[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut WireReader, w: &mut WireWriter) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?;
+        let data = r.bytes(count as usize)?;
+        self.check_writable()?;
+        let len = self.fid(fid)?.file()?.write_at(data, offset)?;
+        w.u32(len as u32);
+        Ok(())
+    }
+
+    fn clunk(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let fid = r.u32()?;
+        match self.fids.remove(&fid) {
+            Some(_) => Ok(()),
+            None => Err(errno(libc::EBADF)),
+        }
+    }
+
+    fn remove(&mut self, r: &mut WireReader) -> io::Result<()> {
+        let fid = r.u32()?;
+        // the fid is clunked even if the remove fails
+        let fid = self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+        self.check_writable()?;
+        let md = self.lstat(&fid.path)?;
+        self.unlink_path(&fid.path, md.is_dir())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    const TAG: u16 = 1;
+
+    fn request(server: &mut Server, type_: u8, body: impl FnOnce(&mut WireWriter)) -> Vec<u8> {
+        let mut w = WireWriter::new(type_, TAG);
+        body(&mut w);
+        let resp = server.handle(&w.finish());
+        assert_eq!(&resp[5..7], &TAG.to_le_bytes());
+        resp
+    }
+
+    fn response_type(resp: &[u8]) -> u8 {
+        resp[4]
+    }
+
+    #[test]
+    fn test_walk_create_read() {
+        let dir = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        fs::create_dir(dir.path().join("sub")).expect("cannot create directory");
+        let mut server = Server::new(dir.path(), false).expect("cannot open shared directory");
+
+        let resp = request(&mut server, TVERSION, |w| {
+            w.u32(1024 * 1024);
+            w.string("9P2000.L");
+        });
+        assert_eq!(response_type(&resp), TVERSION + 1);
+        let mut r = WireReader::new(&resp[HEADER_SIZE..]);
+        assert_eq!(r.u32().expect("msize"), MAX_MSIZE);
+
+        let resp = request(&mut server, TATTACH, |w| {
+            w.u32(0);
+            w.u32(!0);
+            w.string("root");
+            w.string("");
+            w.u32(0);
+        });
+        assert_eq!(response_type(&resp), TATTACH + 1);
+
+        // walking above the root stays in the shared directory
+        let resp = request(&mut server, TWALK, |w| {
+            w.u32(0);
+            w.u32(1);
+            w.u16(2);
+            w.string("..");
+            w.string("sub");
+        });
+        assert_eq!(response_type(&resp), TWALK + 1);
+        let mut r = WireReader::new(&resp[HEADER_SIZE..]);
+        assert_eq!(r.u16().expect("nwqid"), 2);
+
+        let resp = request(&mut server, TLCREATE, |w| {
+            w.u32(1);
+            w.string("file");
+            w.u32(DOTL_RDWR);
+            w.u32(0o644);
+            w.u32(0);
+        });
+        assert_eq!(response_type(&resp), TLCREATE + 1);
+
+        let resp = request(&mut server, TWRITE, |w| {
+            w.u32(1);
+            w.u64(0);
+            w.u32(5);
+            w.bytes(b"hello");
+        });
+        assert_eq!(response_type(&resp), TWRITE + 1);
+        assert_eq!(
+            fs::read(dir.path().join("sub/file")).expect("cannot read file"),
+            b"hello"
+        );
+
+        let resp = request(&mut server, TREAD, |w| {
+            w.u32(1);
+            w.u64(1);
+            w.u32(100);
+        });
+        let mut r = WireReader::new(&resp[HEADER_SIZE..]);
+        let len = r.u32().expect("count");
+        assert_eq!(r.bytes(len as usize).expect("data"), b"ello");
+
+        // names must not contain slashes
+        let resp = request(&mut server, TWALK, |w| {
+            w.u32(0);
+            w.u32(2);
+            w.u16(1);
+            w.string("sub/file");
+        });
+        assert_eq!(response_type(&resp), RLERROR);
+    }
+
+    fn attach(server: &mut Server) {
+        let resp = request(server, TATTACH, |w| {
+            w.u32(0);
+            w.u32(!0);
+            w.string("root");
+            w.string("");
+            w.u32(0);
+        });
+        assert_eq!(response_type(&resp), TATTACH + 1);
+    }
+
+    #[test]
+    fn test_symlink_escape() {
+        let dir = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        let outside = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        fs::create_dir(dir.path().join("sub")).expect("cannot create directory");
+        let mut server = Server::new(dir.path(), false).expect("cannot open shared directory");
+        attach(&mut server);
+        let resp = request(&mut server, TWALK, |w| {
+            w.u32(0);
+            w.u32(1);
+            w.u16(1);
+            w.string("sub");
+        });
+        assert_eq!(response_type(&resp), TWALK + 1);
+
+        // the host replaces the walked directory with a symlink that leads outside
+        fs::remove_dir(dir.path().join("sub")).expect("cannot remove directory");
+        symlink(outside.path(), dir.path().join("sub")).expect("cannot create symlink");
+        let resp = request(&mut server, TLCREATE, |w| {
+            w.u32(1);
+            w.string("file");
+            w.u32(DOTL_RDWR);
+            w.u32(0o644);
+            w.u32(0);
+        });
+        assert_eq!(response_type(&resp), RLERROR);
+        assert!(!outside.path().join("file").exists());
+    }
+
+    fn walk(server: &mut Server, newfid: u32, name: &str) {
+        let resp = request(server, TWALK, |w| {
+            w.u32(0);
+            w.u32(newfid);
+            w.u16(1);
+            w.string(name);
+        });
+        assert_eq!(response_type(&resp), TWALK + 1);
+    }
+
+    fn setattr_mode(server: &mut Server, fid: u32, mode: u32) -> Vec<u8> {
+        request(server, TSETATTR, |w| {
+            w.u32(fid);
+            w.u32(SETATTR_MODE);
+            w.u32(mode);
+            w.u32(0);
+            w.u32(0);
+            for _ in 0..5 {
+                w.u64(0);
+            }
+        })
+    }
+
+    #[test]
+    fn test_setattr_mode() {
+        let dir = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        let outside = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        let target = outside.path().join("target");
+        fs::write(dir.path().join("file"), b"").expect("cannot create file");
+        fs::write(&target, b"").expect("cannot create file");
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).expect("cannot chmod file");
+        symlink(&target, dir.path().join("link")).expect("cannot create symlink");
+        let mut server = Server::new(dir.path(), false).expect("cannot open shared directory");
+        attach(&mut server);
+
+        walk(&mut server, 1, "file");
+        let resp = setattr_mode(&mut server, 1, 0o600);
+        assert_eq!(response_type(&resp), TSETATTR + 1);
+        let md = fs::metadata(dir.path().join("file")).expect("cannot stat file");
+        assert_eq!(md.mode() & 0o777, 0o600);
+
+        // symlinks have no mode of their own, their target must stay untouched
+        walk(&mut server, 2, "link");
+        let resp = setattr_mode(&mut server, 2, 0o777);
+        assert_eq!(response_type(&resp), TSETATTR + 1);
+        let md = fs::metadata(&target).expect("cannot stat file");
+        assert_eq!(md.mode() & 0o777, 0o644);
+    }
+
+    #[test]
+    fn test_no_setuid() {
+        let dir = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        let mut server = Server::new(dir.path(), false).expect("cannot open shared directory");
+        attach(&mut server);
+        let resp = request(&mut server, TLCREATE, |w| {
+            w.u32(0);
+            w.string("file");
+            w.u32(DOTL_RDWR);
+            w.u32(0o6755);
+            w.u32(0);
+        });
+        assert_eq!(response_type(&resp), TLCREATE + 1);
+        let md = fs::metadata(dir.path().join("file")).expect("cannot stat file");
+        assert_eq!(md.mode() & 0o6000, 0);
+    }
+
+    #[test]
+    fn test_small_msize() {
+        let dir = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        let mut server = Server::new(dir.path(), false).expect("cannot open shared directory");
+        let resp = request(&mut server, TVERSION, |w| {
+            w.u32(IO_HEADER_SIZE - 1);
+            w.string("9P2000.L");
+        });
+        assert_eq!(response_type(&resp), RLERROR);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = ioutils::tmp::tempdir().expect("cannot create tempdir");
+        let mut server = Server::new(dir.path(), true).expect("cannot open shared directory");
+        request(&mut server, TATTACH, |w| {
+            w.u32(0);
+            w.u32(!0);
+            w.string("root");
+            w.string("");
+            w.u32(0);
+        });
+        let resp = request(&mut server, TMKDIR, |w| {
+            w.u32(0);
+            w.string("dir");
+            w.u32(0o755);
+            w.u32(0);
+        });
+        assert_eq!(response_type(&resp), RLERROR);
+        let mut r = WireReader::new(&resp[HEADER_SIZE..]);
+        assert_eq!(r.u32().expect("ecode"), libc::EROFS as u32);
+    }
+}
@@ -0,0 +1,176 @@
+use std::borrow::{Borrow, BorrowMut};
+
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, try_with};
+use virtio_device::{VirtioConfig, VirtioMmioDevice};
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+
+use crate::kvm::hypervisor::ioevent::IoEvent;
+use crate::result::Result;
+
+// Registers of the virtio mmio transport (virtio spec 4.2.2) written by the driver during setup.
+const DRIVER_FEATURES: u64 = 0x20;
+const DRIVER_FEATURES_SEL: u64 = 0x24;
+const QUEUE_SEL: u64 = 0x30;
+const QUEUE_NUM: u64 = 0x38;
+const QUEUE_READY: u64 = 0x44;
+const STATUS: u64 = 0x70;
+const QUEUE_DESC_LOW: u64 = 0x80;
+const QUEUE_DESC_HIGH: u64 = 0x84;
+const QUEUE_AVAIL_LOW: u64 = 0x90;
+const QUEUE_AVAIL_HIGH: u64 = 0x94;
+const QUEUE_USED_LOW: u64 = 0xa0;
+const QUEUE_USED_HIGH: u64 = 0xa4;
+
+const STATUS_ACKNOWLEDGE: u8 = 0x01;
+const STATUS_DRIVER: u8 = 0x02;
+const STATUS_DRIVER_OK: u8 = 0x04;
+const STATUS_FEATURES_OK: u8 = 0x08;
+
+/// Offset of the index field in the used ring.
+const USED_RING_IDX_OFFSET: u64 = 2;
+
+/// Implemented by devices that can take over from a device served by an earlier vmsh process.
+pub trait Restore {
+    /// Notifies the queue handlers as if the driver did. Notifications of the driver got lost
+    /// while no vmsh process was attached.
+    fn notify_queues(&self);
+}
+
+/// Helper for `Restore::notify_queues`.
+pub fn notify_ioevents<'a>(ioevents: impl IntoIterator<Item = &'a IoEvent>) {
+    for ioevent in ioevents {
+        if let Err(e) = ioevent.write(1) {
+            log::warn!("cannot notify queue handler: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueState {
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+}
+
+/// What the driver in the VM negotiated with a device. Everything else the device needs to know
+/// lives in guest memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtioState {
+    /// identifies the device
+    pub mmio_base: u64,
+    pub driver_features: u64,
+    pub device_status: u8,
+    pub queues: Vec<QueueState>,
+}
+
+pub fn save<M, D>(dev: &D, mmio_base: u64) -> VirtioState
+where
+    M: GuestAddressSpace,
+    D: Borrow<VirtioConfig<M>>,
+{
+    let cfg: &VirtioConfig<M> = dev.borrow();
+    VirtioState {
+        mmio_base,
+        driver_features: cfg.driver_features,
+        device_status: cfg.device_status,
+        queues: cfg
+            .queues
+            .iter()
+            .map(|q| QueueState {
+                size: q.size,
+                ready: q.ready,
+                desc_table: q.desc_table.0,
+                avail_ring: q.avail_ring.0,
+                used_ring: q.used_ring.0,
+            })
+            .collect(),
+    }
+}
+
+fn write_reg<M, D>(dev: &mut D, offset: u64, val: u32)
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M>,
+{
+    dev.write(offset, &val.to_le_bytes());
+}
+
+fn write_addr<M, D>(dev: &mut D, low: u64, high: u64, addr: u64)
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M>,
+{
+    write_reg(dev, low, addr as u32);
+    write_reg(dev, high, (addr >> 32) as u32);
+}
+
+/// Brings a freshly created device into the state saved by `save`, by repeating the setup of the
+/// driver. Activates the device if the driver had done so.
+pub fn restore<M, D>(dev: &mut D, state: &VirtioState, mem: &GuestMemoryMmap) -> Result<()>
+where
+    M: GuestAddressSpace,
+    D: VirtioMmioDevice<M> + BorrowMut<VirtioConfig<M>> + Restore,
+{
+    if state.device_status & STATUS_DRIVER_OK == 0 {
+        // the driver did not finish the setup, so it will not use the device
+        return Ok(());
+    }
+
+    let mut status = STATUS_ACKNOWLEDGE;
+    write_reg(dev, STATUS, status as u32);
+    status |= STATUS_DRIVER;
+    write_reg(dev, STATUS, status as u32);
+    for sel in 0..2 {
+        write_reg(dev, DRIVER_FEATURES_SEL, sel);
+        write_reg(
+            dev,
+            DRIVER_FEATURES,
+            (state.driver_features >> (32 * sel)) as u32,
+        );
+    }
+    status |= STATUS_FEATURES_OK;
+    write_reg(dev, STATUS, status as u32);
+
+    for (idx, queue) in state.queues.iter().enumerate() {
+        if !queue.ready {
+            continue;
+        }
+        write_reg(dev, QUEUE_SEL, idx as u32);
+        write_reg(dev, QUEUE_NUM, queue.size as u32);
+        write_addr(dev, QUEUE_DESC_LOW, QUEUE_DESC_HIGH, queue.desc_table);
+        write_addr(dev, QUEUE_AVAIL_LOW, QUEUE_AVAIL_HIGH, queue.avail_ring);
+        write_addr(dev, QUEUE_USED_LOW, QUEUE_USED_HIGH, queue.used_ring);
+        write_reg(dev, QUEUE_READY, 1);
+
+        // Our queue handlers put every descriptor chain they take into the used ring before
+        // returning, so the device has consumed exactly as many chains as it has used.
+        let used_idx: u16 = try_with!(
+            mem.read_obj(GuestAddress(queue.used_ring + USED_RING_IDX_OFFSET)),
+            "cannot read used ring of queue {}",
+            idx
+        );
+        let cfg: &mut VirtioConfig<M> = dev.borrow_mut();
+        let q = &mut cfg.queues[idx];
+        q.set_next_avail(used_idx);
+        q.set_next_used(used_idx);
+    }
+
+    // picked up by the queue handlers once they are registered on activation
+    dev.notify_queues();
+
+    status |= STATUS_DRIVER_OK;
+    write_reg(dev, STATUS, status as u32);
+
+    let cfg: &VirtioConfig<M> = dev.borrow();
+    if !cfg.device_activated {
+        bail!(
+            "device at {:#x} did not accept the restored state (status {:#x})",
+            state.mmio_base,
+            cfg.device_status
+        );
+    }
+    Ok(())
+}
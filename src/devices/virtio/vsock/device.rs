@@ -0,0 +1,297 @@
+use std::borrow::{Borrow, BorrowMut};
+use std::fs;
+use std::ops::DerefMut;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use virtio_device::{VirtioDevice, VirtioDeviceType};
+
+use event_manager::{MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId};
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioMmioDevice, VirtioQueueNotifiable};
+use virtio_queue::Queue;
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::MmioManager;
+use vm_device::{DeviceMmio, MutDeviceMmio};
+use vm_memory::GuestAddressSpace;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::virtio::features::{VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1};
+use crate::devices::virtio::state::{notify_ioevents, Restore};
+use crate::devices::virtio::vsock::muxer::VsockMuxer;
+use crate::devices::virtio::{
+    IoCounters, IrqAckHandler, MmioConfig, SingleFdSignalQueue, QUEUE_MAX_SIZE,
+};
+use crate::devices::MaybeIoRegionFd;
+use crate::devices::MmioBackend;
+use crate::kvm::hypervisor::{
+    ioevent::IoEvent, ioregionfd::IoRegionFd, userspaceioeventfd::UserspaceIoEventFd,
+};
+
+use super::{build_config_space, Error, Result, VsockArgs, VSOCK_DEVICE_ID};
+
+pub(super) const RX_QUEUE_IDX: u16 = 0;
+pub(super) const TX_QUEUE_IDX: u16 = 1;
+
+pub struct Vsock<M: GuestAddressSpace> {
+    virtio_cfg: VirtioConfig<M>,
+    pub mmio_cfg: MmioConfig,
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+    pub irq_ack_handler: Arc<Mutex<IrqAckHandler>>,
+    irqfd: Arc<EventFd>,
+    pub ioregionfd: Option<IoRegionFd>,
+    pub uioefd: UserspaceIoEventFd,
+    /// shared with the muxer, so that the device can be activated again after a reset
+    rx_fd: Arc<IoEvent>,
+    tx_fd: Arc<IoEvent>,
+    /// only used when ioregionfd != None
+    sub_id: Option<SubscriberId>,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: Arc<UnixListener>,
+    /// shared with the muxer
+    pub io: Arc<IoCounters>,
+
+    // Before resetting we return the handler to the mmio thread for cleanup
+    #[allow(dead_code)]
+    handler: Option<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
+}
+
+impl<M> Vsock<M>
+where
+    M: GuestAddressSpace + Clone + Send + 'static,
+{
+    pub fn new<B>(mut args: VsockArgs<M, B>) -> Result<Arc<Mutex<Self>>>
+    where
+        // We're using this (more convoluted) bound so we can pass both references and smart
+        // pointers such as mutex guards here.
+        B: DerefMut,
+        B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
+    {
+        let device_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_RING_EVENT_IDX;
+
+        // A vsock device has an rx, a tx and an event queue. We never send events, so the last one
+        // stays unused.
+        let queues = vec![Queue::new(args.common.mem.clone(), QUEUE_MAX_SIZE); 3];
+
+        let config_space = build_config_space(args.opts.guest_cid);
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        // Used to send notifications to the driver.
+        log::debug!("register irqfd on gsi {}", args.common.mmio_cfg.gsi);
+        let irqfd = Arc::new(
+            args.common
+                .vmm
+                .irqfd(args.common.mmio_cfg.gsi)
+                .map_err(Error::Simple)?,
+        );
+
+        let mmio_cfg = args.common.mmio_cfg;
+
+        let irq_ack_handler = Arc::new(Mutex::new(IrqAckHandler::new(
+            virtio_cfg.interrupt_status.clone(),
+            Arc::clone(&irqfd),
+        )));
+
+        let mut ioregionfd = None;
+        if mmio_cfg.backend == MmioBackend::IoRegionFd {
+            ioregionfd = Some(
+                args.common
+                    .vmm
+                    .ioregionfd(mmio_cfg.range.base().0, mmio_cfg.range.size() as usize)
+                    .map_err(Error::Simple)?,
+            );
+        }
+
+        let mut uioefd = UserspaceIoEventFd::default();
+        let rx_fd = IoEvent::register(
+            &args.common.vmm,
+            &mut uioefd,
+            &mmio_cfg,
+            RX_QUEUE_IDX as u64,
+        )
+        .map_err(Error::Simple)?;
+        let tx_fd = IoEvent::register(
+            &args.common.vmm,
+            &mut uioefd,
+            &mmio_cfg,
+            TX_QUEUE_IDX as u64,
+        )
+        .map_err(Error::Simple)?;
+
+        let listener = UnixListener::bind(&args.opts.uds_path).map_err(Error::UnixSocket)?;
+        listener.set_nonblocking(true).map_err(Error::UnixSocket)?;
+
+        let vsock = Arc::new(Mutex::new(Vsock {
+            virtio_cfg,
+            mmio_cfg,
+            endpoint: args.common.event_mgr.remote_endpoint(),
+            irq_ack_handler,
+            irqfd,
+            ioregionfd,
+            uioefd,
+            rx_fd: Arc::new(rx_fd),
+            tx_fd: Arc::new(tx_fd),
+            sub_id: None,
+            handler: None,
+            guest_cid: args.opts.guest_cid,
+            uds_path: args.opts.uds_path,
+            listener: Arc::new(listener),
+            io: Arc::new(IoCounters::default()),
+        }));
+
+        // Register the device on the MMIO bus.
+        args.common
+            .mmio_mgr
+            .register_mmio(mmio_cfg.range, vsock.clone())
+            .map_err(Error::Bus)?;
+
+        Ok(vsock)
+    }
+
+    fn _activate(&mut self) -> Result<()> {
+        if self.virtio_cfg.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        // We do not support legacy drivers.
+        if self.virtio_cfg.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::BadFeatures(self.virtio_cfg.driver_features));
+        }
+
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.irqfd.clone(),
+            interrupt_status: self.virtio_cfg.interrupt_status.clone(),
+            ack_handler: self.irq_ack_handler.clone(),
+        };
+
+        let handler = Arc::new(Mutex::new(VsockMuxer::new(
+            driver_notify,
+            Arc::clone(&self.rx_fd),
+            Arc::clone(&self.tx_fd),
+            self.virtio_cfg.queues[RX_QUEUE_IDX as usize].clone(),
+            self.virtio_cfg.queues[TX_QUEUE_IDX as usize].clone(),
+            self.guest_cid,
+            self.uds_path.clone(),
+            Arc::clone(&self.listener),
+            Arc::clone(&self.io),
+        )));
+
+        // Register the queue handler with the `EventManager`. We record the `sub_id`
+        // (and/or keep a handler clone) to remove the subscriber when resetting the device
+        let sub_id = self
+            .endpoint
+            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                Ok(mgr.add_subscriber(handler))
+            })
+            .map_err(|e| {
+                log::warn!("{}", e);
+                Error::Endpoint(e)
+            })?;
+        self.sub_id = Some(sub_id);
+
+        log::debug!("activating device: ok");
+        self.virtio_cfg.device_activated = true;
+
+        Ok(())
+    }
+
+    fn _reset(&mut self) -> Result<()> {
+        // we remove the handler here, since we need to free up the ioeventfd resources
+        // in the mmio thread rather the eventmanager thread.
+        if let Some(sub_id) = self.sub_id.take() {
+            let handler = self
+                .endpoint
+                .call_blocking(move |mgr| mgr.remove_subscriber(sub_id))
+                .map_err(|e| {
+                    log::warn!("{}", e);
+                    Error::Endpoint(e)
+                })?;
+            self.handler = Some(handler);
+        }
+        self.virtio_cfg.device_activated = false;
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace> Drop for Vsock<M> {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.uds_path) {
+            log::warn!(
+                "cannot remove vsock socket {}: {}",
+                self.uds_path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> MaybeIoRegionFd for Vsock<M> {
+    fn get_ioregionfd(&mut self) -> &mut Option<IoRegionFd> {
+        &mut self.ioregionfd
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Restore for Vsock<M> {
+    fn notify_queues(&self) {
+        notify_ioevents(vec![self.rx_fd.as_ref(), self.tx_fd.as_ref()]);
+    }
+}
+
+// We now implement `WithVirtioConfig` and `WithDeviceOps` to get the automatic implementation
+// for `VirtioDevice`.
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Vsock<M> {
+    fn device_type(&self) -> u32 {
+        VSOCK_DEVICE_ID
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Vsock<M> {
+    fn borrow(&self) -> &VirtioConfig<M> {
+        &self.virtio_cfg
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> BorrowMut<VirtioConfig<M>> for Vsock<M> {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<M> {
+        &mut self.virtio_cfg
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Vsock<M> {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let ret = self._activate();
+        if let Err(ref e) = ret {
+            log::warn!("failed to activate vsock device: {:?}", e);
+        }
+        ret
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.set_device_status(0);
+        self._reset()?;
+        Ok(())
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioQueueNotifiable for Vsock<M> {
+    fn queue_notify(&mut self, val: u32) {
+        if self.mmio_cfg.backend == MmioBackend::IoRegionFd {
+            self.uioefd.queue_notify(val);
+            log::trace!("queue_notify {}", val);
+        }
+    }
+}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioMmioDevice<M> for Vsock<M> {}
+
+impl<M: GuestAddressSpace + Clone + Send + 'static> MutDeviceMmio for Vsock<M> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
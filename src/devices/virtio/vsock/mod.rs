@@ -0,0 +1,55 @@
+mod device;
+mod muxer;
+mod packet;
+
+use std::io;
+use std::path::PathBuf;
+
+use event_manager::Error as EvmgrError;
+use serde::{Deserialize, Serialize};
+use vm_device::bus;
+
+use crate::devices::virtio::CommonArgs;
+use simple_error::SimpleError;
+
+pub use device::Vsock;
+
+/// Vsock device ID as defined by the standard.
+pub const VSOCK_DEVICE_ID: u32 = 19;
+
+/// The well-known CID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    AlreadyActivated,
+    BadFeatures(u64),
+    Bus(bus::Error),
+    Endpoint(EvmgrError),
+    UnixSocket(io::Error),
+    Simple(SimpleError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// How the vsock device is exposed on the host.
+///
+/// This follows the model of firecracker: Host processes connect to `uds_path` and write
+/// `CONNECT <port>\n` to reach a listener on `<port>` in the guest. Connections initiated by the
+/// guest to port `<port>` of the host are forwarded to the unix socket `<uds_path>_<port>`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VsockOptions {
+    pub uds_path: PathBuf,
+    pub guest_cid: u64,
+}
+
+fn build_config_space(guest_cid: u64) -> Vec<u8> {
+    // struct virtio_vsock_config { le64 guest_cid; }
+    guest_cid.to_le_bytes().to_vec()
+}
+
+// Arguments required when building a vsock device.
+pub struct VsockArgs<'a, M, B> {
+    pub common: CommonArgs<'a, M, B>,
+    pub opts: VsockOptions,
+}
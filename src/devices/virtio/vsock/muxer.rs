@@ -0,0 +1,1036 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::result;
+use std::sync::Arc;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use virtio_queue::{DescriptorChain, Queue};
+use vm_memory::{self, Bytes, GuestAddress, GuestAddressSpace};
+
+use super::device::{RX_QUEUE_IDX, TX_QUEUE_IDX};
+use super::packet::{
+    PacketHeader, HDR_SIZE, OP_CREDIT_REQUEST, OP_CREDIT_UPDATE, OP_REQUEST, OP_RESPONSE, OP_RST,
+    OP_RW, OP_SHUTDOWN, SHUTDOWN_RCV, SHUTDOWN_SEND, TYPE_STREAM,
+};
+use super::VSOCK_HOST_CID;
+use crate::devices::virtio::{IoCounters, SignalUsedQueue};
+use crate::kvm::hypervisor::ioevent::IoEvent;
+
+/// Event data of the listening unix socket. The queues use their index as event data.
+const LISTENER_TOKEN: u32 = 16;
+/// Event data of connections is allocated from here on upwards.
+const FIRST_CONN_TOKEN: u32 = 17;
+
+/// Credit we grant the guest per connection, i.e. how much data we buffer for the host socket.
+const CONN_BUF_ALLOC: u32 = 256 * 1024;
+/// How much data we read ahead from a host socket before the guest consumed it.
+const RX_BUF_LIMIT: usize = 64 * 1024;
+/// Upper bound for packets we accept from the guest.
+const MAX_PKT_SIZE: usize = HDR_SIZE + 64 * 1024;
+/// Ports of host initiated connections are allocated from here on upwards.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+/// Longest `CONNECT <port>` line we accept from the host.
+const MAX_CONNECT_LINE: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    PacketTooLarge,
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Parses the `CONNECT <port>` line host processes send after connecting to the muxer socket.
+fn parse_connect_line(line: &[u8]) -> Option<u32> {
+    std::str::from_utf8(line)
+        .ok()?
+        .trim_end_matches('\r')
+        .strip_prefix("CONNECT ")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// The host connected to the muxer socket but did not send `CONNECT <port>` yet.
+    AwaitingConnect,
+    /// We forwarded the connection request to the guest and wait for its response.
+    RequestSent,
+    Established,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: ConnState,
+    local_port: u32,
+    peer_port: u32,
+    /// partial `CONNECT <port>` line
+    connect_line: Vec<u8>,
+    /// data from the guest that was not written to the host socket yet
+    tx_buf: Vec<u8>,
+    /// data from the host socket that was not passed to the guest yet
+    rx_buf: Vec<u8>,
+    /// bytes written to the host socket
+    fwd_cnt: u32,
+    /// `fwd_cnt` as last advertised to the guest
+    last_fwd_cnt_sent: u32,
+    /// bytes passed to the guest
+    rx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// the host socket reached end of file
+    host_eof: bool,
+    /// we told the guest that the host will not send any more data
+    shutdown_sent: bool,
+    /// the guest will not send any more data
+    guest_shutdown_send: bool,
+    /// the guest closed the connection and waits for our reset
+    guest_closed: bool,
+    /// the events we are currently registered for
+    interest: EventSet,
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: ConnState, local_port: u32, peer_port: u32) -> Self {
+        Connection {
+            stream,
+            state,
+            local_port,
+            peer_port,
+            connect_line: vec![],
+            tx_buf: vec![],
+            rx_buf: vec![],
+            fwd_cnt: 0,
+            last_fwd_cnt_sent: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            host_eof: false,
+            shutdown_sent: false,
+            guest_shutdown_send: false,
+            guest_closed: false,
+            interest: EventSet::empty(),
+        }
+    }
+
+    /// Builds a header for a packet to the guest. Every packet carries our credit.
+    fn header(&mut self, guest_cid: u64, op: u16) -> PacketHeader {
+        self.last_fwd_cnt_sent = self.fwd_cnt;
+        PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: guest_cid,
+            src_port: self.local_port,
+            dst_port: self.peer_port,
+            type_: TYPE_STREAM,
+            op,
+            buf_alloc: CONN_BUF_ALLOC,
+            fwd_cnt: self.fwd_cnt,
+            ..Default::default()
+        }
+    }
+
+    /// How many bytes the guest is still willing to receive.
+    fn peer_credit(&self) -> usize {
+        let in_flight = self.rx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
+    }
+
+    fn has_rx_data(&self) -> bool {
+        self.state == ConnState::Established && !self.rx_buf.is_empty() && self.peer_credit() > 0
+    }
+
+    fn needs_shutdown(&self) -> bool {
+        self.state == ConnState::Established
+            && self.host_eof
+            && self.rx_buf.is_empty()
+            && !self.shutdown_sent
+    }
+
+    fn wanted_events(&self) -> EventSet {
+        let mut events = EventSet::empty();
+        let readable = match self.state {
+            ConnState::AwaitingConnect => true,
+            ConnState::RequestSent => false,
+            ConnState::Established => !self.host_eof && self.rx_buf.is_empty(),
+        };
+        if readable {
+            events |= EventSet::IN;
+        }
+        if !self.tx_buf.is_empty() {
+            events |= EventSet::OUT;
+        }
+        events
+    }
+
+    /// Reads the `CONNECT <port>` line. Returns None if the line is not complete yet.
+    fn read_connect_line(&mut self) -> io::Result<Option<u32>> {
+        let mut byte = [0u8; 1];
+        loop {
+            match self.stream.read(&mut byte) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before CONNECT",
+                    ))
+                }
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) if self.connect_line.len() >= MAX_CONNECT_LINE => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "CONNECT line too long",
+                    ))
+                }
+                Ok(_) => self.connect_line.push(byte[0]),
+                Err(e) if would_block(&e) => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match parse_connect_line(&self.connect_line) {
+            Some(port) => Ok(Some(port)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected 'CONNECT <port>', got '{}'",
+                    String::from_utf8_lossy(&self.connect_line)
+                ),
+            )),
+        }
+    }
+
+    /// Writes buffered guest data to the host socket.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.tx_buf.is_empty() {
+            match self.stream.write(&self.tx_buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.tx_buf.drain(..n);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+                }
+                Err(e) if would_block(&e) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.guest_shutdown_send {
+            // also fails if the host closed the socket already, which is fine
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+
+    /// Reads from the host socket until it would block or `limit` bytes are buffered.
+    fn fill_rx_buf(&mut self, limit: usize) -> io::Result<()> {
+        let mut chunk = [0u8; 4096];
+        while !self.host_eof && self.rx_buf.len() < limit {
+            let len = cmp::min(chunk.len(), limit - self.rx_buf.len());
+            match self.stream.read(&mut chunk[..len]) {
+                Ok(0) => self.host_eof = true,
+                Ok(n) => self.rx_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if would_block(&e) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwards the streams of the guest to unix sockets on the host and vice versa.
+pub(crate) struct VsockMuxer<M: GuestAddressSpace, S: SignalUsedQueue> {
+    driver_notify: S,
+    rx_fd: Arc<IoEvent>,
+    tx_fd: Arc<IoEvent>,
+    rxq: Queue<M>,
+    txq: Queue<M>,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: Arc<UnixListener>,
+    /// connections by event token
+    conns: HashMap<u32, Connection>,
+    /// event token by (local port, peer port)
+    ports: HashMap<(u32, u32), u32>,
+    next_token: u32,
+    next_local_port: u32,
+    /// packets without payload that wait for a free rx buffer
+    rx_control: VecDeque<PacketHeader>,
+    /// removed connections that are still registered with the event manager
+    closed: Vec<Connection>,
+    io: Arc<IoCounters>,
+}
+
+impl<M, S> VsockMuxer<M, S>
+where
+    M: GuestAddressSpace,
+    S: SignalUsedQueue,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        driver_notify: S,
+        rx_fd: Arc<IoEvent>,
+        tx_fd: Arc<IoEvent>,
+        rxq: Queue<M>,
+        txq: Queue<M>,
+        guest_cid: u64,
+        uds_path: PathBuf,
+        listener: Arc<UnixListener>,
+        io: Arc<IoCounters>,
+    ) -> Self {
+        VsockMuxer {
+            driver_notify,
+            rx_fd,
+            tx_fd,
+            rxq,
+            txq,
+            guest_cid,
+            uds_path,
+            listener,
+            conns: HashMap::new(),
+            ports: HashMap::new(),
+            next_token: FIRST_CONN_TOKEN,
+            next_local_port: FIRST_LOCAL_PORT,
+            rx_control: VecDeque::new(),
+            closed: vec![],
+            io,
+        }
+    }
+
+    fn handle_error<Msg: AsRef<str>>(&self, s: Msg, ops: &mut EventOps) {
+        error!("{}", s.as_ref());
+        ops.remove(Events::empty(self.rx_fd.as_ref()))
+            .expect("Failed to remove rx ioevent");
+        ops.remove(Events::empty(self.tx_fd.as_ref()))
+            .expect("Failed to remove tx ioevent");
+    }
+
+    /// Answers a packet that does not belong to any connection with a reset.
+    fn reply_rst(&mut self, hdr: &PacketHeader) {
+        self.rx_control.push_back(PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: hdr.dst_port,
+            dst_port: hdr.src_port,
+            type_: TYPE_STREAM,
+            op: OP_RST,
+            ..Default::default()
+        });
+    }
+
+    fn queue_packet(&mut self, token: u32, op: u16, flags: u32) {
+        let guest_cid = self.guest_cid;
+        if let Some(conn) = self.conns.get_mut(&token) {
+            let mut hdr = conn.header(guest_cid, op);
+            hdr.flags = flags;
+            self.rx_control.push_back(hdr);
+        }
+    }
+
+    fn add_connection(&mut self, conn: Connection) -> u32 {
+        let token = self.next_token;
+        self.next_token = self.next_token.checked_add(1).unwrap_or(FIRST_CONN_TOKEN);
+        if conn.state != ConnState::AwaitingConnect {
+            self.ports.insert((conn.local_port, conn.peer_port), token);
+        }
+        self.conns.insert(token, conn);
+        token
+    }
+
+    /// Connections registered with the event manager are closed by `update_interests`.
+    fn remove_connection(&mut self, token: u32) {
+        if let Some(conn) = self.conns.remove(&token) {
+            if conn.state != ConnState::AwaitingConnect {
+                self.ports.remove(&(conn.local_port, conn.peer_port));
+            }
+            if !conn.interest.is_empty() {
+                self.closed.push(conn);
+            }
+        }
+    }
+
+    /// Tells the guest about the reset unless it does not know the connection yet.
+    fn reset_connection(&mut self, token: u32) {
+        let known_to_guest = match self.conns.get(&token) {
+            Some(conn) => conn.state != ConnState::AwaitingConnect,
+            None => return,
+        };
+        if known_to_guest {
+            self.queue_packet(token, OP_RST, 0);
+        }
+        self.remove_connection(token);
+    }
+
+    fn accept_connections(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if would_block(&e) => return,
+                Err(e) => {
+                    warn!("cannot accept vsock connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("cannot make vsock connection non-blocking: {}", e);
+                continue;
+            }
+            self.add_connection(Connection::new(stream, ConnState::AwaitingConnect, 0, 0));
+        }
+    }
+
+    /// The host asked for a connection to `port` in the guest.
+    fn connect_to_guest(&mut self, token: u32, port: u32) {
+        let local_port = self.next_local_port;
+        self.next_local_port = self
+            .next_local_port
+            .checked_add(1)
+            .unwrap_or(FIRST_LOCAL_PORT);
+        if let Some(conn) = self.conns.get_mut(&token) {
+            conn.state = ConnState::RequestSent;
+            conn.local_port = local_port;
+            conn.peer_port = port;
+            self.ports.insert((local_port, port), token);
+        }
+        self.queue_packet(token, OP_REQUEST, 0);
+    }
+
+    /// The guest asked for a connection to `hdr.dst_port` on the host.
+    fn connect_to_host(&mut self, hdr: &PacketHeader) {
+        let mut path = self.uds_path.clone().into_os_string();
+        path.push(format!("_{}", hdr.dst_port));
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("cannot connect vsock port {} to host: {}", hdr.dst_port, e);
+                self.reply_rst(hdr);
+                return;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(true) {
+            warn!("cannot make vsock connection non-blocking: {}", e);
+            self.reply_rst(hdr);
+            return;
+        }
+        let mut conn = Connection::new(stream, ConnState::Established, hdr.dst_port, hdr.src_port);
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+        let token = self.add_connection(conn);
+        self.queue_packet(token, OP_RESPONSE, 0);
+    }
+
+    fn handle_conn_packet(&mut self, token: u32, hdr: &PacketHeader, payload: &[u8]) {
+        let conn = match self.conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+        match (hdr.op, conn.state) {
+            (OP_RESPONSE, ConnState::RequestSent) => {
+                conn.state = ConnState::Established;
+                let ok = format!("OK {}\n", conn.local_port);
+                // the socket was idle so far, this fits into its buffer
+                if let Err(e) = conn.stream.write_all(ok.as_bytes()) {
+                    debug!("cannot acknowledge vsock connection: {}", e);
+                    self.reset_connection(token);
+                }
+            }
+            (OP_RW, ConnState::Established) => {
+                if conn.tx_buf.len() + payload.len() > CONN_BUF_ALLOC as usize {
+                    warn!("guest exceeded vsock credit on port {}", conn.local_port);
+                    self.reset_connection(token);
+                } else {
+                    conn.tx_buf.extend_from_slice(payload);
+                }
+            }
+            (OP_CREDIT_UPDATE, _) => {}
+            (OP_CREDIT_REQUEST, _) => self.queue_packet(token, OP_CREDIT_UPDATE, 0),
+            (OP_SHUTDOWN, _) => {
+                conn.guest_shutdown_send |= hdr.flags & SHUTDOWN_SEND != 0;
+                conn.guest_closed =
+                    hdr.flags & (SHUTDOWN_SEND | SHUTDOWN_RCV) == SHUTDOWN_SEND | SHUTDOWN_RCV;
+            }
+            (OP_RST, _) => self.remove_connection(token),
+            _ => {
+                debug!(
+                    "unexpected vsock op {} in state {:?}, resetting connection",
+                    hdr.op, conn.state
+                );
+                self.reset_connection(token);
+            }
+        }
+    }
+
+    fn handle_tx_packet(&mut self, pkt: &[u8]) {
+        let hdr = match PacketHeader::parse(pkt) {
+            Some(hdr) => hdr,
+            None => {
+                warn!("dropping truncated vsock packet");
+                self.io.error();
+                return;
+            }
+        };
+        let payload = match hdr.payload(pkt) {
+            Some(payload) => payload,
+            None => {
+                warn!("dropping vsock packet with invalid length {}", hdr.len);
+                self.io.error();
+                return;
+            }
+        };
+        self.io.request(payload.len());
+        if hdr.src_cid != self.guest_cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.type_ != TYPE_STREAM
+        {
+            if hdr.op != OP_RST {
+                self.reply_rst(&hdr);
+            }
+            return;
+        }
+
+        let token = self.ports.get(&(hdr.dst_port, hdr.src_port)).copied();
+        match (token, hdr.op) {
+            (Some(token), _) => self.handle_conn_packet(token, &hdr, payload),
+            (None, OP_REQUEST) => self.connect_to_host(&hdr),
+            (None, OP_RST) => {}
+            (None, _) => self.reply_rst(&hdr),
+        }
+    }
+
+    fn read_chain(chain: &mut DescriptorChain<M>) -> result::Result<Vec<u8>, Error> {
+        let mut pkt = vec![];
+        while let Some(desc) = chain.next() {
+            if desc.is_write_only() {
+                break;
+            }
+            let start = pkt.len();
+            if start + desc.len() as usize > MAX_PKT_SIZE {
+                return Err(Error::PacketTooLarge);
+            }
+            pkt.resize(start + desc.len() as usize, 0);
+            chain.memory().read_slice(&mut pkt[start..], desc.addr())?;
+        }
+        Ok(pkt)
+    }
+
+    /// Guest sends packets (tx)
+    fn process_txq(&mut self) -> result::Result<(), Error> {
+        let mut used = false;
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `vm_virtio`.
+        loop {
+            self.txq.disable_notification()?;
+
+            while let Some(mut chain) = self.txq.iter()?.next() {
+                let pkt = Self::read_chain(&mut chain);
+                self.txq.add_used(chain.head_index(), 0)?;
+                used = true;
+                match pkt {
+                    Ok(pkt) => self.handle_tx_packet(&pkt),
+                    Err(Error::PacketTooLarge) => {
+                        warn!("dropping oversized vsock packet");
+                        self.io.error();
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if !self.txq.enable_notification()? {
+                break;
+            }
+        }
+        if used && self.txq.needs_notification()? {
+            self.driver_notify.signal_used_queue(TX_QUEUE_IDX);
+        }
+        Ok(())
+    }
+
+    fn has_rx_packet(&self) -> bool {
+        !self.rx_control.is_empty()
+            || self
+                .conns
+                .values()
+                .any(|conn| conn.has_rx_data() || conn.needs_shutdown())
+    }
+
+    /// Picks the next packet for the guest with at most `max_len` bytes of payload.
+    fn next_rx_packet(&mut self, max_len: usize) -> Option<(PacketHeader, Vec<u8>)> {
+        if let Some(hdr) = self.rx_control.pop_front() {
+            return Some((hdr, vec![]));
+        }
+        let guest_cid = self.guest_cid;
+        if let Some(conn) = self.conns.values_mut().find(|conn| conn.has_rx_data()) {
+            let len = cmp::min(cmp::min(conn.rx_buf.len(), conn.peer_credit()), max_len);
+            let data = conn.rx_buf.drain(..len).collect::<Vec<_>>();
+            conn.rx_cnt = conn.rx_cnt.wrapping_add(len as u32);
+            let mut hdr = conn.header(guest_cid, OP_RW);
+            hdr.len = len as u32;
+            return Some((hdr, data));
+        }
+        if let Some(conn) = self.conns.values_mut().find(|conn| conn.needs_shutdown()) {
+            conn.shutdown_sent = true;
+            let mut hdr = conn.header(guest_cid, OP_SHUTDOWN);
+            hdr.flags = SHUTDOWN_SEND;
+            return Some((hdr, vec![]));
+        }
+        None
+    }
+
+    /// Fills an rx buffer of the guest with the next packet.
+    fn process_rx_chain(&mut self, mut chain: DescriptorChain<M>) -> result::Result<(), Error> {
+        let mut bufs = vec![];
+        while let Some(desc) = chain.next() {
+            bufs.push((desc.addr(), desc.len() as usize));
+        }
+        let capacity = bufs.iter().map(|(_, len)| len).sum::<usize>();
+        if capacity < HDR_SIZE {
+            warn!("vsock rx buffer too small for a packet header");
+            self.rxq.add_used(chain.head_index(), 0)?;
+            return Ok(());
+        }
+        let (hdr, data) = match self.next_rx_packet(capacity - HDR_SIZE) {
+            Some(pkt) => pkt,
+            None => {
+                self.rxq.add_used(chain.head_index(), 0)?;
+                return Ok(());
+            }
+        };
+        let hdr = hdr.to_bytes();
+        let mut pkt = hdr.iter().chain(data.iter()).copied().collect::<Vec<_>>();
+        let len = pkt.len();
+        let mut bufs = bufs.into_iter();
+        while !pkt.is_empty() {
+            let (addr, buf_len): (GuestAddress, usize) = match bufs.next() {
+                Some(buf) => buf,
+                None => break,
+            };
+            let n = cmp::min(buf_len, pkt.len());
+            chain.memory().write_slice(&pkt[..n], addr)?;
+            pkt.drain(..n);
+        }
+        self.rxq.add_used(chain.head_index(), len as u32)?;
+        self.io.request(data.len());
+        Ok(())
+    }
+
+    /// Guest receives packets (rx)
+    fn process_rxq(&mut self) -> result::Result<(), Error> {
+        let mut used = false;
+        loop {
+            self.rxq.disable_notification()?;
+
+            while self.has_rx_packet() {
+                let chain = match self.rxq.iter()?.next() {
+                    Some(chain) => chain,
+                    None => break,
+                };
+                self.process_rx_chain(chain)?;
+                used = true;
+            }
+
+            // we only need to know about new rx buffers if we have something to send
+            if !self.has_rx_packet() || !self.rxq.enable_notification()? {
+                break;
+            }
+        }
+        if used && self.rxq.needs_notification()? {
+            self.driver_notify.signal_used_queue(RX_QUEUE_IDX);
+        }
+        Ok(())
+    }
+
+    fn handle_conn_event(&mut self, token: u32, events: EventSet) {
+        let conn = match self.conns.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if conn.state == ConnState::AwaitingConnect {
+            match conn.read_connect_line() {
+                Ok(Some(port)) => self.connect_to_guest(token, port),
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("dropping vsock connection: {}", e);
+                    self.remove_connection(token);
+                }
+            }
+            return;
+        }
+
+        let mut res = Ok(());
+        if events.contains(EventSet::OUT) {
+            res = conn.flush();
+        }
+        if res.is_ok() && events.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::ERROR) {
+            // after a hang up we drain the socket, so that we do not get woken up again
+            let limit = if events.contains(EventSet::IN) {
+                RX_BUF_LIMIT
+            } else {
+                usize::MAX
+            };
+            res = conn.fill_rx_buf(limit);
+        }
+        if let Err(e) = res {
+            debug!("resetting vsock connection: {}", e);
+            self.reset_connection(token);
+        }
+    }
+
+    /// Flushes pending guest data, tells the guest about freed credit and closes finished
+    /// connections.
+    fn update_connections(&mut self) {
+        let tokens = self.conns.keys().copied().collect::<Vec<_>>();
+        for token in tokens {
+            let conn = match self.conns.get_mut(&token) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            if conn.state != ConnState::Established {
+                continue;
+            }
+            if let Err(e) = conn.flush() {
+                debug!("resetting vsock connection: {}", e);
+                self.reset_connection(token);
+            } else if conn.guest_closed && conn.tx_buf.is_empty() {
+                self.reset_connection(token);
+            } else if conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt_sent) >= CONN_BUF_ALLOC / 2 {
+                self.queue_packet(token, OP_CREDIT_UPDATE, 0);
+            }
+        }
+    }
+
+    fn update_interests(&mut self, ops: &mut EventOps) {
+        for conn in self.closed.drain(..) {
+            if let Err(e) = ops.remove(Events::empty(&conn.stream)) {
+                warn!("cannot unregister vsock connection: {:?}", e);
+            }
+        }
+        for (token, conn) in self.conns.iter_mut() {
+            let wanted = conn.wanted_events();
+            if wanted == conn.interest {
+                continue;
+            }
+            let res = if conn.interest.is_empty() {
+                ops.add(Events::with_data(&conn.stream, *token, wanted))
+            } else if wanted.is_empty() {
+                ops.remove(Events::empty(&conn.stream))
+            } else {
+                ops.modify(Events::with_data(&conn.stream, *token, wanted))
+            };
+            match res {
+                Ok(()) => conn.interest = wanted,
+                Err(e) => error!("cannot update events of vsock connection: {:?}", e),
+            }
+        }
+    }
+}
+
+impl<M: GuestAddressSpace, S: SignalUsedQueue> MutEventSubscriber for VsockMuxer<M, S> {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        match events.data() {
+            data if data == RX_QUEUE_IDX as u32 => {
+                // the guest added rx buffers
+                if self.rx_fd.read().is_err() {
+                    self.handle_error("Rx ioevent read", ops);
+                    return;
+                }
+            }
+            data if data == TX_QUEUE_IDX as u32 => {
+                if self.tx_fd.read().is_err() {
+                    self.handle_error("Tx ioevent read", ops);
+                    return;
+                }
+                if let Err(e) = self.process_txq() {
+                    self.handle_error(format!("Process tx error {:?}", e), ops);
+                    return;
+                }
+            }
+            LISTENER_TOKEN => self.accept_connections(),
+            token => self.handle_conn_event(token, events.event_set()),
+        }
+
+        self.update_connections();
+        if let Err(e) = self.process_rxq() {
+            self.handle_error(format!("Process rx error {:?}", e), ops);
+            return;
+        }
+        self.update_interests(ops);
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(
+            self.rx_fd.as_ref(),
+            RX_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register rx ioeventfd for vsock muxer");
+        ops.add(Events::with_data(
+            self.tx_fd.as_ref(),
+            TX_QUEUE_IDX as u32,
+            EventSet::IN,
+        ))
+        .expect("Failed to register tx ioeventfd for vsock muxer");
+        ops.add(Events::with_data(
+            self.listener.as_ref(),
+            LISTENER_TOKEN,
+            EventSet::IN,
+        ))
+        .expect("Failed to register unix socket for vsock muxer");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::path::Path;
+    use std::time::Duration;
+    use vm_memory::GuestMemoryMmap;
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+    const GUEST_CID: u64 = 3;
+
+    struct NoSignal;
+
+    impl SignalUsedQueue for NoSignal {
+        fn signal_used_queue(&self, _index: u16) {}
+    }
+
+    type TestMuxer = VsockMuxer<Arc<GuestMemoryMmap>, NoSignal>;
+
+    fn ioevent() -> Arc<IoEvent> {
+        Arc::new(IoEvent::EventFd(
+            EventFd::new(EFD_NONBLOCK).expect("cannot create eventfd"),
+        ))
+    }
+
+    fn muxer(dir: &Path) -> TestMuxer {
+        let mem = Arc::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)])
+                .expect("cannot create guest memory"),
+        );
+        let uds_path = dir.join("vsock");
+        let listener = UnixListener::bind(&uds_path).expect("cannot bind vsock socket");
+        listener
+            .set_nonblocking(true)
+            .expect("cannot make socket non-blocking");
+        VsockMuxer::new(
+            NoSignal,
+            ioevent(),
+            ioevent(),
+            Queue::new(Arc::clone(&mem), 16),
+            Queue::new(mem, 16),
+            GUEST_CID,
+            uds_path,
+            Arc::new(listener),
+            Arc::new(IoCounters::default()),
+        )
+    }
+
+    fn guest_header(op: u16, src_port: u32, dst_port: u32) -> PacketHeader {
+        PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port,
+            dst_port,
+            type_: TYPE_STREAM,
+            op,
+            buf_alloc: CONN_BUF_ALLOC,
+            ..Default::default()
+        }
+    }
+
+    fn packet(mut hdr: PacketHeader, payload: &[u8]) -> Vec<u8> {
+        hdr.len = payload.len() as u32;
+        let mut pkt = hdr.to_bytes().to_vec();
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    fn next_packet(muxer: &mut TestMuxer) -> (PacketHeader, Vec<u8>) {
+        muxer.next_rx_packet(4096).expect("no packet for the guest")
+    }
+
+    fn read_eof(stream: &mut UnixStream) -> bool {
+        let mut buf = [0u8; 16];
+        matches!(stream.read(&mut buf), Ok(0))
+    }
+
+    /// Connects guest port 1000 to the host socket `vsock_1234`, the guest grants `buf_alloc`.
+    fn connect_from_guest(muxer: &mut TestMuxer, dir: &Path, buf_alloc: u32) -> UnixStream {
+        let listener = UnixListener::bind(dir.join("vsock_1234")).expect("cannot bind port");
+        let mut hdr = guest_header(OP_REQUEST, 1000, 1234);
+        hdr.buf_alloc = buf_alloc;
+        muxer.handle_tx_packet(&packet(hdr, b""));
+
+        let (hdr, _) = next_packet(muxer);
+        assert_eq!(hdr.op, OP_RESPONSE);
+        assert_eq!((hdr.src_port, hdr.dst_port), (1234, 1000));
+        let (peer, _) = listener.accept().expect("muxer did not connect");
+        peer.set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("cannot set timeout");
+        peer
+    }
+
+    #[test]
+    fn test_connect_from_host() {
+        let dir = tempfile::tempdir().expect("cannot create tempdir");
+        let mut muxer = muxer(dir.path());
+        let mut host = UnixStream::connect(dir.path().join("vsock")).expect("cannot connect");
+        host.set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("cannot set timeout");
+        host.write_all(b"CONNECT 52\n")
+            .expect("cannot send CONNECT");
+
+        muxer.accept_connections();
+        muxer.handle_conn_event(FIRST_CONN_TOKEN, EventSet::IN);
+        let (hdr, _) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_REQUEST);
+        assert_eq!((hdr.src_port, hdr.dst_port), (FIRST_LOCAL_PORT, 52));
+
+        muxer.handle_tx_packet(&packet(
+            guest_header(OP_RESPONSE, 52, FIRST_LOCAL_PORT),
+            b"",
+        ));
+        let mut line = String::new();
+        BufReader::new(&host)
+            .read_line(&mut line)
+            .expect("cannot read reply");
+        assert_eq!(line, format!("OK {}\n", FIRST_LOCAL_PORT));
+
+        host.write_all(b"ping").expect("cannot send data");
+        muxer.handle_conn_event(FIRST_CONN_TOKEN, EventSet::IN);
+        let (hdr, data) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_RW);
+        assert_eq!(data, b"ping");
+    }
+
+    #[test]
+    fn test_credit_update() {
+        let dir = tempfile::tempdir().expect("cannot create tempdir");
+        let mut muxer = muxer(dir.path());
+        let mut peer = connect_from_guest(&mut muxer, dir.path(), 4);
+
+        peer.write_all(b"abcdefgh").expect("cannot send data");
+        muxer.handle_conn_event(FIRST_CONN_TOKEN, EventSet::IN);
+        let (hdr, data) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_RW);
+        assert_eq!(data, b"abcd");
+        // the guest has no credit left
+        assert!(muxer.next_rx_packet(4096).is_none());
+
+        let mut hdr = guest_header(OP_CREDIT_UPDATE, 1000, 1234);
+        hdr.buf_alloc = 4;
+        hdr.fwd_cnt = 4;
+        muxer.handle_tx_packet(&packet(hdr, b""));
+        let (_, data) = next_packet(&mut muxer);
+        assert_eq!(data, b"efgh");
+
+        muxer.handle_tx_packet(&packet(guest_header(OP_RW, 1000, 1234), b"hello"));
+        muxer.update_connections();
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).expect("cannot read data");
+        assert_eq!(&buf, b"hello");
+
+        muxer.handle_tx_packet(&packet(guest_header(OP_CREDIT_REQUEST, 1000, 1234), b""));
+        let (hdr, _) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_CREDIT_UPDATE);
+        assert_eq!(hdr.buf_alloc, CONN_BUF_ALLOC);
+        assert_eq!(hdr.fwd_cnt, 5);
+    }
+
+    #[test]
+    fn test_peer_shutdown() {
+        let dir = tempfile::tempdir().expect("cannot create tempdir");
+        let mut muxer = muxer(dir.path());
+        let peer = connect_from_guest(&mut muxer, dir.path(), CONN_BUF_ALLOC);
+
+        // the host closes its end
+        drop(peer);
+        muxer.handle_conn_event(FIRST_CONN_TOKEN, EventSet::IN | EventSet::HANG_UP);
+        let (hdr, _) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_SHUTDOWN);
+        assert_eq!(hdr.flags, SHUTDOWN_SEND);
+
+        // the guest closes its end and waits for our reset
+        let mut hdr = guest_header(OP_SHUTDOWN, 1000, 1234);
+        hdr.flags = SHUTDOWN_SEND | SHUTDOWN_RCV;
+        muxer.handle_tx_packet(&packet(hdr, b""));
+        muxer.update_connections();
+        let (hdr, _) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_RST);
+        assert!(muxer.conns.is_empty());
+        assert!(muxer.ports.is_empty());
+    }
+
+    #[test]
+    fn test_guest_shutdown_send() {
+        let dir = tempfile::tempdir().expect("cannot create tempdir");
+        let mut muxer = muxer(dir.path());
+        let mut peer = connect_from_guest(&mut muxer, dir.path(), CONN_BUF_ALLOC);
+
+        let mut hdr = guest_header(OP_SHUTDOWN, 1000, 1234);
+        hdr.flags = SHUTDOWN_SEND;
+        muxer.handle_tx_packet(&packet(hdr, b""));
+        muxer.update_connections();
+        assert!(read_eof(&mut peer));
+        // the host may still send data
+        assert_eq!(muxer.conns.len(), 1);
+        assert!(muxer.next_rx_packet(4096).is_none());
+    }
+
+    #[test]
+    fn test_rst() {
+        let dir = tempfile::tempdir().expect("cannot create tempdir");
+        let mut muxer = muxer(dir.path());
+        let mut peer = connect_from_guest(&mut muxer, dir.path(), CONN_BUF_ALLOC);
+
+        muxer.handle_tx_packet(&packet(guest_header(OP_RST, 1000, 1234), b""));
+        assert!(muxer.conns.is_empty());
+        assert!(read_eof(&mut peer));
+        // a reset is never answered
+        assert!(muxer.next_rx_packet(4096).is_none());
+
+        // packets of unknown connections are
+        muxer.handle_tx_packet(&packet(guest_header(OP_RW, 1000, 1234), b"lost"));
+        let (hdr, _) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_RST);
+        assert_eq!((hdr.src_port, hdr.dst_port), (1234, 1000));
+
+        // so are requests to ports nobody listens on
+        muxer.handle_tx_packet(&packet(guest_header(OP_REQUEST, 1001, 4321), b""));
+        let (hdr, _) = next_packet(&mut muxer);
+        assert_eq!(hdr.op, OP_RST);
+        assert!(muxer.conns.is_empty());
+    }
+
+    #[test]
+    fn test_parse_connect_line() {
+        assert_eq!(parse_connect_line(b"CONNECT 52"), Some(52));
+        assert_eq!(parse_connect_line(b"CONNECT 1234\r"), Some(1234));
+        assert_eq!(parse_connect_line(b"CONNECT"), None);
+        assert_eq!(parse_connect_line(b"CONNECT -1"), None);
+        assert_eq!(parse_connect_line(b"LISTEN 52"), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! The `virtio_vsock_hdr` that precedes every packet on the rx and tx queues.
+
+use std::convert::TryInto;
+
+/// Size of `struct virtio_vsock_hdr`
+pub const HDR_SIZE: usize = 44;
+
+/// The only socket type we support
+pub const TYPE_STREAM: u16 = 1;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_RESPONSE: u16 = 2;
+pub const OP_RST: u16 = 3;
+pub const OP_SHUTDOWN: u16 = 4;
+pub const OP_RW: u16 = 5;
+pub const OP_CREDIT_UPDATE: u16 = 6;
+pub const OP_CREDIT_REQUEST: u16 = 7;
+
+/// The peer will not receive any more data
+pub const SHUTDOWN_RCV: u32 = 1;
+/// The peer will not send any more data
+pub const SHUTDOWN_SEND: u32 = 2;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub type_: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+fn u16_at(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[off..off + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl PacketHeader {
+    /// Parses the header from the start of `buf`. Returns None if the buffer is too short.
+    pub fn parse(buf: &[u8]) -> Option<PacketHeader> {
+        if buf.len() < HDR_SIZE {
+            return None;
+        }
+        Some(PacketHeader {
+            src_cid: u64_at(buf, 0),
+            dst_cid: u64_at(buf, 8),
+            src_port: u32_at(buf, 16),
+            dst_port: u32_at(buf, 20),
+            len: u32_at(buf, 24),
+            type_: u16_at(buf, 28),
+            op: u16_at(buf, 30),
+            flags: u32_at(buf, 32),
+            buf_alloc: u32_at(buf, 36),
+            fwd_cnt: u32_at(buf, 40),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HDR_SIZE] {
+        let mut buf = [0u8; HDR_SIZE];
+        buf[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.len.to_le_bytes());
+        buf[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        buf[30..32].copy_from_slice(&self.op.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        buf
+    }
+
+    /// Payload following the header in `buf`, as far as it is covered by `len`.
+    pub fn payload<'a>(&self, buf: &'a [u8]) -> Option<&'a [u8]> {
+        let len: usize = self.len.try_into().ok()?;
+        buf.get(HDR_SIZE..HDR_SIZE.checked_add(len)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let hdr = PacketHeader {
+            src_cid: 3,
+            dst_cid: 2,
+            src_port: 1234,
+            dst_port: 52,
+            len: 5,
+            type_: TYPE_STREAM,
+            op: OP_RW,
+            flags: 0,
+            buf_alloc: 256 * 1024,
+            fwd_cnt: 42,
+        };
+        let mut buf = hdr.to_bytes().to_vec();
+        buf.extend_from_slice(b"hello");
+        let parsed = PacketHeader::parse(&buf).expect("cannot parse header");
+        assert_eq!(parsed, hdr);
+        assert_eq!(parsed.payload(&buf), Some(&b"hello"[..]));
+
+        // len must not point past the buffer
+        let truncated = &buf[..buf.len() - 1];
+        assert_eq!(parsed.payload(truncated), None);
+        assert_eq!(PacketHeader::parse(&buf[..HDR_SIZE - 1]), None);
+    }
+}
@@ -49,6 +49,8 @@ pub const NT_SIGINFO: Elf_Word = 0x53494749;
 pub const NT_FILE: Elf_Word = 0x46494c45;
 #[cfg(target_arch = "x86_64")]
 pub const NT_PRXFPREG: Elf_Word = 0x46e62b7f;
+/// x86 extended state using xsave
+pub const NT_X86_XSTATE: Elf_Word = 0x202;
 
 // e_version
 pub const EV_NONE: Elf_Word = 0;
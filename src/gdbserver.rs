@@ -0,0 +1,647 @@
+use kvm_bindings as kvmb;
+use log::{debug, info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+
+use crate::cpu::Regs;
+use crate::daemon::bind_private;
+use crate::guest_mem::GuestMem;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::mem::{self, GuestAddress};
+use crate::page_math::page_size;
+use crate::result::Result;
+
+/// Registers of GDB's i386:x86-64 target without target description: rax, rbx, rcx, rdx, rsi,
+/// rdi, rbp, rsp, r8-r15 and rip with 8 bytes, followed by eflags, cs, ss, ds, es, fs and gs
+/// with 4 bytes.
+const REGISTER_COUNT: usize = 24;
+const WIDE_REGISTER_COUNT: usize = 17;
+/// Registers up to eflags can be written with `set_regs`
+const WRITABLE_REGISTER_COUNT: usize = 18;
+
+/// Largest memory read answered in one packet, half of the packet size we announce.
+const MAX_READ: usize = 0x2000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub enum GdbListen {
+    /// reachable by every local user if bound to localhost
+    Tcp(String),
+    /// only accessible by the owner
+    Unix(PathBuf),
+}
+
+pub struct GdbServerOptions {
+    pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub listen: GdbListen,
+    /// writes to registers and memory are refused unless set
+    pub allow_write: bool,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn register_size(idx: usize) -> usize {
+    if idx < WIDE_REGISTER_COUNT {
+        8
+    } else {
+        4
+    }
+}
+
+fn register_values(regs: &Regs, sregs: &kvmb::kvm_sregs) -> [u64; REGISTER_COUNT] {
+    [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rbp,
+        regs.rsp,
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        regs.rip,
+        regs.eflags,
+        sregs.cs.selector as u64,
+        sregs.ss.selector as u64,
+        sregs.ds.selector as u64,
+        sregs.es.selector as u64,
+        sregs.fs.selector as u64,
+        sregs.gs.selector as u64,
+    ]
+}
+
+/// Updates the registers that KVM_SET_REGS can write, starting at register `first`.
+fn set_register_values(regs: &mut Regs, first: usize, values: &[u64]) {
+    for (idx, value) in (first..).zip(values.iter().copied()) {
+        let reg = match idx {
+            0 => &mut regs.rax,
+            1 => &mut regs.rbx,
+            2 => &mut regs.rcx,
+            3 => &mut regs.rdx,
+            4 => &mut regs.rsi,
+            5 => &mut regs.rdi,
+            6 => &mut regs.rbp,
+            7 => &mut regs.rsp,
+            8 => &mut regs.r8,
+            9 => &mut regs.r9,
+            10 => &mut regs.r10,
+            11 => &mut regs.r11,
+            12 => &mut regs.r12,
+            13 => &mut regs.r13,
+            14 => &mut regs.r14,
+            15 => &mut regs.r15,
+            16 => &mut regs.rip,
+            17 => &mut regs.eflags,
+            _ => return,
+        };
+        *reg = value;
+    }
+}
+
+fn encode_registers(values: &[u64]) -> String {
+    values
+        .iter()
+        .enumerate()
+        .map(|(idx, v)| to_hex(&v.to_le_bytes()[..register_size(idx)]))
+        .collect()
+}
+
+/// Decodes the little endian register values of a `G` or `P` packet, starting at register
+/// `first`.
+fn decode_registers(first: usize, bytes: &[u8]) -> Vec<u64> {
+    let mut values = vec![];
+    let mut offset = 0;
+    for idx in first..REGISTER_COUNT {
+        let size = register_size(idx);
+        if offset + size > bytes.len() {
+            break;
+        }
+        let mut value = [0u8; 8];
+        value[..size].copy_from_slice(&bytes[offset..offset + size]);
+        values.push(u64::from_le_bytes(value));
+        offset += size;
+    }
+    values
+}
+
+/// Parses `addr,len` of memory packets.
+fn parse_range(s: &str) -> Result<(usize, usize)> {
+    let (addr, len) = require_with!(s.split_once(','), "invalid memory range '{}'", s);
+    let addr = try_with!(
+        usize::from_str_radix(addr, 16),
+        "invalid address '{}'",
+        addr
+    );
+    let len = try_with!(usize::from_str_radix(len, 16), "invalid length '{}'", len);
+    Ok((addr, len))
+}
+
+enum Packet {
+    Command(String),
+    /// ctrl-c in gdb
+    Interrupt,
+}
+
+/// Packet layer of the GDB remote serial protocol: `$<data>#<checksum>`, acknowledged with `+`
+/// until gdb switches to no-ack mode.
+struct Connection<R, W> {
+    reader: R,
+    writer: W,
+    no_ack: bool,
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0u8];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => bail!("cannot read from gdb: {}", e),
+            }
+        }
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<()> {
+        try_with!(self.writer.write_all(data), "cannot write to gdb");
+        try_with!(self.writer.flush(), "cannot write to gdb");
+        Ok(())
+    }
+
+    /// Returns `None` once gdb closed the connection.
+    fn read_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                // acks and garbage between packets
+                Some(_) => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let sum = match (self.read_byte()?, self.read_byte()?) {
+                (Some(hi), Some(lo)) => {
+                    let digits = [hi, lo];
+                    let digits = std::str::from_utf8(&digits).unwrap_or("");
+                    u8::from_str_radix(digits, 16).ok()
+                }
+                _ => return Ok(None),
+            };
+            if sum != Some(checksum(&data)) {
+                warn!("drop gdb packet with bad checksum");
+                self.send_raw(b"-")?;
+                continue;
+            }
+            if !self.no_ack {
+                self.send_raw(b"+")?;
+            }
+            return Ok(Some(Packet::Command(
+                String::from_utf8_lossy(&data).into_owned(),
+            )));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.send_raw(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => continue,
+                }
+            }
+        }
+    }
+}
+
+enum Action {
+    Reply(String),
+    StartNoAck,
+    Continue,
+    Detach,
+}
+
+/// Protocol state of a stopped VM, vcpus are exposed as threads with id `vcpu index + 1`.
+struct Session<'a> {
+    hv: &'a Hypervisor,
+    /// vcpu selected by `Hg`
+    vcpu: usize,
+    /// page tables and memslots of `vcpu`, reset when it runs again
+    mem: Option<GuestMem>,
+    allow_write: bool,
+}
+
+impl Session<'_> {
+    fn guest_mem(&mut self) -> Result<&GuestMem> {
+        let mem = match self.mem.take() {
+            Some(mem) => mem,
+            None => GuestMem::for_vcpu(self.hv, self.vcpu)?,
+        };
+        Ok(self.mem.insert(mem))
+    }
+
+    fn stop_reply(&self, signal: u8) -> String {
+        format!("T{:02x}thread:{:x};", signal, self.vcpu + 1)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.mem = None;
+        self.hv.resume()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.hv.stop()
+    }
+
+    /// Parses a thread id, `None` for any thread.
+    fn parse_thread(&self, tid: &str) -> Result<Option<usize>> {
+        parse_thread(tid, self.hv.vcpus.len())
+    }
+
+    fn vcpu_registers(&self) -> Result<(Regs, [u64; REGISTER_COUNT])> {
+        let vcpu = &self.hv.vcpus[self.vcpu];
+        let regs = self.hv.get_regs(vcpu)?;
+        let sregs = self.hv.get_sregs(vcpu)?;
+        let values = register_values(&regs, &sregs);
+        Ok((regs, values))
+    }
+
+    fn check_write(&self) -> Result<()> {
+        if !self.allow_write {
+            bail!("writing registers or memory requires --allow-write");
+        }
+        Ok(())
+    }
+
+    fn write_registers(&mut self, first: usize, values: &[u64]) -> Result<()> {
+        if first + values.len() > WRITABLE_REGISTER_COUNT {
+            bail!("only general purpose registers, rip and eflags can be written");
+        }
+        info!(
+            "write {} registers from {} of vcpu {} of hypervisor {}",
+            values.len(),
+            first,
+            self.vcpu,
+            self.hv.pid
+        );
+        let (mut regs, _) = self.vcpu_registers()?;
+        set_register_values(&mut regs, first, values);
+        self.hv.set_regs(&self.hv.vcpus[self.vcpu], &regs)
+    }
+
+    /// Reads as much as possible of the range, gdb accepts partial reads.
+    fn read_memory(&mut self, addr: usize, len: usize) -> Result<Vec<u8>> {
+        let hv = self.hv;
+        let mem = self.guest_mem()?;
+        let mut data = vec![0; std::cmp::min(len, MAX_READ)];
+        let mut done = 0;
+        while done < data.len() {
+            let start = addr.wrapping_add(done);
+            let page_left = page_size() - (start & (page_size() - 1));
+            let end = std::cmp::min(data.len(), done + page_left);
+            let res = mem::read_guest(
+                hv,
+                mem,
+                GuestAddress::Virtual(start),
+                None,
+                &mut data[done..end],
+            );
+            if let Err(e) = res {
+                if done == 0 {
+                    return Err(e);
+                }
+                break;
+            }
+            done = end;
+        }
+        data.truncate(done);
+        Ok(data)
+    }
+
+    fn handle(&mut self, cmd: &str) -> Action {
+        match self.dispatch(cmd) {
+            Ok(action) => action,
+            Err(e) => {
+                warn!("gdb command '{}' failed: {}", cmd, e);
+                Action::Reply("E01".to_string())
+            }
+        }
+    }
+
+    fn dispatch(&mut self, cmd: &str) -> Result<Action> {
+        let (kind, args) = cmd.split_at(std::cmp::min(1, cmd.len()));
+        let reply = match kind {
+            "?" => self.stop_reply(SIGTRAP),
+            "g" => encode_registers(&self.vcpu_registers()?.1),
+            "G" => {
+                self.check_write()?;
+                let values = decode_registers(0, &mem::parse_hex(args)?);
+                let writable = std::cmp::min(values.len(), WRITABLE_REGISTER_COUNT);
+                self.write_registers(0, &values[..writable])?;
+                "OK".to_string()
+            }
+            "p" => {
+                let idx = try_with!(usize::from_str_radix(args, 16), "invalid register");
+                let (_, values) = self.vcpu_registers()?;
+                if idx >= REGISTER_COUNT {
+                    bail!("unknown register {}", idx);
+                }
+                to_hex(&values[idx].to_le_bytes()[..register_size(idx)])
+            }
+            "P" => {
+                self.check_write()?;
+                let (idx, value) = require_with!(args.split_once('='), "invalid P packet");
+                let idx = try_with!(usize::from_str_radix(idx, 16), "invalid register");
+                let values = decode_registers(idx, &mem::parse_hex(value)?);
+                self.write_registers(idx, &values[..std::cmp::min(values.len(), 1)])?;
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, len) = parse_range(args)?;
+                to_hex(&self.read_memory(addr, len)?)
+            }
+            "M" => {
+                self.check_write()?;
+                let (range, data) = require_with!(args.split_once(':'), "invalid M packet");
+                let (addr, len) = parse_range(range)?;
+                let data = mem::parse_hex(data)?;
+                if data.len() != len {
+                    bail!("M packet has {} bytes instead of {}", data.len(), len);
+                }
+                let hv = self.hv;
+                mem::write_guest(
+                    hv,
+                    self.guest_mem()?,
+                    GuestAddress::Virtual(addr),
+                    None,
+                    &data,
+                )?;
+                "OK".to_string()
+            }
+            "H" => {
+                let (op, tid) = args.split_at(std::cmp::min(1, args.len()));
+                if let Some(vcpu) = self.parse_thread(tid)? {
+                    if op == "g" && vcpu != self.vcpu {
+                        self.vcpu = vcpu;
+                        self.mem = None;
+                    }
+                }
+                "OK".to_string()
+            }
+            "T" => {
+                self.parse_thread(args)?;
+                "OK".to_string()
+            }
+            // Breakpoints need KVM_SET_GUEST_DEBUG. An empty reply would make gdb write int3
+            // into guest memory instead, which the guest would trip over after we detach.
+            "Z" | "z" => "E22".to_string(),
+            "c" => return Ok(Action::Continue),
+            "D" | "k" => return Ok(Action::Detach),
+            _ => match cmd {
+                "QStartNoAckMode" => return Ok(Action::StartNoAck),
+                "qAttached" => "1".to_string(),
+                "qC" => format!("QC{:x}", self.vcpu + 1),
+                "qfThreadInfo" => {
+                    let threads = (1..=self.hv.vcpus.len())
+                        .map(|tid| format!("{:x}", tid))
+                        .collect::<Vec<_>>();
+                    format!("m{}", threads.join(","))
+                }
+                "qsThreadInfo" => "l".to_string(),
+                "vCont?" => "vCont;c".to_string(),
+                _ if cmd.starts_with("vCont;c") => return Ok(Action::Continue),
+                _ if cmd.starts_with("qSupported") => {
+                    format!("PacketSize={:x};QStartNoAckMode+", 2 * MAX_READ + 0x100)
+                }
+                // unsupported, e.g. single stepping which needs KVM_SET_GUEST_DEBUG
+                _ => String::new(),
+            },
+        };
+        Ok(Action::Reply(reply))
+    }
+}
+
+/// Parses the thread id `tid` of a vm with `vcpus` vcpus, `None` for any (0) or all (-1) threads.
+/// Threads are numbered from 1.
+fn parse_thread(tid: &str, vcpus: usize) -> Result<Option<usize>> {
+    if tid == "-1" {
+        return Ok(None);
+    }
+    let tid = try_with!(usize::from_str_radix(tid, 16), "invalid thread '{}'", tid);
+    match tid.checked_sub(1) {
+        None => Ok(None),
+        Some(vcpu) if vcpu < vcpus => Ok(Some(vcpu)),
+        Some(_) => bail!("vm has no thread {:x}", tid),
+    }
+}
+
+fn serve<R: BufRead, W: Write>(conn: &mut Connection<R, W>, session: &mut Session) -> Result<()> {
+    loop {
+        let cmd = match conn.read_packet()? {
+            Some(Packet::Command(cmd)) => cmd,
+            // already stopped
+            Some(Packet::Interrupt) => continue,
+            None => {
+                info!("gdb disconnected");
+                return Ok(());
+            }
+        };
+        debug!("gdb: {}", cmd);
+        match session.handle(&cmd) {
+            Action::Reply(reply) => conn.write_packet(&reply)?,
+            Action::StartNoAck => {
+                conn.write_packet("OK")?;
+                conn.no_ack = true;
+            }
+            Action::Continue => {
+                session.resume()?;
+                loop {
+                    match conn.read_packet()? {
+                        Some(Packet::Interrupt) => break,
+                        Some(Packet::Command(cmd)) => {
+                            debug!("ignore gdb command '{}' while the vm runs", cmd)
+                        }
+                        None => {
+                            info!("gdb disconnected");
+                            return Ok(());
+                        }
+                    }
+                }
+                session.stop()?;
+                conn.write_packet(&session.stop_reply(SIGINT))?;
+            }
+            Action::Detach => {
+                conn.write_packet("OK")?;
+                info!("gdb detached");
+                return Ok(());
+            }
+        }
+    }
+}
+
+type Stream = (Box<dyn Read>, Box<dyn Write>);
+
+fn accept(listen: &GdbListen) -> Result<Stream> {
+    match listen {
+        GdbListen::Tcp(addr) => {
+            let listener = try_with!(TcpListener::bind(addr), "cannot listen on {}", addr);
+            info!("waiting for gdb, connect with: target remote {}", addr);
+            let (stream, peer) = try_with!(listener.accept(), "cannot accept gdb connection");
+            info!("gdb connected from {}", peer);
+            try_with!(stream.set_nodelay(true), "cannot set TCP_NODELAY");
+            let reader = try_with!(stream.try_clone(), "cannot clone gdb connection");
+            Ok((Box::new(reader), Box::new(stream)))
+        }
+        GdbListen::Unix(path) => {
+            let listener = try_with!(bind_private(path), "cannot bind {}", path.display());
+            info!(
+                "waiting for gdb, connect with: target remote {}",
+                path.display()
+            );
+            let res = listener.accept();
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("cannot remove {}: {}", path.display(), e);
+            }
+            let (stream, _) = try_with!(res, "cannot accept gdb connection");
+            info!("gdb connected");
+            let reader = try_with!(stream.try_clone(), "cannot clone gdb connection");
+            Ok((Box::new(reader), Box::new(stream)))
+        }
+    }
+}
+
+/// Serves one gdb connection. The VM is stopped while gdb is connected unless gdb continues
+/// it, and runs again once gdb detaches.
+pub fn gdbserver(opts: &GdbServerOptions) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::select_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    let (reader, writer) = accept(&opts.listen)?;
+    vm.stop()?;
+
+    let mut conn = Connection {
+        reader: BufReader::new(reader),
+        writer,
+        no_ack: false,
+    };
+    let mut session = Session {
+        hv: &vm,
+        vcpu: 0,
+        mem: None,
+        allow_write: opts.allow_write,
+    };
+    let res = serve(&mut conn, &mut session);
+    vm.resume()?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn connection(input: &[u8]) -> Connection<Cursor<Vec<u8>>, Vec<u8>> {
+        Connection {
+            reader: Cursor::new(input.to_vec()),
+            writer: vec![],
+            no_ack: false,
+        }
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let mut conn = connection(b"+$qC#b4$g#00$?#3f\x03");
+        match conn.read_packet() {
+            Ok(Some(Packet::Command(cmd))) => assert_eq!(cmd, "qC"),
+            _ => panic!("expected qC"),
+        }
+        // the bad checksum of `g` is rejected
+        match conn.read_packet() {
+            Ok(Some(Packet::Command(cmd))) => assert_eq!(cmd, "?"),
+            _ => panic!("expected ?"),
+        }
+        assert!(matches!(conn.read_packet(), Ok(Some(Packet::Interrupt))));
+        assert!(matches!(conn.read_packet(), Ok(None)));
+        assert_eq!(conn.writer, b"+-+");
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut conn = connection(b"-+");
+        conn.write_packet("OK").expect("cannot write packet");
+        assert_eq!(conn.writer, b"$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut values = [0u64; REGISTER_COUNT];
+        values[0] = 0x1122_3344_5566_7788;
+        values[16] = 0xffff_ffff_8100_0000;
+        values[17] = 0x246;
+        values[18] = 0x10;
+        let hex = encode_registers(&values);
+        assert_eq!(hex.len(), 2 * (17 * 8 + 7 * 4));
+        assert!(hex.starts_with("8877665544332211"));
+        assert_eq!(&hex[2 * 16 * 8..2 * 17 * 8], "00000081ffffffff");
+        assert_eq!(&hex[2 * 17 * 8..2 * 17 * 8 + 16], "4602000010000000");
+
+        let bytes = mem::parse_hex(&hex).expect("invalid hex");
+        assert_eq!(decode_registers(0, &bytes), values.to_vec());
+        assert_eq!(decode_registers(17, &bytes[..4]), vec![0x5566_7788]);
+    }
+
+    #[test]
+    fn test_parse_thread() {
+        assert_eq!(parse_thread("0", 2).ok(), Some(None));
+        assert_eq!(parse_thread("00", 2).ok(), Some(None));
+        assert_eq!(parse_thread("-1", 2).ok(), Some(None));
+        assert_eq!(parse_thread("1", 2).ok(), Some(Some(0)));
+        assert_eq!(parse_thread("2", 2).ok(), Some(Some(1)));
+        assert!(parse_thread("3", 2).is_err());
+        assert!(parse_thread("x", 2).is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_range("ffffffff81000000,40").ok(),
+            Some((0xffff_ffff_8100_0000, 0x40))
+        );
+        assert!(parse_range("1000").is_err());
+    }
+}
@@ -11,14 +11,16 @@ use crate::kvm::hypervisor::memory::PhysMem;
 use crate::kvm::hypervisor::Hypervisor;
 use crate::page_math::huge_page_size;
 use crate::page_table::{
-    self, PageTable, PageTableFlags, PageTableIteratorValue, PhysAddr, VirtMem,
+    self, PageTable, PageTableFlags, PageTableIteratorValue, Paging, PhysAddr, VirtMem,
 };
 use crate::result::Result;
+use crate::tracer::proc::Mapping;
 
 pub struct GuestMem {
     maps: Arc<PhysHostMap>,
     regs: Regs,
-    pml4: PhysAddr,
+    root_table: PhysAddr,
+    paging: Paging,
 }
 
 // x86_64 & linux address to load the Linux kernel too
@@ -27,7 +29,7 @@ const PHYS_ADDR_MASK: u64 = 0xFFFFFFFFFF000;
 // enable PCID support
 const X86_CR4_PCIDE: u64 = 0x00020000;
 
-fn get_page_table_addr(sregs: &kvmb::kvm_sregs) -> usize {
+pub(crate) fn get_page_table_addr(sregs: &kvmb::kvm_sregs) -> usize {
     (if sregs.cr4 & X86_CR4_PCIDE != 0 {
         sregs.cr3 & PHYS_ADDR_MASK
     } else {
@@ -35,6 +37,18 @@ fn get_page_table_addr(sregs: &kvmb::kvm_sregs) -> usize {
     }) as usize
 }
 
+/// Where a guest virtual address is mapped to, see `GuestMem::translate`.
+#[derive(Clone, Debug)]
+pub struct Translation {
+    pub virt_addr: usize,
+    pub phys_addr: usize,
+    /// Address in the hypervisor, `None` if no memslot backs the page, i.e. for MMIO
+    pub host_addr: Option<usize>,
+    pub page_size: usize,
+    /// Flags of the page table entry that maps the page
+    pub flags: PageTableFlags,
+}
+
 /// Contineous physical memory that is mapped virtual contineous
 #[derive(Clone, Debug)]
 pub struct MappedMemory {
@@ -102,8 +116,13 @@ impl PhysHostMap {
         PhysHostMap { memslots: vec }
     }
 
-    pub fn last_range(&self) -> Option<Range<usize>> {
-        self.memslots.last().map(|v| v.0.clone())
+    /// Returns the last range that starts below `addr`.
+    pub fn last_range_below(&self, addr: usize) -> Option<Range<usize>> {
+        self.memslots
+            .iter()
+            .rev()
+            .find(|v| v.0.start < addr)
+            .map(|v| v.0.clone())
     }
 
     pub fn get_range(&self, phys_addr: usize) -> Option<(Range<usize>, isize)> {
@@ -128,44 +147,53 @@ impl PhysHostMap {
 
 impl GuestMem {
     pub fn new(hv: &Hypervisor) -> Result<GuestMem> {
+        GuestMem::for_vcpu(hv, 0)
+    }
+
+    /// Uses the registers and page tables of vcpu `idx`.
+    pub fn for_vcpu(hv: &Hypervisor, idx: usize) -> Result<GuestMem> {
         // We only get maps once. This information could get all if the
         // hypervisor dynamically allocates physical memory. However this is
         // problematic anyway since it could override allocations made by us.
         // To make the design sound we try to allocate memory near the 4 Peta
         // byte limit in the hope that VMs are not getting close to this limit
         // any time soon.
-        let mut mappings = try_with!(hv.get_maps(), "cannot vm memory allocations");
+        let mappings = try_with!(hv.get_maps(), "cannot vm memory allocations");
+        GuestMem::from_maps(hv, idx, mappings)
+    }
+
+    /// Like `for_vcpu`, but with the memslots `mappings`.
+    pub fn from_maps(hv: &Hypervisor, idx: usize, mut mappings: Vec<Mapping>) -> Result<GuestMem> {
         mappings.sort_by_key(|m| m.phys_addr);
 
         let maps =
             Arc::new(PhysHostMap::new(mappings.iter().map(|m| {
                 (m.phys_addr..m.phys_end() - 1, m.phys_to_host_offset())
             })));
-        let first_core = &hv.vcpus[0];
-        let regs = try_with!(hv.get_regs(first_core), "failed to get vcpu registers");
-        let sregs = try_with!(
-            hv.get_sregs(first_core),
-            "failed to get vcpu special registers"
-        );
+        let vcpu = require_with!(hv.vcpus.get(idx), "vm has no vcpu {}", idx);
+        let regs = try_with!(hv.get_regs(vcpu), "failed to get vcpu registers");
+        let sregs = try_with!(hv.get_sregs(vcpu), "failed to get vcpu special registers");
 
         let pt_addr = get_page_table_addr(&sregs);
 
-        debug!("pml4: {:#x}\n", pt_addr);
+        let paging = Paging::from_cr4(sregs.cr4);
+        debug!("root page table ({:?}): {:#x}\n", paging, pt_addr);
 
         let host_offset = require_with!(maps.get(pt_addr), "cannot find page table memory");
 
         Ok(GuestMem {
             maps,
             regs,
-            pml4: PhysAddr {
+            root_table: PhysAddr {
                 value: pt_addr,
                 host_offset,
             },
+            paging,
         })
     }
 
-    pub fn last_memslot_range(&self) -> Option<Range<usize>> {
-        self.maps.last_range()
+    pub fn last_memslot_range_below(&self, addr: usize) -> Option<Range<usize>> {
+        self.maps.last_range_below(addr)
     }
 
     pub fn map_memory(
@@ -174,7 +202,67 @@ impl GuestMem {
         phys_mem: PhysMem<u8>,
         map: &[MappedMemory],
     ) -> Result<VirtMem> {
-        page_table::map_memory(hv, phys_mem, &mut self.pml4, map, &self.maps)
+        page_table::map_memory(hv, phys_mem, &self.root_table, self.paging, map, &self.maps)
+    }
+
+    pub fn paging(&self) -> Paging {
+        self.paging
+    }
+
+    /// Physical address of the root page table of the vcpu
+    pub fn root_table_addr(&self) -> usize {
+        self.root_table.value
+    }
+
+    /// Hypervisor address of `phys_addr` and the number of bytes from there to the end of its
+    /// memslot.
+    pub fn host_range(&self, phys_addr: usize) -> Option<(usize, usize)> {
+        let (range, host_offset) = self.maps.get_range(phys_addr)?;
+        let host_addr = PhysAddr {
+            value: phys_addr,
+            host_offset,
+        }
+        .host_addr();
+        Some((host_addr, range.end - phys_addr + 1))
+    }
+
+    /// Translates `virt_addr` with the page tables of the vcpu or the ones at `cr3`.
+    pub fn translate(
+        &self,
+        hv: &Hypervisor,
+        virt_addr: usize,
+        cr3: Option<usize>,
+    ) -> Result<Translation> {
+        let root = match cr3 {
+            Some(cr3) => {
+                let value = cr3 & PHYS_ADDR_MASK as usize;
+                let host_offset = require_with!(
+                    self.maps.get(value),
+                    "page table at {:#x} is not backed by a memslot",
+                    value
+                );
+                PhysAddr { value, host_offset }
+            }
+            None => self.root_table.clone(),
+        };
+        let e = page_table::walk(hv, &root, self.paging, &self.maps, virt_addr as u64)?;
+        let page_size = e.size() as usize;
+        let phys_addr =
+            (e.entry.addr() as usize & !(page_size - 1)) + (virt_addr & (page_size - 1));
+        let host_addr = self.maps.get(phys_addr).map(|host_offset| {
+            PhysAddr {
+                value: phys_addr,
+                host_offset,
+            }
+            .host_addr()
+        });
+        Ok(Translation {
+            virt_addr,
+            phys_addr,
+            host_addr,
+            page_size,
+            flags: e.entry.flags(),
+        })
     }
 
     pub fn find_kernel_sections(
@@ -187,13 +275,13 @@ impl GuestMem {
             bail!("program stopped in userspace. Linux kernel might be not mapped in thise mode");
         }
 
-        // level/virt_addr is wrong, but does not matter
-        let pml4 = try_with!(
-            PageTable::read(hv, &self.pml4, 0, 0),
-            "cannot read pml4 page table"
+        // virt_addr is wrong, but does not matter
+        let root = try_with!(
+            PageTable::read(hv, &self.root_table, 0, self.paging.root_level()),
+            "cannot read root page table"
         );
 
-        let mut iter = pml4.iter(hv, Arc::clone(&self.maps), range.clone());
+        let mut iter = root.iter(hv, Arc::clone(&self.maps), range.clone());
         let mut sections: Vec<_> = vec![];
 
         let mut largest_gap = 0..0;
@@ -241,6 +329,46 @@ impl GuestMem {
         }
         Ok((sections, largest_gap))
     }
+
+    /// Mappings in `range` that are contiguous in virtual and physical memory and have the same
+    /// protection. Pages that are not backed by a memslot, i.e. device memory, are left out.
+    pub fn virtual_mappings(
+        &self,
+        hv: &Hypervisor,
+        range: Range<usize>,
+    ) -> Result<Vec<MappedMemory>> {
+        let root = try_with!(
+            PageTable::read(hv, &self.root_table, 0, self.paging.root_level()),
+            "cannot read root page table"
+        );
+
+        let mut mappings: Vec<MappedMemory> = vec![];
+        for e in root.iter(hv, Arc::clone(&self.maps), range.clone()) {
+            let entry = try_with!(e, "cannot read page table");
+            let virt_addr = entry.virt_addr as usize;
+            if virt_addr < range.start {
+                continue;
+            }
+            if virt_addr >= range.end {
+                break;
+            }
+            let phys_addr = entry.entry.addr() as usize;
+            let prot = prot_flags(entry.entry.flags());
+            if let Some(last) = mappings.last_mut() {
+                if last.prot == prot
+                    && last.virt_start + last.len == virt_addr
+                    && last.phys_start.value + last.len == phys_addr
+                {
+                    last.len += huge_page_size(entry.level);
+                    continue;
+                }
+            }
+            if let Some(host_offset) = self.maps.get(phys_addr) {
+                mappings.push(mapped_memory(&entry, host_offset));
+            }
+        }
+        Ok(mappings)
+    }
 }
 
 #[cfg(test)]
@@ -255,4 +383,12 @@ mod tests {
         assert_eq!(m.get(11), Some(2));
         assert_eq!(m.get(16), None);
     }
+
+    #[test]
+    fn last_range_below() {
+        let m = PhysHostMap::new(vec![(1..9, 1), (10..15, 2)].into_iter());
+        assert_eq!(m.last_range_below(100), Some(10..15));
+        assert_eq!(m.last_range_below(10), Some(1..9));
+        assert_eq!(m.last_range_below(1), None);
+    }
 }
@@ -1,87 +1,342 @@
 //mod device;
 
 use crate::guest_mem::GuestMem;
-use crate::kernel::find_kernel;
+use crate::kernel::{find_kernel, Kernel};
 use crate::result::Result;
+use kvm_bindings as kvmb;
 use log::*;
+use nix::sys::mman::ProtFlags;
 use nix::unistd::Pid;
-use simple_error::try_with;
+use serde::Serialize;
+use simple_error::{bail, try_with};
+use std::io::{self, Write};
 
+use crate::cpu;
 use crate::kvm;
+use crate::kvm::hypervisor::ioregionfd::IoRegionFd;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::kvm::irq::KVM_IRQCHIP_IOAPIC;
+use crate::tracer::proc::Mapping;
 
 pub struct InspectOptions {
     pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    /// print a json document to stdout instead of logging
+    pub json: bool,
 }
 
-pub fn inspect(opts: &InspectOptions) -> Result<()> {
-    let vm = try_with!(
-        kvm::hypervisor::get_hypervisor(opts.pid),
-        "cannot get vms for process {}",
-        opts.pid
-    );
-    vm.stop()?;
+#[derive(Serialize)]
+pub struct MemslotInfo {
+    pub phys_addr: usize,
+    pub host_addr: usize,
+    pub size: usize,
+    pub prot: String,
+    pub pathname: String,
+}
 
-    for map in vm.get_maps()? {
-        info!(
-            "vm mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
-            map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
-        )
+#[derive(Serialize)]
+pub struct VcpuInfo {
+    pub idx: usize,
+    pub fd: i32,
+    /// address of the mapped `kvm_run` structure in the hypervisor
+    pub kvm_run_addr: Option<usize>,
+    /// only shown in log mode
+    #[serde(skip)]
+    pub kvm_run_map: Option<Mapping>,
+    pub exit_reason: Option<u32>,
+    pub regs: cpu::Regs,
+}
+
+#[derive(Serialize)]
+pub struct SectionInfo {
+    pub virt_start: usize,
+    pub phys_start: usize,
+    pub size: usize,
+    pub prot: String,
+}
+
+#[derive(Serialize)]
+pub struct KernelInfo {
+    pub start: usize,
+    pub end: usize,
+    pub kaslr_slide: isize,
+    pub space_before: usize,
+    pub space_after: usize,
+    pub sections: Vec<SectionInfo>,
+    pub symbols: usize,
+}
+
+#[derive(Serialize)]
+pub struct PicInfo {
+    pub irr: u8,
+    pub imr: u8,
+    pub isr: u8,
+    pub irq_base: u8,
+    pub elcr: u8,
+}
+
+#[derive(Serialize)]
+pub struct IoapicInfo {
+    pub base_address: u64,
+    pub ioregsel: u32,
+    pub id: u32,
+    pub irr: u32,
+    /// raw bits of the redirection table entries
+    pub redirtbl: Vec<u64>,
+}
+
+#[derive(Serialize)]
+pub struct IrqchipInfo {
+    pub pic_master: PicInfo,
+    pub pic_slave: PicInfo,
+    pub ioapic: IoapicInfo,
+}
+
+#[derive(Serialize)]
+pub struct VmReport {
+    pub pid: i32,
+    pub vm_fd: i32,
+    pub memslots: Vec<MemslotInfo>,
+    pub vcpus: Vec<VcpuInfo>,
+    pub kernel: Option<KernelInfo>,
+    pub irqchip: IrqchipInfo,
+    pub ioregionfd: bool,
+}
+
+fn prot_string(prot: ProtFlags) -> String {
+    [
+        (ProtFlags::PROT_READ, 'r'),
+        (ProtFlags::PROT_WRITE, 'w'),
+        (ProtFlags::PROT_EXEC, 'x'),
+    ]
+    .iter()
+    .map(|(flag, c)| if prot.contains(*flag) { *c } else { '-' })
+    .collect()
+}
+
+fn memslot_info(map: &Mapping) -> MemslotInfo {
+    MemslotInfo {
+        phys_addr: map.phys_addr,
+        host_addr: map.start,
+        size: map.size(),
+        prot: prot_string(map.prot_flags),
+        pathname: map.pathname.clone(),
     }
+}
 
-    info!("vcpu maps");
-    for map in vm.get_vcpu_maps()? {
-        info!(
-            "vm cpu mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
-            map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
-        );
+fn vcpu_info(pid: Pid, vm: &Hypervisor, vcpu: &kvm::hypervisor::VCPU) -> Result<VcpuInfo> {
+    let kvm_run_addr = vcpu.vcpu_map.as_ref().map(|map| map.start);
+    let exit_reason = match kvm_run_addr {
+        Some(addr) => {
+            let kvm_run: kvmb::kvm_run =
+                kvm::hypervisor::memory::process_read(pid, addr as *const libc::c_void)?;
+            Some(kvm_run.exit_reason)
+        }
+        None => None,
+    };
+    Ok(VcpuInfo {
+        idx: vcpu.idx,
+        fd: vcpu.fd_num,
+        kvm_run_addr,
+        kvm_run_map: vcpu.vcpu_map.clone(),
+        exit_reason,
+        regs: try_with!(
+            vm.get_regs(vcpu),
+            "cannot get registers of vcpu {}",
+            vcpu.idx
+        ),
+    })
+}
 
-        let map_ptr = map.start as *const kvm_bindings::kvm_run;
-        let kvm_run: kvm_bindings::kvm_run =
-            kvm::hypervisor::memory::process_read(opts.pid, map_ptr as *const libc::c_void)?;
-        info!("kvm_run: exit_reason {}", kvm_run.exit_reason);
+fn kernel_info(kernel: &Kernel) -> KernelInfo {
+    KernelInfo {
+        start: kernel.range.start,
+        end: kernel.range.end,
+        kaslr_slide: kernel.kaslr_slide(),
+        space_before: kernel.space_before(),
+        space_after: kernel.space_after(),
+        sections: kernel
+            .memory_sections
+            .iter()
+            .map(|m| SectionInfo {
+                virt_start: m.virt_start,
+                phys_start: m.phys_start.value,
+                size: m.len,
+                prot: prot_string(m.prot),
+            })
+            .collect(),
+        symbols: kernel.symbols.len(),
+    }
+}
 
-        let reason_ptr: *const u32 = unsafe { &((*map_ptr).exit_reason) };
-        let reason: u32 =
-            kvm::hypervisor::memory::process_read(opts.pid, reason_ptr as *const libc::c_void)?;
-        info!("reason ptr = {:?}", reason_ptr);
-        info!("reason = {}", reason);
+fn pic_info(pic: &kvmb::kvm_pic_state) -> PicInfo {
+    PicInfo {
+        irr: pic.irr,
+        imr: pic.imr,
+        isr: pic.isr,
+        irq_base: pic.irq_base,
+        elcr: pic.elcr,
     }
+}
+
+fn irqchip_info(vm: &Hypervisor) -> Result<IrqchipInfo> {
+    let pic1 = vm.get_irqchip(0)?;
+    let pic2 = vm.get_irqchip(1)?;
+    let ioapic = vm.get_irqchip(KVM_IRQCHIP_IOAPIC)?;
+    let ioa = unsafe { ioapic.chip.ioapic };
+    Ok(IrqchipInfo {
+        pic_master: pic_info(unsafe { &pic1.chip.pic }),
+        pic_slave: pic_info(unsafe { &pic2.chip.pic }),
+        ioapic: IoapicInfo {
+            base_address: ioa.base_address,
+            ioregsel: ioa.ioregsel,
+            id: ioa.id,
+            irr: ioa.irr,
+            redirtbl: ioa.redirtbl.iter().map(|e| unsafe { e.bits }).collect(),
+        },
+    })
+}
+
+fn collect_report(pid: Pid, vm: &Hypervisor) -> Result<VmReport> {
+    let memslots = vm.get_maps()?.iter().map(memslot_info).collect();
+    let vcpus = vm
+        .vcpus
+        .iter()
+        .map(|vcpu| vcpu_info(pid, vm, vcpu))
+        .collect::<Result<Vec<_>>>()?;
 
-    let mem = GuestMem::new(&vm)?;
+    let mem = GuestMem::new(vm)?;
+    let kernel = match find_kernel(&mem, vm) {
+        Ok(kernel) => Some(kernel_info(&kernel)),
+        Err(e) => {
+            info!("could not find kernel: {}", e);
+            None
+        }
+    };
+
+    Ok(VmReport {
+        pid: pid.as_raw(),
+        vm_fd: vm.vm_fd,
+        memslots,
+        vcpus,
+        kernel,
+        irqchip: irqchip_info(vm)?,
+        ioregionfd: IoRegionFd::capability_present(vm)?,
+    })
+}
+
+fn log_report(report: &VmReport) {
+    for slot in &report.memslots {
+        info!(
+            "vm mem: {:#x} -> {:#x} (physical: {:#x}, {}) @@ {}",
+            slot.host_addr,
+            slot.host_addr + slot.size,
+            slot.phys_addr,
+            slot.prot,
+            slot.pathname
+        )
+    }
 
-    match find_kernel(&mem, &vm) {
-        Ok(kernel) => {
-            let sections = &kernel.memory_sections;
+    info!("vcpu maps");
+    for vcpu in &report.vcpus {
+        if let Some(map) = &vcpu.kvm_run_map {
             info!(
-                "found kernel at {:#x}-{:#x} (free space before: {} kib, free space after: {} kib)",
-                kernel.range.start,
-                kernel.range.end,
-                kernel.space_before() / 1024,
-                kernel.space_after() / 1024,
+                "vm cpu mem: {:#x} -> {:#x} (physical: {:#x}, flags: {:?} | {:?}) @@ {}",
+                map.start, map.end, map.phys_addr, map.prot_flags, map.map_flags, map.pathname
             );
-            info!("kernel sections:");
-            for m in sections {
-                info!("{:#x} ({}kb, {:?})", m.virt_start, m.len / 1024, m.prot)
-            }
-            info!("{} found kernel symbols", kernel.symbols.len());
         }
-        Err(e) => info!("could not find kernel: {}", e),
+        match (vcpu.kvm_run_addr, vcpu.exit_reason) {
+            (Some(addr), Some(reason)) => info!(
+                "vcpu {} (fd {}): kvm_run at {:#x}, exit_reason {}",
+                vcpu.idx, vcpu.fd, addr, reason
+            ),
+            _ => info!("vcpu {} (fd {}): kvm_run not mapped", vcpu.idx, vcpu.fd),
+        }
+        debug!("vcpu {} regs: {:?}", vcpu.idx, vcpu.regs);
     }
 
-    let pic1 = vm.get_irqchip(0)?;
-    info!("pic1: {:?}", unsafe { pic1.chip.pic });
-    let pic2 = vm.get_irqchip(1)?;
-    info!("pic2: {:?}", unsafe { pic2.chip.pic });
-    let ioapic = vm.get_irqchip(2)?;
-    let ioa = unsafe { ioapic.chip.ioapic };
+    if let Some(kernel) = &report.kernel {
+        info!(
+            "found kernel at {:#x}-{:#x} (free space before: {} kib, free space after: {} kib, kaslr slide: {:#x})",
+            kernel.start,
+            kernel.end,
+            kernel.space_before / 1024,
+            kernel.space_after / 1024,
+            kernel.kaslr_slide,
+        );
+        info!("kernel sections:");
+        for m in &kernel.sections {
+            info!("{:#x} ({}kb, {})", m.virt_start, m.size / 1024, m.prot)
+        }
+        info!("{} found kernel symbols", kernel.symbols);
+    }
+
+    let irqchip = &report.irqchip;
+    for (name, pic) in &[("pic1", &irqchip.pic_master), ("pic2", &irqchip.pic_slave)] {
+        info!(
+            "{}: irr={:x} imr={:x} isr={:x} irq_base={:x} elcr={:x}",
+            name, pic.irr, pic.imr, pic.isr, pic.irq_base, pic.elcr
+        );
+    }
+    let ioa = &irqchip.ioapic;
     info!(
         "ioapic: base_address={:x} ioregsel={:x} id={:x} irr={:x}",
         ioa.base_address, ioa.ioregsel, ioa.id, ioa.irr
     );
     // this is quite verbose
-    //for (i, field) in ioa.redirtbl.iter().enumerate() {
-    //    info!("ioapic[{}]=bits={:x}: fields={:?}", i, unsafe { field.bits }, unsafe { field.fields });
-    //}
+    for (i, bits) in ioa.redirtbl.iter().enumerate() {
+        debug!("ioapic[{}]=bits={:x}", i, bits);
+    }
+    info!("ioregionfd supported: {}", report.ioregionfd);
+}
+
+/// Collects what `inspect` shows about a VM of the hypervisor `pid`.
+pub fn report(pid: Pid, vm: Option<VmSelector>) -> Result<VmReport> {
+    let vm = try_with!(
+        kvm::hypervisor::select_hypervisor(pid, vm),
+        "cannot get vms for process {}",
+        pid
+    );
+    vm.stop()?;
+    collect_report(pid, &vm)
+}
+
+pub fn inspect(opts: &InspectOptions) -> Result<()> {
+    let vms = try_with!(
+        kvm::hypervisor::list_vms(opts.pid),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    for (idx, vm) in vms.iter().enumerate() {
+        let vcpus = vm
+            .vcpus
+            .iter()
+            .map(|vcpu| format!("{} (fd {})", vcpu.idx, vcpu.fd_num))
+            .collect::<Vec<_>>();
+        info!("vm {}: fd {}, vcpus: {}", idx, vm.vm_fd, vcpus.join(", "));
+    }
+    if vms.len() > 1 && opts.vm.is_none() {
+        if opts.json {
+            bail!("found {} vms, select one with --vm", vms.len());
+        }
+        info!("select a vm to inspect it in detail");
+        return Ok(());
+    }
+
+    let report = report(opts.pid, opts.vm)?;
+
+    if opts.json {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        try_with!(
+            serde_json::to_writer(&mut out, &report),
+            "cannot serialize inspect report"
+        );
+        try_with!(writeln!(out), "cannot write inspect report");
+    } else {
+        log_report(&report);
+    }
 
     Ok(())
 }
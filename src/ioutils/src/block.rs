@@ -0,0 +1,14 @@
+//! Conventions shared between vmsh and stage2 to tell apart multiple block devices.
+
+/// Serial of the n-th block device as found in /sys/block/*/serial.
+/// The first block device is the root of the attached environment, unless a host directory
+/// is shared instead (see `share`), in that case serials start at 1.
+pub fn serial(idx: usize) -> String {
+    format!("vmsh{}", idx)
+}
+
+/// Environment variable that holds the mountpoint of the n-th block device
+/// relative to /var/lib/vmsh. Not set for the first block device.
+pub fn mount_env(idx: usize) -> String {
+    format!("VMSH_MOUNT_{}", idx)
+}
@@ -0,0 +1,183 @@
+//! Framing used by `vmsh exec` to carry the output and the exit status of a
+//! guest command over the single vmsh console.
+//!
+//! Every frame starts with a one byte frame kind followed by the payload length
+//! as little endian u32.
+
+use std::io::{self, Write};
+
+/// Set in the environment of stage2 if the command output should be framed.
+pub const EXEC_ENV: &str = "VMSH_EXEC";
+
+const HEADER_SIZE: usize = 5;
+/// Larger payloads are split into several frames. A header announcing more is garbage, not a
+/// frame we should wait for.
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum FrameKind {
+    Stdout = 1,
+    Stderr = 2,
+    /// payload is the exit code as little endian i32
+    Exit = 3,
+}
+
+impl FrameKind {
+    fn from_u8(kind: u8) -> Option<FrameKind> {
+        match kind {
+            1 => Some(FrameKind::Stdout),
+            2 => Some(FrameKind::Stderr),
+            3 => Some(FrameKind::Exit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exit(i32),
+}
+
+pub fn write_frame<W: Write>(out: &mut W, kind: FrameKind, payload: &[u8]) -> io::Result<()> {
+    for chunk in payload.chunks(MAX_PAYLOAD_SIZE) {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0] = kind as u8;
+        header[1..].copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+        let mut frame = header.to_vec();
+        frame.extend_from_slice(chunk);
+        // one write, so frames of different writers do not interleave
+        out.write_all(&frame)?;
+    }
+    out.flush()
+}
+
+pub fn write_exit<W: Write>(out: &mut W, code: i32) -> io::Result<()> {
+    write_frame(out, FrameKind::Exit, &code.to_le_bytes())
+}
+
+/// Reassembles frames from a byte stream that might be split at arbitrary positions.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame or None if more data is needed. After an error the stream
+    /// cannot be resynchronized.
+    pub fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let kind = match FrameKind::from_u8(self.buf[0]) {
+            Some(kind) => kind,
+            None => {
+                let invalid = self.buf[0];
+                // we cannot resynchronize with the stream, so drop what we have
+                self.buf.clear();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid frame kind: {}", invalid),
+                ));
+            }
+        };
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&self.buf[1..HEADER_SIZE]);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            self.buf.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame is too large: {} bytes", len),
+            ));
+        }
+        let end = HEADER_SIZE + len;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let payload = self.buf[HEADER_SIZE..end].to_vec();
+        self.buf.drain(..end);
+
+        Ok(Some(match kind {
+            FrameKind::Stdout => Frame::Stdout(payload),
+            FrameKind::Stderr => Frame::Stderr(payload),
+            FrameKind::Exit => {
+                if payload.len() != 4 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("exit frame has invalid size: {}", payload.len()),
+                    ));
+                }
+                let mut code = [0u8; 4];
+                code.copy_from_slice(&payload);
+                Frame::Exit(i32::from_le_bytes(code))
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_frames() {
+        let mut stream = vec![];
+        write_frame(&mut stream, FrameKind::Stdout, b"out\n").unwrap();
+        write_frame(&mut stream, FrameKind::Stderr, b"err\n").unwrap();
+        write_exit(&mut stream, 42).unwrap();
+
+        let mut decoder = FrameDecoder::default();
+        let mut frames = vec![];
+        for byte in stream {
+            decoder.push(&[byte]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Stdout(b"out\n".to_vec()),
+                Frame::Stderr(b"err\n".to_vec()),
+                Frame::Exit(42)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_frame() {
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&[0xff, 0, 0, 0, 0]);
+        assert!(decoder.next_frame().is_err());
+        assert_eq!(decoder.next_frame().unwrap(), None);
+
+        // text that looks like a header with a huge length
+        decoder.push(b"\x01dmesg:\n");
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_large_payload() {
+        let payload = vec![b'x'; MAX_PAYLOAD_SIZE + 1];
+        let mut stream = vec![];
+        write_frame(&mut stream, FrameKind::Stderr, &payload).unwrap();
+
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&stream);
+        let mut received = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            match frame {
+                Frame::Stderr(data) => received.extend_from_slice(&data),
+                f => panic!("unexpected frame {:?}", f),
+            }
+        }
+        assert_eq!(received, payload);
+    }
+}
@@ -1 +1,4 @@
+pub mod block;
+pub mod exec;
+pub mod share;
 pub mod tmp;
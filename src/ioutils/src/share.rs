@@ -0,0 +1,8 @@
+//! Conventions shared between vmsh and stage2 to mount a host directory shared over 9p.
+
+/// Mount tag of the shared directory.
+pub const SHARE_TAG: &str = "vmsh";
+
+/// Set in the environment of stage2 to the mount tag if a host directory replaces the root block
+/// device.
+pub const SHARE_ENV: &str = "VMSH_SHARE";
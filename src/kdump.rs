@@ -0,0 +1,332 @@
+//! Writer for the kdump-compressed format of makedumpfile, as read by `crash`.
+//!
+//! The file consists of a header block, a sub header with the VMCOREINFO and the ELF notes, two
+//! bitmaps of all page frames (the first marks the ones that exist, the second the ones that
+//! were dumped), one descriptor per dumped page and finally the page data. Zero pages are not
+//! dumped.
+#![allow(non_camel_case_types)]
+use libc::timeval;
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::coredump::{
+    any_as_bytes, is_zero, read_hypervisor, Compression, DUMP_CHUNK_SIZE, ZSTD_LEVEL,
+};
+use crate::page_math::page_size;
+use crate::result::Result;
+use crate::tracer::proc::Mapping;
+use crate::vmcoreinfo::VmcoreInfo;
+
+const KDUMP_SIGNATURE: [u8; 8] = *b"KDUMP   ";
+const KDUMP_HEADER_VERSION: i32 = 6;
+/// Page is compressed with zstd, also set in the header if any page may be.
+const DUMP_DH_COMPRESSED_ZSTD: u32 = 0x20;
+/// makedumpfile dump level that excludes zero pages
+const DUMP_LEVEL_EXCLUDE_ZERO: i32 = 1;
+const NEW_UTS_LEN: usize = 65;
+/// Number of page descriptors that are buffered before writing them.
+const DESC_BATCH: usize = 4096;
+
+#[repr(C)]
+struct new_utsname {
+    sysname: [u8; NEW_UTS_LEN],
+    nodename: [u8; NEW_UTS_LEN],
+    release: [u8; NEW_UTS_LEN],
+    version: [u8; NEW_UTS_LEN],
+    machine: [u8; NEW_UTS_LEN],
+    domainname: [u8; NEW_UTS_LEN],
+}
+
+#[repr(C)]
+struct disk_dump_header {
+    signature: [u8; 8],
+    header_version: i32,
+    utsname: new_utsname,
+    timestamp: timeval,
+    status: u32,
+    block_size: i32,
+    sub_hdr_size: i32,
+    bitmap_blocks: u32,
+    max_mapnr: u32,
+    total_ram_blocks: u32,
+    device_blocks: u32,
+    written_blocks: u32,
+    current_cpu: u32,
+    nr_cpus: i32,
+    // followed by `nr_cpus` task_struct pointers, we leave them zero
+}
+
+#[repr(C)]
+struct kdump_sub_header {
+    phys_base: u64,
+    dump_level: i32,
+    split: i32,
+    start_pfn: u64,
+    end_pfn: u64,
+    offset_vmcoreinfo: i64,
+    size_vmcoreinfo: u64,
+    offset_note: i64,
+    size_note: u64,
+    offset_eraseinfo: i64,
+    size_eraseinfo: u64,
+    start_pfn_64: u64,
+    end_pfn_64: u64,
+    max_mapnr_64: u64,
+}
+
+#[repr(C)]
+struct page_desc {
+    offset: i64,
+    size: u32,
+    flags: u32,
+    page_flags: u64,
+}
+
+fn uts_field(s: &str) -> [u8; NEW_UTS_LEN] {
+    let mut field = [0; NEW_UTS_LEN];
+    let len = min(s.len(), NEW_UTS_LEN - 1);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+    field
+}
+
+fn blocks(len: usize, block_size: usize) -> usize {
+    (len + block_size - 1) / block_size
+}
+
+fn set_bit(bitmap: &mut [u8], pfn: usize) {
+    bitmap[pfn / 8] |= 1 << (pfn % 8);
+}
+
+/// Bitmap of all page frames that are backed by a memslot.
+fn valid_bitmap(maps: &[Mapping], max_mapnr: usize) -> Vec<u8> {
+    let mut bitmap = vec![0; blocks(max_mapnr, 8)];
+    for m in maps {
+        let start = m.phys_addr / page_size();
+        for pfn in start..start + m.size() / page_size() {
+            set_bit(&mut bitmap, pfn);
+        }
+    }
+    bitmap
+}
+
+struct PageWriter<'a> {
+    file: &'a File,
+    compressor: Option<zstd::bulk::Compressor<'static>>,
+    descs: Vec<u8>,
+    desc_offset: u64,
+    data_offset: u64,
+}
+
+impl PageWriter<'_> {
+    fn write_page(&mut self, page: &[u8]) -> Result<()> {
+        let compressed = match &mut self.compressor {
+            Some(c) => Some(try_with!(c.compress(page), "cannot compress page")),
+            None => None,
+        };
+        // pages that do not get smaller are stored as they are
+        let (data, flags) = match &compressed {
+            Some(c) if c.len() < page.len() => (c.as_slice(), DUMP_DH_COMPRESSED_ZSTD),
+            _ => (page, 0),
+        };
+        try_with!(
+            self.file.write_all_at(data, self.data_offset),
+            "cannot write page"
+        );
+        let desc = page_desc {
+            offset: self.data_offset as i64,
+            size: data.len() as u32,
+            flags,
+            page_flags: 0,
+        };
+        self.descs.extend_from_slice(unsafe { any_as_bytes(&desc) });
+        self.data_offset += data.len() as u64;
+        if self.descs.len() >= DESC_BATCH * size_of::<page_desc>() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        try_with!(
+            self.file.write_all_at(&self.descs, self.desc_offset),
+            "cannot write page descriptors"
+        );
+        self.desc_offset += self.descs.len() as u64;
+        self.descs.clear();
+        Ok(())
+    }
+}
+
+/// Writes guest memory in `maps` to `file` in the kdump-compressed format. `notes` are the ELF
+/// notes with the vcpu registers, like in the PT_NOTE segment of the ELF coredump.
+pub fn write_kdump(
+    pid: Pid,
+    file: &File,
+    maps: &[Mapping],
+    notes: &[u8],
+    nr_cpus: usize,
+    vmcoreinfo: Option<&VmcoreInfo>,
+    compression: Compression,
+) -> Result<()> {
+    let block_size = page_size();
+    if size_of::<disk_dump_header>() + nr_cpus * size_of::<usize>() > block_size {
+        bail!("kdump header has no space for {} vcpus", nr_cpus);
+    }
+    let note = vmcoreinfo.map(|info| info.to_note()).unwrap_or_default();
+
+    let max_mapnr = maps
+        .iter()
+        .map(|m| (m.phys_addr + m.size()) / page_size())
+        .max()
+        .unwrap_or(0);
+    let mut valid = valid_bitmap(maps, max_mapnr);
+    let bitmap_len = blocks(valid.len(), block_size) * block_size;
+    valid.resize(bitmap_len, 0);
+    let mut dumped = vec![0; bitmap_len];
+
+    let sub_hdr_len = size_of::<kdump_sub_header>() + note.len() + notes.len();
+    let sub_hdr_size = blocks(sub_hdr_len, block_size);
+    let bitmap_blocks = 2 * bitmap_len / block_size;
+    let bitmap_offset = (1 + sub_hdr_size) * block_size;
+    let desc_offset = bitmap_offset + bitmap_blocks * block_size;
+    // reserve descriptors for all pages, only the ones of non-zero pages are written
+    let pages = maps.iter().map(|m| m.size() / page_size()).sum::<usize>();
+    let data_offset = desc_offset + blocks(pages * size_of::<page_desc>(), block_size) * block_size;
+
+    let compressor = match compression {
+        Compression::None => None,
+        Compression::Zstd => Some(try_with!(
+            zstd::bulk::Compressor::new(ZSTD_LEVEL),
+            "cannot create zstd compressor"
+        )),
+    };
+    let mut writer = PageWriter {
+        file,
+        compressor,
+        descs: vec![],
+        desc_offset: desc_offset as u64,
+        data_offset: data_offset as u64,
+    };
+    let mut buf = vec![0; DUMP_CHUNK_SIZE];
+    // crash looks up descriptors in the order of page frames
+    let mut sorted = maps.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|m| m.phys_addr);
+    for m in sorted {
+        let mut offset = 0;
+        while offset < m.size() {
+            let len = min(buf.len(), m.size() - offset);
+            read_hypervisor(pid, m.start + offset, &mut buf[..len])?;
+            for (i, page) in buf[..len].chunks(page_size()).enumerate() {
+                if is_zero(page) {
+                    continue;
+                }
+                set_bit(&mut dumped, (m.phys_addr + offset) / page_size() + i);
+                writer.write_page(page)?;
+            }
+            offset += len;
+        }
+    }
+    writer.flush()?;
+
+    let now = try_with!(
+        SystemTime::now().duration_since(UNIX_EPOCH),
+        "cannot get time"
+    );
+    let release = vmcoreinfo
+        .and_then(|info| info.osrelease.as_deref())
+        .unwrap_or("");
+    let header = disk_dump_header {
+        signature: KDUMP_SIGNATURE,
+        header_version: KDUMP_HEADER_VERSION,
+        utsname: new_utsname {
+            sysname: uts_field("Linux"),
+            nodename: uts_field(""),
+            release: uts_field(release),
+            version: uts_field(""),
+            machine: uts_field("x86_64"),
+            domainname: uts_field(""),
+        },
+        timestamp: timeval {
+            tv_sec: now.as_secs() as libc::time_t,
+            tv_usec: now.subsec_micros() as libc::suseconds_t,
+        },
+        status: match compression {
+            Compression::None => 0,
+            Compression::Zstd => DUMP_DH_COMPRESSED_ZSTD,
+        },
+        block_size: block_size as i32,
+        sub_hdr_size: sub_hdr_size as i32,
+        bitmap_blocks: bitmap_blocks as u32,
+        max_mapnr: u32::try_from(max_mapnr).unwrap_or(u32::MAX),
+        total_ram_blocks: 0,
+        device_blocks: 0,
+        written_blocks: 0,
+        current_cpu: 0,
+        nr_cpus: nr_cpus as i32,
+    };
+    let vmcoreinfo_offset = block_size + size_of::<kdump_sub_header>();
+    let sub_header = kdump_sub_header {
+        phys_base: vmcoreinfo.map_or(0, |info| info.phys_base) as u64,
+        dump_level: DUMP_LEVEL_EXCLUDE_ZERO,
+        split: 0,
+        start_pfn: 0,
+        end_pfn: u32::try_from(max_mapnr).unwrap_or(u32::MAX) as u64,
+        offset_vmcoreinfo: vmcoreinfo_offset as i64,
+        size_vmcoreinfo: note.len() as u64,
+        offset_note: (vmcoreinfo_offset + note.len()) as i64,
+        size_note: notes.len() as u64,
+        offset_eraseinfo: 0,
+        size_eraseinfo: 0,
+        start_pfn_64: 0,
+        end_pfn_64: max_mapnr as u64,
+        max_mapnr_64: max_mapnr as u64,
+    };
+
+    let parts: [(&[u8], usize); 6] = [
+        (unsafe { any_as_bytes(&header) }, 0),
+        (unsafe { any_as_bytes(&sub_header) }, block_size),
+        (note.as_bytes(), vmcoreinfo_offset),
+        (notes, vmcoreinfo_offset + note.len()),
+        (&valid, bitmap_offset),
+        (&dumped, bitmap_offset + bitmap_len),
+    ];
+    for (data, offset) in parts.iter() {
+        try_with!(
+            file.write_all_at(data, *offset as u64),
+            "cannot write kdump header"
+        );
+    }
+    try_with!(
+        file.set_len(writer.data_offset),
+        "cannot truncate core file"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        // offsets of makedumpfile's structs on x86_64
+        assert_eq!(size_of::<new_utsname>(), 390);
+        assert_eq!(size_of::<disk_dump_header>(), 464);
+        assert_eq!(size_of::<kdump_sub_header>(), 104);
+        assert_eq!(size_of::<page_desc>(), 24);
+    }
+
+    #[test]
+    fn test_set_bit() {
+        let mut bitmap = vec![0; 2];
+        set_bit(&mut bitmap, 0);
+        set_bit(&mut bitmap, 9);
+        assert_eq!(bitmap, vec![0b1, 0b10]);
+    }
+}
@@ -13,6 +13,8 @@ use crate::result::Result;
 
 /// Kernel range on x86_64
 pub const LINUX_KERNEL_KASLR_RANGE: Range<usize> = 0xFFFFFFFF80000000..0xFFFFFFFFC0000000;
+/// Kernel start on x86_64 without KASLR (__START_KERNEL_map + CONFIG_PHYSICAL_START)
+pub const LINUX_KERNEL_DEFAULT_START: usize = 0xFFFFFFFF81000000;
 
 fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     haystack
@@ -299,6 +301,10 @@ impl Kernel {
     pub fn space_after(&self) -> usize {
         LINUX_KERNEL_KASLR_RANGE.end - self.range.end
     }
+    /// Offset of the kernel to its default location, assuming the default physical start
+    pub fn kaslr_slide(&self) -> isize {
+        self.range.start as isize - LINUX_KERNEL_DEFAULT_START as isize
+    }
 }
 
 pub fn find_kernel(guest_mem: &GuestMem, hv: &Hypervisor) -> Result<Kernel> {
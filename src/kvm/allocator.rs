@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::sync::Arc;
 
 use crate::{
@@ -6,6 +7,7 @@ use crate::{
 };
 use log::debug;
 use nix::sys::mman::ProtFlags;
+use serde::{Deserialize, Serialize};
 use simple_error::{bail, require_with, try_with};
 use vm_device::bus::{MmioAddress, MmioRange};
 
@@ -20,6 +22,18 @@ pub struct PhysMemAllocator {
     /// Physical address where we last allocated memory from.
     /// After an allocating we substract the allocation size from this value.
     next_allocation: usize,
+    /// MMIO ranges handed out so far
+    mmio_ranges: Vec<MmioRange>,
+    /// MMIO ranges of an earlier vmsh process, handed out again before allocating new ones
+    reused_mmio_ranges: VecDeque<MmioRange>,
+}
+
+/// What a detached vmsh process allocated, so that the next one can continue from there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllocatorState {
+    next_allocation: usize,
+    /// start and size of each MMIO range in the order they were allocated
+    mmio_ranges: Vec<(u64, u64)>,
 }
 
 const EXTEND_CPU_INFO_FUNCTION: u32 = 0x80000001;
@@ -79,8 +93,8 @@ fn get_first_allocation(hv: &Arc<Hypervisor>) -> Result<usize> {
         "vm/cpu: phys_bits: {}, virt_bits: {}",
         vm_phys_bits, vm_virt_bits
     );
-    // we only implement 4-Level paging for now
-    if vm_virt_bits != 48 {
+    // 4-level or 5-level paging, the one used by the guest is read from CR4 in `GuestMem`
+    if vm_virt_bits != 48 && vm_virt_bits != 57 {
         bail!(
             "VM cpu uses {} bits for virtual addresses. This is unsupported at the moment",
             vm_virt_bits
@@ -120,13 +134,48 @@ impl PhysMemAllocator {
             guest_mem,
             next_allocation,
             //next_allocation: 0xd0000000 + 0x1000 * 2,
+            mmio_ranges: vec![],
+            reused_mmio_ranges: VecDeque::new(),
         })
     }
 
+    /// Continues where the allocator of a detached vmsh process stopped. Its memory is still
+    /// attached to the VM and its MMIO ranges are handed out again in the same order.
+    pub fn resume(hv: Arc<Hypervisor>, state: &AllocatorState) -> Result<Self> {
+        let mut allocator = Self::new(hv)?;
+        if state.next_allocation > allocator.next_allocation {
+            bail!(
+                "previous allocation at {:#x} is outside of the physical address space",
+                state.next_allocation
+            );
+        }
+        allocator.next_allocation = state.next_allocation;
+        for (start, size) in &state.mmio_ranges {
+            allocator.reused_mmio_ranges.push_back(try_with!(
+                MmioRange::new(MmioAddress(*start), *size),
+                "invalid mmio range"
+            ));
+        }
+        Ok(allocator)
+    }
+
+    pub fn state(&self) -> AllocatorState {
+        AllocatorState {
+            next_allocation: self.next_allocation,
+            mmio_ranges: self
+                .mmio_ranges
+                .iter()
+                .map(|r| (r.base().0, r.size()))
+                .collect(),
+        }
+    }
+
     fn next_addr(&mut self, size: usize) -> Result<usize> {
         let start = require_with!(self.next_allocation.checked_sub(size), "out of memory");
+        // memory above was allocated by us, possibly by a detached vmsh process
         let last_range = require_with!(
-            self.guest_mem.last_memslot_range(),
+            self.guest_mem
+                .last_memslot_range_below(self.next_allocation),
             "vm has no memory assigned"
         );
         let last_alloc = last_range.end;
@@ -179,10 +228,27 @@ impl PhysMemAllocator {
     }
 
     pub fn alloc_mmio_range(&mut self, size: usize) -> Result<MmioRange> {
-        let start = self.next_addr(size)?;
-        Ok(try_with!(
-            MmioRange::new(MmioAddress(start as u64), size as u64),
-            "failed to allocate mmio range"
-        ))
+        let range = match self.reused_mmio_ranges.pop_front() {
+            Some(range) => {
+                if range.size() != size as u64 {
+                    bail!(
+                        "cannot reuse mmio range at {:#x} of size {:#x} for size {:#x}",
+                        range.base().0,
+                        range.size(),
+                        size
+                    );
+                }
+                range
+            }
+            None => {
+                let start = self.next_addr(size)?;
+                try_with!(
+                    MmioRange::new(MmioAddress(start as u64), size as u64),
+                    "failed to allocate mmio range"
+                )
+            }
+        };
+        self.mmio_ranges.push(range);
+        Ok(range)
     }
 }
@@ -4,12 +4,14 @@ use crate::tracer::inject_syscall;
 use kvm_bindings as kvmb;
 use libc::c_int;
 use log::*;
+use nix::errno::Errno;
 use nix::unistd::Pid;
 use simple_error::{bail, require_with, simple_error, try_with};
 use std::ffi::OsStr;
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
@@ -18,12 +20,17 @@ use super::ioregionfd::IoRegionFd;
 use super::memory::*;
 use crate::kvm::fd_transfer;
 use crate::kvm::ioctls;
-use crate::kvm::tracee::{kvm_msrs, Tracee};
+use crate::kvm::layout;
+use crate::kvm::memslots::{MemSlot, MemSlotReader, Memslots};
+use crate::kvm::tracee::{kvm_msrs, Tracee, MAX_MSR_ENTRIES};
 use crate::page_math::{self, compute_host_offset};
 use crate::result::Result;
 use crate::tracer::proc::{openpid, Mapping, PidHandle};
 use crate::tracer::wrap_syscall::KvmRunWrapper;
 
+/// How many slot ids `vm_add_mem` tries before giving up.
+const MAX_SLOT_PROBES: u32 = 64;
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 pub struct VCPU {
@@ -38,9 +45,16 @@ impl VCPU {
     pub fn match_maps(vcpus: &mut Vec<VCPU>, vcpu_maps: &[Mapping]) {
         for vcpu in vcpus {
             let name = format!("{}{}", VCPUFD_INODE_NAME_STARTS_WITH, vcpu.idx);
-            match vcpu_maps.iter().find(|map| map.pathname == name) {
-                Some(map) => vcpu.vcpu_map = Some(map.clone()),
-                None => warn!(
+            let mut maps = vcpu_maps.iter().filter(|map| map.pathname == name);
+            match (maps.next(), maps.next()) {
+                (Some(map), None) => vcpu.vcpu_map = Some(map.clone()),
+                // The maps are only named after the vcpu id,
+                // which is ambiguous if the process runs multiple VMs.
+                (Some(_), Some(_)) => warn!(
+                    "cannot tell apart the mapped memory of vcpu fd {} from other VMs ({})",
+                    vcpu.fd_num, name
+                ),
+                (None, _) => warn!(
                     "no mapped memory of vcpu fd {} found called {}",
                     vcpu.fd_num, name
                 ),
@@ -229,11 +243,23 @@ impl Hypervisor {
     }
 
     pub fn get_maps(&self) -> Result<Vec<Mapping>> {
-        let tracee = try_with!(
-            self.tracee.read(),
-            "cannot obtain tracee read lock: poinsoned"
-        );
-        tracee.get_maps()
+        let slots = {
+            let tracee = try_with!(
+                self.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            tracee.get_maps()?
+        };
+        match slots {
+            Memslots::Read(maps) => Ok(maps),
+            Memslots::Guessed(maps) => {
+                try_with!(
+                    layout::check_guess(self, &maps),
+                    "guessed memslots do not fit the VM, reading memslots requires bcc"
+                );
+                Ok(maps)
+            }
+        }
     }
 
     pub fn get_vcpu_maps(&self) -> Result<Vec<Mapping>> {
@@ -259,7 +285,7 @@ impl Hypervisor {
         let hv_memslot = self.alloc_mem_padded::<T>(slot_len)?;
         let mut flags = 0;
         flags |= if readonly { kvmb::KVM_MEM_READONLY } else { 0 };
-        let arg = kvmb::kvm_userspace_memory_region {
+        let mut arg = kvmb::kvm_userspace_memory_region {
             slot: self.get_maps()?.len() as u32, // guess a hopfully available slot id
             flags,
             guest_phys_addr: guest_addr, // must be page aligned
@@ -267,13 +293,22 @@ impl Hypervisor {
             userspace_addr: hv_memslot.ptr as u64,
         };
         let arg_hv = self.alloc_mem()?;
-        arg_hv.write(&arg)?;
 
         let tracee = try_with!(
             self.tracee.read(),
             "cannot obtain tracee write lock: poinsoned"
         );
-        let ret = tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &arg_hv)?;
+        // Guessed memslots miss some slots and our own ones, see `memslots::get_maps`.
+        // KVM refuses to move a used slot to a different userspace address with EINVAL.
+        let mut ret = -libc::EINVAL;
+        for _ in 0..MAX_SLOT_PROBES {
+            arg_hv.write(&arg)?;
+            ret = tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &arg_hv)?;
+            if ret != -libc::EINVAL {
+                break;
+            }
+            arg.slot += 1;
+        }
         if ret != 0 {
             bail!("ioctl_with_ref failed: {}", ret)
         }
@@ -288,6 +323,15 @@ impl Hypervisor {
         })
     }
 
+    /// Takes back memory attached to the VM by an earlier vmsh process, to free it on drop.
+    pub fn reclaim_phys_mem<T: Copy>(&self, state: &PhysMemState) -> PhysMem<T> {
+        PhysMem {
+            mem: HvMem::from_raw(state.mem, self.pid, self.tracee.clone()),
+            ioctl_arg: HvMem::from_raw(state.ioctl_arg, self.pid, self.tracee.clone()),
+            guest_phys_addr: state.guest_phys_addr.clone(),
+        }
+    }
+
     pub fn alloc_mem<T: Copy>(&self) -> Result<HvMem<T>> {
         self.alloc_mem_padded::<T>(size_of::<T>())
     }
@@ -415,6 +459,121 @@ impl Hypervisor {
         Ok(-1)
     }
 
+    /// Requires the hypervisor to be stopped.
+    pub fn get_memslots(&self, reader: MemSlotReader) -> Result<Vec<(MemSlot, Mapping)>> {
+        let tracee = try_with!(
+            self.tracee.read(),
+            "cannot obtain tracee read lock: poinsoned"
+        );
+        reader.read(&tracee)
+    }
+
+    /// Allocate `len` bytes in the hypervisor, see `alloc_mem` for memory of a fixed type.
+    pub fn alloc_buffer(&self, len: usize) -> Result<HvBuffer> {
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        let ptr = tracee.mmap(len)?;
+        Ok(HvBuffer {
+            ptr: ptr as libc::uintptr_t,
+            len,
+            pid: self.pid,
+            tracee: self.tracee.clone(),
+        })
+    }
+
+    /// Changes the `KVM_MEM_*` flags of an existing memslot, i.e. to toggle dirty page logging.
+    pub fn set_memslot_flags(&self, slot: &MemSlot, flags: u32) -> Result<()> {
+        let region = kvmb::kvm_userspace_memory_region {
+            slot: slot.id(),
+            flags,
+            guest_phys_addr: slot.physical_start() as u64,
+            memory_size: slot.size() as u64,
+            userspace_addr: slot.start() as u64,
+        };
+        let mem = self.alloc_mem()?;
+        mem.write(&region)?;
+        let ret = {
+            let tracee = try_with!(
+                self.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            try_with!(
+                tracee.vm_ioctl_with_ref(ioctls::KVM_SET_USER_MEMORY_REGION(), &mem),
+                "kvm set user memory region ioctl injection failed"
+            )
+        };
+        if ret != 0 {
+            bail!(
+                "cannot set flags of memslot {} via ioctl: {}",
+                slot.id(),
+                Errno::from_i32(-ret)
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the pages of `slot` written since the last call, one bit per page. Requires
+    /// dirty page logging for the slot and a `bitmap` of `slot.dirty_bitmap_len()` bytes.
+    pub fn get_dirty_log(&self, slot: &MemSlot, bitmap: &HvBuffer) -> Result<Vec<u8>> {
+        let mem = self.alloc_mem()?;
+        mem.write(&ioctls::kvm_dirty_log {
+            slot: slot.id(),
+            padding1: 0,
+            dirty_bitmap: bitmap.ptr as u64,
+        })?;
+        let ret = {
+            let tracee = try_with!(
+                self.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            try_with!(
+                tracee.vm_ioctl_with_ref(ioctls::KVM_GET_DIRTY_LOG(), &mem),
+                "kvm get dirty log ioctl injection failed"
+            )
+        };
+        if ret != 0 {
+            bail!(
+                "cannot get dirty log of memslot {} via ioctl: {}",
+                slot.id(),
+                Errno::from_i32(-ret)
+            );
+        }
+        bitmap.read()
+    }
+
+    /// Write-protects the pages in `bitmap` again, which is only needed if the hypervisor enabled
+    /// KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2. Otherwise `get_dirty_log` already did it and this
+    /// does nothing.
+    pub fn clear_dirty_log(&self, slot: &MemSlot, bitmap: &HvBuffer) -> Result<()> {
+        let mem = self.alloc_mem()?;
+        mem.write(&ioctls::kvm_clear_dirty_log {
+            slot: slot.id(),
+            num_pages: slot.npages() as u32,
+            first_page: 0,
+            dirty_bitmap: bitmap.ptr as u64,
+        })?;
+        let ret = {
+            let tracee = try_with!(
+                self.tracee.read(),
+                "cannot obtain tracee read lock: poinsoned"
+            );
+            try_with!(
+                tracee.vm_ioctl_with_ref(ioctls::KVM_CLEAR_DIRTY_LOG(), &mem),
+                "kvm clear dirty log ioctl injection failed"
+            )
+        };
+        if ret != 0 {
+            bail!(
+                "cannot clear dirty log of memslot {} via ioctl: {}",
+                slot.id(),
+                Errno::from_i32(-ret)
+            );
+        }
+        Ok(())
+    }
+
     pub fn check_extension(&self, cap: c_int) -> Result<c_int> {
         let tracee = try_with!(
             self.tracee.read(),
@@ -535,7 +694,7 @@ impl Hypervisor {
             mem.write(&kvm_msrs {
                 nmsrs: 1,
                 pad: 0,
-                entries: [*msr; 1],
+                entries: [*msr; MAX_MSR_ENTRIES],
             }),
             "cannot obtain tracee write lock: poinsoned"
         );
@@ -545,12 +704,89 @@ impl Hypervisor {
         );
         tracee.get_msr(vcpu, &mem)
     }
+
+    /// Reads the msrs with the given `indices`, stops at the first one KVM cannot read.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_msrs(&self, vcpu: &VCPU, indices: &[u32]) -> Result<Vec<kvmb::kvm_msr_entry>> {
+        if indices.len() > MAX_MSR_ENTRIES {
+            bail!(
+                "cannot read more than {} msrs at once, got {}",
+                MAX_MSR_ENTRIES,
+                indices.len()
+            );
+        }
+        let mut msrs = kvm_msrs {
+            nmsrs: indices.len() as u32,
+            pad: 0,
+            entries: [kvmb::kvm_msr_entry::default(); MAX_MSR_ENTRIES],
+        };
+        for (entry, index) in msrs.entries.iter_mut().zip(indices) {
+            entry.index = *index;
+        }
+        let mem = self.alloc_mem()?;
+        try_with!(mem.write(&msrs), "cannot write kvm_msrs structure");
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.get_msrs(vcpu, &mem)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_lapic(&self, vcpu: &VCPU) -> Result<kvmb::kvm_lapic_state> {
+        let mem = self.alloc_mem()?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.get_lapic(vcpu, &mem)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_vcpu_events(&self, vcpu: &VCPU) -> Result<kvmb::kvm_vcpu_events> {
+        let mem = self.alloc_mem()?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.get_vcpu_events(vcpu, &mem)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_debugregs(&self, vcpu: &VCPU) -> Result<kvmb::kvm_debugregs> {
+        let mem = self.alloc_mem()?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.get_debugregs(vcpu, &mem)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xsave(&self, vcpu: &VCPU) -> Result<kvmb::kvm_xsave> {
+        let mem = self.alloc_mem()?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.get_xsave(vcpu, &mem)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xcrs(&self, vcpu: &VCPU) -> Result<kvmb::kvm_xcrs> {
+        let mem = self.alloc_mem()?;
+        let tracee = try_with!(
+            self.tracee.write(),
+            "cannot obtain tracee write lock: poinsoned"
+        );
+        tracee.get_xcrs(vcpu, &mem)
+    }
 }
 
 pub const VMFD_INODE_NAME: &str = "anon_inode:kvm-vm";
 pub const VCPUFD_INODE_NAME_STARTS_WITH: &str = "anon_inode:kvm-vcpu:";
 
-fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
+pub(crate) fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
     let mut vm_fds: Vec<RawFd> = vec![];
     let mut vcpu_fds: Vec<VCPU> = vec![];
     let fds = try_with!(
@@ -577,7 +813,7 @@ fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
                 "cannot parse number {}",
                 parts[0]
             );
-            info!("vcpu {} fd {}", idx, fd.fd_num);
+            debug!("vcpu {} fd {}", idx, fd.fd_num);
             vcpu_fds.push(VCPU {
                 idx,
                 fd_num: fd.fd_num,
@@ -585,27 +821,147 @@ fn find_vm_fd(handle: &PidHandle) -> Result<(Vec<RawFd>, Vec<VCPU>)> {
             })
         }
     }
-    let old_len = vcpu_fds.len();
-    vcpu_fds.dedup_by_key(|vcpu| vcpu.idx);
-    if old_len != vcpu_fds.len() {
-        bail!("found multiple vcpus with same id, assume multiple VMs in same hypervisor. This is not supported yet")
-    };
+    vm_fds.sort_unstable();
 
     Ok((vm_fds, vcpu_fds))
 }
 
-pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
+/// Selects a VM in hypervisor processes that run more than one VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmSelector {
+    /// Position in the list of VMs ordered by their file descriptor number
+    Index(usize),
+    /// File descriptor number of the VM in the hypervisor process
+    Fd(RawFd),
+}
+
+impl FromStr for VmSelector {
+    type Err = String;
+
+    /// Parses `fd:<num>` as file descriptor and `<num>` as index.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix("fd:") {
+            Some(fd) => fd
+                .parse::<RawFd>()
+                .map(VmSelector::Fd)
+                .map_err(|e| format!("invalid vm fd '{}': {}", fd, e)),
+            None => s
+                .parse::<usize>()
+                .map(VmSelector::Index)
+                .map_err(|e| format!("invalid vm index '{}': {}", s, e)),
+        }
+    }
+}
+
+/// A VM in the hypervisor process and the vcpus that belong to it.
+#[derive(Debug, Clone)]
+pub struct VmInfo {
+    pub vm_fd: RawFd,
+    pub vcpus: Vec<VCPU>,
+}
+
+/// Lists all VMs of a hypervisor process ordered by their file descriptor number.
+/// If there is more than one VM, the process is briefly stopped to ask the kernel which vcpu
+/// belongs to which VM.
+pub fn list_vms(pid: Pid) -> Result<Vec<VmInfo>> {
     let handle = try_with!(openpid(pid), "cannot open handle in proc");
+    let (vm_fds, vcpus) = try_with!(find_vm_fd(&handle), "failed to access kvm fds");
+
+    if vm_fds.len() <= 1 {
+        return Ok(vm_fds
+            .into_iter()
+            .map(|vm_fd| VmInfo {
+                vm_fd,
+                vcpus: vcpus.clone(),
+            })
+            .collect());
+    }
+
+    let fds = vm_fds
+        .iter()
+        .copied()
+        .chain(vcpus.iter().map(|vcpu| vcpu.fd_num))
+        .collect::<Vec<_>>();
+    let ids = {
+        let mut tracee = Hypervisor::attach(pid, vm_fds[0]);
+        tracee.attach()?;
+        // the process is resumed when tracee is dropped
+        try_with!(
+            tracee.get_vm_ids(&fds),
+            "cannot find out which vcpu belongs to which vm"
+        )
+    };
+    let (vm_ids, vcpu_ids) = ids.split_at(vm_fds.len());
+
+    let mut vms = vm_fds
+        .iter()
+        .map(|vm_fd| VmInfo {
+            vm_fd: *vm_fd,
+            vcpus: vec![],
+        })
+        .collect::<Vec<_>>();
+    for (vcpu, id) in vcpus.into_iter().zip(vcpu_ids) {
+        match vm_ids.iter().position(|vm_id| vm_id == id) {
+            Some(idx) => vms[idx].vcpus.push(vcpu),
+            None => warn!(
+                "vcpu {} (fd {}) does not belong to any vm fd",
+                vcpu.idx, vcpu.fd_num
+            ),
+        }
+    }
+    Ok(vms)
+}
 
-    let (vm_fds, mut vcpus) = try_with!(find_vm_fd(&handle), "failed to access kvm fds");
-    if vm_fds.is_empty() {
+/// Returns the only VM of the process, see `select_hypervisor` for processes with multiple VMs.
+pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
+    select_hypervisor(pid, None)
+}
+
+/// Returns the VM picked by `selector`. The selector can be omitted if the process has only one VM.
+pub fn select_hypervisor(pid: Pid, selector: Option<VmSelector>) -> Result<Hypervisor> {
+    let mut vms = list_vms(pid)?;
+    if vms.is_empty() {
         bail!("no KVM-VMs found. If this is qemu, does it enable KVM?");
     }
-    if vm_fds.len() > 1 {
-        bail!("multiple VMs found, this is not supported yet.");
+
+    let vm = match selector {
+        None if vms.len() == 1 => vms.remove(0),
+        None => {
+            let fds = vms
+                .iter()
+                .map(|vm| vm.vm_fd.to_string())
+                .collect::<Vec<_>>();
+            bail!(
+                "found {} VMs (vm fds: {}), select one by index or by fd:<num>",
+                vms.len(),
+                fds.join(", ")
+            )
+        }
+        Some(VmSelector::Index(idx)) => {
+            if idx >= vms.len() {
+                bail!("vm index {} out of range, found {} VMs", idx, vms.len());
+            }
+            vms.remove(idx)
+        }
+        Some(VmSelector::Fd(fd)) => require_with!(
+            vms.into_iter().find(|vm| vm.vm_fd == fd),
+            "no vm with fd {} found",
+            fd
+        ),
+    };
+    let mut vcpus = vm.vcpus;
+    for vcpu in &vcpus {
+        info!("vcpu {} fd {}", vcpu.idx, vcpu.fd_num);
     }
 
-    let tracee = Hypervisor::attach(pid, vm_fds[0]);
+    let old_len = vcpus.len();
+    vcpus.sort_unstable_by_key(|vcpu| vcpu.idx);
+    vcpus.dedup_by_key(|vcpu| vcpu.idx);
+    if old_len != vcpus.len() {
+        bail!("found multiple vcpus with same id in vm fd {}", vm.vm_fd)
+    };
+
+    let tracee = Hypervisor::attach(pid, vm.vm_fd);
     let vcpu_maps = try_with!(tracee.get_vcpu_maps(), "cannot get vcpufd memory maps");
     if vcpus.is_empty() {
         bail!("found KVM instance but no VCPUs");
@@ -617,9 +973,22 @@ pub fn get_hypervisor(pid: Pid) -> Result<Hypervisor> {
     Ok(Hypervisor {
         pid,
         tracee: Arc::new(RwLock::new(tracee)),
-        vm_fd: vm_fds[0],
+        vm_fd: vm.vm_fd,
         vcpus,
         wrapper: Mutex::new(None),
         transfer_ctx: Mutex::new(None),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::VmSelector;
+
+    #[test]
+    fn test_parse_vm_selector() {
+        assert_eq!("1".parse::<VmSelector>(), Ok(VmSelector::Index(1)));
+        assert_eq!("fd:12".parse::<VmSelector>(), Ok(VmSelector::Fd(12)));
+        assert!("fd:".parse::<VmSelector>().is_err());
+        assert!("foo".parse::<VmSelector>().is_err());
+    }
+}
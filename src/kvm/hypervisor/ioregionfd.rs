@@ -35,7 +35,7 @@ pub struct IoRegionFd {
 
 impl IoRegionFd {
     pub fn new(hv: &Hypervisor, guest_paddr: u64, len: usize) -> Result<Self> {
-        if Self::capability_present(hv)? {
+        if !Self::capability_present(hv)? {
             bail!("This operation requires KVM_CAP_IOREGIONFD which your KVM does not have.");
         }
 
@@ -105,7 +105,7 @@ impl IoRegionFd {
             hv.check_extension(kvm_ioregionfd::KVM_CAP_IOREGIONFD as i32),
             "cannot check kvm extension capabilities"
         );
-        Ok(has_cap == 0)
+        Ok(has_cap != 0)
     }
 }
 
@@ -2,10 +2,13 @@ use crate::page_table::PhysAddr;
 use kvm_bindings as kvmb;
 use libc::c_void;
 use log::*;
+use nix::sys::uio::{process_vm_readv, IoVec, RemoteIoVec};
 use nix::unistd::Pid;
-use simple_error::simple_error;
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, simple_error, try_with};
 use std::marker::PhantomData;
-use std::mem::size_of;
+use std::mem::{size_of, ManuallyDrop};
+use std::ptr;
 use std::sync::{Arc, RwLock};
 use vm_memory::remote_mem;
 
@@ -65,6 +68,29 @@ impl<T: Copy> Drop for HvMem<T> {
 }
 
 impl<T: Copy> HvMem<T> {
+    /// Takes ownership of memory that was left mapped by `into_raw`, possibly by an earlier vmsh
+    /// process.
+    pub(super) fn from_raw(
+        ptr: libc::uintptr_t,
+        pid: Pid,
+        tracee: Arc<RwLock<Tracee>>,
+    ) -> HvMem<T> {
+        HvMem {
+            ptr,
+            pid,
+            tracee,
+            phantom: SendPhantom::default(),
+        }
+    }
+
+    /// Leaves the memory mapped in the hypervisor and returns its address.
+    pub fn into_raw(self) -> libc::uintptr_t {
+        let this = ManuallyDrop::new(self);
+        // only release our reference on the tracee
+        drop(unsafe { ptr::read(&this.tracee) });
+        this.ptr
+    }
+
     pub fn read(&self) -> Result<T> {
         process_read(self.pid, self.ptr as *mut c_void)
     }
@@ -73,6 +99,49 @@ impl<T: Copy> HvMem<T> {
     }
 }
 
+/// Hypervisor memory with a size only known at runtime, i.e. the dirty bitmap of a memslot
+#[derive(Debug)]
+pub struct HvBuffer {
+    pub ptr: libc::uintptr_t,
+    pub len: usize,
+    pub(super) pid: Pid,
+    pub(super) tracee: Arc<RwLock<Tracee>>,
+}
+
+impl Drop for HvBuffer {
+    fn drop(&mut self) {
+        let tracee = match self.tracee.write() {
+            Err(e) => {
+                warn!("Could not aquire lock to drop HvBuffer: {}", e);
+                return;
+            }
+            Ok(t) => t,
+        };
+        if let Err(e) = tracee.munmap(self.ptr as *mut c_void, self.len) {
+            warn!("failed to unmap memory from process: {}", e);
+        }
+    }
+}
+
+impl HvBuffer {
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0; self.len];
+        let dst_iovs = [IoVec::from_mut_slice(&mut buf)];
+        let src_iovs = [RemoteIoVec {
+            base: self.ptr,
+            len: self.len,
+        }];
+        let read = try_with!(
+            process_vm_readv(self.pid, &dst_iovs, &src_iovs),
+            "cannot read hypervisor memory"
+        );
+        if read != self.len {
+            bail!("short read, expected {}, read: {}", self.len, read);
+        }
+        Ok(buf)
+    }
+}
+
 /// Physical Memory attached to a VM. Backed by `PhysMem.mem`.
 #[derive(Debug)]
 pub struct PhysMem<T: Copy> {
@@ -81,6 +150,42 @@ pub struct PhysMem<T: Copy> {
     pub guest_phys_addr: PhysAddr,
 }
 
+/// Location of a `PhysMem` that stays attached to the VM after vmsh exits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhysMemState {
+    /// hypervisor address of the memory backing the memslot
+    pub mem: libc::uintptr_t,
+    /// hypervisor address of the `kvm_userspace_memory_region` used to add the memslot
+    pub ioctl_arg: libc::uintptr_t,
+    pub guest_phys_addr: PhysAddr,
+}
+
+impl PhysMemState {
+    /// Addresses in the hypervisor that `Hypervisor::reclaim_phys_mem` accesses
+    pub fn host_addrs(&self) -> [libc::uintptr_t; 2] {
+        [self.mem, self.ioctl_arg]
+    }
+}
+
+impl<T: Copy> PhysMem<T> {
+    /// Leaves the memory attached to the VM, see `Hypervisor::reclaim_phys_mem` to free it later.
+    pub fn into_raw(self) -> PhysMemState {
+        let this = ManuallyDrop::new(self);
+        let (mem, ioctl_arg, guest_phys_addr) = unsafe {
+            (
+                ptr::read(&this.mem),
+                ptr::read(&this.ioctl_arg),
+                ptr::read(&this.guest_phys_addr),
+            )
+        };
+        PhysMemState {
+            mem: mem.into_raw(),
+            ioctl_arg: ioctl_arg.into_raw(),
+            guest_phys_addr,
+        }
+    }
+}
+
 impl<T: Copy> Drop for PhysMem<T> {
     fn drop(&mut self) {
         // useful for debugging
@@ -214,6 +214,29 @@ ioctl_iow_nr!(
     kvmb::kvm_userspace_memory_region
 );
 
+/// `struct kvm_dirty_log` with the bitmap, which lives in the hypervisor, as plain address
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct kvm_dirty_log {
+    pub slot: u32,
+    pub padding1: u32,
+    pub dirty_bitmap: u64,
+}
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvm_dirty_log);
+
+/// `struct kvm_clear_dirty_log` with the bitmap as plain address
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct kvm_clear_dirty_log {
+    pub slot: u32,
+    pub num_pages: u32,
+    pub first_page: u64,
+    pub dirty_bitmap: u64,
+}
+pub const KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2: u32 = 168;
+// Available with KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2
+ioctl_iowr_nr!(KVM_CLEAR_DIRTY_LOG, KVMIO, 0xc0, kvm_clear_dirty_log);
+
 // Available with KVM_CAP_IOREGIONFD
 ioctl_iow_nr!(KVM_SET_IOREGION, KVMIO, 0x49, kvm_ioregion);
 
@@ -248,6 +271,16 @@ ioctl_iow_nr!(KVM_SET_FPU, KVMIO, 0x8d, kvmb::kvm_fpu);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 ioctl_iowr_nr!(KVM_GET_MSRS, KVMIO, 0x88, kvmb::kvm_msrs);
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_LAPIC, KVMIO, 0x8e, kvmb::kvm_lapic_state);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvmb::kvm_vcpu_events);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_DEBUGREGS, KVMIO, 0xa1, kvmb::kvm_debugregs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_XSAVE, KVMIO, 0xa4, kvmb::kvm_xsave);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+ioctl_ior_nr!(KVM_GET_XCRS, KVMIO, 0xa6, kvmb::kvm_xcrs);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 
 /// according to arch/x86/include/asm/kvm_host.h
 pub const KVM_MAX_CPUID_ENTRIES: usize = 256;
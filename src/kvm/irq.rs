@@ -0,0 +1,96 @@
+//! Finds an interrupt line for our devices that is not used by the guest.
+
+use kvm_bindings as kvmb;
+use log::{debug, warn};
+use simple_error::{bail, try_with};
+
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+
+/// chip_id of the IOAPIC for KVM_GET_IRQCHIP
+pub const KVM_IRQCHIP_IOAPIC: u32 = 2;
+
+/// Set in a redirection table entry if the guest does not listen on this line.
+const IOAPIC_REDIR_MASKED: u64 = 1 << 16;
+
+/// We only consider legacy ISA interrupts since for those linux uses the GSI as irq number.
+const ISA_IRQS: usize = 16;
+
+/// The timer (0), keyboard (1), cascade (2), rtc (8), mouse (12) and fpu (13) are never shared.
+/// Lines that were used by vmsh before are tried first.
+const IRQ_CANDIDATES: &[usize] = &[6, 4, 5, 7, 3, 9, 10, 11, 14, 15];
+
+fn get_ioapic(hv: &Hypervisor) -> Result<kvmb::kvm_ioapic_state> {
+    let irqchip = try_with!(
+        hv.get_irqchip(KVM_IRQCHIP_IOAPIC),
+        "cannot read ioapic state (no in-kernel irqchip?)"
+    );
+    Ok(unsafe { irqchip.chip.ioapic })
+}
+
+/// Whether pin `irq` is masked and has no pending interrupt, `None` if the IOAPIC has no such pin.
+fn is_free(ioapic: &kvmb::kvm_ioapic_state, irq: usize) -> Option<bool> {
+    let entry = unsafe { ioapic.redirtbl.get(irq)?.bits };
+    let pending = ioapic.irr & (1 << irq) != 0;
+    debug!(
+        "ioapic pin {}: redirection entry {:#x}, pending: {}",
+        irq, entry, pending
+    );
+    Some(entry & IOAPIC_REDIR_MASKED != 0 && !pending)
+}
+
+/// Returns the first interrupt line that is masked in the IOAPIC of the guest,
+/// i.e. no driver in the guest requested it yet.
+pub fn find_free_irq(hv: &Hypervisor) -> Result<usize> {
+    let ioapic = get_ioapic(hv)?;
+    for &irq in IRQ_CANDIDATES {
+        if is_free(&ioapic, irq) == Some(true) {
+            return Ok(irq);
+        }
+    }
+    bail!("no free interrupt line found in the ioapic, force one with --irq");
+}
+
+/// Checks that `irq`, i.e. given with --irq, is an ISA interrupt line of the IOAPIC. Lines the
+/// guest already uses are accepted with a warning, since the user forces them.
+pub fn check_irq(hv: &Hypervisor, irq: usize) -> Result<()> {
+    if irq >= ISA_IRQS {
+        bail!(
+            "irq {} is not an ISA interrupt line (0-{})",
+            irq,
+            ISA_IRQS - 1
+        );
+    }
+    let ioapic = get_ioapic(hv)?;
+    match is_free(&ioapic, irq) {
+        None => bail!(
+            "irq {} is not a pin of the ioapic, which has {} pins",
+            irq,
+            ioapic.redirtbl.len()
+        ),
+        Some(false) => {
+            warn!(
+                "irq {} is already used by the guest, sharing it may confuse its drivers",
+                irq
+            );
+            Ok(())
+        }
+        Some(true) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_free() {
+        let mut ioapic = kvmb::kvm_ioapic_state::default();
+        assert_eq!(is_free(&ioapic, 5), Some(false));
+        ioapic.redirtbl[5].bits = IOAPIC_REDIR_MASKED;
+        assert_eq!(is_free(&ioapic, 5), Some(true));
+        ioapic.irr = 1 << 5;
+        assert_eq!(is_free(&ioapic, 5), Some(false));
+        assert_eq!(is_free(&ioapic, ioapic.redirtbl.len()), None);
+    }
+}
@@ -0,0 +1,354 @@
+/// Guesses the memslots of a VM from the memory mappings of its hypervisor and where the
+/// hypervisor is known to place guest memory on x86_64. Used when the memslots cannot be read
+/// from the kernel with BPF, see `memslots::get_maps`.
+///
+/// Only the main memory of the guest is found, smaller slots such as firmware ROMs are missing.
+use log::{info, warn};
+use nix::sys::mman::ProtFlags;
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::fs;
+
+use crate::guest_mem::{get_page_table_addr, GuestMem};
+use crate::kernel::find_kernel;
+use crate::kvm::hypervisor::Hypervisor;
+use crate::result::Result;
+use crate::tracer::proc::{openpid, Mapping};
+
+const FIRST_ADDR_PAST_32BITS: usize = 1 << 32;
+
+/// Memory between the end of conventional memory and 1 MiB is VGA memory and ROMs in QEMU.
+const LEGACY_HOLE_START: usize = 0xa_0000;
+const LEGACY_HOLE_END: usize = 0x10_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Vmm {
+    /// `q35`: The machine is not the default `pc` machine.
+    Qemu {
+        q35: bool,
+    },
+    Kvmtool,
+    Firecracker,
+    Crosvm,
+    CloudHypervisor,
+}
+
+impl Vmm {
+    fn detect(exe: &str, cmdline: &[String]) -> Option<Vmm> {
+        let name = exe.rsplit('/').next().unwrap_or(exe);
+        let name = name.strip_suffix(" (deleted)").unwrap_or(name);
+        if name.starts_with("qemu") {
+            // -M and -machine take `q35` or `type=q35,...`
+            let q35 = cmdline
+                .windows(2)
+                .filter(|w| w[0] == "-M" || w[0] == "-machine" || w[0] == "--machine")
+                .any(|w| w[1].split(',').any(|o| o == "q35" || o == "type=q35"));
+            Some(Vmm::Qemu { q35 })
+        } else if name == "lkvm" || name == "kvmtool" {
+            Some(Vmm::Kvmtool)
+        } else if name.starts_with("firecracker") {
+            Some(Vmm::Firecracker)
+        } else if name == "crosvm" {
+            Some(Vmm::Crosvm)
+        } else if name == "cloud-hypervisor" {
+            Some(Vmm::CloudHypervisor)
+        } else {
+            None
+        }
+    }
+
+    /// Guest physical address where memory stops below 4 GiB, for guests with `ram_size` bytes
+    /// of memory.
+    fn low_mem_end(self, ram_size: usize) -> usize {
+        let hole_start = match self {
+            // see pc_init1() and pc_q35_init() in QEMU
+            Vmm::Qemu { q35: false } if ram_size >= 0xe000_0000 => 0xc000_0000,
+            Vmm::Qemu { q35: true } if ram_size >= 0xb000_0000 => 0x8000_0000,
+            Vmm::Qemu { .. } => ram_size,
+            Vmm::Kvmtool | Vmm::Firecracker | Vmm::Crosvm => 0xd000_0000,
+            Vmm::CloudHypervisor => 0xc000_0000,
+        };
+        std::cmp::min(ram_size, hole_start)
+    }
+}
+
+/// Mappings that can back guest memory: anonymous memory, memfds, shared memory and hugetlbfs.
+fn is_ram_candidate(m: &Mapping) -> bool {
+    let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+    m.prot_flags.contains(rw)
+        && (m.pathname.is_empty()
+            || m.pathname.starts_with("/memfd:")
+            || m.pathname.starts_with("/dev/shm/")
+            || m.pathname.contains("hugepages"))
+}
+
+fn slot(m: &Mapping, host_start: usize, phys_addr: usize, size: usize) -> Mapping {
+    let mut slot = m.clone();
+    slot.start = host_start;
+    slot.end = host_start + size;
+    slot.phys_addr = phys_addr;
+    slot
+}
+
+/// Largest candidate mapping, preferring the ones whose name contains `name`.
+fn largest<'a>(candidates: &[&'a Mapping], name: Option<&str>) -> Option<&'a Mapping> {
+    let named = candidates
+        .iter()
+        .filter(|m| matches!(name, Some(n) if m.pathname.contains(n)))
+        .max_by_key(|m| m.size());
+    named
+        .or_else(|| candidates.iter().max_by_key(|m| m.size()))
+        .copied()
+}
+
+fn guess(vmm: Vmm, mappings: &[Mapping]) -> Result<Vec<Mapping>> {
+    let candidates = mappings
+        .iter()
+        .filter(|m| is_ram_candidate(m))
+        .collect::<Vec<_>>();
+    let name = match vmm {
+        Vmm::Crosvm => Some("crosvm_guest"),
+        Vmm::CloudHypervisor => Some("ch_ram"),
+        _ => None,
+    };
+    let low = require_with!(
+        largest(&candidates, name),
+        "no mapping found that could hold the memory of the guest"
+    );
+
+    let mut slots = vec![];
+    match vmm {
+        // one mapping for all of the memory, the part above 4 GiB follows the low memory
+        Vmm::Qemu { .. } => {
+            let ram_size = low.size();
+            let low_end = vmm.low_mem_end(ram_size);
+            if low_end > LEGACY_HOLE_START {
+                slots.push(slot(low, low.start, 0, LEGACY_HOLE_START));
+            }
+            if low_end > LEGACY_HOLE_END {
+                slots.push(slot(
+                    low,
+                    low.start + LEGACY_HOLE_END,
+                    LEGACY_HOLE_END,
+                    low_end - LEGACY_HOLE_END,
+                ));
+            }
+            if ram_size > low_end {
+                slots.push(slot(
+                    low,
+                    low.start + low_end,
+                    FIRST_ADDR_PAST_32BITS,
+                    ram_size - low_end,
+                ));
+            }
+        }
+        // one mapping, but the hole below 4 GiB is mapped too and protected,
+        // which splits it in /proc/pid/maps.
+        Vmm::Kvmtool => {
+            slots.push(slot(low, low.start, 0, low.size()));
+            if low.size() == vmm.low_mem_end(usize::MAX) {
+                let high_start = low.start + FIRST_ADDR_PAST_32BITS;
+                if let Some(high) = candidates.iter().find(|m| m.start == high_start) {
+                    slots.push(slot(high, high.start, FIRST_ADDR_PAST_32BITS, high.size()));
+                }
+            }
+        }
+        // one mapping for the memory below and one for the memory above 4 GiB
+        Vmm::Firecracker | Vmm::Crosvm | Vmm::CloudHypervisor => {
+            slots.push(slot(low, low.start, 0, low.size()));
+            if low.size() == vmm.low_mem_end(usize::MAX) {
+                let rest = candidates
+                    .iter()
+                    .filter(|m| m.start != low.start)
+                    .copied()
+                    .collect::<Vec<_>>();
+                if let Some(high) = largest(&rest, name) {
+                    warn!(
+                        "guessing that {:#x}-{:#x} holds the guest memory above 4 GiB",
+                        high.start, high.end
+                    );
+                    slots.push(slot(high, high.start, FIRST_ADDR_PAST_32BITS, high.size()));
+                }
+            }
+        }
+    }
+    Ok(slots)
+}
+
+/// Checks that the page tables of all vcpus are in the guessed memslots `slots`.
+fn check_roots(slots: &[Mapping], roots: &[usize]) -> Result<()> {
+    for (idx, root) in roots.iter().enumerate() {
+        if !slots
+            .iter()
+            .any(|s| s.phys_addr <= *root && *root < s.phys_end())
+        {
+            bail!(
+                "page table {:#x} of vcpu {} is not in the guessed memory",
+                root,
+                idx
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks guessed memslots against the VM: the page tables of the vcpus have to be in them and
+/// the kernel has to be found. Requires the VM to be stopped.
+pub fn check_guess(hv: &Hypervisor, slots: &[Mapping]) -> Result<()> {
+    let mut roots = vec![];
+    for vcpu in &hv.vcpus {
+        let sregs = try_with!(hv.get_sregs(vcpu), "failed to get vcpu special registers");
+        roots.push(get_page_table_addr(&sregs));
+    }
+    check_roots(slots, &roots)?;
+    // the kernel is only mapped while a vcpu runs in it
+    let mut err = None;
+    for idx in 0..hv.vcpus.len() {
+        let res =
+            GuestMem::from_maps(hv, idx, slots.to_vec()).and_then(|mem| find_kernel(&mem, hv));
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) => err = Some(e),
+        }
+    }
+    match err {
+        Some(e) => bail!("cannot find the kernel in the guessed memory: {}", e),
+        None => bail!("vm has no vcpus"),
+    }
+}
+
+/// Guesses the memslots of the VM in the hypervisor `pid`.
+pub fn guess_memslots(pid: Pid) -> Result<Vec<Mapping>> {
+    let handle = try_with!(openpid(pid), "cannot open handle in proc");
+    let exe_path = handle.entry("exe");
+    let exe = try_with!(
+        fs::read_link(&exe_path),
+        "cannot read {}",
+        exe_path.display()
+    );
+    let exe = exe.to_string_lossy();
+    let cmdline_path = handle.entry("cmdline");
+    let cmdline = try_with!(
+        fs::read(&cmdline_path),
+        "cannot read {}",
+        cmdline_path.display()
+    );
+    let cmdline = cmdline
+        .split(|c| *c == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect::<Vec<_>>();
+
+    let vmm = match Vmm::detect(&exe, &cmdline) {
+        Some(vmm) => vmm,
+        None => bail!(
+            "cannot guess the memory layout of hypervisor {}, \
+             reading memslots requires bcc for other hypervisors",
+            exe
+        ),
+    };
+    let mappings = try_with!(handle.maps(), "cannot read process maps");
+    let slots = guess(vmm, &mappings)?;
+    info!(
+        "guessed {} memslots from the memory layout of {:?}",
+        slots.len(),
+        vmm
+    );
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::mman::MapFlags;
+
+    fn mapping(start: usize, size: usize, pathname: &str) -> Mapping {
+        Mapping {
+            start,
+            end: start + size,
+            prot_flags: ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            map_flags: MapFlags::MAP_PRIVATE,
+            offset: 0,
+            major_dev: 0,
+            minor_dev: 0,
+            inode: 0,
+            pathname: pathname.to_string(),
+            phys_addr: 0,
+        }
+    }
+
+    fn ranges(slots: &[Mapping]) -> Vec<(usize, usize, usize)> {
+        slots
+            .iter()
+            .map(|s| (s.phys_addr, s.start, s.size()))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_roots() {
+        let mut low = mapping(0x7f00_0000_0000, 0x1000_0000, "");
+        low.phys_addr = 0x10_0000;
+        let slots = [low];
+        assert!(check_roots(&slots, &[0x10_0000, 0x1000_0000]).is_ok());
+        assert!(check_roots(&slots, &[0x2000]).is_err());
+        assert!(check_roots(&slots, &[0x1010_0000]).is_err());
+    }
+
+    #[test]
+    fn test_detect() {
+        let args = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            Vmm::detect("/usr/bin/qemu-system-x86_64", &args("qemu -m 512")),
+            Some(Vmm::Qemu { q35: false })
+        );
+        assert_eq!(
+            Vmm::detect("/usr/bin/qemu-kvm", &args("qemu -M type=q35,accel=kvm")),
+            Some(Vmm::Qemu { q35: true })
+        );
+        assert_eq!(
+            Vmm::detect("/bin/firecracker-v1.0", &[]),
+            Some(Vmm::Firecracker)
+        );
+        assert_eq!(Vmm::detect("/bin/bash", &[]), None);
+    }
+
+    #[test]
+    fn test_guess_qemu() {
+        let heap = mapping(0x5000_0000, 0x20_0000, "[heap]");
+        let arena = mapping(0x7f00_0000_0000, 0x400_0000, "");
+        let ram = mapping(0x7f10_0000_0000, 0x2000_0000, "");
+        let slots =
+            guess(Vmm::Qemu { q35: false }, &[heap, arena, ram.clone()]).expect("cannot guess");
+        assert_eq!(
+            ranges(&slots),
+            vec![
+                (0, ram.start, 0xa_0000),
+                (0x10_0000, ram.start + 0x10_0000, 0x2000_0000 - 0x10_0000),
+            ]
+        );
+
+        // 4 GiB on q35 are split at 2 GiB
+        let ram = mapping(0x7f10_0000_0000, 0x1_0000_0000, "");
+        let slots =
+            guess(Vmm::Qemu { q35: true }, std::slice::from_ref(&ram)).expect("cannot guess");
+        assert_eq!(
+            ranges(&slots)[2],
+            (0x1_0000_0000, ram.start + 0x8000_0000, 0x8000_0000)
+        );
+    }
+
+    #[test]
+    fn test_guess_firecracker() {
+        let low = mapping(0x7f10_0000_0000, 0xd000_0000, "");
+        let high = mapping(0x7f20_0000_0000, 0x3000_0000, "");
+        let arena = mapping(0x7f00_0000_0000, 0x400_0000, "");
+        let slots =
+            guess(Vmm::Firecracker, &[arena, low.clone(), high.clone()]).expect("cannot guess");
+        assert_eq!(
+            ranges(&slots),
+            vec![
+                (0, low.start, 0xd000_0000),
+                (0x1_0000_0000, high.start, 0x3000_0000)
+            ]
+        );
+    }
+}
@@ -2,16 +2,21 @@ use bcc::perf_event::PerfMapBuilder;
 use bcc::{BPFBuilder, Kprobe, BPF};
 use core::slice::from_raw_parts as make_slice;
 use libc::{c_ulong, size_t};
-use log::warn;
+use log::{info, warn};
 use nix::unistd::Pid;
 use simple_error::bail;
 use simple_error::require_with;
 use simple_error::try_with;
+use std::ffi::OsStr;
+use std::os::unix::prelude::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::time::Duration;
 use std::{fmt, ptr};
 
 use crate::kvm::hypervisor;
+use crate::kvm::ioctls::KVM_CHECK_EXTENSION;
+use crate::kvm::layout;
 use crate::result::Result;
 use crate::tracer::proc::openpid;
 use crate::tracer::proc::{self, Mapping};
@@ -23,9 +28,30 @@ pub struct MemSlot {
     base_gfn: u64,
     npages: c_ulong,
     userspace_addr: c_ulong,
+    flags: u32,
+    id: u32,
 }
 
 impl MemSlot {
+    /// Slot id as passed to KVM_SET_USER_MEMORY_REGION
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// `KVM_MEM_*` flags of the slot
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn npages(&self) -> usize {
+        self.npages as usize
+    }
+
+    /// KVM uses one bit per page, rounded up to a multiple of 64 bits.
+    pub fn dirty_bitmap_len(&self) -> usize {
+        (self.npages() + 63) / 64 * 8
+    }
+
     pub fn start(&self) -> usize {
         self.userspace_addr as usize
     }
@@ -64,6 +90,8 @@ struct memslot {
     gfn_t base_gfn;
     unsigned long npages;
     unsigned long userspace_addr;
+    u32 flags;
+    u32 id;
 };
 
 // KVM_MEM_SLOTS_NUM became to big to handle it in ebpf
@@ -102,12 +130,40 @@ void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
       out_slot->base_gfn = in_slot->base_gfn;
       out_slot->npages = in_slot->npages;
       out_slot->userspace_addr = in_slot->userspace_addr;
+      out_slot->flags = in_slot->flags;
+      out_slot->id = in_slot->id;
     }
     memslots.perf_submit(ctx, out, sizeof(*out));
 }"#;
 
-fn bpf_prog(pid: Pid) -> Result<BPF> {
-    let builder = try_with!(BPFBuilder::new(BPF_TEXT), "cannot compile bpf program");
+/// Reports the `struct kvm` a vm or vcpu file descriptor belongs to.
+/// The address is only used to tell VMs apart.
+const VM_ID_BPF_TEXT: &str = r#"
+#include <linux/kvm_host.h>
+
+BPF_PERF_OUTPUT(vm_ids);
+
+void kvm_vm_ioctl(struct pt_regs *ctx, struct file *filp) {
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    if (pid != TARGET_PID) {
+        return;
+    }
+    u64 kvm = (u64)filp->private_data;
+    vm_ids.perf_submit(ctx, &kvm, sizeof(kvm));
+}
+
+void kvm_vcpu_ioctl(struct pt_regs *ctx, struct file *filp) {
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    if (pid != TARGET_PID) {
+        return;
+    }
+    struct kvm_vcpu *vcpu = (struct kvm_vcpu *)filp->private_data;
+    u64 kvm = (u64)vcpu->kvm;
+    vm_ids.perf_submit(ctx, &kvm, sizeof(kvm));
+}"#;
+
+fn bpf_prog(text: &str, pid: Pid) -> Result<BPF> {
+    let builder = try_with!(BPFBuilder::new(text), "cannot compile bpf program");
     let cflags = &[format!("-DTARGET_PID={}", pid)];
     let builder_with_cflags = try_with!(builder.cflags(cflags), "could not pass cflags");
     Ok(try_with!(
@@ -116,14 +172,26 @@ fn bpf_prog(pid: Pid) -> Result<BPF> {
     ))
 }
 
+/// Set once building or loading the bpf program failed, i.e. because bcc, the kernel headers or
+/// CAP_BPF are missing, so that we do not try again for every lookup.
+static BPF_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Memslots of a VM, see `get_maps`
+pub enum Memslots {
+    /// read from the kernel
+    Read(Vec<Mapping>),
+    /// guessed from the memory layout of the hypervisor, see `layout::check_guess`
+    Guessed(Vec<Mapping>),
+}
+
 pub fn fetch_mappings(pid: Pid) -> Result<Vec<Mapping>> {
     let handle = try_with!(openpid(pid), "cannot open handle in proc");
     let mappings = try_with!(handle.maps(), "cannot read process maps");
     Ok(mappings)
 }
 
-pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
-    let mut module = bpf_prog(tracee.pid())?;
+fn memslots_prog(pid: Pid) -> Result<BPF> {
+    let mut module = bpf_prog(BPF_TEXT, pid)?;
     try_with!(
         Kprobe::new()
             .handler("kvm_vm_ioctl")
@@ -131,6 +199,74 @@ pub fn get_maps(tracee: &Tracee) -> Result<Vec<Mapping>> {
             .attach(&mut module),
         "failed to install kprobe"
     );
+    Ok(module)
+}
+
+fn count_vms(pid: Pid) -> Result<usize> {
+    let handle = try_with!(openpid(pid), "cannot open handle in proc");
+    let fds = try_with!(
+        handle.fds(),
+        "cannot lookup file descriptors of process {}",
+        pid
+    );
+    Ok(fds
+        .iter()
+        .filter(|fd| fd.path.file_name() == Some(OsStr::new(hypervisor::VMFD_INODE_NAME)))
+        .count())
+}
+
+/// The memslots are read from the VM of `tracee.vm_fd`, other VMs in the same process are ignored.
+/// Without bpf they are guessed from the memory layout of the hypervisor instead, see
+/// `layout::guess_memslots`, which only works if the process runs a single VM.
+pub fn get_maps(tracee: &Tracee) -> Result<Memslots> {
+    if !BPF_UNAVAILABLE.load(Ordering::Relaxed) {
+        match memslots_prog(tracee.pid()) {
+            Ok(module) => return Ok(Memslots::Read(get_maps_bpf(tracee, module)?)),
+            Err(e) => {
+                warn!("cannot read memslots with bpf: {}", e);
+                BPF_UNAVAILABLE.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+    let vms = count_vms(tracee.pid())?;
+    if vms > 1 {
+        bail!(
+            "cannot guess which memory belongs to which of the {} VMs of process {}, \
+             reading memslots requires bcc",
+            vms,
+            tracee.pid()
+        );
+    }
+    info!("falling back to guessing memslots from the memory layout of the hypervisor");
+    Ok(Memslots::Guessed(layout::guess_memslots(tracee.pid())?))
+}
+
+fn get_maps_bpf(tracee: &Tracee, module: BPF) -> Result<Vec<Mapping>> {
+    let slots = read_memslots(tracee, module)?;
+    Ok(slots.into_iter().map(|(_, m)| m).collect())
+}
+
+/// Memslots of the VM of `tracee.vm_fd` with the hypervisor mappings backing them. Unlike
+/// `get_maps` this needs bpf, because slot ids and flags cannot be guessed from the memory layout.
+/// Building the bpf program takes a while, so it is done before the VM needs to be stopped.
+pub struct MemSlotReader {
+    module: BPF,
+}
+
+impl MemSlotReader {
+    pub fn new(pid: Pid) -> Result<MemSlotReader> {
+        Ok(MemSlotReader {
+            module: memslots_prog(pid)?,
+        })
+    }
+
+    /// Requires the tracee to be attached.
+    pub fn read(self, tracee: &Tracee) -> Result<Vec<(MemSlot, Mapping)>> {
+        read_memslots(tracee, self.module)
+    }
+}
+
+fn read_memslots(tracee: &Tracee, module: BPF) -> Result<Vec<(MemSlot, Mapping)>> {
     let table = try_with!(module.table("memslots"), "failed to get perf event table");
 
     let (sender, receiver) = channel();
@@ -161,13 +297,13 @@ We might miss physical memory allocations."
     }
     let mappings = fetch_mappings(tracee.pid())?;
     memslots
-        .iter()
+        .into_iter()
         .map(|slot| match proc::find_mapping(&mappings, slot.start()) {
             Some(mut m) => {
                 m.start = slot.start();
                 m.end = slot.end();
                 m.phys_addr = slot.physical_start();
-                Ok(m)
+                Ok((slot, m))
             }
             None => bail!(
                 "No mapping of memslot {} found in hypervisor (/proc/{}/maps)",
@@ -178,6 +314,52 @@ We might miss physical memory allocations."
         .collect()
 }
 
+/// Returns for each kvm file descriptor (vm or vcpu) an id of the VM it belongs to.
+/// File descriptors of the same VM have the same id.
+pub fn get_vm_ids(tracee: &Tracee, fds: &[RawFd]) -> Result<Vec<u64>> {
+    let mut module = bpf_prog(VM_ID_BPF_TEXT, tracee.pid())?;
+    for function in &["kvm_vm_ioctl", "kvm_vcpu_ioctl"] {
+        try_with!(
+            Kprobe::new()
+                .handler(function)
+                .function(function)
+                .attach(&mut module),
+            "failed to install kprobe for {}",
+            function
+        );
+    }
+    let table = try_with!(module.table("vm_ids"), "failed to get perf event table");
+
+    let (sender, receiver) = channel();
+    let builder = PerfMapBuilder::new(table, move || {
+        let sender = sender.clone();
+        Box::new(move |x| {
+            let id = unsafe { ptr::read_unaligned(x.as_ptr() as *const u64) };
+            sender.send(id).expect("failed send vm id back");
+        })
+    });
+    let mut perf_map = try_with!(builder.build(), "could not install perf event handler");
+    let proc = tracee.try_get_proc()?;
+
+    fds.iter()
+        .map(|fd| {
+            // we only need the kprobe to trigger, vcpu fds will just return an error here.
+            try_with!(
+                proc.ioctl(*fd, KVM_CHECK_EXTENSION(), 0),
+                "cannot inject ioctl on fd {}",
+                fd
+            );
+            // events from different cpus are not ordered, hence we fetch them one by one
+            perf_map.poll(0);
+            Ok(try_with!(
+                receiver.recv_timeout(Duration::from_secs(0)),
+                "could not receive vm id of fd {} from kernel",
+                fd
+            ))
+        })
+        .collect()
+}
+
 /// ordered list of the hypervisor memory mapped to [vcpu0fd, vcpu1fd, ...]
 pub fn get_vcpu_maps(pid: Pid) -> Result<Vec<Mapping>> {
     let mappings = fetch_mappings(pid)?;
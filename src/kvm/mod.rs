@@ -2,7 +2,9 @@ pub mod allocator;
 pub mod fd_transfer;
 pub mod hypervisor;
 pub mod ioctls;
+pub mod irq;
 pub mod kvm_ioregionfd;
+pub mod layout;
 pub mod memslots;
 pub mod tracee;
-pub use self::allocator::PhysMemAllocator;
+pub use self::allocator::{AllocatorState, PhysMemAllocator};
@@ -10,20 +10,23 @@ use std::ptr;
 use super::ioctls;
 use crate::kvm::hypervisor::{memory::HvMem, VCPU};
 use crate::kvm::ioctls::KVM_CHECK_EXTENSION;
-use crate::kvm::memslots::{get_maps, get_vcpu_maps};
+use crate::kvm::memslots::{get_maps, get_vcpu_maps, get_vm_ids, Memslots};
 use crate::result::Result;
 use crate::tracer::inject_syscall;
 use crate::tracer::inject_syscall::Process as Injectee;
 use crate::tracer::proc::Mapping;
 
-/// In theory this is dynamic however for for simplicity we limit it to 1 entry to not have to rewrite our vm allocation stack
+/// Maximum number of msrs read at once with `KVM_GET_MSRS`
+pub const MAX_MSR_ENTRIES: usize = 16;
+
+/// In theory this is dynamic however for for simplicity we limit it to `MAX_MSR_ENTRIES` entries to not have to rewrite our vm allocation stack
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct kvm_msrs {
     pub nmsrs: u32,
     pub pad: u32,
     //
-    pub entries: [kvmb::kvm_msr_entry; 1],
+    pub entries: [kvmb::kvm_msr_entry; MAX_MSR_ENTRIES],
 }
 
 /// This is a handle with abstractions for the syscall injector. Its primary goal is to be an interface for the
@@ -239,11 +242,14 @@ impl Tracee {
     pub fn get_irqchip(&self, irqchip: &HvMem<kvmb::kvm_irqchip>) -> Result<kvmb::kvm_irqchip> {
         use crate::kvm::ioctls::KVM_GET_IRQCHIP;
 
-        try_with!(
+        let ret = try_with!(
             self.vm_ioctl(KVM_GET_IRQCHIP(), irqchip.ptr as c_ulong),
-            "vcpu_ioctl failed"
+            "vm_ioctl failed"
         );
-        let irqchip = try_with!(irqchip.read(), "cannot read cpuid");
+        if ret != 0 {
+            bail!("KVM_GET_IRQCHIP failed: {}", ret);
+        }
+        let irqchip = try_with!(irqchip.read(), "cannot read irqchip");
         Ok(irqchip)
     }
 
@@ -358,6 +364,100 @@ impl Tracee {
         Ok(msrs.entries[0])
     }
 
+    /// Get the model-specific registers in `msrs`. KVM stops at the first msr it cannot read,
+    /// only the entries before are returned.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_msrs(
+        &self,
+        vcpu: &VCPU,
+        msrs: &HvMem<kvm_msrs>,
+    ) -> Result<Vec<kvmb::kvm_msr_entry>> {
+        use crate::kvm::ioctls::KVM_GET_MSRS;
+        let read = try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_MSRS(), msrs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let msrs = try_with!(msrs.read(), "cannot read registers");
+        let read = std::cmp::min(read as usize, msrs.nmsrs as usize);
+        Ok(msrs.entries[..read].to_vec())
+    }
+
+    /// Get the local APIC registers of VCPU, requires the in-kernel irqchip.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_lapic(
+        &self,
+        vcpu: &VCPU,
+        lapic: &HvMem<kvmb::kvm_lapic_state>,
+    ) -> Result<kvmb::kvm_lapic_state> {
+        use crate::kvm::ioctls::KVM_GET_LAPIC;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_LAPIC(), lapic.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let lapic = try_with!(lapic.read(), "cannot read lapic state");
+        Ok(lapic)
+    }
+
+    /// Get pending exceptions, interrupts and NMIs of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_vcpu_events(
+        &self,
+        vcpu: &VCPU,
+        events: &HvMem<kvmb::kvm_vcpu_events>,
+    ) -> Result<kvmb::kvm_vcpu_events> {
+        use crate::kvm::ioctls::KVM_GET_VCPU_EVENTS;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_VCPU_EVENTS(), events.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let events = try_with!(events.read(), "cannot read vcpu events");
+        Ok(events)
+    }
+
+    /// Get debug registers of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_debugregs(
+        &self,
+        vcpu: &VCPU,
+        regs: &HvMem<kvmb::kvm_debugregs>,
+    ) -> Result<kvmb::kvm_debugregs> {
+        use crate::kvm::ioctls::KVM_GET_DEBUGREGS;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_DEBUGREGS(), regs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let regs = try_with!(regs.read(), "cannot read debug registers");
+        Ok(regs)
+    }
+
+    /// Get the XSAVE area of VCPU in the uncompacted format
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xsave(
+        &self,
+        vcpu: &VCPU,
+        xsave: &HvMem<kvmb::kvm_xsave>,
+    ) -> Result<kvmb::kvm_xsave> {
+        use crate::kvm::ioctls::KVM_GET_XSAVE;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_XSAVE(), xsave.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let xsave = try_with!(xsave.read(), "cannot read xsave area");
+        Ok(xsave)
+    }
+
+    /// Get extended control registers (XCR0) of VCPU
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xcrs(&self, vcpu: &VCPU, xcrs: &HvMem<kvmb::kvm_xcrs>) -> Result<kvmb::kvm_xcrs> {
+        use crate::kvm::ioctls::KVM_GET_XCRS;
+        try_with!(
+            self.vcpu_ioctl(vcpu, KVM_GET_XCRS(), xcrs.ptr as c_ulong),
+            "vcpu_ioctl failed"
+        );
+        let xcrs = try_with!(xcrs.read(), "cannot read extended control registers");
+        Ok(xcrs)
+    }
+
     /// Unmap memory in the process
     ///
     /// length in bytes.
@@ -379,11 +479,15 @@ impl Tracee {
         self.pid
     }
 
-    pub fn get_maps(&self) -> Result<Vec<Mapping>> {
+    pub fn get_maps(&self) -> Result<Memslots> {
         get_maps(self)
     }
 
     pub fn get_vcpu_maps(&self) -> Result<Vec<Mapping>> {
         get_vcpu_maps(self.pid)
     }
+
+    pub fn get_vm_ids(&self, fds: &[RawFd]) -> Result<Vec<u64>> {
+        get_vm_ids(self, fds)
+    }
 }
@@ -12,20 +12,31 @@
 //)]
 
 pub mod attach;
+pub mod builder;
+pub mod control;
 pub mod coredump;
 pub mod cpu;
+pub mod daemon;
 pub mod debug;
 pub mod devices;
 pub mod elf;
+pub mod gdbserver;
 pub mod guest_mem;
 pub mod inspect;
 pub mod interrutable_thread;
+pub mod kdump;
 pub mod kernel;
 pub mod kvm;
+pub mod list;
 pub mod loader;
+pub mod mem;
 pub mod page_math;
 pub mod page_table;
 pub mod result;
+pub mod session;
 pub mod signal_handler;
+pub mod snapshot;
 pub mod stage1;
 pub mod tracer;
+pub mod translate;
+pub mod vmcoreinfo;
@@ -0,0 +1,244 @@
+use log::*;
+use nix::unistd::Pid;
+use serde::Serialize;
+use simple_error::try_with;
+use std::fs::{self, read_dir};
+use std::io::{self, Write};
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+
+use crate::kvm::hypervisor;
+use crate::kvm::layout;
+use crate::result::Result;
+use crate::tracer::proc::{openpid, PidHandle};
+
+/// Where KVM puts the statistics of each VM
+const KVM_DEBUGFS: &str = "/sys/kernel/debug/kvm";
+
+pub struct ListOptions {
+    pub json: bool,
+}
+
+/// A KVM VM found on the host
+#[derive(Serialize, Debug)]
+pub struct VmEntry {
+    pub pid: i32,
+    /// Name of the hypervisor process
+    pub comm: String,
+    /// Index of the VM within the hypervisor process, as accepted by `--vm`
+    pub vm_index: usize,
+    pub vm_fd: RawFd,
+    /// None if the process has several VMs and debugfs does not tell which vcpu belongs to which
+    pub vcpus: Option<usize>,
+    /// Guest memory size in bytes, guessed from the memory layout of the hypervisor. None if
+    /// the layout is unknown or the process has several VMs.
+    pub memory: Option<u64>,
+    pub cmdline: Vec<String>,
+}
+
+fn read_comm(handle: &PidHandle) -> String {
+    let path = handle.entry("comm");
+    match fs::read_to_string(&path) {
+        Ok(comm) => comm.trim_end().to_string(),
+        Err(e) => {
+            debug!("cannot read {}: {}", path.display(), e);
+            String::new()
+        }
+    }
+}
+
+fn read_cmdline(handle: &PidHandle) -> Vec<String> {
+    let path = handle.entry("cmdline");
+    match fs::read(&path) {
+        Ok(cmdline) => cmdline
+            .split(|c| *c == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect(),
+        Err(e) => {
+            debug!("cannot read {}: {}", path.display(), e);
+            vec![]
+        }
+    }
+}
+
+/// Guesses the guest memory from the memory layout of the hypervisor. Unlike reading the
+/// memslots this neither stops the hypervisor nor needs bpf.
+fn guest_memory(pid: Pid) -> Result<u64> {
+    let slots = layout::guess_memslots(pid)?;
+    Ok(slots.iter().map(|map| map.size() as u64).sum())
+}
+
+/// KVM creates a debugfs directory per VM, named after the process and the VM fd, with one
+/// directory per vcpu.
+fn debugfs_vcpus(pid: Pid, vm_fd: RawFd) -> Option<usize> {
+    let path = PathBuf::from(KVM_DEBUGFS).join(format!("{}-{}", pid, vm_fd));
+    match read_dir(&path) {
+        Ok(entries) => Some(
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("vcpu"))
+                .count(),
+        ),
+        Err(e) => {
+            debug!("cannot read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Unlike `hypervisor::list_vms` this never stops the process, it only reads /proc and debugfs.
+fn list_process(pid: Pid) -> Result<Vec<VmEntry>> {
+    let handle = try_with!(openpid(pid), "cannot open handle in proc");
+    let (vm_fds, vcpus) = hypervisor::find_vm_fd(&handle)?;
+    if vm_fds.is_empty() {
+        return Ok(vec![]);
+    }
+    let comm = read_comm(&handle);
+    let cmdline = read_cmdline(&handle);
+
+    // the layout of the process does not tell which memory belongs to which vm
+    let single = vm_fds.len() == 1;
+    let memory = if single {
+        match guest_memory(pid) {
+            Ok(size) => Some(size),
+            Err(e) => {
+                debug!("cannot guess guest memory of process {}: {}", pid, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(vm_fds
+        .into_iter()
+        .enumerate()
+        .map(|(vm_index, vm_fd)| VmEntry {
+            pid: pid.as_raw(),
+            comm: comm.clone(),
+            vm_index,
+            vm_fd,
+            vcpus: if single {
+                Some(vcpus.len())
+            } else {
+                debugfs_vcpus(pid, vm_fd)
+            },
+            memory,
+            cmdline: cmdline.clone(),
+        })
+        .collect())
+}
+
+/// Finds all processes on the host that hold a KVM VM file descriptor.
+pub fn list_vms() -> Result<Vec<VmEntry>> {
+    let entries = try_with!(read_dir("/proc"), "cannot read /proc");
+    let mut pids = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .map(Pid::from_raw)
+        .collect::<Vec<_>>();
+    pids.sort_unstable();
+
+    let mut vms = vec![];
+    for pid in pids {
+        match list_process(pid) {
+            Ok(entries) => vms.extend(entries),
+            // processes might exit or belong to other users, we only care about those we can inspect
+            Err(e) => debug!("skip process {}: {}", pid, e),
+        }
+    }
+    Ok(vms)
+}
+
+/// Shown for values `list` cannot find out without stopping the hypervisor
+const UNKNOWN: &str = "unknown";
+
+fn format_memory(memory: Option<u64>) -> String {
+    match memory {
+        Some(bytes) => format!("{}M", bytes / (1024 * 1024)),
+        None => String::from(UNKNOWN),
+    }
+}
+
+fn format_vcpus(vcpus: Option<usize>) -> String {
+    match vcpus {
+        Some(vcpus) => vcpus.to_string(),
+        None => String::from(UNKNOWN),
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, vms: &[VmEntry]) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<8} {:<16} {:<4} {:<6} {:<8} {:<8} CMDLINE",
+        "PID", "COMM", "VM", "VM_FD", "VCPUS", "MEMORY"
+    )?;
+    for vm in vms {
+        writeln!(
+            out,
+            "{:<8} {:<16} {:<4} {:<6} {:<8} {:<8} {}",
+            vm.pid,
+            vm.comm,
+            vm.vm_index,
+            vm.vm_fd,
+            format_vcpus(vm.vcpus),
+            format_memory(vm.memory),
+            vm.cmdline.join(" ")
+        )?;
+    }
+    Ok(())
+}
+
+pub fn list(opts: &ListOptions) -> Result<()> {
+    let vms = list_vms()?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if opts.json {
+        try_with!(
+            serde_json::to_writer(&mut out, &vms),
+            "cannot serialize vm list"
+        );
+        try_with!(writeln!(out), "cannot write vm list");
+    } else {
+        try_with!(write_table(&mut out, &vms), "cannot write vm list");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_table() {
+        let vms = vec![
+            VmEntry {
+                pid: 42,
+                comm: String::from("qemu-kvm"),
+                vm_index: 0,
+                vm_fd: 11,
+                vcpus: Some(2),
+                memory: Some(512 * 1024 * 1024),
+                cmdline: vec![String::from("qemu-kvm"), String::from("-enable-kvm")],
+            },
+            VmEntry {
+                pid: 43,
+                comm: String::from("firecracker"),
+                vm_index: 1,
+                vm_fd: 12,
+                vcpus: None,
+                memory: None,
+                cmdline: vec![],
+            },
+        ];
+        let mut out = vec![];
+        write_table(&mut out, &vms).expect("cannot write table");
+        let out = String::from_utf8(out).expect("table is not utf-8");
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("42 "));
+        assert!(lines[1].contains(" 512M "));
+        assert!(lines[1].ends_with("qemu-kvm -enable-kvm"));
+        assert!(lines[2].contains(" unknown  unknown "));
+    }
+}
@@ -9,7 +9,7 @@ use log::{debug, error, warn};
 use nix::sys::mman::ProtFlags;
 use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
 use simple_error::{bail, require_with, try_with};
-use stage1_interface::{DeviceState, Stage1Args};
+use stage1_interface::{DeviceState, Stage1Args, MAX_ARGV, MAX_DEVICES, MAX_ENVP};
 use xmas_elf::sections::{SectionData, SHN_UNDEF};
 use xmas_elf::symbol_table::{Binding, DynEntry64};
 
@@ -159,33 +159,53 @@ impl<'a> Loader<'a> {
     fn write_stage1_args(
         &mut self,
         command: &[String],
+        environment: &[String],
         irq_num: usize,
         mmio_ranges: Vec<u64>,
     ) -> Result<(DeviceStatus, DriverStatus)> {
+        // reserve space for the null-terminator
+        if command.len() >= MAX_ARGV {
+            bail!("too many arguments: {} >= {}", command.len(), MAX_ARGV);
+        }
+        if mmio_ranges.len() > MAX_DEVICES {
+            bail!("too many devices: {} > {}", mmio_ranges.len(), MAX_DEVICES);
+        }
+        if environment.len() >= MAX_ENVP {
+            bail!(
+                "too many environment variables: {} >= {}",
+                environment.len(),
+                MAX_ENVP
+            );
+        }
         let virt_mem = require_with!(self.virt_mem.as_ref(), "no virtual memory assigned");
         let string_mapping =
             require_with!(virt_mem.mappings.last(), "no virtual mappings found").clone();
 
         let mut strings: Vec<u8> = Vec::with_capacity(self.string_arg_size);
 
-        let mut argv = command
-            .iter()
-            .map(|arg| {
-                let ptr = strings.len() + string_mapping.virt_start;
-                strings.extend_from_slice(arg.as_bytes());
-                // make string null-terminated
-                strings.push(b'\0');
-                ptr as *mut libc::c_char
-            })
-            .collect::<Vec<_>>();
+        let mut copy_strings = |list: &[String]| {
+            let mut pointers = list
+                .iter()
+                .map(|arg| {
+                    let ptr = strings.len() + string_mapping.virt_start;
+                    strings.extend_from_slice(arg.as_bytes());
+                    // make string null-terminated
+                    strings.push(b'\0');
+                    ptr as *mut libc::c_char
+                })
+                .collect::<Vec<_>>();
+            // make array null-terminated
+            pointers.push(ptr::null_mut());
+            pointers
+        };
+        let argv = copy_strings(command);
+        let envp = copy_strings(environment);
 
         self.loadables.push(Loadable {
             content: strings,
             mapping: string_mapping,
             virt_offset: 0,
         });
-        // make argv null-terminated
-        argv.push(ptr::null_mut());
 
         let addr = self.vmsh_stage1_args;
         let loadable = require_with!(
@@ -207,6 +227,7 @@ impl<'a> Loader<'a> {
         let stage1_args = unsafe { &mut (*stage1_args) };
 
         stage1_args.argv[0..argv.len()].clone_from_slice(argv.as_slice());
+        stage1_args.envp[0..envp.len()].clone_from_slice(envp.as_slice());
         stage1_args.device_addrs[0..mmio_ranges.len()].clone_from_slice(&mmio_ranges);
         stage1_args.device_status = DeviceState::Initializing;
         stage1_args.irq_num = irq_num;
@@ -232,16 +253,23 @@ impl<'a> Loader<'a> {
     pub fn load_binary(
         &mut self,
         command: &[String],
+        environment: &[String],
         irq_num: usize,
         mmio_ranges: Vec<u64>,
     ) -> Result<(VirtMem, DeviceStatus, DriverStatus)> {
         let binary = try_core_res!(ElfBinary::new(self.binary), "cannot parse elf binary");
 
-        self.string_arg_size = page_align(command.iter().map(|c| c.len() + 1).sum());
+        self.string_arg_size = page_align(
+            command
+                .iter()
+                .chain(environment.iter())
+                .map(|c| c.len() + 1)
+                .sum(),
+        );
         try_core_res!(binary.load(self), "cannot load elf binary");
 
         let (device_status, driver_status) = try_with!(
-            self.write_stage1_args(command, irq_num, mmio_ranges),
+            self.write_stage1_args(command, environment, irq_num, mmio_ranges),
             "failed to write stage1 arguments"
         );
 
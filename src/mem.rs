@@ -0,0 +1,343 @@
+use log::{debug, info};
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use simple_error::{bail, require_with, try_with};
+use std::cmp::min;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::guest_mem::GuestMem;
+use crate::kvm;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::result::Result;
+
+// process_vm_readv and process_vm_writev accept at most IOV_MAX iovecs
+const MAX_IOVECS: usize = 1024;
+/// `vmsh mem read` reads this much at once, i.e. one `process_vm_readv` with 4k pages
+const READ_CHUNK_SIZE: usize = MAX_IOVECS * 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestAddress {
+    Physical(usize),
+    /// Translated with the page tables of the vcpu
+    Virtual(usize),
+}
+
+impl GuestAddress {
+    fn value(self) -> usize {
+        match self {
+            GuestAddress::Physical(addr) | GuestAddress::Virtual(addr) => addr,
+        }
+    }
+
+    fn add(self, offset: usize) -> Self {
+        match self {
+            GuestAddress::Physical(addr) => GuestAddress::Physical(addr + offset),
+            GuestAddress::Virtual(addr) => GuestAddress::Virtual(addr + offset),
+        }
+    }
+}
+
+impl fmt::Display for GuestAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestAddress::Physical(addr) => write!(f, "physical address {:#x}", addr),
+            GuestAddress::Virtual(addr) => write!(f, "virtual address {:#x}", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Hexdump,
+    Raw,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "hexdump" => Ok(Format::Hexdump),
+            "raw" => Ok(Format::Raw),
+            _ => Err(format!("unknown format '{}'", s)),
+        }
+    }
+}
+
+pub struct MemReadOptions {
+    pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub addr: GuestAddress,
+    pub len: usize,
+    /// vcpu whose page tables translate virtual addresses
+    pub vcpu: usize,
+    /// page tables to use instead of the ones of the vcpu
+    pub cr3: Option<usize>,
+    pub format: Format,
+    /// write the raw memory to this file instead of stdout
+    pub output: Option<PathBuf>,
+}
+
+pub struct MemWriteOptions {
+    pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub addr: GuestAddress,
+    /// vcpu whose page tables translate virtual addresses
+    pub vcpu: usize,
+    /// page tables to use instead of the ones of the vcpu
+    pub cr3: Option<usize>,
+    pub data: Vec<u8>,
+    /// writes are refused unless set
+    pub allow_write: bool,
+}
+
+/// Parses bytes given as hex string, e.g. `deadbeef` or `de ad be ef`.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let digits = s.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        bail!("hex string '{}' has an odd number of digits", s);
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte = pair.iter().collect::<String>();
+            Ok(try_with!(
+                u8::from_str_radix(&byte, 16),
+                "invalid hex byte '{}'",
+                byte
+            ))
+        })
+        .collect()
+}
+
+/// Hypervisor memory that holds `len` bytes of guest memory at `addr`, split at page and
+/// memslot boundaries.
+fn remote_iovecs(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    addr: GuestAddress,
+    cr3: Option<usize>,
+    len: usize,
+) -> Result<Vec<RemoteIoVec>> {
+    if len > 0 && addr.value().checked_add(len - 1).is_none() {
+        bail!("{} with length {:#x} is out of range", addr, len);
+    }
+    let mut iovecs = vec![];
+    let mut done = 0;
+    while done < len {
+        let (host_addr, available) = match addr {
+            GuestAddress::Physical(start) => {
+                let phys_addr = start + done;
+                require_with!(
+                    mem.host_range(phys_addr),
+                    "physical address {:#x} is not backed by a memslot",
+                    phys_addr
+                )
+            }
+            GuestAddress::Virtual(start) => {
+                let virt_addr = start + done;
+                let t = mem.translate(hv, virt_addr, cr3)?;
+                let (host_addr, available) = require_with!(
+                    mem.host_range(t.phys_addr),
+                    "virtual address {:#x} maps to {:#x}, which is not backed by a memslot",
+                    virt_addr,
+                    t.phys_addr
+                );
+                let page_left = t.page_size - (virt_addr & (t.page_size - 1));
+                (host_addr, min(available, page_left))
+            }
+        };
+        let chunk = min(available, len - done);
+        iovecs.push(RemoteIoVec {
+            base: host_addr,
+            len: chunk,
+        });
+        done += chunk;
+    }
+    Ok(iovecs)
+}
+
+/// Reads guest memory at `addr` into `buf`, the hypervisor must be stopped.
+pub fn read_guest(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    addr: GuestAddress,
+    cr3: Option<usize>,
+    buf: &mut [u8],
+) -> Result<()> {
+    let remote = remote_iovecs(hv, mem, addr, cr3, buf.len())?;
+    let mut offset = 0;
+    for remote in remote.chunks(MAX_IOVECS) {
+        let len = remote.iter().map(|r| r.len).sum::<usize>();
+        let local = [IoVec::from_mut_slice(&mut buf[offset..offset + len])];
+        let read = try_with!(
+            process_vm_readv(hv.pid, &local, remote),
+            "cannot read guest memory"
+        );
+        if read != len {
+            bail!("short read, expected {}, read: {}", len, read);
+        }
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Writes `data` to guest memory at `addr` and logs it, the hypervisor must be stopped.
+pub fn write_guest(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    addr: GuestAddress,
+    cr3: Option<usize>,
+    data: &[u8],
+) -> Result<()> {
+    let remote = remote_iovecs(hv, mem, addr, cr3, data.len())?;
+    info!(
+        "write {} bytes to guest {} of hypervisor {}",
+        data.len(),
+        addr,
+        hv.pid
+    );
+    let mut offset = 0;
+    for remote in remote.chunks(MAX_IOVECS) {
+        let len = remote.iter().map(|r| r.len).sum::<usize>();
+        for r in remote {
+            debug!("write {} bytes at host address {:#x}", r.len, r.base);
+        }
+        let local = [IoVec::from_slice(&data[offset..offset + len])];
+        let written = try_with!(
+            process_vm_writev(hv.pid, &local, remote),
+            "cannot write guest memory"
+        );
+        if written != len {
+            bail!("short write, expected {}, written: {}", len, written);
+        }
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Reads guest memory in chunks of at most `READ_CHUNK_SIZE` bytes and passes them to `f`, so
+/// that large reads do not need as much memory. The hypervisor is stopped while doing so.
+fn read_memory(opts: &MemReadOptions, f: &mut dyn FnMut(&[u8]) -> Result<()>) -> Result<()> {
+    let vm = try_with!(
+        kvm::hypervisor::select_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let mem = GuestMem::for_vcpu(&vm, opts.vcpu)?;
+    let mut buf = vec![0; min(opts.len, READ_CHUNK_SIZE)];
+    let mut done = 0;
+    while done < opts.len {
+        let chunk = &mut buf[..min(READ_CHUNK_SIZE, opts.len - done)];
+        read_guest(&vm, &mem, opts.addr.add(done), opts.cr3, chunk)?;
+        f(chunk)?;
+        done += chunk.len();
+    }
+    Ok(())
+}
+
+/// Writes guest memory, the hypervisor is stopped while doing so.
+pub fn write_memory(opts: &MemWriteOptions) -> Result<()> {
+    if !opts.allow_write {
+        bail!("writing guest memory requires --allow-write");
+    }
+    let vm = try_with!(
+        kvm::hypervisor::select_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let mem = GuestMem::for_vcpu(&vm, opts.vcpu)?;
+    write_guest(&vm, &mem, opts.addr, opts.cr3, &opts.data)
+}
+
+/// Prints `data` like `hexdump -C`, with addresses starting at `start`.
+fn hexdump(start: usize, data: &[u8], out: &mut dyn Write) -> io::Result<()> {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        writeln!(
+            out,
+            "{:016x}  {:<47}  |{}|",
+            start.wrapping_add(i * 16),
+            hex,
+            ascii
+        )?;
+    }
+    Ok(())
+}
+
+pub fn read(opts: &MemReadOptions) -> Result<()> {
+    if opts.len > 0 && opts.addr.value().checked_add(opts.len - 1).is_none() {
+        bail!("{} with length {:#x} is out of range", opts.addr, opts.len);
+    }
+    if let Some(path) = &opts.output {
+        let mut file = try_with!(File::create(path), "cannot create {}", path.display());
+        read_memory(opts, &mut |data| {
+            try_with!(file.write_all(data), "cannot write {}", path.display());
+            Ok(())
+        })?;
+        info!("wrote {} bytes to {}", opts.len, path.display());
+        return Ok(());
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut start = opts.addr.value();
+    read_memory(opts, &mut |data| {
+        let res = match opts.format {
+            Format::Hexdump => hexdump(start, data, &mut out),
+            Format::Raw => out.write_all(data),
+        };
+        try_with!(res, "cannot write guest memory to stdout");
+        start = start.wrapping_add(data.len());
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(
+            parse_hex("deadBEEF").ok(),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_hex("00 ff").ok(), Some(vec![0x00, 0xff]));
+        assert!(parse_hex("abc").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut out = vec![];
+        let data = b"vmsh\x00\x01 guest memory!";
+        hexdump(0xffff_ffff_8100_0000, data, &mut out).expect("cannot write hexdump");
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "ffffffff81000000  76 6d 73 68 00 01 20 67 75 65 73 74 20 6d 65 6d  |vmsh.. guest mem|\n\
+             ffffffff81000010  6f 72 79 21                                      |ory!|\n"
+        );
+    }
+}
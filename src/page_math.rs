@@ -7,7 +7,7 @@ pub fn page_size() -> usize {
 }
 
 pub fn huge_page_size(level: u8) -> usize {
-    page_size() << (9 * (3 - level))
+    page_size() << (9 * (level - 1))
 }
 
 pub fn page_start(v: usize) -> usize {
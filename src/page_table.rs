@@ -2,24 +2,68 @@ use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::cmp::max;
 use std::collections::HashMap;
-use std::mem::{size_of, size_of_val};
+use std::mem::{size_of, size_of_val, ManuallyDrop};
 use std::ops::Range;
+use std::ptr;
 use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::guest_mem::{MappedMemory, PhysHostMap};
-use crate::kvm::hypervisor::{memory::process_read, memory::PhysMem, Hypervisor};
+use crate::kvm::hypervisor::{
+    memory::process_read, memory::PhysMem, memory::PhysMemState, Hypervisor,
+};
 use crate::page_math::{is_page_aligned, page_align, page_size};
 use crate::result::Result;
 use bitflags::bitflags;
 use log::{error, info};
 use nix::sys::mman::ProtFlags;
 use nix::sys::uio::{process_vm_writev, IoVec, RemoteIoVec};
+use serde::{Deserialize, Serialize};
 use simple_error::{bail, require_with, try_with};
 use vm_memory::remote_mem::any_as_bytes;
 
 const ENTRY_COUNT: usize = 512;
-const LEVEL_COUNT: usize = 4;
+/// Levels of 5-level paging, which needs one table more than 4-level paging.
+const LEVEL_COUNT: usize = 5;
+
+// enables 5-level paging
+const X86_CR4_LA57: u64 = 1 << 12;
+
+/// Paging mode of the guest. Page table levels are numbered from the bottom: level 1 tables
+/// map 4 KiB pages and the root table is at level 4 (PML4) or at level 5 (PML5).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Paging {
+    FourLevel,
+    FiveLevel,
+}
+
+impl Paging {
+    pub fn from_cr4(cr4: u64) -> Paging {
+        if cr4 & X86_CR4_LA57 != 0 {
+            Paging::FiveLevel
+        } else {
+            Paging::FourLevel
+        }
+    }
+
+    pub fn root_level(self) -> u8 {
+        match self {
+            Paging::FourLevel => 4,
+            Paging::FiveLevel => 5,
+        }
+    }
+
+    /// Width of canonical virtual addresses
+    pub fn virt_bits(self) -> u8 {
+        get_shift(self.root_level()) + 9
+    }
+
+    /// Canonical addresses have all bits above `virt_bits` set to the most significant bit.
+    pub fn is_canonical(self, virt_addr: u64) -> bool {
+        let high = virt_addr >> (self.virt_bits() - 1);
+        high == 0 || high == !0 >> (self.virt_bits() - 1)
+    }
+}
 
 bitflags! {
     /// Possible flags for a page table entry.
@@ -134,6 +178,8 @@ pub struct PageTable {
 
 pub struct PageTableIterator<'a> {
     hv: &'a Hypervisor,
+    /// width of canonical virtual addresses, 48 or 57 bits
+    virt_bits: u8,
     phys_host_map: Arc<PhysHostMap>,
     page_table: PageTable,
     range: Range<usize>,
@@ -176,6 +222,7 @@ impl PageTable {
         })
     }
 
+    /// Iterates over the pages mapped in `range`, `self` must be the root table.
     pub fn iter(
         self,
         hv: &Hypervisor,
@@ -184,6 +231,7 @@ impl PageTable {
     ) -> PageTableIterator {
         PageTableIterator {
             hv,
+            virt_bits: get_shift(self.level) + 9,
             phys_host_map,
             range,
             page_table: self,
@@ -194,8 +242,8 @@ impl PageTable {
 }
 
 fn get_shift(level: u8) -> u8 {
-    assert!(level <= 3);
-    12 + 9 * (3 - level)
+    assert!((1..=5).contains(&level));
+    12 + 9 * (level - 1)
 }
 
 fn get_index(virt: u64, level: u8) -> u64 {
@@ -229,6 +277,79 @@ pub struct VirtMem {
     pub mappings: Vec<MappedMemory>,
 }
 
+/// Page table modifications of a `VirtMem` that stays mapped after vmsh exits.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VirtMemState {
+    /// original content of the page tables we modified
+    old_tables: Vec<PageTableState>,
+    phys_mem: PhysMemState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PageTableState {
+    phys_addr: PhysAddr,
+    entries: Vec<u64>,
+}
+
+impl VirtMemState {
+    pub fn host_addrs(&self) -> [usize; 2] {
+        self.phys_mem.host_addrs()
+    }
+}
+
+impl VirtMem {
+    /// Leaves the memory mapped in the VM, i.e. because stage1 still runs in it.
+    pub fn into_raw(self) -> VirtMemState {
+        let mut this = ManuallyDrop::new(self);
+        let old_tables = this
+            .old_tables
+            .iter()
+            .map(|t| PageTableState {
+                phys_addr: t.phys_addr.clone(),
+                entries: t.entries.iter().map(|e| e.entry).collect(),
+            })
+            .collect();
+        // release everything but the physical memory, which stays attached to the VM
+        let phys_mem = unsafe {
+            ptr::drop_in_place(&mut this.hv);
+            ptr::drop_in_place(&mut this.old_tables);
+            ptr::drop_in_place(&mut this.mappings);
+            ptr::read(&this.phys_mem)
+        };
+        VirtMemState {
+            old_tables,
+            phys_mem: phys_mem.into_raw(),
+        }
+    }
+
+    /// Takes back memory mapped by an earlier vmsh process. Dropping the returned value restores
+    /// the page tables and frees the memory.
+    pub fn reclaim(hv: Arc<Hypervisor>, state: &VirtMemState) -> Result<VirtMem> {
+        let mut old_tables = vec![];
+        for t in &state.old_tables {
+            if t.entries.len() != ENTRY_COUNT {
+                bail!(
+                    "page table at {:#x} has {} entries",
+                    t.phys_addr.value,
+                    t.entries.len()
+                );
+            }
+            let mut table = PageTable::empty(t.phys_addr.clone());
+            for (entry, value) in table.entries.iter_mut().zip(&t.entries) {
+                entry.entry = *value;
+            }
+            old_tables.push(table);
+        }
+        let phys_mem = hv.reclaim_phys_mem(&state.phys_mem);
+        Ok(VirtMem {
+            hv,
+            old_tables,
+            phys_mem,
+            mappings: vec![],
+        })
+    }
+}
+
 impl Drop for VirtMem {
     fn drop(&mut self) {
         // useful for debugging
@@ -241,7 +362,7 @@ impl Drop for VirtMem {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PhysAddr {
     /// The actual physical address
     pub value: usize,
@@ -346,14 +467,6 @@ fn get_page_table(
     }
 }
 
-fn get_start_idx(virt_addr: usize, idx: usize, level: u8) -> usize {
-    if idx != 0 {
-        0
-    } else {
-        get_index(virt_addr as u64, level) as usize
-    }
-}
-
 fn commit_page_tables(hv: &Hypervisor, tables: &[PageTable]) -> Result<()> {
     let mut local_iovec = vec![];
     let mut remote_iovec = vec![];
@@ -389,10 +502,11 @@ fn page_table_flags(p: ProtFlags) -> PageTableFlags {
     flags
 }
 
+#[allow(clippy::too_many_arguments)]
 fn map_memory_single(
     hv: &Hypervisor,
-    pml4: &mut PageTable,
-
+    root: &PageTableRef,
+    root_level: u8,
     m: &MappedMemory,
     upsert_tables: &mut UpsertTable,
     old_tables: &mut Vec<PageTable>,
@@ -400,60 +514,31 @@ fn map_memory_single(
     phys_host_map: &PhysHostMap,
 ) -> Result<()> {
     let mut phys_addr = m.phys_start.clone();
-    let mut len = m.len;
-    let start0 = get_index(m.virt_start as u64, 0) as usize;
-    'outer: for (i0, entry0) in pml4.entries[start0..].iter_mut().enumerate() {
-        let start1 = get_start_idx(m.virt_start, i0, 1);
-        let pt1 = get_page_table(
-            hv,
-            entry0,
-            pt_addr,
-            old_tables,
-            upsert_tables,
-            phys_host_map,
-        )?;
-        let mut pt1 = pt1.borrow_mut();
-
-        for (i1, entry1) in pt1.entries[start1..].iter_mut().enumerate() {
-            let start2 = get_start_idx(m.virt_start, i1, 2);
-            let pt2 = get_page_table(
+    for virt_addr in (m.virt_start..m.virt_start + m.len).step_by(page_size()) {
+        let mut table = Rc::clone(root);
+        for level in (2..=root_level).rev() {
+            let idx = get_index(virt_addr as u64, level) as usize;
+            let next = get_page_table(
                 hv,
-                entry1,
+                &mut table.borrow_mut().entries[idx],
                 pt_addr,
                 old_tables,
                 upsert_tables,
                 phys_host_map,
             )?;
-            let mut pt2 = pt2.borrow_mut();
-
-            for (i2, entry2) in pt2.entries[start2..].iter_mut().enumerate() {
-                let start3 = get_start_idx(m.virt_start, i2, 3);
-                let pt3 = get_page_table(
-                    hv,
-                    entry2,
-                    pt_addr,
-                    old_tables,
-                    upsert_tables,
-                    phys_host_map,
-                )?;
-                let mut pt3 = pt3.borrow_mut();
-
-                for (i, entry3) in pt3.entries[start3..].iter_mut().enumerate() {
-                    if entry3.flags().contains(PageTableFlags::PRESENT) {
-                        bail!(
-                            "found already mapped page in page table at {:#x}",
-                            entry2.addr() as usize + i + start3
-                        );
-                    }
-                    entry3.set_addr(&phys_addr, page_table_flags(m.prot));
-                    phys_addr.value += page_size();
-                    len -= page_size();
-                    if len == 0 {
-                        break 'outer;
-                    }
-                }
-            }
+            table = next;
+        }
+        let mut pt = table.borrow_mut();
+        let idx = get_index(virt_addr as u64, 1) as usize;
+        if pt.entries[idx].flags().contains(PageTableFlags::PRESENT) {
+            bail!(
+                "found already mapped page at {:#x} in page table at {:#x}",
+                virt_addr,
+                pt.phys_addr.value
+            );
         }
+        pt.entries[idx].set_addr(&phys_addr, page_table_flags(m.prot));
+        phys_addr.value += page_size();
     }
     Ok(())
 }
@@ -465,7 +550,8 @@ fn map_memory_single(
 pub fn map_memory(
     hv: Arc<Hypervisor>,
     phys_mem: PhysMem<u8>,
-    pml4_addr: &mut PhysAddr,
+    root_addr: &PhysAddr,
+    paging: Paging,
     mappings: &[MappedMemory],
     phys_host_map: &PhysHostMap,
 ) -> Result<VirtMem> {
@@ -473,15 +559,15 @@ pub fn map_memory(
     let mut upsert_tables: UpsertTable = HashMap::new();
     // Tables that we need to revert to their old content
     let mut old_tables: Vec<PageTable> = vec![];
-    let pml4 = try_with!(
+    let root = try_with!(
         read_page_table(
             &hv,
-            pml4_addr.value,
+            root_addr.value,
             &mut old_tables,
             &mut upsert_tables,
             phys_host_map
         ),
-        "cannot read pml4 page table"
+        "cannot read root page table"
     );
 
     for (i, m) in mappings.iter().enumerate() {
@@ -505,7 +591,8 @@ pub fn map_memory(
     for mapping in mappings {
         map_memory_single(
             &hv,
-            &mut pml4.borrow_mut(),
+            &root,
+            paging.root_level(),
             mapping,
             &mut upsert_tables,
             &mut old_tables,
@@ -529,6 +616,51 @@ pub fn map_memory(
     })
 }
 
+/// Walks the page tables below `root` to the entry that maps the page of `virt_addr`.
+pub fn walk(
+    hv: &Hypervisor,
+    root: &PhysAddr,
+    paging: Paging,
+    phys_host_map: &PhysHostMap,
+    virt_addr: u64,
+) -> Result<PageTableIteratorValue> {
+    if !paging.is_canonical(virt_addr) {
+        bail!(
+            "{:#x} is not a canonical address with {} bit virtual addresses",
+            virt_addr,
+            paging.virt_bits()
+        );
+    }
+    let mut table_addr = root.clone();
+    let mut level = paging.root_level();
+    loop {
+        let pt = try_with!(
+            PageTable::read(hv, &table_addr, 0, level),
+            "cannot read level {} page table at {:#x}",
+            level,
+            table_addr.value
+        );
+        let entry = pt.entries[get_index(virt_addr, level) as usize];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            bail!(
+                "{:#x} is not mapped, entry in level {} page table at {:#x} is not present",
+                virt_addr,
+                level,
+                table_addr.value
+            );
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(PageTableIteratorValue {
+                virt_addr: virt_addr & !((1 << get_shift(level)) - 1),
+                level,
+                entry,
+            });
+        }
+        table_addr = pt.phys_addr(entry, phys_host_map)?;
+        level -= 1;
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct PageTableIteratorValue {
     pub virt_addr: u64,
@@ -541,7 +673,7 @@ impl PageTableIteratorValue {
     pub fn size(&self) -> u64 {
         assert!(
             self.entry.flags().contains(PageTableFlags::PRESENT)
-                && (self.level == 3 || self.entry.flags().contains(PageTableFlags::HUGE_PAGE))
+                && (self.level == 1 || self.entry.flags().contains(PageTableFlags::HUGE_PAGE))
         );
         1 << get_shift(self.level)
     }
@@ -568,14 +700,14 @@ impl<'a> Iterator for PageTableIterator<'a> {
             self.count += 1;
             let mut virt_addr = pt.virt_addr + (idx << get_shift(pt.level));
             // sign extend most significant bit
-            if virt_addr >> 47 != 0 {
-                virt_addr |= 0xFFFF << 48
+            if virt_addr >> (self.virt_bits - 1) != 0 {
+                virt_addr |= !0 << self.virt_bits
             }
             if !entry.flags().contains(PageTableFlags::PRESENT) {
                 continue;
             }
 
-            if pt.level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            if pt.level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
                 return Some(Ok(PageTableIteratorValue {
                     virt_addr,
                     level: pt.level,
@@ -584,7 +716,7 @@ impl<'a> Iterator for PageTableIterator<'a> {
             }
             let next_phys_addr = pt.phys_addr(*entry, &self.phys_host_map).ok()?;
             let next_pt =
-                PageTable::read(self.hv, &next_phys_addr, virt_addr, pt.level + 1).ok()?;
+                PageTable::read(self.hv, &next_phys_addr, virt_addr, pt.level - 1).ok()?;
 
             let start = if idx as usize > start {
                 0
@@ -597,7 +729,15 @@ impl<'a> Iterator for PageTableIterator<'a> {
                 self.range.end
             };
 
-            let mut inner = next_pt.iter(self.hv, Arc::clone(&self.phys_host_map), start..end);
+            let mut inner = PageTableIterator {
+                hv: self.hv,
+                virt_bits: self.virt_bits,
+                phys_host_map: Arc::clone(&self.phys_host_map),
+                page_table: next_pt,
+                range: start..end,
+                count: 0,
+                inner: None,
+            };
             if let Some(next) = &inner.next() {
                 self.inner = Some(Box::new(inner));
                 return Some(next.clone());
@@ -611,7 +751,10 @@ impl<'a> Iterator for PageTableIterator<'a> {
 mod tests {
     use crate::page_math::page_size;
 
-    use super::{estimate_page_table_size, ENTRY_COUNT, LEVEL_COUNT};
+    use super::{
+        estimate_page_table_size, get_index, get_shift, Paging, ENTRY_COUNT, LEVEL_COUNT,
+        X86_CR4_LA57,
+    };
     #[test]
     fn test_page_table_size() {
         assert_eq!(estimate_page_table_size(1), page_size() * LEVEL_COUNT);
@@ -621,4 +764,28 @@ mod tests {
             page_size() + page_size() * LEVEL_COUNT
         );
     }
+
+    #[test]
+    fn test_paging_levels() {
+        assert_eq!(Paging::from_cr4(0x3506f0).root_level(), 4);
+        assert_eq!(Paging::from_cr4(0x3506f0 | X86_CR4_LA57).root_level(), 5);
+        // a page table entry in the root table spans 512 GiB or 256 TiB
+        assert_eq!(1u64 << get_shift(4), 512 << 30);
+        assert_eq!(1u64 << get_shift(5), 256 << 40);
+        let kernel_text = 0xffff_ffff_8100_0000;
+        assert_eq!(get_index(kernel_text, 5), 0x1ff);
+        assert_eq!(get_index(kernel_text, 4), 0x1ff);
+        assert_eq!(get_index(kernel_text, 3), 0x1fe);
+        assert_eq!(get_index(kernel_text, 1), 0);
+    }
+
+    #[test]
+    fn test_canonical() {
+        let paging = Paging::FourLevel;
+        assert!(paging.is_canonical(0x7fff_ffff_f000));
+        assert!(paging.is_canonical(0xffff_8000_0000_0000));
+        assert!(!paging.is_canonical(0x8000_0000_0000));
+        assert!(!paging.is_canonical(0xff00_8000_0000_0000));
+        assert!(Paging::FiveLevel.is_canonical(0xff00_0000_0000_0000));
+    }
 }
@@ -0,0 +1,217 @@
+/// Bookkeeping of attached and detached vmsh processes, so that `vmsh detach` finds the process
+/// to stop and `vmsh reattach` can take over the devices it left behind in the VM.
+use log::debug;
+use nix::sys::signal::kill;
+use nix::unistd::{geteuid, Pid};
+use serde::{Deserialize, Serialize};
+use simple_error::{bail, require_with, try_with};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use crate::attach::{BackingFile, SharedDir};
+use crate::devices::virtio::state::VirtioState;
+use crate::devices::virtio::vsock::VsockOptions;
+use crate::kvm::hypervisor::{self, VmSelector};
+use crate::kvm::AllocatorState;
+use crate::result::Result;
+use crate::stage1::Stage1State;
+use crate::tracer::proc::openpid;
+
+/// Sessions hold addresses in the hypervisor that vmsh writes to on reattach, so nobody else
+/// may write to this directory.
+const SESSION_DIR: &str = "/run/vmsh";
+
+/// Devices of the session, created again with the same options on reattach.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDevices {
+    pub backing: Vec<BackingFile>,
+    pub share: Option<SharedDir>,
+    pub vsock: Option<VsockOptions>,
+    pub irq_num: usize,
+}
+
+/// What a detached vmsh process left behind in the VM.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetachedState {
+    pub allocator: AllocatorState,
+    pub stage1: Stage1State,
+    pub virtio: Vec<VirtioState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    /// vmsh process serving the devices, None while detached
+    pub vmsh_pid: Option<i32>,
+    pub devices: SessionDevices,
+    /// only set while detached
+    pub detached: Option<DetachedState>,
+}
+
+fn session_dir() -> PathBuf {
+    PathBuf::from(SESSION_DIR)
+}
+
+/// Refuses a session directory that others could write to. Returns false if there is none.
+fn check_session_dir() -> Result<bool> {
+    let dir = session_dir();
+    let md = match fs::symlink_metadata(&dir) {
+        Ok(md) => md,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => bail!("cannot stat {}: {}", dir.display(), e),
+    };
+    let uid = geteuid().as_raw();
+    if !md.is_dir() || (md.uid() != 0 && md.uid() != uid) || md.mode() & 0o777 != 0o700 {
+        bail!(
+            "refuse to use {}: it must be a directory owned by root or uid {} with mode 0700",
+            dir.display(),
+            uid
+        );
+    }
+    Ok(true)
+}
+
+fn create_session_dir() -> Result<()> {
+    let dir = session_dir();
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => bail!("cannot create {}: {}", dir.display(), e),
+    }
+    check_session_dir()?;
+    Ok(())
+}
+
+/// Location of the session of the VM `vm_fd` in the hypervisor `pid`.
+pub fn session_path(pid: Pid, vm_fd: RawFd) -> PathBuf {
+    session_dir().join(format!("{}-{}.json", pid, vm_fd))
+}
+
+/// Finds the session of a VM without stopping the hypervisor, which might be traced by the
+/// vmsh process of the session.
+pub fn find_session(pid: Pid, vm: Option<VmSelector>) -> Result<PathBuf> {
+    let vm_fd = match vm {
+        Some(VmSelector::Fd(fd)) => fd,
+        Some(VmSelector::Index(idx)) => {
+            let vms = hypervisor::list_vms(pid)?;
+            require_with!(vms.get(idx), "vm index {} out of range", idx).vm_fd
+        }
+        None => {
+            let prefix = format!("{}-", pid);
+            if !check_session_dir()? {
+                bail!("no vmsh session found for process {}", pid)
+            }
+            let entries = match fs::read_dir(session_dir()) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    bail!("no vmsh session found for process {}", pid)
+                }
+                Err(e) => bail!("cannot read {}: {}", session_dir().display(), e),
+            };
+            let mut paths = vec![];
+            for entry in entries {
+                let entry = try_with!(entry, "cannot read {}", session_dir().display());
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with(&prefix) && name.ends_with(".json") {
+                    paths.push(entry.path());
+                }
+            }
+            match paths.len() {
+                0 => bail!("no vmsh session found for process {}", pid),
+                1 => return Ok(paths.remove(0)),
+                n => bail!(
+                    "found {} vmsh sessions for process {}, select the vm with --vm fd:<num>",
+                    n,
+                    pid
+                ),
+            }
+        }
+    };
+    Ok(session_path(pid, vm_fd))
+}
+
+impl DetachedState {
+    /// Checks that the hypervisor addresses of the session are mapped in the hypervisor `pid`.
+    pub fn check(&self, pid: Pid) -> Result<()> {
+        let handle = try_with!(openpid(pid), "cannot open handle in proc");
+        let maps = try_with!(handle.maps(), "cannot read process maps");
+        for addr in self.stage1.host_addrs() {
+            if !maps.iter().any(|m| m.start <= addr && addr < m.end) {
+                bail!(
+                    "session refers to address {:#x}, which is not mapped in process {}",
+                    addr,
+                    pid
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Returns None if there is no session or the vmsh process of the session died.
+    pub fn load(path: &Path) -> Result<Option<Session>> {
+        if !check_session_dir()? {
+            return Ok(None);
+        }
+        let mut content = vec![];
+        let res = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .and_then(|mut f| f.read_to_end(&mut content));
+        match res {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!("cannot read session {}: {}", path.display(), e),
+        };
+        let session: Session = try_with!(
+            serde_json::from_slice(&content),
+            "cannot parse session {}",
+            path.display()
+        );
+        if let Some(pid) = session.vmsh_pid {
+            if kill(Pid::from_raw(pid), None).is_err() {
+                debug!("remove stale session {} of pid {}", path.display(), pid);
+                Self::remove(path)?;
+                return Ok(None);
+            }
+        }
+        Ok(Some(session))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        create_session_dir()?;
+        let content = try_with!(serde_json::to_vec(self), "cannot serialize session");
+        // the rename makes sure nobody reads a partially written session
+        let tmp = path.with_extension("tmp");
+        // left behind if vmsh crashed while saving
+        Self::remove(&tmp)?;
+        let res = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&tmp)
+            .and_then(|mut f| f.write_all(&content));
+        try_with!(res, "cannot write {}", tmp.display());
+        try_with!(
+            fs::rename(&tmp, path),
+            "cannot rename {} to {}",
+            tmp.display(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    pub fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => bail!("cannot remove session {}: {}", path.display(), e),
+        }
+    }
+}
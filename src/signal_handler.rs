@@ -1,44 +1,53 @@
-use std::sync::{mpsc::SyncSender, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::info;
 use nix::sys::signal;
 use simple_error::try_with;
 
+use crate::control::Control;
 use crate::result::Result;
 
 lazy_static! {
-    static ref SIGNAL_SENDER: Mutex<Option<SyncSender<()>>> = Mutex::new(None);
-}
-
-fn _stop_vmsh(is_signal: bool) {
-    let sender = match SIGNAL_SENDER.lock().expect("cannot lock sender").take() {
-        Some(s) => {
-            info!("shutdown vmsh");
-            s
-        }
-        None => {
-            if is_signal {
-                info!("received sigterm. stopping already in progress");
-            }
-            return;
-        }
+    static ref CONTROL: Mutex<Option<Arc<Control>>> = Mutex::new(None);
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn _stop_vmsh(detach: bool) {
+    let guard = CONTROL.lock().expect("cannot lock control");
+    let control = match guard.as_ref() {
+        Some(control) => control,
+        None => return,
     };
-    if let Err(e) = sender.send(()) {
-        error!("cannot notify main process: {}", e);
+    let stopping = if detach {
+        control.detach()
+    } else {
+        control.stop()
+    };
+    if stopping {
+        info!("shutdown vmsh");
+    } else {
+        info!("received sigterm. stopping already in progress");
     }
 }
 
-pub fn stop_vmsh() {
+extern "C" fn signal_handler(_: ::libc::c_int) {
     _stop_vmsh(false);
 }
 
-extern "C" fn signal_handler(_: ::libc::c_int) {
+extern "C" fn detach_handler(_: ::libc::c_int) {
     _stop_vmsh(true);
 }
 
-pub fn setup(sender: &SyncSender<()>) -> Result<()> {
-    try_with!(SIGNAL_SENDER.lock(), "cannot get lock").replace(sender.clone());
+extern "C" fn interrupt_handler(_: ::libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Stops `control` on SIGINT and SIGTERM.
+pub fn setup(control: &Arc<Control>) -> Result<()> {
+    try_with!(CONTROL.lock(), "cannot get lock").replace(Arc::clone(control));
 
     let sig_action = signal::SigAction::new(
         signal::SigHandler::Handler(signal_handler),
@@ -58,3 +67,45 @@ pub fn setup(sender: &SyncSender<()>) -> Result<()> {
     }
     Ok(())
 }
+
+/// Lets SIGUSR1 (`vmsh detach`) detach the control passed to `setup`.
+pub fn setup_detach() -> Result<()> {
+    let sig_action = signal::SigAction::new(
+        signal::SigHandler::Handler(detach_handler),
+        signal::SaFlags::empty(),
+        signal::SigSet::empty(),
+    );
+    unsafe {
+        try_with!(
+            signal::sigaction(signal::SIGUSR1, &sig_action),
+            "unable to register SIGUSR1 handler"
+        );
+    }
+    Ok(())
+}
+
+/// Lets SIGINT and SIGTERM set a flag instead of killing vmsh, for commands that have to undo
+/// changes to the hypervisor before they exit. They check it with `interrupted`.
+pub fn setup_interrupt() -> Result<()> {
+    let sig_action = signal::SigAction::new(
+        signal::SigHandler::Handler(interrupt_handler),
+        signal::SaFlags::empty(),
+        signal::SigSet::empty(),
+    );
+    unsafe {
+        try_with!(
+            signal::sigaction(signal::SIGINT, &sig_action),
+            "unable to register SIGINT handler"
+        );
+        try_with!(
+            signal::sigaction(signal::SIGTERM, &sig_action),
+            "unable to register SIGTERM handler"
+        );
+    }
+    Ok(())
+}
+
+/// True after SIGINT or SIGTERM if `setup_interrupt` was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
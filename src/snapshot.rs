@@ -0,0 +1,502 @@
+//! Snapshots of a running VM in the ELF format of `coredump`, with only short pauses.
+//!
+//! KVM logs the pages the guest writes to while the memory is copied with the VM running.
+//! Dirty pages are copied again in rounds until only a few are left. The last ones are copied
+//! together with the vcpu state in a final pause, so memory and registers are from the same
+//! point in time. Memory the hypervisor itself writes to, e.g. by emulating DMA of devices, is not
+//! logged by KVM. With `SnapshotOptions::soft_dirty` those pages are found with the soft-dirty bits
+//! of the page tables of the hypervisor in /proc/<pid>/pagemap and copied again as well. Clearing
+//! the bits affects the whole hypervisor process, so this is opt-in: it breaks other users of
+//! the bits such as CRIU.
+//!
+//! The memslot flags are restored on every exit, including errors and SIGINT, unless the
+//! hypervisor changed the memslots in the meantime.
+use kvm_bindings as kvmb;
+use log::{debug, info, warn};
+use nix::unistd::Pid;
+use simple_error::{bail, try_with};
+use std::cmp::min;
+use std::fs::{self, File, OpenOptions};
+use std::mem::size_of;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::coredump::{
+    any_as_bytes, elf_header, is_zero, pt_load_header, pt_note_header, read_hypervisor,
+    write_note_sections, VcpuState, DUMP_CHUNK_SIZE,
+};
+use crate::elf::{Ehdr, Elf_Half, Elf_Off, Phdr};
+use crate::kvm;
+use crate::kvm::hypervisor::memory::HvBuffer;
+use crate::kvm::hypervisor::{Hypervisor, VmSelector};
+use crate::kvm::ioctls;
+use crate::kvm::memslots::{MemSlot, MemSlotReader};
+use crate::page_math::{page_align, page_size};
+use crate::result::Result;
+use crate::signal_handler;
+use crate::tracer::proc::Mapping;
+use crate::vmcoreinfo;
+
+/// Less dirty pages than this are left for the final pause.
+const CONVERGED_PAGES: usize = 256;
+
+/// Set in /proc/<pid>/pagemap entries of pages written since the last reset via clear_refs.
+const PM_SOFT_DIRTY: u64 = 1 << 55;
+const PAGEMAP_ENTRY_SIZE: usize = size_of::<u64>();
+/// Written to /proc/<pid>/clear_refs to reset the soft-dirty bits
+const CLEAR_REFS_SOFT_DIRTY: &str = "4";
+
+pub struct SnapshotOptions {
+    pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub path: PathBuf,
+    /// rounds of copying dirty pages with the VM running before the final pause
+    pub max_rounds: usize,
+    /// also track writes of the hypervisor with the soft-dirty bits of its whole address space
+    pub soft_dirty: bool,
+}
+
+/// A memslot and the place of its memory in the core file
+struct Slot {
+    slot: MemSlot,
+    map: Mapping,
+    /// file offset of the PT_LOAD segment
+    offset: u64,
+    /// Bitmap for KVM_GET_DIRTY_LOG. Slots without one are copied again in the final pause.
+    bitmap: Option<HvBuffer>,
+}
+
+/// Ranges of page indices in a memslot
+type Runs = Vec<Range<usize>>;
+
+/// Slots with dirty page logging. Dropping it stops the VM to restore their flags, so also errors
+/// and interrupts leave the memslots as they were.
+struct DirtyLog<'a> {
+    vm: &'a Hypervisor,
+    slots: Vec<Slot>,
+}
+
+impl DirtyLog<'_> {
+    /// Restores the flags of the slots and resumes the VM.
+    fn stop(&mut self) -> Result<()> {
+        self.vm.stop()?;
+        stop_dirty_log(self.vm, &mut self.slots);
+        self.vm.resume()
+    }
+}
+
+impl Drop for DirtyLog<'_> {
+    fn drop(&mut self) {
+        if self.slots.iter().all(|s| s.bitmap.is_none()) {
+            return;
+        }
+        if let Err(e) = self.stop() {
+            warn!("cannot restore the flags of the memslots: {}", e);
+        }
+    }
+}
+
+/// Ranges of page indices set in `bitmap`.
+fn dirty_runs(bitmap: &[u8], npages: usize) -> Runs {
+    let mut runs: Runs = vec![];
+    for page in 0..npages {
+        if bitmap[page / 8] & (1 << (page % 8)) == 0 {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if run.end == page => run.end += 1,
+            _ => runs.push(page..page + 1),
+        }
+    }
+    runs
+}
+
+fn count_pages(runs: &[Range<usize>]) -> usize {
+    runs.iter().map(|r| r.len()).sum()
+}
+
+/// Copies the pages in `runs` of a slot into the core file. With `sparse` zero pages are
+/// skipped, which is only correct if nothing was written there before.
+fn copy_pages(pid: Pid, file: &File, s: &Slot, runs: &[Range<usize>], sparse: bool) -> Result<()> {
+    let mut buf = vec![0; DUMP_CHUNK_SIZE];
+    for run in runs {
+        let mut offset = run.start * page_size();
+        let end = run.end * page_size();
+        while offset < end {
+            if signal_handler::interrupted() {
+                bail!("snapshot was interrupted");
+            }
+            let len = min(buf.len(), end - offset);
+            read_hypervisor(pid, s.map.start + offset, &mut buf[..len])?;
+            for (i, page) in buf[..len].chunks(page_size()).enumerate() {
+                if sparse && is_zero(page) {
+                    continue;
+                }
+                let file_offset = s.offset + (offset + i * page_size()) as u64;
+                try_with!(
+                    file.write_all_at(page, file_offset),
+                    "cannot write core file"
+                );
+            }
+            offset += len;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the bits of the pages in `bitmap` that the hypervisor wrote to since the soft-dirty bits
+/// were cleared.
+fn add_soft_dirty(pagemap: &File, s: &Slot, bitmap: &mut [u8]) -> Result<()> {
+    let first = s.map.start / page_size();
+    let mut entries = vec![0; DUMP_CHUNK_SIZE];
+    let mut page = 0;
+    while page < s.slot.npages() {
+        let len = min(entries.len(), (s.slot.npages() - page) * PAGEMAP_ENTRY_SIZE);
+        try_with!(
+            pagemap.read_exact_at(
+                &mut entries[..len],
+                ((first + page) * PAGEMAP_ENTRY_SIZE) as u64
+            ),
+            "cannot read pagemap of hypervisor"
+        );
+        for entry in entries[..len].chunks(PAGEMAP_ENTRY_SIZE) {
+            let mut raw = [0; PAGEMAP_ENTRY_SIZE];
+            raw.copy_from_slice(entry);
+            if u64::from_ne_bytes(raw) & PM_SOFT_DIRTY != 0 {
+                bitmap[page / 8] |= 1 << (page % 8);
+            }
+            page += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Without CONFIG_MEM_SOFT_DIRTY the bit is never set. Otherwise it is for the pages of vmsh,
+/// which does not clear its own.
+fn soft_dirty_supported() -> Result<bool> {
+    let pagemap = try_with!(File::open("/proc/self/pagemap"), "cannot open own pagemap");
+    let page = [1u8];
+    let mut entry = [0; PAGEMAP_ENTRY_SIZE];
+    try_with!(
+        pagemap.read_exact_at(
+            &mut entry,
+            (page.as_ptr() as usize / page_size() * PAGEMAP_ENTRY_SIZE) as u64
+        ),
+        "cannot read own pagemap"
+    );
+    Ok(u64::from_ne_bytes(entry) & PM_SOFT_DIRTY != 0)
+}
+
+fn clear_soft_dirty(pid: Pid) -> Result<()> {
+    let path = format!("/proc/{}/clear_refs", pid);
+    try_with!(
+        fs::write(&path, CLEAR_REFS_SOFT_DIRTY),
+        "cannot clear soft-dirty bits via {}",
+        path
+    );
+    Ok(())
+}
+
+/// Pages of every slot that KVM logged or the hypervisor wrote to since the last call, `None`
+/// for slots that are not tracked. Writes of the hypervisor are only found with its `pagemap`.
+/// Requires the VM to be stopped.
+fn fetch_dirty(
+    vm: &Hypervisor,
+    slots: &[Slot],
+    clear: bool,
+    pagemap: Option<&File>,
+) -> Result<Vec<Option<Runs>>> {
+    let mut dirty = vec![];
+    for s in slots {
+        let bitmap = match &s.bitmap {
+            Some(bitmap) => bitmap,
+            None => {
+                dirty.push(None);
+                continue;
+            }
+        };
+        let mut log = vm.get_dirty_log(&s.slot, bitmap)?;
+        if clear {
+            vm.clear_dirty_log(&s.slot, bitmap)?;
+        }
+        if let Some(pagemap) = pagemap {
+            add_soft_dirty(pagemap, s, &mut log)?;
+        }
+        dirty.push(Some(dirty_runs(&log, s.slot.npages())));
+    }
+    if pagemap.is_some() {
+        clear_soft_dirty(vm.pid)?;
+    }
+    Ok(dirty)
+}
+
+/// Enables dirty page logging for all slots the guest can write to, unless the hypervisor
+/// already does so: both would consume the same log.
+fn start_dirty_log(
+    vm: &Hypervisor,
+    slots: &mut [Slot],
+    clear: bool,
+    pagemap: Option<&File>,
+) -> Result<()> {
+    for s in slots.iter_mut() {
+        let flags = s.slot.flags();
+        if flags & kvmb::KVM_MEM_LOG_DIRTY_PAGES != 0 {
+            warn!(
+                "hypervisor logs dirty pages of memslot {} itself, copy it in the final pause",
+                s.slot.id()
+            );
+            continue;
+        }
+        if flags & kvmb::KVM_MEM_READONLY != 0 {
+            continue;
+        }
+        let bitmap = vm.alloc_buffer(s.slot.dirty_bitmap_len())?;
+        vm.set_memslot_flags(&s.slot, flags | kvmb::KVM_MEM_LOG_DIRTY_PAGES)?;
+        s.bitmap = Some(bitmap);
+    }
+    // with KVM_DIRTY_LOG_INITIALLY_SET all pages start dirty
+    fetch_dirty(vm, slots, clear, pagemap)?;
+    Ok(())
+}
+
+/// Fails if the hypervisor changed the memslots since the snapshot started. The dirty logging of
+/// changed slots is left alone, their flags are no longer ours to restore.
+/// Requires the VM to be stopped.
+fn check_memslots(slots: &mut [Slot], current: &[(MemSlot, Mapping)]) -> Result<()> {
+    let mut changed = current.len() != slots.len();
+    for s in slots.iter_mut() {
+        let mut flags = s.slot.flags();
+        if s.bitmap.is_some() {
+            flags |= kvmb::KVM_MEM_LOG_DIRTY_PAGES;
+        }
+        let unchanged = current.iter().any(|(c, _)| {
+            c.id() == s.slot.id()
+                && c.physical_start() == s.slot.physical_start()
+                && c.size() == s.slot.size()
+                && c.start() == s.slot.start()
+                && c.flags() == flags
+        });
+        if !unchanged {
+            warn!("memslot {} changed during the snapshot", s.slot.id());
+            s.bitmap = None;
+            changed = true;
+        }
+    }
+    if changed {
+        bail!("the hypervisor changed the memslots during the snapshot");
+    }
+    Ok(())
+}
+
+/// Restores the flags of the slots. Requires the VM to be stopped.
+fn stop_dirty_log(vm: &Hypervisor, slots: &mut [Slot]) {
+    for s in slots.iter_mut() {
+        if s.bitmap.take().is_none() {
+            continue;
+        }
+        if let Err(e) = vm.set_memslot_flags(&s.slot, s.slot.flags()) {
+            warn!(
+                "cannot disable dirty logging of memslot {}: {}",
+                s.slot.id(),
+                e
+            );
+        }
+    }
+}
+
+/// Copies all memory and then dirty pages in rounds while the VM runs.
+fn precopy(
+    vm: &Hypervisor,
+    file: &File,
+    slots: &[Slot],
+    clear: bool,
+    pagemap: Option<&File>,
+    rounds: usize,
+) -> Result<()> {
+    for s in slots {
+        copy_pages(vm.pid, file, s, &[0..s.slot.npages()], true)?;
+    }
+    for round in 0..rounds {
+        vm.stop()?;
+        let dirty = fetch_dirty(vm, slots, clear, pagemap);
+        vm.resume()?;
+        let dirty = dirty?;
+        let mut pages = 0;
+        for (s, runs) in slots.iter().zip(dirty) {
+            if let Some(runs) = runs {
+                pages += count_pages(&runs);
+                copy_pages(vm.pid, file, s, &runs, false)?;
+            }
+        }
+        debug!("copied {} dirty pages in round {}", pages, round);
+        if pages < CONVERGED_PAGES {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Copies what is left with the VM stopped and captures the vcpus.
+fn final_copy(
+    vm: &Hypervisor,
+    file: &File,
+    slots: &[Slot],
+    clear: bool,
+    pagemap: Option<&File>,
+) -> Result<Vec<VcpuState>> {
+    let dirty = fetch_dirty(vm, slots, clear, pagemap)?;
+    let mut pages = 0;
+    for (s, runs) in slots.iter().zip(dirty) {
+        let runs = runs.unwrap_or_else(|| vec![0..s.slot.npages()]);
+        pages += count_pages(&runs);
+        copy_pages(vm.pid, file, s, &runs, false)?;
+    }
+    info!("copied {} pages in the final pause", pages);
+    let res = vm
+        .vcpus
+        .iter()
+        .map(|vcpu| VcpuState::new(vcpu, vm))
+        .collect::<Result<Vec<VcpuState>>>();
+    Ok(try_with!(res, "fail to dump vcpu registers"))
+}
+
+/// Writes the ELF and program headers at the start of the file and the notes after the memory.
+fn write_headers(file: &File, slots: &[Slot], notes: &[u8], notes_offset: u64) -> Result<()> {
+    let ehdr = elf_header((slots.len() + 1) as Elf_Half);
+    let mut headers = vec![pt_note_header(notes_offset, notes.len() as Elf_Off)];
+    headers.extend(slots.iter().map(|s| pt_load_header(&s.map, s.offset)));
+    let mut metadata = unsafe { any_as_bytes(&ehdr) }.to_vec();
+    for h in &headers {
+        metadata.extend_from_slice(unsafe { any_as_bytes(h) });
+    }
+    try_with!(file.write_all_at(&metadata, 0), "cannot write elf header");
+    try_with!(
+        file.write_all_at(notes, notes_offset),
+        "cannot write elf notes"
+    );
+    Ok(())
+}
+
+pub fn snapshot(opts: &SnapshotOptions) -> Result<()> {
+    let file = try_with!(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&opts.path),
+        "cannot open snapshot file: {}",
+        opts.path.display()
+    );
+    let vm = try_with!(
+        kvm::hypervisor::select_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    // building the bpf programs takes a while, do it before the VM is stopped
+    let reader = try_with!(
+        MemSlotReader::new(opts.pid),
+        "snapshots need bpf to read the memslots of the VM"
+    );
+    let final_reader = MemSlotReader::new(opts.pid)?;
+    let pagemap = if opts.soft_dirty {
+        let pagemap_path = format!("/proc/{}/pagemap", opts.pid);
+        let pagemap = try_with!(File::open(&pagemap_path), "cannot open {}", pagemap_path);
+        if !soft_dirty_supported()? {
+            warn!("kernel has no soft-dirty bits, memory the hypervisor writes may be older than the vcpu state");
+        }
+        Some(pagemap)
+    } else {
+        info!("memory the hypervisor writes may be older than the vcpu state, see --soft-dirty");
+        None
+    };
+    signal_handler::setup_interrupt()?;
+
+    // the kernel does not move, collect what debuggers need to find it without delaying the
+    // dirty logging
+    vm.stop()?;
+    let vmcoreinfo = match vmcoreinfo::collect(&vm) {
+        Ok(info) => Some(info.to_note()),
+        Err(e) => {
+            warn!("cannot collect VMCOREINFO, omit it from snapshot: {}", e);
+            None
+        }
+    };
+    vm.resume()?;
+
+    let mut pause = Instant::now();
+    vm.stop()?;
+    let memslots = vm.get_memslots(reader)?;
+    let phnum = memslots.len() + 1;
+    if phnum > Elf_Half::MAX as usize {
+        bail!("too many program headers: {}", phnum);
+    }
+    let mut offset = page_align(size_of::<Ehdr>() + phnum * size_of::<Phdr>()) as u64;
+    let mut log = DirtyLog {
+        vm: &vm,
+        slots: vec![],
+    };
+    for (slot, map) in memslots {
+        let size = map.size() as u64;
+        log.slots.push(Slot {
+            slot,
+            map,
+            offset,
+            bitmap: None,
+        });
+        offset += size;
+    }
+    // Without KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 there is no KVM_CLEAR_DIRTY_LOG, if the
+    // hypervisor did not enable it KVM_GET_DIRTY_LOG clears the log already.
+    let clear = vm.check_extension(ioctls::KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 as i32)? > 0;
+
+    start_dirty_log(&vm, &mut log.slots, clear, pagemap.as_ref())?;
+    vm.resume()?;
+    info!(
+        "VM was paused for {:?} to start dirty logging",
+        pause.elapsed()
+    );
+    precopy(
+        &vm,
+        &file,
+        &log.slots,
+        clear,
+        pagemap.as_ref(),
+        opts.max_rounds,
+    )?;
+
+    pause = Instant::now();
+    vm.stop()?;
+    let memslots = vm.get_memslots(final_reader)?;
+    check_memslots(&mut log.slots, &memslots)?;
+    let vcpus = final_copy(&vm, &file, &log.slots, clear, pagemap.as_ref())?;
+    log.stop()?;
+    info!("VM was paused for {:?} for the final copy", pause.elapsed());
+
+    let mut notes = vec![];
+    write_note_sections(&mut notes, &vcpus, vmcoreinfo.as_deref())?;
+    write_headers(&file, &log.slots, &notes, offset)?;
+    try_with!(
+        file.set_len(offset + notes.len() as u64),
+        "cannot truncate snapshot file"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_runs() {
+        let bitmap = [0b1000_0110, 0b0000_0001, 0b1000_0000];
+        assert_eq!(dirty_runs(&bitmap, 24), vec![1..3, 7..9, 23..24]);
+        // bits after the last page are ignored
+        assert_eq!(dirty_runs(&bitmap, 20), vec![1..3, 7..9]);
+        assert_eq!(count_pages(&dirty_runs(&bitmap, 24)), 5);
+        assert_eq!(dirty_runs(&[0; 8], 64), vec![]);
+    }
+}
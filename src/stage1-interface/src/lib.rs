@@ -3,9 +3,9 @@
 use chlorine::{c_char, c_ulonglong};
 
 /// Holds the device we create by this code, so we can unregister it later
-pub const MAX_DEVICES: usize = 3;
+pub const MAX_DEVICES: usize = 8;
 pub const MAX_ARGV: usize = 256;
-/// ideally we could have our own IRQ here... 6 seems so far shareable with other devices
+pub const MAX_ENVP: usize = 16;
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 #[repr(C)]
@@ -24,7 +24,9 @@ pub struct Stage1Args {
     /// null terminated array
     /// the first argument is always stage2_path, the actual arguments come after
     pub argv: [*mut c_char; MAX_ARGV],
-    /// HACK we need to set IRQs depending on the hypervisor
+    /// null terminated array of `KEY=VALUE` strings passed as environment to stage2
+    pub envp: [*mut c_char; MAX_ENVP],
+    /// interrupt line of our devices, chosen by vmsh to not collide with guest devices
     pub irq_num: usize,
     pub device_status: DeviceState,
     pub driver_status: DeviceState,
@@ -1,6 +1,7 @@
 use crate::cpu::Regs;
 use libc::c_void;
 use log::{debug, info};
+use serde::{Deserialize, Serialize};
 /// This module loads kernel code into the VM that we want to attach to.
 use simple_error::bail;
 use simple_error::try_with;
@@ -15,19 +16,19 @@ use crate::kernel::find_kernel;
 use crate::kvm;
 use crate::kvm::hypervisor::{memory::process_read, memory::process_write, Hypervisor};
 use crate::loader::Loader;
-use crate::page_table::VirtMem;
+use crate::page_table::{VirtMem, VirtMemState};
 use crate::result::Result;
 
 const STAGE1_LIB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libstage1.so"));
 
 pub struct Stage1 {
-    #[allow(unused)]
     virt_mem: VirtMem,
     pub device_status: Option<DeviceStatus>,
     pub driver_status: Option<DriverStatus>,
     regs: Regs,
 }
 
+#[derive(Clone)]
 pub struct DeviceStatus {
     pub host_addr: usize,
 }
@@ -53,10 +54,46 @@ impl DriverStatus {
     }
 }
 
+/// Stage1 left running in the VM by a detached vmsh process.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stage1State {
+    virt_mem: VirtMemState,
+    device_status: usize,
+    driver_status: usize,
+}
+
+impl Stage1State {
+    pub fn device_status(&self) -> DeviceStatus {
+        DeviceStatus {
+            host_addr: self.device_status,
+        }
+    }
+
+    pub fn driver_status(&self) -> DriverStatus {
+        DriverStatus {
+            host_addr: self.driver_status,
+        }
+    }
+
+    /// Addresses in the hypervisor that are accessed to restore stage1
+    pub fn host_addrs(&self) -> Vec<usize> {
+        let mut addrs = self.virt_mem.host_addrs().to_vec();
+        addrs.push(self.device_status);
+        addrs.push(self.driver_status);
+        addrs
+    }
+
+    /// Takes back the memory of stage1 to free it on drop, once stage1 has terminated.
+    pub fn reclaim(&self, hv: Arc<Hypervisor>) -> Result<VirtMem> {
+        VirtMem::reclaim(hv, &self.virt_mem)
+    }
+}
+
 impl Stage1 {
     pub fn new(
-        mut allocator: kvm::PhysMemAllocator,
+        allocator: &mut kvm::PhysMemAllocator,
         command: &[String],
+        environment: &[String],
         irq_num: usize,
         mmio_ranges: Vec<u64>,
     ) -> Result<Stage1> {
@@ -68,14 +105,14 @@ impl Stage1 {
         );
 
         let mut loader = try_with!(
-            Loader::new(STAGE1_LIB, &kernel, regs.ip() as usize, &mut allocator),
+            Loader::new(STAGE1_LIB, &kernel, regs.ip() as usize, allocator),
             "cannot load stage1"
         );
 
         let init_func = loader.init_func;
 
         let (virt_mem, device_status, driver_status) = try_with!(
-            loader.load_binary(command, irq_num, mmio_ranges),
+            loader.load_binary(command, environment, irq_num, mmio_ranges),
             "cannot load stage1"
         );
 
@@ -98,6 +135,16 @@ impl Stage1 {
         })
     }
 
+    /// Leaves stage1 running in the VM when vmsh detaches. Takes the status fields handed out
+    /// with `device_status` and `driver_status`.
+    pub fn detach(self, device_status: &DeviceStatus, driver_status: &DriverStatus) -> Stage1State {
+        Stage1State {
+            virt_mem: self.virt_mem.into_raw(),
+            device_status: device_status.host_addr,
+            driver_status: driver_status.host_addr,
+        }
+    }
+
     pub fn spawn(
         &self,
         hv: Arc<Hypervisor>,
@@ -11,7 +11,7 @@ use core::ptr;
 use core::str;
 use ffi::resource;
 use ffi::ssize_t;
-use stage1_interface::{DeviceState, Stage1Args, MAX_ARGV, MAX_DEVICES};
+use stage1_interface::{DeviceState, Stage1Args, MAX_ARGV, MAX_DEVICES, MAX_ENVP};
 
 use chlorine::{c_char, c_int, c_long, c_uint, c_void, size_t};
 use ffi::loff_t;
@@ -27,6 +27,7 @@ const STAGE2_EXE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/stage2"));
 static mut VMSH_STAGE1_ARGS: Stage1Args = Stage1Args {
     device_addrs: [0; MAX_DEVICES],
     argv: [ptr::null_mut(); MAX_ARGV],
+    envp: [ptr::null_mut(); MAX_ENVP],
     irq_num: 0,
     device_status: DeviceState::Undefined,
     driver_status: DeviceState::Undefined,
@@ -391,7 +392,8 @@ fn usleep_range(min: c_ulong, max: c_ulong) {
 }
 
 // cannot put this onto the stack without stackoverflows?
-static mut DEVICES: [Option<PlatformDevice>; MAX_DEVICES] = [None, None, None];
+const NO_DEVICE: Option<PlatformDevice> = None;
+static mut DEVICES: [Option<PlatformDevice>; MAX_DEVICES] = [NO_DEVICE; MAX_DEVICES];
 
 unsafe fn run_stage2() -> Result<(), ()> {
     let version = get_kernel_version()?;
@@ -492,13 +494,11 @@ unsafe fn run_stage2() -> Result<(), ()> {
     }
     drop(file);
 
-    let mut envp: [*mut c_char; 1] = [ptr::null_mut()];
-
     loop {
         let res = ffi::call_usermodehelper(
             VMSH_STAGE1_ARGS.argv[0],
             VMSH_STAGE1_ARGS.argv.as_mut_ptr(),
-            envp.as_mut_ptr(),
+            VMSH_STAGE1_ARGS.envp.as_mut_ptr(),
             ffi::UMH_WAIT_EXEC,
         );
         if res == -ffi::ETXTBSY {
@@ -9,12 +9,15 @@ use std::os::unix::prelude::{AsRawFd, FromRawFd};
 use std::path::PathBuf;
 use std::{fs, path::Path};
 
+use crate::exec;
 use crate::procfs;
 use crate::result::Result;
 use crate::sys_ext::mknodat;
 
 pub struct BlockDevice {
     dev_type: libc::dev_t,
+    /// set by the kernel if vmsh advertises the device as read-only
+    read_only: bool,
 }
 
 pub struct DeviceFile {
@@ -58,7 +61,7 @@ impl Drop for DeviceFile {
             "vmsh-blk",
             UnlinkatFlags::NoRemoveDir,
         ) {
-            eprintln!("cannot remove temporary block device: {}", e);
+            exec::report(&format!("cannot remove temporary block device: {}\n", e));
         }
     }
 }
@@ -106,10 +109,10 @@ fn dump_dmesg() -> Result<()> {
     set_blocking(&file, false)?;
     let reader = BufReader::new(file);
 
-    println!("dmesg:");
+    exec::report("dmesg:\n");
     for line in reader.lines() {
         match line {
-            Ok(line) => println!("{}", &line[3..]),
+            Ok(line) => exec::report(&format!("{}\n", &line[3..])),
             // end of file
             Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
             Err(e) => bail!("error reading: {}", e),
@@ -125,6 +128,11 @@ impl BlockDevice {
             "cannot create block device file"
         );
         let filesystems = try_with!(get_filesystems(), "could not read supported filesystems");
+        let flags = if self.read_only {
+            nix::mount::MsFlags::MS_RDONLY
+        } else {
+            nix::mount::MsFlags::empty()
+        };
         for fs in &filesystems {
             let mount_flags = selinux_context
                 .as_ref()
@@ -135,7 +143,7 @@ impl BlockDevice {
                 Some(&dev_file.path),
                 mountpoint,
                 Some(fs.as_str()),
-                nix::mount::MsFlags::empty(),
+                flags,
                 ref_mount_flags,
             );
             match res {
@@ -143,7 +151,7 @@ impl BlockDevice {
                 Err(Errno::EINVAL) => {}
                 Err(e) => {
                     if let Err(e) = dump_dmesg() {
-                        eprintln!("dmesg failed {}", e);
+                        exec::report(&format!("dmesg failed {}\n", e));
                     }
 
                     bail!(
@@ -162,7 +170,7 @@ impl BlockDevice {
     }
 }
 
-pub fn find_vmsh_blockdev() -> Result<BlockDevice> {
+pub fn find_vmsh_blockdev(serial: &str) -> Result<BlockDevice> {
     let dir = try_with!(
         fs::read_dir("/sys/block"),
         "failed to read /sys/block directory"
@@ -173,7 +181,7 @@ pub fn find_vmsh_blockdev() -> Result<BlockDevice> {
         let serial_path = entry.path().join("serial");
         match fs::read_to_string(&serial_path) {
             // not all block devices implement serial
-            Ok(s) if s == "vmsh0" => s,
+            Ok(s) if s == serial => s,
             _ => continue,
         };
         let dev_path = entry.path().join("dev");
@@ -198,8 +206,18 @@ pub fn find_vmsh_blockdev() -> Result<BlockDevice> {
             splits[1]
         );
         let dev_type = unsafe { libc::makedev(major, minor) };
-        return Ok(BlockDevice { dev_type });
+        let ro_path = entry.path().join("ro");
+        let ro = try_with!(
+            fs::read_to_string(&ro_path),
+            "cannot read read-only flag from {}",
+            ro_path.display()
+        );
+        let read_only = ro.trim_end() == "1";
+        return Ok(BlockDevice {
+            dev_type,
+            read_only,
+        });
     }
 
-    bail!("no vmsh block device found");
+    bail!("no vmsh block device with serial {} found", serial);
 }
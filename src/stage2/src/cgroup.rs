@@ -6,6 +6,7 @@ use std::io::Write;
 use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 
+use crate::exec;
 use crate::procfs;
 use crate::types::{Error, Result};
 
@@ -103,7 +104,7 @@ pub fn move_to(pid: unistd::Pid, target_pid: unistd::Pid) -> Result<()> {
                     );
                 }
                 Err(err) => {
-                    eprintln!("failed to enter {} namespace: {}", cgroup, err);
+                    exec::report(&format!("failed to enter {} namespace: {}\n", cgroup, err));
                 }
             }
         }
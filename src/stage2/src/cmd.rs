@@ -8,6 +8,7 @@ use std::io::{BufRead, BufReader};
 use std::os::unix::ffi::OsStringExt;
 use std::process::Child;
 use std::process::Command;
+use std::process::Stdio;
 
 use crate::procfs;
 use crate::result::Result;
@@ -70,7 +71,8 @@ impl Cmd {
             environment: variables,
         })
     }
-    pub fn spawn(mut self) -> Result<Child> {
+    /// If `capture_output` is set, stdout and stderr are piped and stdin is closed.
+    pub fn spawn(mut self, capture_output: bool) -> Result<Child> {
         let default_path =
             OsString::from("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin");
         self.environment.insert(
@@ -82,10 +84,15 @@ impl Cmd {
             self.environment.insert(OsString::from("HOME"), path);
         }
 
-        let child = Command::new(&self.command)
-            .args(&self.arguments)
-            .envs(self.environment)
-            .spawn();
+        let mut command = Command::new(&self.command);
+        command.args(&self.arguments).envs(self.environment);
+        if capture_output {
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+        }
+        let child = command.spawn();
         Ok(try_with!(
             child,
             "failed to spawn {} {}",
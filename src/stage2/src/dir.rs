@@ -1,5 +1,6 @@
 use std::fs::create_dir_all;
-use std::{io, path::Path};
+use std::io;
+use std::path::{Path, PathBuf};
 
 pub fn mkdir_p<P: AsRef<Path>>(path: &P) -> io::Result<()> {
     if let Err(e) = create_dir_all(path) {
@@ -9,3 +10,19 @@ pub fn mkdir_p<P: AsRef<Path>>(path: &P) -> io::Result<()> {
     }
     Ok(())
 }
+
+/// Like `mkdir_p`, but returns the directories it had to create, the outermost first.
+pub fn mkdir_p_created<P: AsRef<Path>>(path: &P) -> io::Result<Vec<PathBuf>> {
+    let mut missing = vec![];
+    let mut dir = Some(path.as_ref());
+    while let Some(d) = dir {
+        if d.exists() {
+            break;
+        }
+        missing.push(d.to_path_buf());
+        dir = d.parent();
+    }
+    mkdir_p(path)?;
+    missing.reverse();
+    Ok(missing)
+}
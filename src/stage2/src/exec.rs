@@ -0,0 +1,107 @@
+use ioutils::exec::{write_exit, write_frame, FrameKind, EXEC_ENV};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios;
+use nix::unistd;
+use simple_error::{require_with, try_with};
+use std::env;
+use std::io;
+use std::os::unix::prelude::{AsRawFd, ExitStatusExt, RawFd};
+use std::process::Child;
+
+use crate::result::Result;
+
+/// True if we were started by `vmsh exec`
+pub fn enabled() -> bool {
+    env::var_os(EXEC_ENV).is_some()
+}
+
+/// Frames are binary, so the console must not translate any bytes we write.
+pub fn setup_console() -> Result<()> {
+    let mut attrs = try_with!(
+        termios::tcgetattr(libc::STDOUT_FILENO),
+        "cannot get console attributes"
+    );
+    termios::cfmakeraw(&mut attrs);
+    try_with!(
+        termios::tcsetattr(libc::STDOUT_FILENO, termios::SetArg::TCSANOW, &attrs),
+        "cannot put console into raw mode"
+    );
+    Ok(())
+}
+
+fn forward(fd: RawFd, kind: FrameKind, buf: &mut [u8]) -> Result<bool> {
+    let n = match unistd::read(fd, buf) {
+        Ok(n) => n,
+        Err(nix::errno::Errno::EINTR) => return Ok(true),
+        Err(e) => try_with!(Err(e), "failed to read output of command"),
+    };
+    if n == 0 {
+        return Ok(false);
+    }
+    try_with!(
+        write_frame(&mut io::stdout(), kind, &buf[..n]),
+        "failed to forward output of command"
+    );
+    Ok(true)
+}
+
+/// Forwards stdout and stderr of the child as frames to the console until both are closed.
+/// Returns the exit code of the child, signals are reported as 128 + signal number like shells do.
+pub fn forward_output(mut child: Child) -> Result<i32> {
+    let stdout = require_with!(child.stdout.take(), "stdout of command is not captured");
+    let stderr = require_with!(child.stderr.take(), "stderr of command is not captured");
+    let mut streams = vec![
+        (stdout.as_raw_fd(), FrameKind::Stdout),
+        (stderr.as_raw_fd(), FrameKind::Stderr),
+    ];
+    let mut buf = [0u8; 4096];
+
+    while !streams.is_empty() {
+        let mut fds = streams
+            .iter()
+            .map(|(fd, _)| PollFd::new(*fd, PollFlags::POLLIN))
+            .collect::<Vec<_>>();
+        match poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => try_with!(Err(e), "failed to poll output of command"),
+        };
+        let mut open = vec![];
+        for (pollfd, (fd, kind)) in fds.iter().zip(streams.iter()) {
+            let revents = pollfd.revents().unwrap_or_else(PollFlags::empty);
+            if revents.is_empty() || forward(*fd, *kind, &mut buf)? {
+                open.push((*fd, *kind));
+            }
+        }
+        streams = open;
+    }
+
+    let status = try_with!(child.wait(), "failed to wait for child process");
+    Ok(status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
+
+pub fn report_exit(code: i32) -> Result<()> {
+    try_with!(
+        write_exit(&mut io::stdout(), code),
+        "failed to report exit code"
+    );
+    Ok(())
+}
+
+/// Prints a diagnostic of stage2 itself. While `vmsh exec` runs, the console carries frames and
+/// unframed text would corrupt the stream, so it is sent as stderr frame.
+pub fn report(msg: &str) {
+    if enabled() {
+        let _ = write_frame(&mut io::stderr(), FrameKind::Stderr, msg.as_bytes());
+    } else {
+        eprint!("{}", msg);
+    }
+}
+
+/// Reports an error of stage2 itself in a way `vmsh exec` can display.
+pub fn report_error(msg: &str) {
+    report(msg);
+    let _ = report_exit(255);
+}
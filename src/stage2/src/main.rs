@@ -6,21 +6,24 @@ use simple_error::{bail, try_with};
 use std::ffi::OsString;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
 use std::{env, io};
 use user_namespace::IdMap;
 
-use crate::block::find_vmsh_blockdev;
+use crate::block::{find_vmsh_blockdev, BlockDevice};
 use crate::cmd::Cmd;
 use crate::dir::mkdir_p;
+use crate::mountns::RootFs;
 use crate::result::Result;
+use crate::share::Share;
 
 mod block;
 mod capabilities;
 mod cmd;
 mod console;
 mod dir;
+mod exec;
 mod kmsg;
 mod lsm;
 mod mount_context;
@@ -28,6 +31,7 @@ mod mountns;
 mod namespace;
 mod procfs;
 mod result;
+mod share;
 mod sys_ext;
 mod user_namespace;
 
@@ -36,6 +40,8 @@ struct Options {
     command: Option<String>,
     args: Vec<String>,
     home: Option<OsString>,
+    /// frame command output and exit status for `vmsh exec`
+    exec: bool,
 }
 
 fn cleanup_vmsh_exe() {
@@ -118,9 +124,37 @@ fn ensure_devtmpfs() -> Result<()> {
     Ok(())
 }
 
+/// Finds the block devices beside the root device and where to mount them below /var/lib/vmsh.
+fn find_extra_blockdevs() -> Result<Vec<(PathBuf, BlockDevice)>> {
+    let mut devs = vec![];
+    for idx in 1.. {
+        let mountpoint = match env::var_os(ioutils::block::mount_env(idx)) {
+            Some(m) => PathBuf::from(m),
+            None => break,
+        };
+        if !mountpoint
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("invalid mountpoint: {}", mountpoint.display());
+        }
+        let serial = ioutils::block::serial(idx);
+        let dev = try_with!(
+            find_vmsh_blockdev(&serial),
+            "cannot find block device for {}",
+            mountpoint.display()
+        );
+        devs.push((mountpoint, dev));
+    }
+    Ok(devs)
+}
+
 fn run_stage2(opts: &Options) -> Result<()> {
     // get a console to report errors as quick as possible
     try_with!(console::setup(), "failed to setup console");
+    if opts.exec {
+        try_with!(exec::setup_console(), "failed to setup console for exec");
+    }
 
     // cleanup ourself
     cleanup_vmsh_exe();
@@ -130,7 +164,14 @@ fn run_stage2(opts: &Options) -> Result<()> {
     try_with!(ensure_sysfs(), "cannot set up /sys");
     try_with!(ensure_devtmpfs(), "cannot set up /dev");
 
-    let dev = try_with!(find_vmsh_blockdev(), "cannot find block_device");
+    let root = match env::var(ioutils::share::SHARE_ENV) {
+        Ok(tag) => RootFs::Share(Share::new(tag)),
+        Err(_) => RootFs::Block(try_with!(
+            find_vmsh_blockdev(&ioutils::block::serial(0)),
+            "cannot find block_device"
+        )),
+    };
+    let extra_devs = find_extra_blockdevs()?;
 
     let (uid_map, gid_map) = try_with!(
         IdMap::new_from_pid(opts.target_pid),
@@ -206,7 +247,7 @@ fn run_stage2(opts: &Options) -> Result<()> {
 
     try_with!(mount_namespace.apply(), "failed to apply mount namespace");
 
-    let mount_ns = mountns::setup(&dev, mount_namespace, &mount_label)?;
+    let mount_ns = mountns::setup(&root, &extra_devs, mount_namespace, &mount_label)?;
     let dropped_groups = if supported_namespaces.contains(namespace::USER.name) {
         unistd::setgroups(&[]).is_ok()
     } else {
@@ -243,10 +284,14 @@ fn run_stage2(opts: &Options) -> Result<()> {
         opts.home.clone(),
     )?;
 
-    let mut child = cmd.spawn()?;
+    let mut child = cmd.spawn(opts.exec)?;
     // now that we have our child, we can drop temporary mount points
 
     drop(mount_ns);
+    if opts.exec {
+        let code = exec::forward_output(child)?;
+        return exec::report_exit(code);
+    }
     let status = try_with!(child.wait(), "failed to wait for child process");
     eprintln!("process finished with {}", status);
     Ok(())
@@ -255,22 +300,24 @@ fn run_stage2(opts: &Options) -> Result<()> {
 fn main() {
     kmsg_log("[stage2] start\n");
     let args = env::args().collect::<Vec<_>>();
-    let command = if args.len() > 2 {
-        Some(args[1].clone())
-    } else {
-        None
-    };
+    // argv[0] is the path of stage2 itself
+    let command = args.get(1).cloned();
     // TODO
     let opts = Options {
         command,
         target_pid: Pid::from_raw(1),
-        args: (&args[2..]).to_vec(),
+        args: args.get(2..).map(|a| a.to_vec()).unwrap_or_default(),
         home: None,
+        exec: exec::enabled(),
     };
     if let Err(e) = run_stage2(&opts) {
         // print to both allocated pty and kmsg
         kmsg_log(&format!("[stage2] {}\n", e));
-        eprintln!("{}", &e);
+        if opts.exec {
+            exec::report_error(&format!("{}\n", e));
+        } else {
+            eprintln!("{}", &e);
+        }
         exit(1);
     }
 }
@@ -5,25 +5,29 @@ use nix::sys::wait::WaitStatus;
 use nix::unistd::fork;
 use nix::{mount, sched, unistd};
 use nix::{mount::MsFlags, unistd::getpid};
-use simple_error::try_with;
 use simple_error::SimpleError;
+use simple_error::{bail, try_with};
 use std::fs::File;
 use std::fs::{metadata, remove_dir};
 use std::fs::{set_permissions, Permissions};
 use std::io;
 use std::os::unix::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::block::BlockDevice;
-use crate::dir::mkdir_p;
+use crate::dir::{mkdir_p, mkdir_p_created};
+use crate::exec;
 use crate::namespace::{self, MOUNT};
 use crate::result::Result;
+use crate::share::Share;
 
 pub struct MountNamespace {
     new_namespace: namespace::Namespace,
     old_namespace: namespace::Namespace,
     mountpoint: PathBuf,
     temp_mountpoint: PathBuf,
+    /// directories created on the root of the VM for extra block devices, the outermost first
+    created_dirs: Vec<PathBuf>,
 }
 
 const MOUNTS: &[&str] = &[
@@ -41,6 +45,21 @@ const MOUNTS: &[&str] = &[
 
 const VMSH_MOUNT_POINT: &str = "var/lib/vmsh";
 
+/// Filesystem that becomes the root of the attached environment.
+pub enum RootFs {
+    Block(BlockDevice),
+    Share(Share),
+}
+
+impl RootFs {
+    fn mount(&self, mountpoint: &Path, mount_label: &Option<String>) -> Result<()> {
+        match self {
+            RootFs::Block(dev) => dev.mount(mountpoint, mount_label),
+            RootFs::Share(share) => share.mount(mountpoint, mount_label),
+        }
+    }
+}
+
 impl MountNamespace {
     fn new(old_namespace: namespace::Namespace) -> Result<MountNamespace> {
         // Find some other writeable mountpoint if / is readonly? /dev/shm fallback?
@@ -74,6 +93,7 @@ impl MountNamespace {
             old_namespace,
             mountpoint: mountpoint.into_path(),
             temp_mountpoint: temp_mountpoint.into_path(),
+            created_dirs: vec![],
         })
     }
 
@@ -92,6 +112,14 @@ impl MountNamespace {
             "failed to cleanup mountpoint {}",
             self.mountpoint.display()
         );
+        // the mounts of the extra block devices only exist in the new namespace
+        for dir in self.created_dirs.iter().rev() {
+            try_with!(
+                remove_dir(dir),
+                "failed to cleanup mountpoint {}",
+                dir.display()
+            );
+        }
         try_with!(
             self.new_namespace.apply(),
             "cannot switch back to new mount namespace"
@@ -111,7 +139,7 @@ impl MountNamespace {
                 let rc = match self._cleanup() {
                     Ok(()) => 0,
                     Err(e) => {
-                        eprintln!("cannot cleanup mount namespace: {}", e);
+                        exec::report(&format!("cannot cleanup mount namespace: {}\n", e));
                         1
                     }
                 };
@@ -127,7 +155,7 @@ impl MountNamespace {
 impl Drop for MountNamespace {
     fn drop(&mut self) {
         if let Err(e) = self.cleanup() {
-            eprintln!("{}", e);
+            exec::report(&format!("{}\n", e));
         }
     }
 }
@@ -197,18 +225,19 @@ pub fn setup_bindmounts(mounts: &[&str]) -> Result<()> {
         );
 
         if res.is_err() {
-            eprintln!("could not bind mount {:?}", mountpoint);
+            exec::report(&format!("could not bind mount {:?}\n", mountpoint));
         }
     }
     Ok(())
 }
 
 pub fn setup(
-    device: &BlockDevice,
+    root: &RootFs,
+    extra_devices: &[(PathBuf, BlockDevice)],
     container_namespace: namespace::Namespace,
     mount_label: &Option<String>,
 ) -> Result<MountNamespace> {
-    let ns = MountNamespace::new(container_namespace)?;
+    let mut ns = MountNamespace::new(container_namespace)?;
 
     try_with!(
         mount::mount(
@@ -233,14 +262,21 @@ pub fn setup(
         "unable to move mounts to temporary mountpoint"
     );
 
-    device.mount(ns.mountpoint.as_path(), mount_label)?;
+    root.mount(ns.mountpoint.as_path(), mount_label)?;
 
     let vmsh_mount_point = &ns.mountpoint.join(VMSH_MOUNT_POINT);
-    try_with!(
-        mkdir_p(&vmsh_mount_point),
-        "cannot create container mountpoint /{}",
-        VMSH_MOUNT_POINT
-    );
+    match mkdir_p(&vmsh_mount_point) {
+        // a read-only root cannot get the mountpoint, the image has to provide it
+        Err(e) if e.raw_os_error() == Some(libc::EROFS) => bail!(
+            "the root filesystem is read-only and has no /{} to mount the old root, please create it in the image",
+            VMSH_MOUNT_POINT
+        ),
+        res => try_with!(
+            res,
+            "cannot create container mountpoint /{}",
+            VMSH_MOUNT_POINT
+        ),
+    };
     let flags = MsFlags::MS_REC | MsFlags::MS_MOVE;
     try_with!(
         mount::mount(
@@ -255,6 +291,23 @@ pub fn setup(
         vmsh_mount_point.display()
     );
 
+    for (mountpoint, dev) in extra_devices {
+        let target = vmsh_mount_point.join(mountpoint);
+        let created = try_with!(
+            mkdir_p_created(&target),
+            "cannot create mountpoint /{}/{}",
+            VMSH_MOUNT_POINT,
+            mountpoint.display()
+        );
+        // remembered as seen from the old namespace, where the root of the VM is /
+        ns.created_dirs.extend(created.iter().filter_map(|dir| {
+            dir.strip_prefix(vmsh_mount_point)
+                .ok()
+                .map(|dir| Path::new("/").join(dir))
+        }));
+        dev.mount(&target, mount_label)?;
+    }
+
     // useful for debugging
     //eprintln!(
     //    "/proc/self/mountinfo: {}",
@@ -0,0 +1,36 @@
+use simple_error::try_with;
+use std::path::Path;
+
+use crate::result::Result;
+
+/// A host directory shared by vmsh over virtio-9p.
+pub struct Share {
+    tag: String,
+}
+
+impl Share {
+    pub fn new(tag: String) -> Share {
+        Share { tag }
+    }
+
+    pub fn mount(&self, mountpoint: &Path, selinux_context: &Option<String>) -> Result<()> {
+        // vmsh agrees on a message size of at most 128K
+        let mut options = String::from("trans=virtio,version=9p2000.L,msize=131072,cache=none");
+        if let Some(ctx) = selinux_context {
+            options.push_str(&format!(",context=\"{}\"", ctx));
+        }
+        try_with!(
+            nix::mount::mount(
+                Some(self.tag.as_str()),
+                mountpoint,
+                Some("9p"),
+                nix::mount::MsFlags::empty(),
+                Some(options.as_str()),
+            ),
+            "mount(\"{}\", \"{}\", \"9p\") failed",
+            self.tag,
+            mountpoint.display()
+        );
+        Ok(())
+    }
+}
@@ -422,7 +422,8 @@ impl KvmRunWrapper {
         {
             Some(vcpu) => vcpu,
             None => {
-                warn!("Caught ioctl(KVM_RUN) for unknown vcpu_fd {}.", ioctl_fd);
+                // might be a vcpu of another VM in the same process
+                debug!("Caught ioctl(KVM_RUN) for unknown vcpu_fd {}.", ioctl_fd);
                 return Ok(None);
             }
         };
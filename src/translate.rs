@@ -0,0 +1,79 @@
+use nix::unistd::Pid;
+use simple_error::try_with;
+use std::io::{self, Write};
+
+use crate::guest_mem::{GuestMem, Translation};
+use crate::kvm;
+use crate::kvm::hypervisor::VmSelector;
+use crate::result::Result;
+
+pub struct TranslateOptions {
+    pub pid: Pid,
+    /// only needed if the hypervisor runs multiple VMs
+    pub vm: Option<VmSelector>,
+    pub virt_addr: usize,
+    /// page tables to use instead of the ones of the vcpu
+    pub cr3: Option<usize>,
+    /// vcpu whose paging mode and page tables are used
+    pub vcpu: usize,
+}
+
+/// Parses addresses given in hex with `0x` prefix or in decimal.
+pub fn parse_address(s: &str) -> Result<usize> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.parse::<usize>(),
+    };
+    Ok(try_with!(res, "invalid address '{}'", s))
+}
+
+/// Translates a guest virtual address to the guest physical and the hypervisor address.
+pub fn lookup(opts: &TranslateOptions) -> Result<Translation> {
+    let vm = try_with!(
+        kvm::hypervisor::select_hypervisor(opts.pid, opts.vm),
+        "cannot get vms for process {}",
+        opts.pid
+    );
+    vm.stop()?;
+    let mem = GuestMem::for_vcpu(&vm, opts.vcpu)?;
+    mem.translate(&vm, opts.virt_addr, opts.cr3)
+}
+
+pub fn translate(opts: &TranslateOptions) -> Result<()> {
+    let t = lookup(opts)?;
+    let host_addr = match t.host_addr {
+        Some(addr) => format!("{:#x}", addr),
+        None => "none".to_string(),
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    try_with!(
+        writeln!(
+            out,
+            "virt {:#x} -> phys {:#x}, host {}, page size {:#x}, flags {:?}",
+            t.virt_addr, t.phys_addr, host_addr, t.page_size, t.flags
+        ),
+        "cannot write translation"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_address;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            parse_address("0xffffffff81000000").ok(),
+            Some(0xffff_ffff_8100_0000)
+        );
+        assert_eq!(
+            parse_address("0xffff_8880_0000_0000").ok(),
+            Some(0xffff_8880_0000_0000)
+        );
+        assert_eq!(parse_address("4096").ok(), Some(4096));
+        assert!(parse_address("ffff").is_err());
+        assert!(parse_address("0x").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+/// The VMCOREINFO note that kdump adds to /proc/vmcore, rebuilt from what vmsh learns about the
+/// guest kernel. `crash` and `drgn` need it to relocate the symbols of vmlinux and to walk the
+/// page tables of the guest.
+use log::warn;
+use nix::sys::mman::ProtFlags;
+use simple_error::{require_with, try_with};
+use std::ops::Range;
+
+use crate::guest_mem::{get_page_table_addr, GuestMem};
+use crate::kernel::{find_kernel, Kernel, LINUX_KERNEL_KASLR_RANGE};
+use crate::kvm::hypervisor::Hypervisor;
+use crate::mem::{read_guest, GuestAddress};
+use crate::page_math::page_size;
+use crate::page_table::Paging;
+use crate::result::Result;
+
+/// Name of the ELF note
+pub const VMCOREINFO_NOTE_NAME: &[u8] = b"VMCOREINFO\0";
+
+/// Entries of a root page table that map the upper half of the address space, which all page
+/// tables share with swapper_pg_dir.
+const KERNEL_HALF: Range<usize> = 2048..4096;
+
+/// Length of the strings in `struct new_utsname`
+const UTS_LEN: usize = 65;
+
+/// PTI_USER_PGTABLE_MASK, set in cr3 while page table isolation runs user space.
+const PTI_USER_PGTABLE_MASK: usize = 1 << 12;
+
+/// Exported symbols that are also part of the VMCOREINFO of Linux or help debuggers.
+const SYMBOLS: &[&str] = &["init_uts_ns", "init_task"];
+
+#[derive(Debug, Default)]
+pub struct VmcoreInfo {
+    pub osrelease: Option<String>,
+    pub page_size: usize,
+    /// KASLR slide of the kernel image
+    pub kernel_offset: isize,
+    /// Physical load address of the kernel image minus its link-time offset
+    pub phys_base: usize,
+    /// Virtual address of the kernel's root page table
+    pub swapper_pg_dir: Option<usize>,
+    pub pgtable_l5_enabled: bool,
+    pub symbols: Vec<(String, usize)>,
+}
+
+impl VmcoreInfo {
+    /// Content of the note in the format of Linux' `crash_save_vmcoreinfo`.
+    pub fn to_note(&self) -> String {
+        let mut lines = vec![];
+        if let Some(release) = &self.osrelease {
+            lines.push(format!("OSRELEASE={}", release));
+        }
+        lines.push(format!("PAGESIZE={}", self.page_size));
+        for (name, addr) in &self.symbols {
+            lines.push(format!("SYMBOL({})={:x}", name, addr));
+        }
+        if let Some(addr) = self.swapper_pg_dir {
+            lines.push(format!("SYMBOL(swapper_pg_dir)={:x}", addr));
+        }
+        lines.push(format!("NUMBER(phys_base)={}", self.phys_base as isize));
+        lines.push(format!(
+            "NUMBER(pgtable_l5_enabled)={}",
+            self.pgtable_l5_enabled as u8
+        ));
+        lines.push(format!("KERNELOFFSET={:x}", self.kernel_offset));
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    }
+}
+
+/// `struct uts_namespace` starts with `struct new_utsname`. Older kernels have a 4 byte
+/// `struct kref` in front of it.
+fn release_from_uts_ns(uts_ns: &[u8]) -> Option<String> {
+    [0, 4].iter().find_map(|&offset| {
+        let name = uts_ns.get(offset..offset + 3 * UTS_LEN)?;
+        if !name.starts_with(b"Linux\0") {
+            return None;
+        }
+        let release = &name[2 * UTS_LEN..];
+        let end = release.iter().position(|c| *c == 0)?;
+        Some(String::from_utf8_lossy(&release[..end]).into_owned())
+    })
+}
+
+fn osrelease(hv: &Hypervisor, mem: &GuestMem, kernel: &Kernel) -> Result<Option<String>> {
+    let addr = match kernel.symbols.get("init_uts_ns") {
+        Some(addr) => *addr,
+        None => return Ok(None),
+    };
+    let mut uts_ns = vec![0; 4 + 3 * UTS_LEN];
+    read_guest(hv, mem, GuestAddress::Virtual(addr), None, &mut uts_ns)?;
+    Ok(release_from_uts_ns(&uts_ns))
+}
+
+/// Looks for the page in the writable sections of the kernel image whose kernel half matches the
+/// one of the root page table at `root`. Only swapper_pg_dir does, early_top_pgt lacks the
+/// direct mapping.
+fn find_swapper_pg_dir(
+    hv: &Hypervisor,
+    mem: &GuestMem,
+    kernel: &Kernel,
+    root: usize,
+) -> Result<Option<usize>> {
+    let mut expected = vec![0; KERNEL_HALF.len()];
+    read_guest(
+        hv,
+        mem,
+        GuestAddress::Physical(root + KERNEL_HALF.start),
+        None,
+        &mut expected,
+    )?;
+    for section in &kernel.memory_sections {
+        if !section.prot.contains(ProtFlags::PROT_WRITE) {
+            continue;
+        }
+        let mut data = vec![0; section.len];
+        let addr = GuestAddress::Physical(section.phys_start.value);
+        read_guest(hv, mem, addr, None, &mut data)?;
+        let found = data
+            .chunks_exact(page_size())
+            .position(|page| page[KERNEL_HALF] == expected[..]);
+        if let Some(idx) = found {
+            return Ok(Some(section.virt_start + idx * page_size()));
+        }
+    }
+    Ok(None)
+}
+
+/// Requires the hypervisor to be stopped while the vcpu runs the kernel.
+pub fn collect(hv: &Hypervisor) -> Result<VmcoreInfo> {
+    let mem = GuestMem::new(hv)?;
+    let kernel = find_kernel(&mem, hv)?;
+    let text = require_with!(kernel.memory_sections.first(), "no kernel sections found");
+    let image_offset = text.virt_start - LINUX_KERNEL_KASLR_RANGE.start;
+    let phys_base = text.phys_start.value.wrapping_sub(image_offset);
+
+    // idle vcpus may use the page tables of init_mm, i.e. swapper_pg_dir in the kernel image
+    let image_phys = text.phys_start.value..text.phys_start.value + kernel.range.len();
+    let mut roots = vec![];
+    for vcpu in &hv.vcpus {
+        let sregs = try_with!(hv.get_sregs(vcpu), "failed to get vcpu special registers");
+        // with page table isolation, user space runs on the page tables that follow the kernel ones
+        roots.push(get_page_table_addr(&sregs) & !PTI_USER_PGTABLE_MASK);
+    }
+    let swapper_pg_dir = match roots.iter().find(|root| image_phys.contains(root)) {
+        Some(root) => Some(LINUX_KERNEL_KASLR_RANGE.start + root.wrapping_sub(phys_base)),
+        None => find_swapper_pg_dir(
+            hv,
+            &mem,
+            &kernel,
+            mem.root_table_addr() & !PTI_USER_PGTABLE_MASK,
+        )
+        .unwrap_or_else(|e| {
+            warn!("cannot read the page tables of the guest kernel: {}", e);
+            None
+        }),
+    };
+    if swapper_pg_dir.is_none() {
+        warn!("cannot find swapper_pg_dir in the kernel image, omitting it from VMCOREINFO");
+    }
+
+    let osrelease = osrelease(hv, &mem, &kernel).unwrap_or_else(|e| {
+        warn!("cannot read kernel release: {}", e);
+        None
+    });
+
+    let symbols = SYMBOLS
+        .iter()
+        .filter_map(|name| Some((name.to_string(), *kernel.symbols.get(*name)?)))
+        .collect();
+
+    Ok(VmcoreInfo {
+        osrelease,
+        page_size: page_size(),
+        kernel_offset: kernel.kaslr_slide(),
+        phys_base,
+        swapper_pg_dir,
+        pgtable_l5_enabled: mem.paging() == Paging::FiveLevel,
+        symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_from_uts_ns() {
+        let mut uts_ns = vec![0; 4 + 3 * UTS_LEN];
+        uts_ns[4..9].copy_from_slice(b"Linux");
+        uts_ns[4 + 2 * UTS_LEN..4 + 2 * UTS_LEN + 6].copy_from_slice(b"5.10.0");
+        assert_eq!(release_from_uts_ns(&uts_ns), Some("5.10.0".to_string()));
+        assert_eq!(
+            release_from_uts_ns(&uts_ns[4..]),
+            Some("5.10.0".to_string())
+        );
+        assert_eq!(release_from_uts_ns(&[0; 4 + 3 * UTS_LEN]), None);
+    }
+
+    #[test]
+    fn test_to_note() {
+        let info = VmcoreInfo {
+            osrelease: Some("5.15.0".to_string()),
+            page_size: 4096,
+            kernel_offset: 0x1e00_0000,
+            phys_base: 0x1000_0000,
+            swapper_pg_dir: Some(0xffff_ffff_a080_a000),
+            pgtable_l5_enabled: false,
+            symbols: vec![("init_task".to_string(), 0xffff_ffff_a061_3940)],
+        };
+        assert_eq!(
+            info.to_note(),
+            "OSRELEASE=5.15.0\n\
+             PAGESIZE=4096\n\
+             SYMBOL(init_task)=ffffffffa0613940\n\
+             SYMBOL(swapper_pg_dir)=ffffffffa080a000\n\
+             NUMBER(phys_base)=268435456\n\
+             NUMBER(pgtable_l5_enabled)=0\n\
+             KERNELOFFSET=1e000000\n"
+        );
+    }
+}
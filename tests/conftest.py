@@ -3,11 +3,13 @@
 import contextlib
 import sys
 from pathlib import Path
+from subprocess import PIPE
 from typing import List, Type
 
 import pytest
 from qemu import QemuVm, VmImage, spawn_qemu
 from nix import notos_image, busybox_image
+from procs import ChildFd
 from root import TEST_ROOT
 from vmsh import spawn_vmsh_command, VmshPopen
 
@@ -34,8 +36,10 @@ class Helpers:
         return spawn_vmsh_command(args, cargo_executable)
 
     @staticmethod
-    def run_vmsh_command(args: List[str], cargo_executable: str = "vmsh") -> VmshPopen:
-        proc = spawn_vmsh_command(args, cargo_executable)
+    def run_vmsh_command(
+        args: List[str], cargo_executable: str = "vmsh", stdout: ChildFd = PIPE
+    ) -> VmshPopen:
+        proc = spawn_vmsh_command(args, cargo_executable, stdout=stdout)
         assert proc.wait() == 0
         return proc
 
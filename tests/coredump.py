@@ -2,14 +2,29 @@
 import ctypes as ct
 import mmap
 import resource
-from typing import IO, Iterable, Iterator, List, Optional, Union, overload
+import subprocess
+from typing import IO, Dict, Iterable, Iterator, List, Optional, Union, overload
 
 from coredump_structs import (
+    DUMP_DH_COMPRESSED_ZSTD,
+    KDUMP_SIGNATURE,
+    NT_VMSH_DEBUGREGS,
+    NT_VMSH_LAPIC,
+    NT_VMSH_MSRS,
+    NT_VMSH_VCPU_EVENTS,
+    NT_VMSH_XCRS,
+    NT_X86_XSTATE,
     KVMSRegs,
     core_user,
+    disk_dump_header,
     elf_fpregset_t,
     elf_prstatus,
+    kvm_debugregs,
+    kvm_lapic_state,
     kvm_msr_entry,
+    kvm_vcpu_events,
+    kvm_xcrs,
+    page_desc,
     user_fpregs_struct,
     user_regs_struct,
 )
@@ -151,6 +166,17 @@ class ElfCore:
     fpu_regs: List["user_fpregs_struct"] = []
     special_regs: List["KVMSRegs"] = []
     msrs: List[List["kvm_msr_entry"]] = []
+    vmcoreinfo: Optional[str] = None
+    # Per vcpu state from the notes following the NT_PRSTATUS of a vcpu, keyed by the
+    # vcpu index. Missing if KVM could not provide it.
+    lapic: Dict[int, "kvm_lapic_state"]
+    vcpu_events: Dict[int, "kvm_vcpu_events"]
+    debugregs: Dict[int, "kvm_debugregs"]
+    xcrs: Dict[int, "kvm_xcrs"]
+    # raw XSAVE area in the layout of NT_X86_XSTATE
+    xsave: Dict[int, bytes]
+    # msr index -> value
+    common_msrs: Dict[int, Dict[int, int]]
 
     def map_segment(self, seg: Segment) -> Memory:
         file_offset = seg.header.p_offset
@@ -182,9 +208,21 @@ class ElfCore:
     def __init__(self, fd: IO[bytes]) -> None:
         self.fd = fd
         self.elf = ELFFile(fd)
+        self.regs = []
+        self.fpu_regs = []
+        self.special_regs = []
+        self.msrs = []
         note_segment = next(self.elf.iter_segments())
         assert isinstance(note_segment, NoteSegment)
+        self.lapic = {}
+        self.vcpu_events = {}
+        self.debugregs = {}
+        self.xcrs = {}
+        self.xsave = {}
+        self.common_msrs = {}
         for note in note_segment.iter_notes():
+            # the vcpu of the last NT_PRSTATUS
+            vcpu = len(self.regs) - 1
             if note.n_type == "NT_PRSTATUS":
                 assert note.n_descsz == ct.sizeof(elf_prstatus)
                 self.regs.append(
@@ -201,3 +239,78 @@ class ElfCore:
                 custom = core_user.from_buffer_copy(note.n_desc.encode("latin1"))
                 self.special_regs.append(custom.sregs)
                 self.msrs.append(custom.msrs)
+            elif note.n_name == "VMCOREINFO":
+                desc = note.n_desc
+                self.vmcoreinfo = desc if isinstance(desc, str) else desc.decode()
+            elif note.n_name == "LINUX" and note.n_type in (
+                NT_X86_XSTATE,
+                "NT_X86_XSTATE",
+            ):
+                self.xsave[vcpu] = note.n_desc.encode("latin-1")
+            elif note.n_name == "VMSH":
+                desc = note.n_desc.encode("latin-1")
+                self.parse_vmsh_note(vcpu, note.n_type, desc)
+
+    def parse_vmsh_note(self, vcpu: int, n_type: int, desc: bytes) -> None:
+        if n_type == NT_VMSH_LAPIC:
+            self.lapic[vcpu] = kvm_lapic_state.from_buffer_copy(desc)
+        elif n_type == NT_VMSH_VCPU_EVENTS:
+            self.vcpu_events[vcpu] = kvm_vcpu_events.from_buffer_copy(desc)
+        elif n_type == NT_VMSH_DEBUGREGS:
+            self.debugregs[vcpu] = kvm_debugregs.from_buffer_copy(desc)
+        elif n_type == NT_VMSH_XCRS:
+            self.xcrs[vcpu] = kvm_xcrs.from_buffer_copy(desc)
+        elif n_type == NT_VMSH_MSRS:
+            size = ct.sizeof(kvm_msr_entry)
+            entries = (
+                kvm_msr_entry.from_buffer_copy(desc[i : i + size])
+                for i in range(0, len(desc), size)
+            )
+            self.common_msrs[vcpu] = {e.index: e.data for e in entries}
+
+
+def bit_set(bitmap: bytes, pfn: int) -> bool:
+    return bool(bitmap[pfn // 8] & (1 << (pfn % 8)))
+
+
+class KdumpCore:
+    """
+    Reads pages of the kdump-compressed files written by the coredump subcommand.
+    """
+
+    def __init__(self, fd: IO[bytes]) -> None:
+        self.fd = fd
+        self.header = disk_dump_header.from_buffer_copy(
+            fd.read(ct.sizeof(disk_dump_header))
+        )
+        assert self.header.signature == KDUMP_SIGNATURE
+        block_size = self.header.block_size
+        bitmap_offset = (1 + self.header.sub_hdr_size) * block_size
+        bitmap_len = self.header.bitmap_blocks // 2 * block_size
+        fd.seek(bitmap_offset)
+        # pages that exist in the guest
+        self.valid = fd.read(bitmap_len)
+        # pages that were dumped, the others are zero
+        self.dumped = fd.read(bitmap_len)
+        self.desc_offset = bitmap_offset + 2 * bitmap_len
+
+    def read_page(self, pfn: int) -> Optional[bytes]:
+        """
+        Returns the content of page frame `pfn` or None if the guest does not have it
+        """
+        if not bit_set(self.valid, pfn):
+            return None
+        if not bit_set(self.dumped, pfn):
+            return bytes(self.header.block_size)
+        # one descriptor per dumped page, ordered by page frame
+        index = sum(bin(b).count("1") for b in self.dumped[: pfn // 8])
+        index += bin(self.dumped[pfn // 8] & ((1 << (pfn % 8)) - 1)).count("1")
+        self.fd.seek(self.desc_offset + index * ct.sizeof(page_desc))
+        desc = page_desc.from_buffer_copy(self.fd.read(ct.sizeof(page_desc)))
+        self.fd.seek(desc.offset)
+        data = self.fd.read(desc.size)
+        if desc.flags & DUMP_DH_COMPRESSED_ZSTD:
+            data = subprocess.run(
+                ["zstd", "-d", "-c"], input=data, stdout=subprocess.PIPE, check=True
+            ).stdout
+        return data
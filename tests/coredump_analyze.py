@@ -426,8 +426,60 @@ def get_kernel_symbols(
     return syms
 
 
+MSR_NAMES = {
+    0xC0000080: "EFER",
+    0xC0000081: "STAR",
+    0xC0000082: "LSTAR",
+    0xC0000083: "CSTAR",
+    0xC0000084: "SYSCALL_MASK",
+    0xC0000100: "FS_BASE",
+    0xC0000101: "GS_BASE",
+    0xC0000102: "KERNEL_GS_BASE",
+    0x10: "TSC",
+    0x1B: "APIC_BASE",
+    0x174: "SYSENTER_CS",
+    0x175: "SYSENTER_ESP",
+    0x176: "SYSENTER_EIP",
+    0x277: "PAT",
+    0x1A0: "MISC_ENABLE",
+    0xC0000103: "TSC_AUX",
+}
+
+# register offsets in the local APIC page
+APIC_ID = 0x20
+APIC_TASKPRI = 0x80
+
+
+def print_vcpu_state(core: ElfCore) -> None:
+    """
+    Print the vcpu state from the VMSH notes that helps to debug hangs
+    """
+    for i, msrs in core.common_msrs.items():
+        print(f"vcpu {i}:")
+        for index, value in msrs.items():
+            name = MSR_NAMES.get(index, f"0x{index:x}")
+            print(f"  {name}=0x{value:x}")
+    for i, lapic in core.lapic.items():
+        apic_id = lapic.reg(APIC_ID) >> 24
+        print(f"vcpu {i}: apic id={apic_id} tpr=0x{lapic.reg(APIC_TASKPRI):x}")
+    for i, events in core.vcpu_events.items():
+        exc = events.exception
+        print(
+            f"vcpu {i}: exception injected={exc.injected} pending={exc.pending} nr={exc.nr}, "
+            f"interrupt injected={events.interrupt.injected} nr={events.interrupt.nr}, "
+            f"nmi pending={events.nmi.pending} masked={events.nmi.masked}"
+        )
+    for i, dbg in core.debugregs.items():
+        db = " ".join(f"0x{d:x}" for d in dbg.db)
+        print(f"vcpu {i}: db={db} dr6=0x{dbg.dr6:x} dr7=0x{dbg.dr7:x}")
+    for i, xcrs in core.xcrs.items():
+        for xcr in xcrs.xcrs[: xcrs.nr_xcrs]:
+            print(f"vcpu {i}: xcr{xcr.xcr}=0x{xcr.value:x}")
+
+
 def inspect_coredump(fd: IO[bytes]) -> None:
     core = ElfCore(fd)
+    print_vcpu_state(core)
     pt_addr = get_page_table_addr(core.special_regs[0])
     segments = Segments(core)
     pt_mem = segments.find_by_addr(pt_addr)
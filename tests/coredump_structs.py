@@ -482,3 +482,140 @@ class core_user(ct.Structure):
         ("sregs", KVMSRegs),
         ("msrs", kvm_msr_entry * 1),
     ]
+
+
+# Notes with the vcpu state that does not fit into NT_PRSTATUS and NT_PRXFPREG.
+# NT_X86_XSTATE is owned by "LINUX" and has the layout of Linux coredumps, the
+# others are owned by "VMSH" and contain the structs of the KVM api.
+NT_X86_XSTATE = 0x202
+NT_VMSH_LAPIC = 0x564D0001
+NT_VMSH_VCPU_EVENTS = 0x564D0002
+NT_VMSH_DEBUGREGS = 0x564D0003
+NT_VMSH_XCRS = 0x564D0004
+NT_VMSH_MSRS = 0x564D0005
+
+KVM_APIC_REG_SIZE = 0x400
+
+
+class kvm_lapic_state(ct.Structure):
+    _fields_ = [
+        ("regs", ct.c_uint8 * KVM_APIC_REG_SIZE),
+    ]
+
+    def reg(self, offset: int) -> int:
+        return int.from_bytes(bytes(self.regs[offset : offset + 4]), "little")
+
+
+class kvm_vcpu_events_exception(ct.Structure):
+    _fields_ = [
+        ("injected", ct.c_uint8),
+        ("nr", ct.c_uint8),
+        ("has_error_code", ct.c_uint8),
+        ("pending", ct.c_uint8),
+        ("error_code", ct.c_uint32),
+    ]
+
+
+class kvm_vcpu_events_interrupt(ct.Structure):
+    _fields_ = [
+        ("injected", ct.c_uint8),
+        ("nr", ct.c_uint8),
+        ("soft", ct.c_uint8),
+        ("shadow", ct.c_uint8),
+    ]
+
+
+class kvm_vcpu_events_nmi(ct.Structure):
+    _fields_ = [
+        ("injected", ct.c_uint8),
+        ("pending", ct.c_uint8),
+        ("masked", ct.c_uint8),
+        ("pad", ct.c_uint8),
+    ]
+
+
+class kvm_vcpu_events_smi(ct.Structure):
+    _fields_ = [
+        ("smm", ct.c_uint8),
+        ("pending", ct.c_uint8),
+        ("smm_inside_nmi", ct.c_uint8),
+        ("latched_init", ct.c_uint8),
+    ]
+
+
+class kvm_vcpu_events(ct.Structure):
+    _fields_ = [
+        ("exception", kvm_vcpu_events_exception),
+        ("interrupt", kvm_vcpu_events_interrupt),
+        ("nmi", kvm_vcpu_events_nmi),
+        ("sipi_vector", ct.c_uint32),
+        ("flags", ct.c_uint32),
+        ("smi", kvm_vcpu_events_smi),
+        ("reserved", ct.c_uint8 * 27),
+        ("exception_has_payload", ct.c_uint8),
+        ("exception_payload", ct.c_uint64),
+    ]
+
+
+class kvm_debugregs(ct.Structure):
+    _fields_ = [
+        ("db", ct.c_uint64 * 4),
+        ("dr6", ct.c_uint64),
+        ("dr7", ct.c_uint64),
+        ("flags", ct.c_uint64),
+        ("reserved", ct.c_uint64 * 9),
+    ]
+
+
+class kvm_xcr(ct.Structure):
+    _fields_ = [
+        ("xcr", ct.c_uint32),
+        ("reserved", ct.c_uint32),
+        ("value", ct.c_uint64),
+    ]
+
+
+KVM_MAX_XCRS = 16
+
+
+class kvm_xcrs(ct.Structure):
+    _fields_ = [
+        ("nr_xcrs", ct.c_uint32),
+        ("flags", ct.c_uint32),
+        ("xcrs", kvm_xcr * KVM_MAX_XCRS),
+        ("padding", ct.c_uint64 * 16),
+    ]
+
+
+# kdump-compressed format of makedumpfile
+KDUMP_SIGNATURE = b"KDUMP   "
+DUMP_DH_COMPRESSED_ZSTD = 0x20
+NEW_UTS_LEN = 65
+
+
+class disk_dump_header(ct.Structure):
+    _fields_ = [
+        ("signature", ct.c_char * 8),
+        ("header_version", ct.c_int),
+        ("utsname", ct.c_char * (6 * NEW_UTS_LEN)),
+        ("timestamp", timeval),
+        ("status", ct.c_uint),
+        ("block_size", ct.c_int),
+        ("sub_hdr_size", ct.c_int),
+        ("bitmap_blocks", ct.c_uint),
+        ("max_mapnr", ct.c_uint),
+        ("total_ram_blocks", ct.c_uint),
+        ("device_blocks", ct.c_uint),
+        ("written_blocks", ct.c_uint),
+        ("current_cpu", ct.c_uint),
+        ("nr_cpus", ct.c_int),
+    ]
+
+
+class page_desc(ct.Structure):
+    _fields_ = [
+        ("offset", ct.c_longlong),
+        ("size", ct.c_uint),
+        ("flags", ct.c_uint),
+        ("page_flags", ct.c_ulonglong),
+    ]
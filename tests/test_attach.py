@@ -1,6 +1,10 @@
 import conftest
 
 import os
+import socket
+import time
+from pathlib import Path
+from tempfile import TemporaryDirectory
 
 from nix import notos_image
 
@@ -67,6 +71,10 @@ def test_attach_multiple_cpus(helpers: conftest.Helpers) -> None:
     test_attach(helpers=helpers, vcpus=8)
 
 
+def test_attach_mmio_auto(helpers: conftest.Helpers) -> None:
+    test_attach(helpers=helpers, mmio="auto")
+
+
 def test_attach_4_4(helpers: conftest.Helpers) -> None:
     test_attach(helpers=helpers, image=".#not-os-image_4_4")
 
@@ -85,3 +93,83 @@ def test_attach_5_15(helpers: conftest.Helpers) -> None:
 
 def test_attach_5_16(helpers: conftest.Helpers) -> None:
     test_attach(helpers=helpers, image=".#not-os-image_5_16")
+
+
+def console_roundtrip(path: Path, marker: str) -> None:
+    with socket.socket(socket.AF_UNIX, socket.SOCK_STREAM) as client:
+        client.connect(str(path))
+        client.settimeout(1)
+        output = b""
+        for _ in range(30):
+            client.sendall(f"echo {marker}-$((40 + 2))\n".encode())
+            try:
+                output += client.recv(4096)
+            except socket.timeout:
+                pass
+            if f"{marker}-42".encode() in output:
+                break
+            time.sleep(1)
+        assert f"{marker}-42".encode() in output
+
+
+def test_attach_console_socket(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, TemporaryDirectory() as temp:
+        path = Path(temp).joinpath("console.sock")
+        with helpers.spawn_qemu(notos_image()) as vm:
+            vm.wait_for_ssh()
+            vmsh = helpers.spawn_vmsh_command(
+                [
+                    "attach",
+                    "--backing-file",
+                    str(img),
+                    "--console-socket",
+                    str(path),
+                    str(vm.pid),
+                    "--",
+                    "/bin/sh",
+                ]
+            )
+
+            with vmsh:
+                vmsh.wait_until_line(
+                    "stage1 driver started", lambda l: "stage1 driver started" in l
+                )
+                console_roundtrip(path, "socket")
+            assert not path.exists()
+
+
+def test_detach_reattach(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, TemporaryDirectory() as temp:
+        first = Path(temp).joinpath("first.sock")
+        second = Path(temp).joinpath("second.sock")
+        with helpers.spawn_qemu(notos_image()) as vm:
+            vm.wait_for_ssh()
+            vmsh = helpers.spawn_vmsh_command(
+                [
+                    "attach",
+                    "--backing-file",
+                    str(img),
+                    "--console-socket",
+                    str(first),
+                    str(vm.pid),
+                    "--",
+                    "/bin/sh",
+                ]
+            )
+            with vmsh:
+                vmsh.wait_until_line(
+                    "stage1 driver started", lambda l: "stage1 driver started" in l
+                )
+                console_roundtrip(first, "attached")
+                helpers.run_vmsh_command(["detach", str(vm.pid)])
+                assert vmsh.wait(timeout=10) == 0
+
+            # the shell survived the first vmsh process
+            vmsh = helpers.spawn_vmsh_command(
+                ["reattach", "--console-socket", str(second), str(vm.pid)]
+            )
+            with vmsh:
+                vmsh.wait_until_line(
+                    "devices restored", lambda l: "devices restored" in l
+                )
+                console_roundtrip(second, "reattached")
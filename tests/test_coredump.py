@@ -1,13 +1,19 @@
 import os
+import re
+import subprocess
 import time
 from tempfile import TemporaryDirectory
-from typing import IO, Dict
+from typing import IO, Dict, List
 
 import conftest
-from coredump import ElfCore
+from coredump import ElfCore, KdumpCore
+from coredump_structs import DUMP_DH_COMPRESSED_ZSTD
 from qemu import QemuVm
+from vmsh import VmshPopen
 
 MSR_EFER = 0xC0000080
+KERNEL_IMAGE_START = 0xFFFFFFFF80000000
+PAGE_SIZE = 4096
 
 
 def check_coredump(fd: IO[bytes], qemu_regs: Dict[str, int], vm: QemuVm) -> None:
@@ -49,3 +55,201 @@ def test_coredump(helpers: conftest.Helpers) -> None:
         helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
         with open(core_path, "rb") as fd:
             check_coredump(fd, qemu_regs, vm)
+
+
+def test_coredump_vmcoreinfo(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        core_path = os.path.join(temp, "core")
+        helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
+        with open(core_path, "rb") as fd:
+            core = ElfCore(fd)
+        assert core.vmcoreinfo is not None
+        info = dict(
+            line.split("=", 1) for line in core.vmcoreinfo.splitlines() if line
+        )
+        release = vm.ssh_cmd(["uname", "-r"]).stdout.strip()
+        assert info["OSRELEASE"] == release
+        assert int(info["PAGESIZE"]) == 4096
+        assert "KERNELOFFSET" in info
+        assert "SYMBOL(swapper_pg_dir)" in info
+        pgd = int(info["SYMBOL(swapper_pg_dir)"], 16)
+        assert pgd >= KERNEL_IMAGE_START
+        # kallsyms is empty without CONFIG_KALLSYMS_ALL
+        kallsyms = vm.ssh_cmd(
+            ["grep", " swapper_pg_dir$", "/proc/kallsyms"], check=False
+        ).stdout
+        if kallsyms:
+            assert int(kallsyms.split()[0], 16) == pgd
+
+
+def test_coredump_sparse_and_kdump(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        vm.send("stop")
+        core_path = os.path.join(temp, "core")
+        helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
+        st = os.stat(core_path)
+        # most of the guest memory is still zero and is skipped
+        assert st.st_blocks * 512 < st.st_size
+
+        kdump_path = os.path.join(temp, "kdump")
+        helpers.run_vmsh_command(
+            ["coredump", "--format", "kdump", "--compress", "zstd"]
+            + [str(vm.pid), kdump_path]
+        )
+        assert os.stat(kdump_path).st_size < st.st_blocks * 512
+
+        # the root page table is mostly empty and gets compressed
+        page_table = vm.regs()["cr3"] & ~(PAGE_SIZE - 1)
+        with open(core_path, "rb") as fd:
+            core = ElfCore(fd)
+            segment = core.find_segment_by_addr(page_table)
+            assert segment is not None
+            data = core.map_segment(segment)
+            elf_page = bytes(data[page_table : page_table + PAGE_SIZE].data)
+        with open(kdump_path, "rb") as fd:
+            kdump = KdumpCore(fd)
+            assert kdump.header.status & DUMP_DH_COMPRESSED_ZSTD
+            assert kdump.read_page(page_table // PAGE_SIZE) == elf_page
+
+
+def test_coredump_zstd(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        vm.send("stop")
+        qemu_regs = vm.regs()
+
+        zst_path = os.path.join(temp, "core.zst")
+        helpers.run_vmsh_command(
+            ["coredump", "--compress", "zstd", str(vm.pid), zst_path]
+        )
+        core_path = os.path.join(temp, "core")
+        subprocess.run(["zstd", "-d", zst_path, "-o", core_path], check=True)
+        with open(core_path, "rb") as fd:
+            check_coredump(fd, qemu_regs, vm)
+
+        stream_path = os.path.join(temp, "stream")
+        with open(stream_path, "wb") as out:
+            zstd = subprocess.Popen(["zstd", "-d"], stdin=subprocess.PIPE, stdout=out)
+            assert zstd.stdin is not None
+            helpers.run_vmsh_command(
+                ["coredump", "--compress", "zstd", str(vm.pid), "-"],
+                stdout=zstd.stdin,
+            )
+            zstd.stdin.close()
+            assert zstd.wait() == 0
+        with open(stream_path, "rb") as fd:
+            check_coredump(fd, qemu_regs, vm)
+
+
+def test_coredump_kernel_image_mappings(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        core_path = os.path.join(temp, "core")
+        helpers.run_vmsh_command(
+            ["coredump", "--kernel-image-mappings", str(vm.pid), core_path]
+        )
+        with open(core_path, "rb") as fd:
+            core = ElfCore(fd)
+            virtual = [
+                seg
+                for seg in core.elf.iter_segments()
+                if seg.header.p_type == "PT_LOAD"
+                and seg.header.p_vaddr >= 0xFFFFFFFF80000000
+            ]
+            assert len(virtual) > 0
+            seg = virtual[0]
+            # the virtual segment shares its data with the physical one
+            phys_seg = core.find_segment_by_addr(seg.header.p_paddr)
+            assert phys_seg is not None
+            phys_offset = phys_seg.header.p_offset + (
+                seg.header.p_paddr - phys_seg.header.p_paddr
+            )
+            assert seg.header.p_offset == phys_offset
+
+
+MSR_LSTAR = 0xC0000082
+MSR_GS_BASE = 0xC0000101
+
+
+def test_coredump_vcpu_state(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        core_path = os.path.join(temp, "core")
+        helpers.run_vmsh_command(["coredump", str(vm.pid), core_path])
+        with open(core_path, "rb") as fd:
+            core = ElfCore(fd)
+        nr_vcpus = len(core.regs)
+        assert len(core.common_msrs) == nr_vcpus
+        msrs = core.common_msrs[0]
+        assert msrs[MSR_EFER] == core.msrs[0][0].data
+        # the kernel has set up syscalls and per-cpu data
+        assert msrs[MSR_LSTAR] >= 0xFFFFFFFF80000000
+        assert MSR_GS_BASE in msrs
+        assert len(core.debugregs) == nr_vcpus
+        assert len(core.vcpu_events) == nr_vcpus
+        assert len(core.lapic) == nr_vcpus
+        assert len(core.xsave) == nr_vcpus
+
+
+# writes the same counter to two files, so /tmp/a holds the value of /tmp/b or one more
+GUEST_WRITER = """
+i=0
+while [ ! -e /tmp/stop ]; do
+  i=$((i + 1))
+  printf 'vmsh-a:%08d\\n' $i 1<> /tmp/a
+  printf 'vmsh-b:%08d\\n' $i 1<> /tmp/b
+done
+"""
+
+
+def latest_counter(core: ElfCore, name: bytes) -> int:
+    pattern = re.compile(b"vmsh-" + name + b":([0-9]{8})\\n")
+    latest = -1
+    for seg in core.elf.iter_segments():
+        if seg.header.p_type != "PT_LOAD":
+            continue
+        data = core.map_segment(seg).data
+        for match in pattern.finditer(data):
+            latest = max(latest, int(match.group(1)))
+    return latest
+
+
+def self_logged_memslots(proc: VmshPopen) -> List[str]:
+    """
+    memslots vmsh leaves alone because their dirty pages are logged already
+    """
+    pattern = re.compile(r"logs dirty pages of memslot (\d+) itself")
+    return [m.group(1) for m in map(pattern.search, proc.output_lines()) if m]
+
+
+def test_snapshot(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as temp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        vm.ssh_cmd(["sh", "-c", f"({GUEST_WRITER}) > /dev/null 2>&1 < /dev/null &"])
+        snapshot_path = os.path.join(temp, "snapshot")
+        proc = helpers.run_vmsh_command(
+            ["snapshot", "--soft-dirty", str(vm.pid), snapshot_path]
+        )
+        vm.ssh_cmd(["touch", "/tmp/stop"])
+        # the VM keeps running
+        assert vm.ssh_cmd(["echo", "ok"]).stdout.strip() == "ok"
+        with open(snapshot_path, "rb") as fd:
+            core = ElfCore(fd)
+            assert len(core.regs) > 0
+            assert len(core.special_regs) == len(core.regs)
+            assert core.vmcoreinfo is not None
+            cr3 = core.special_regs[0].cr3 & ~0xFFF
+            assert core.find_segment_by_addr(cr3) is not None
+            # memory of the final pause, the pages of /tmp/a and /tmp/b were not copied
+            # at different times
+            a = latest_counter(core, b"a")
+            b = latest_counter(core, b"b")
+            assert b > 0
+            assert a - b in (0, 1)
+
+        # with the flags of the first run left behind, dirty logging looks like it was
+        # enabled by the hypervisor
+        second = helpers.run_vmsh_command(["snapshot", str(vm.pid), snapshot_path])
+        assert self_logged_memslots(second) == self_logged_memslots(proc)
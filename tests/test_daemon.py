@@ -0,0 +1,76 @@
+import conftest
+
+import json
+import socket
+import time
+from pathlib import Path
+from tempfile import TemporaryDirectory
+from typing import Any, Dict
+
+from nix import notos_image
+
+
+class ControlClient:
+    def __init__(self, path: Path) -> None:
+        self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        for _ in range(100):
+            try:
+                self.sock.connect(str(path))
+                break
+            except (FileNotFoundError, ConnectionRefusedError):
+                time.sleep(0.1)
+        else:
+            raise Exception(f"daemon did not create {path}")
+        self.reader = self.sock.makefile("r")
+
+    def request(self, command: str, **args: Any) -> Any:
+        msg = dict(command=command, **args)
+        self.sock.sendall(json.dumps(msg).encode() + b"\n")
+        response = json.loads(self.reader.readline())
+        assert "error" not in response, response
+        return response
+
+
+def test_daemon(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, TemporaryDirectory() as temp:
+        path = Path(temp).joinpath("vmsh.sock")
+        with helpers.spawn_qemu(notos_image()) as vm:
+            vm.wait_for_ssh()
+            daemon = helpers.spawn_vmsh_command(["daemon", "--socket", str(path)])
+            with daemon:
+                client = ControlClient(path)
+                assert path.stat().st_mode & 0o777 == 0o600
+                response = client.request(
+                    "attach",
+                    pid=vm.pid,
+                    backing=[str(img)],
+                    command_line=["/bin/sh", "-c", "sleep 1000"],
+                )
+                id = response["attached"]["id"]
+
+                attachment: Dict[str, Any] = {}
+                for _ in range(60):
+                    attachments = client.request("list")["attachments"]
+                    attachment = next(a for a in attachments if a["id"] == id)
+                    if attachment["state"] != "starting":
+                        break
+                    time.sleep(1)
+                assert attachment["state"] == "running", attachment
+
+                stats = client.request("stats", id=id)["stats"]
+                kinds = [device["kind"] for device in stats]
+                assert kinds == ["block", "console"]
+                assert all(device["activated"] for device in stats)
+                # stage2 reads the disk during startup
+                assert stats[0]["io"]["requests"] > 0, stats
+
+                assert client.request("stop", id=id) == "ok"
+                assert client.request("list")["attachments"] == []
+
+                # the VM survives the attachment
+                res = vm.ssh_cmd(["echo", "ping"], check=False)
+                assert res.stdout == "ping\n"
+
+                assert client.request("shutdown") == "ok"
+                assert daemon.wait(timeout=10) == 0
+            assert not path.exists()
@@ -0,0 +1,104 @@
+import conftest
+from pathlib import Path
+from tempfile import TemporaryDirectory
+
+from nix import notos_image
+
+
+def test_exec(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, helpers.spawn_qemu(notos_image()) as vm:
+        vm.wait_for_ssh()
+        vmsh = helpers.spawn_vmsh_command(
+            [
+                "exec",
+                "--backing-file",
+                str(img),
+                str(vm.pid),
+                "--",
+                "/bin/sh",
+                "-c",
+                "echo stdout-works; echo stderr-works >&2; exit 3",
+            ]
+        )
+
+        with vmsh:
+            vmsh.wait_until_line("stdout-works", lambda l: "stdout-works" in l)
+            vmsh.wait_until_line("stderr-works", lambda l: "stderr-works" in l)
+            assert vmsh.wait() == 3
+
+        # See that the VM is still alive after exec
+        res = vm.ssh_cmd(["echo", "ping"], check=False)
+        assert res.stdout == "ping\n"
+        assert res.returncode == 0
+
+
+def test_exec_read_only(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, helpers.spawn_qemu(notos_image()) as vm:
+        vm.wait_for_ssh()
+        vmsh = helpers.spawn_vmsh_command(
+            [
+                "exec",
+                "--backing-file",
+                f"{img}:ro",
+                str(vm.pid),
+                "--",
+                "/bin/sh",
+                "-c",
+                "echo ran; touch /read-only-test",
+            ]
+        )
+
+        with vmsh:
+            vmsh.wait_until_line("ran", lambda l: l.strip() == "ran")
+            vmsh.wait_until_line("touch fails", lambda l: "Read-only file system" in l)
+            assert vmsh.wait() != 0
+
+
+def test_exec_multiple_backing_files(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, helpers.busybox_image() as extra_img:
+        with helpers.spawn_qemu(notos_image()) as vm:
+            vm.wait_for_ssh()
+            vmsh = helpers.spawn_vmsh_command(
+                [
+                    "exec",
+                    "--backing-file",
+                    str(img),
+                    "--backing-file",
+                    f"{extra_img}:mount=extra",
+                    str(vm.pid),
+                    "--",
+                    "/bin/sh",
+                    "-c",
+                    "test -e /var/lib/vmsh/extra/bin/sh",
+                ]
+            )
+
+            with vmsh:
+                assert vmsh.wait() == 0
+
+
+def test_exec_share(helpers: conftest.Helpers) -> None:
+    with helpers.busybox_image() as img, TemporaryDirectory() as share:
+        Path(share, "hello").write_text("from-host\n")
+        with helpers.spawn_qemu(notos_image()) as vm:
+            vm.wait_for_ssh()
+            # the shared directory is the root, so we take the shell from an extra image
+            vmsh = helpers.spawn_vmsh_command(
+                [
+                    "exec",
+                    "--share",
+                    share,
+                    "--backing-file",
+                    f"{img}:mount=busybox",
+                    str(vm.pid),
+                    "--",
+                    "/var/lib/vmsh/busybox/bin/sh",
+                    "-c",
+                    "/var/lib/vmsh/busybox/bin/cat /hello; echo from-guest > /written",
+                ]
+            )
+
+            with vmsh:
+                vmsh.wait_until_line("from-host", lambda l: "from-host" in l)
+                assert vmsh.wait() == 0
+        assert Path(share, "written").read_text() == "from-guest\n"
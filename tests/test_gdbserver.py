@@ -0,0 +1,65 @@
+import socket
+from pathlib import Path
+from tempfile import TemporaryDirectory
+
+import conftest
+
+
+def checksum(data: str) -> str:
+    return "%02x" % (sum(data.encode()) % 256)
+
+
+class GdbClient:
+    def __init__(self, path: Path) -> None:
+        self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+        self.sock.connect(str(path))
+        self.buf = b""
+
+    def read_packet(self) -> str:
+        while b"#" not in self.buf or len(self.buf) < self.buf.index(b"#") + 3:
+            data = self.sock.recv(4096)
+            assert data, "gdbserver closed the connection"
+            self.buf += data
+        start = self.buf.index(b"$")
+        end = self.buf.index(b"#")
+        packet = self.buf[start + 1 : end].decode()
+        assert self.buf[end + 1 : end + 3].decode() == checksum(packet)
+        self.buf = self.buf[end + 3 :]
+        self.sock.sendall(b"+")
+        return packet
+
+    def command(self, cmd: str) -> str:
+        self.sock.sendall(f"${cmd}#{checksum(cmd)}".encode())
+        return self.read_packet()
+
+
+def test_gdbserver(helpers: conftest.Helpers) -> None:
+    with TemporaryDirectory() as tmp, helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        path = Path(tmp) / "gdb.sock"
+        vmsh = helpers.spawn_vmsh_command(
+            ["gdbserver", "--socket", str(path), str(vm.pid)]
+        )
+        with vmsh:
+            vmsh.wait_until_line("waiting for gdb", lambda l: "waiting for gdb" in l)
+            gdb = GdbClient(path)
+            assert gdb.command("?").startswith("T05")
+            assert gdb.command("qC") == "QC1"
+            # 17 registers with 8 bytes and 7 with 4 bytes
+            regs = gdb.command("g")
+            assert len(regs) == 2 * (17 * 8 + 7 * 4)
+            rip = int.from_bytes(bytes.fromhex(regs[16 * 16 : 17 * 16]), "little")
+            code = gdb.command(f"m{rip:x},4")
+            assert len(code) == 8, f"cannot read code at rip {rip:#x}"
+            # breakpoints are not supported and must not be emulated by gdb
+            assert gdb.command(f"Z0,{rip:x},1") == "E22"
+            assert gdb.command(f"m{rip:x},4") == code
+            # writes need --allow-write
+            assert gdb.command(f"M{rip:x},1:90") == "E01"
+            assert gdb.command(f"P10={rip:016x}") == "E01"
+            assert gdb.command("vCont?") == "vCont;c"
+            assert gdb.command("D") == "OK"
+            assert vmsh.wait() == 0
+
+        res = vm.ssh_cmd(["echo", "ping"], check=False)
+        assert res.stdout == "ping\n"
@@ -1,3 +1,5 @@
+import json
+
 import conftest
 
 
@@ -14,3 +16,19 @@ def test_inspect(helpers: conftest.Helpers) -> None:
                 found = True
                 break
         assert found, "could not find kernel"
+
+
+def test_inspect_json(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        proc = helpers.run_vmsh_command(["inspect", "--json", str(vm.pid)])
+        report = next(
+            (json.loads(line) for line in proc.output_lines() if line.startswith("{")),
+            None,
+        )
+        assert report is not None, "no json output found"
+        assert report["pid"] == vm.pid
+        assert len(report["memslots"]) > 0
+        assert len(report["vcpus"]) > 0
+        assert report["kernel"] is not None, "could not find kernel"
+        assert report["kernel"]["symbols"] > 0
@@ -0,0 +1,18 @@
+import json
+
+import conftest
+
+
+def test_list(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        proc = helpers.run_vmsh_command(["list", "--json"])
+        vms = next(
+            (json.loads(line) for line in proc.output_lines() if line.startswith("[")),
+            None,
+        )
+        assert vms is not None, "no json output found"
+        found = [v for v in vms if v["pid"] == vm.pid]
+        assert len(found) == 1, f"vm {vm.pid} not in {vms}"
+        assert found[0]["vcpus"] > 0
+        assert found[0]["memory"] > 0
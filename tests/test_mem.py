@@ -0,0 +1,35 @@
+import json
+from typing import List
+
+import conftest
+
+
+def hexdump_bytes(proc: conftest.VmshPopen) -> List[str]:
+    # "<address>  <bytes>  |<ascii>|"
+    return [line.split("  ")[1] for line in proc.output_lines() if line.endswith("|")]
+
+
+def test_mem(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        proc = helpers.run_vmsh_command(["inspect", "--json", str(vm.pid)])
+        report = next(
+            json.loads(line) for line in proc.output_lines() if line.startswith("{")
+        )
+        section = report["kernel"]["sections"][0]
+
+        virt = helpers.run_vmsh_command(
+            ["mem", "read", str(vm.pid), hex(section["virt_start"]), "32"]
+        )
+        phys = helpers.run_vmsh_command(
+            ["mem", "read", "--phys", str(vm.pid), hex(section["phys_start"]), "32"]
+        )
+        virt_bytes = hexdump_bytes(virt)
+        assert len(virt_bytes) == 2
+        assert virt_bytes == hexdump_bytes(phys)
+
+        # writes need --allow-write
+        write = helpers.spawn_vmsh_command(
+            ["mem", "write", str(vm.pid), hex(section["virt_start"]), "--hex", "00"]
+        )
+        assert write.wait() != 0
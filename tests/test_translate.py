@@ -0,0 +1,27 @@
+import json
+import re
+
+import conftest
+
+
+def find_line(proc: conftest.VmshPopen, prefix: str) -> str:
+    for line in proc.output_lines():
+        if line.startswith(prefix):
+            return line
+    raise AssertionError(f"no line starting with {prefix} found")
+
+
+def test_translate(helpers: conftest.Helpers) -> None:
+    with helpers.spawn_qemu(helpers.notos_image()) as vm:
+        vm.wait_for_ssh()
+        proc = helpers.run_vmsh_command(["inspect", "--json", str(vm.pid)])
+        report = json.loads(find_line(proc, "{"))
+        section = report["kernel"]["sections"][0]
+
+        proc = helpers.run_vmsh_command(
+            ["translate", str(vm.pid), hex(section["virt_start"] + 0x10)]
+        )
+        line = find_line(proc, "virt ")
+        match = re.search(r"phys (0x[0-9a-f]+)", line)
+        assert match is not None, f"unexpected output: {line}"
+        assert int(match.group(1), 16) == section["phys_start"] + 0x10
@@ -9,6 +9,7 @@ from nix import notos_image
 from typing import Union, Any, Callable, List, Optional, Dict
 from root import PROJECT_ROOT
 from shlex import quote
+from procs import ChildFd
 
 EOF = 1
 
@@ -47,7 +48,11 @@ def build_artifacts(target: str) -> Path:
 class VmshPopen(subprocess.Popen):
     def process_stdout(self) -> None:
         self.lines: Queue[Union[str, int]] = Queue()
-        threading.Thread(target=self.print_stdout).start()
+        if self.stdout is None:
+            # stdout goes to a file or another process
+            self.lines.put(EOF)
+        else:
+            threading.Thread(target=self.print_stdout).start()
         threading.Thread(target=self.print_stderr).start()
 
     def terminate(self) -> None:
@@ -83,6 +88,21 @@ class VmshPopen(subprocess.Popen):
     def print_stdout(self) -> None:
         self.print_stdio_with_prefix(self.stdout)
 
+    def output_lines(self) -> List[str]:
+        """
+        returns all lines printed by the process
+        blocks until both stdout and stderr are closed
+        """
+        lines = []
+        eofs = 0
+        while eofs < 2:
+            line = self.lines.get()
+            if line == EOF:
+                eofs += 1
+            else:
+                lines.append(str(line))
+        return lines
+
     def wait_until_line(self, tag: str, condition: Callable[[str], bool]) -> None:
         """
         blocks until a line matching the given condition is printed
@@ -105,6 +125,7 @@ def spawn_vmsh_command(
     cargo_executable: str = "vmsh",
     target: str = "debug",
     pin_cores: Optional[str] = None,
+    stdout: ChildFd = subprocess.PIPE,
 ) -> VmshPopen:
     if not os.path.isdir("/sys/module/kheaders"):
         subprocess.run(["sudo", "modprobe", "kheaders"])
@@ -136,6 +157,13 @@ def spawn_vmsh_command(
         cmd_quoted,
     ]
     print("$ " + " ".join(map(quote, cmd)))
-    p = VmshPopen(cmd, stdout=subprocess.PIPE, stderr=subprocess.PIPE, text=True)
+    # vmsh uses an interactive stdin as console, tests talk to it through --pts or sockets
+    p = VmshPopen(
+        cmd,
+        stdin=subprocess.DEVNULL,
+        stdout=stdout,
+        stderr=subprocess.PIPE,
+        text=True,
+    )
     p.process_stdout()
     return p